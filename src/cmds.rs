@@ -1,10 +1,67 @@
+use crate::config;
+use crate::lockfile;
+use crate::plan::{self as plans, Action, Kind, Plan};
+use crate::utils::backup;
+use crate::utils::brew;
+use crate::utils::crypt;
+use crate::utils::defaults;
+use crate::utils::diff;
+use crate::utils::dottyignore;
+use crate::utils::dry_run;
 use crate::utils::fs;
 use crate::utils::git;
+use crate::utils::graph;
+use crate::utils::hosting;
+use crate::utils::ipc;
+use crate::utils::known_hosts;
+use crate::utils::launchd;
+use crate::utils::lock;
+use crate::utils::manifest;
+use crate::utils::notification;
+use crate::utils::packages::Manager;
 use crate::utils::path;
+use crate::utils::profile;
+use crate::utils::prompt;
+use crate::utils::secrets;
+use crate::utils::selinux;
+use crate::utils::snapshot;
+use crate::utils::state;
+use crate::utils::string;
+use crate::utils::systemd;
+use crate::utils::template;
+use crate::utils::timings::{self, Phase};
+use crate::utils::users;
+use crate::utils::watcher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Instant, SystemTime};
 
-pub fn init(repo: &Path) -> Result<(), String> {
-    git::init_or_open(repo)?;
+pub fn init(repo: &Path, url: Option<&str>, identity: config::Identity) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "initialize dotty repository {}",
+        repo.display()
+    )) {
+        return Ok(());
+    }
+    let git_repo = git::init_or_open(repo)?;
+    if identity != config::Identity::default() {
+        let mut config = config::load(repo)?;
+        config.identity = Some(identity);
+        config::save(repo, &config)?;
+        apply_identity(repo, &git_repo)?;
+    }
+    if let Some(url) = url {
+        git::set_remote(&git_repo, url)?;
+        create_remote(url)?;
+        if git::head_id(&git_repo)?.is_some() {
+            git::push(&git_repo, None, false)?;
+        }
+    }
 
     log::info!(
         "successfully initialized dotty repository {}",
@@ -13,8 +70,26 @@ pub fn init(repo: &Path) -> Result<(), String> {
     Ok(())
 }
 
-pub fn clone(repo: &Path, url: &str) -> Result<(), String> {
-    git::clone_recurse(repo, url)?;
+pub fn clone(
+    repo: &Path,
+    url: &str,
+    branch: Option<&str>,
+    shallow_submodules: bool,
+    locked: bool,
+) -> Result<(), String> {
+    if dry_run::skip(format_args!("clone {} into {}", url, repo.display())) {
+        return Ok(());
+    }
+    let git_repo = git::clone_recurse(repo, url, branch, shallow_submodules)?;
+    if let Some(branch) = branch {
+        let mut local = config::load_local(repo)?;
+        local.branch = Some(branch.to_owned());
+        save_local(repo, &local)?;
+    }
+    apply_identity(repo, &git_repo)?;
+    if locked {
+        apply_lock(repo)?;
+    }
     // Check that it is a valid dotty repository
     log::info!(
         "successfully cloned dotty repository {} from {}",
@@ -24,19 +99,303 @@ pub fn clone(repo: &Path, url: &str) -> Result<(), String> {
     Ok(())
 }
 
-pub fn add(repo: &Path, root: &Path, paths: &Vec<PathBuf>) -> Result<(), String> {
+/// Offers to create the repository on GitHub or GitLab when it does not exist yet
+fn create_remote(url: &str) -> Result<(), String> {
+    let Some(hosted) = hosting::parse(url) else {
+        return Ok(());
+    };
+    let Some(token) = hosting::token(hosted.provider) else {
+        log::debug!(
+            "no {} token to check that {} exists",
+            hosted.provider,
+            hosted
+        );
+        return Ok(());
+    };
+    if hosting::exists(&hosted, &token)? {
+        return Ok(());
+    }
+    if !prompt::proceed(&format!("Create private repository {}?", hosted))? {
+        return Ok(());
+    }
+    hosting::create(&hosted, &token)?;
+    log::info!("successfully created repository {}", hosted);
+    Ok(())
+}
+
+/// Sets the identity and signing in dotty.yaml, if any, in the git config of the repository
+fn apply_identity(repo: &Path, git_repo: &git2::Repository) -> Result<(), String> {
+    let Some(identity) = config::load(repo)?.identity else {
+        return Ok(());
+    };
+    git::set_identity(
+        git_repo,
+        identity.name.as_deref(),
+        identity.email.as_deref(),
+        identity.sign.map(|sign| match sign {
+            config::Signing::Gpg => "openpgp",
+            config::Signing::Ssh => "ssh",
+        }),
+        identity.signing_key.as_deref(),
+    )
+}
+
+#[derive(Default)]
+pub struct AddOptions<'a> {
+    pub defaults_domains: &'a [String],
+    pub migrate_from: Option<&'a Path>,
+    pub into_package: Option<&'a str>,
+    pub into_profile: Option<&'a str>,
+    pub into_host: bool,
+    pub os: Option<config::Os>,
+    pub hosts: &'a [String],
+    pub target: Option<&'a Path>,
+    pub pre_restore: Option<&'a str>,
+    pub post_restore: Option<&'a str>,
+    pub allow_secrets: bool,
+    pub force: bool,
+    pub copy: bool,
+    pub no_commit: bool,
+    pub message: Option<&'a str>,
+    pub interactive: bool,
+    pub as_dir: bool,
+    pub stored_as: Option<&'a Path>,
+    pub hardlink: bool,
+    pub encrypt: bool,
+    pub tags: &'a [String],
+    pub vendor: bool,
+}
+
+impl AddOptions<'_> {
+    /// Whether the options are recorded in the dotty.yaml entries of the added paths
+    fn is_recorded(&self) -> bool {
+        self.os.is_some()
+            || !self.hosts.is_empty()
+            || self.target.is_some()
+            || self.pre_restore.is_some()
+            || self.post_restore.is_some()
+            || self.copy
+            || self.hardlink
+            || self.as_dir
+            || self.stored_as.is_some()
+            || self.encrypt
+            || !self.tags.is_empty()
+    }
+
+    fn method(&self) -> fs::Method {
+        match (self.copy, self.hardlink) {
+            (true, _) => fs::Method::Copy,
+            (false, true) => fs::Method::Hardlink,
+            (false, false) => fs::Method::Symlink,
+        }
+    }
+}
+
+/// Modes that a git checkout reproduces with the usual umask, so are not recorded
+const CHECKOUT_MODES: [u32; 2] = [0o644, 0o755];
+
+pub fn add(repo: &Path, root: &Path, paths: &[PathBuf], opts: &AddOptions) -> Result<(), String> {
+    let mut paths = paths.to_vec();
+    if opts.interactive {
+        paths.append(&mut pick_paths_to_add(repo, root)?);
+        if paths.is_empty() {
+            log::info!("no paths selected to add");
+            return Ok(());
+        }
+    }
+
+    if opts.target.is_some() && paths.len() > 1 {
+        return Err("a target can only be given for a single path".to_owned());
+    }
+
+    if opts.encrypt && crypt::load_key(git::open(repo)?.path())?.is_none() {
+        return Err(format!(
+            "not adding unencrypted files to dotty repository {}, use dotty crypt init first",
+            repo.display()
+        ));
+    }
+    if !opts.no_commit {
+        check_nothing_else_staged(&git::open(repo)?, &[])?;
+    }
+
     let mut to_commit: Vec<PathBuf> = Vec::new();
     let mut submodules: Vec<PathBuf> = Vec::new();
 
-    for (path, path_type) in flatten_paths_to_add(paths)? {
-        match move_to_dotty_repo(repo, root, &path) {
+    if !opts.defaults_domains.is_empty() {
+        to_commit.append(&mut export_defaults(repo, opts.defaults_domains)?);
+    }
+
+    let config = config::load(repo)?;
+    let mut paths_to_add = Vec::new();
+    let mut listed = Vec::new();
+    let mut migrated = Vec::new();
+    let migrate_from = opts.migrate_from.map(path::canonicalize).transpose()?;
+    if let Some(old_git_repo) = migrate_from.as_deref().and_then(|old| git::open(old).ok()) {
+        check_nothing_else_staged(&old_git_repo, &[])?;
+    }
+    // only listed entries are managed, so what dotty.yaml cannot list stays where it is
+    for path in &paths {
+        check_recordable(&config.relative_path(root, &path::absolute(path)?)?)?;
+    }
+    for path in &paths {
+        let link = path::absolute(path)?;
+        match fs::symlink_target(&link)? {
+            Some(target) if !target.starts_with(repo) => match &migrate_from {
+                Some(old_repo) if target.starts_with(old_repo) => {
+                    let relative_path = config.relative_path(root, &link)?;
+                    log::debug!(
+                        "migrating {} from {} to {}",
+                        link.display(),
+                        target.display(),
+                        repo.display()
+                    );
+                    fs::migrate(&link, &target, &repo.join(&relative_path))?;
+                    migrated.push(path::relative_from_root(old_repo, &target)?);
+                    listed.push(relative_path.clone());
+                    to_commit.push(relative_path);
+                }
+                _ => log::warn!(
+                    "{} is a symlink to {}; use --migrate-from to move it into {}",
+                    link.display(),
+                    target.display(),
+                    repo.display()
+                ),
+            },
+            Some(_) => {
+                log::debug!("{} already added.", link.display());
+                listed.push(config.relative_path(root, &link)?);
+            }
+            None => paths_to_add.push(path.to_owned()),
+        }
+    }
+
+    let stored_as = match opts.stored_as {
+        Some(stored_as) => Some(check_stored_as(stored_as, &paths_to_add)?),
+        None => None,
+    };
+
+    if let Some(name) = opts.into_package {
+        check_package_name(name)?;
+        let mut local = config::load_local(repo)?;
+        if !local.enabled.iter().any(|enabled| enabled == name) {
+            log::info!("enabling package {} on this machine", name);
+            local.enabled.push(name.to_owned());
+            save_local(repo, &local)?;
+        }
+    }
+    let target = match (opts.into_package, opts.into_profile, opts.into_host) {
+        (Some(name), _, _) => repo.join(config::Config::package_path(name)),
+        (None, Some(name), _) => repo.join(config::Config::profile_path(name)),
+        (None, None, true) => repo.join(config::Config::host_path(&profile::hostname())),
+        (None, None, false) => repo.to_owned(),
+    };
+    let ignore = dottyignore::load(repo, root)?;
+    let flattened = flatten_paths_to_add(&paths_to_add, Some(&ignore))?;
+    let files: Vec<PathBuf> = flattened
+        .iter()
+        .filter(|(_, path_type)| *path_type == PathType::File)
+        .map(|(path, _)| path.clone())
+        .collect();
+    check_limits(
+        config.limits.as_ref().unwrap_or(&config::Limits::default()),
+        &files,
+        opts.force,
+    )?;
+    check_secrets(repo, &files, opts.allow_secrets)?;
+    run_hook(repo, root, "pre-add", &files)?;
+    let mut modes = Vec::new();
+    let mut owners = Vec::new();
+    if !opts.as_dir {
+        for file in &files {
+            let relative_path = config.relative_path(root, file)?;
+            let mode = fs::mode(file)?;
+            let (uid, gid) = fs::owner(file)?;
+            let is_default_mode = CHECKOUT_MODES.contains(&mode);
+            let is_owned = uid == users::current_uid() && gid == users::current_gid();
+            if (!is_default_mode || !is_owned) && check_recordable(&relative_path).is_err() {
+                log::warn!(
+                    "not recording the mode and owner of {} in {}, only UTF-8 paths can be",
+                    relative_path.display(),
+                    config::FILE_NAME
+                );
+                continue;
+            }
+            if !is_default_mode {
+                modes.push((relative_path.clone(), mode));
+            }
+            if !is_owned {
+                owners.push((relative_path, uid, gid));
+            }
+        }
+    }
+    let given: Vec<PathBuf> = paths_to_add
+        .iter()
+        .map(|p| path::absolute(p))
+        .collect::<Result<_, _>>()?;
+    let mut moved = Vec::new();
+    let mut vendored = Vec::new();
+    let to_move = match opts.as_dir {
+        true => whole_dirs_to_add(&paths_to_add)?,
+        false => flattened,
+    };
+    for (path, path_type) in to_move {
+        let stored_path = match &stored_as {
+            Some((added, stored_as)) => Some(stored_path(added, stored_as, &path)?),
+            None => None,
+        };
+        let vendor_url = match (opts.vendor, &path_type) {
+            (true, PathType::GitRepo) => match git::remote_url(&git::open(&path)?, None) {
+                Some(url) => Some(url),
+                None => {
+                    log::warn!(
+                        "not vendoring {}, it has no origin to revendor from",
+                        path.display()
+                    );
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        // directories move whole, so what add skips in them is kept out of git instead
+        let skipped: Vec<PathBuf> = match (opts.as_dir, &path_type) {
+            (true, PathType::Dir) => flatten_paths_to_add(&vec![path.clone()], None)?
+                .into_iter()
+                .map(|(file, _)| file)
+                .filter(|file| !files.contains(file))
+                .collect(),
+            _ => Vec::new(),
+        };
+        match move_to_dotty_repo(
+            repo,
+            &target,
+            &config,
+            root,
+            &path,
+            stored_path.as_deref(),
+            opts.method(),
+        ) {
             Ok(Some(relative_path)) => {
-                if path_type == PathType::GitRepo {
+                if let Some(url) = vendor_url {
+                    log::debug!("vendoring {} from {}", relative_path.display(), url);
+                    let vendored_repo = repo.join(&relative_path);
+                    let commit = git::head_id(&git::open(&vendored_repo)?)?;
+                    fs::remove_all(&vendored_repo.join(".git"))?;
+                    vendored.push((relative_path.clone(), url, commit));
+                } else if path_type == PathType::GitRepo {
                     submodules.push(relative_path.clone())
                 }
+                for file in skipped {
+                    let skipped_path = relative_path.join(path::relative_from_root(&path, &file)?);
+                    log::debug!("excluding {} from git", skipped_path.display());
+                    git::exclude(&git::open(repo)?, &format!("/{}", skipped_path.display()))?;
+                }
                 to_commit.push(relative_path);
+                moved.push(path);
+            }
+            Ok(None) => {
+                log::debug!("{} already added.", path.display());
+                moved.push(path);
             }
-            Ok(None) => log::debug!("{} already added.", path.display()),
             Err(err) => log::warn!(
                 "failed to add {} to repo {} - {}",
                 path.display(),
@@ -46,60 +405,323 @@ pub fn add(repo: &Path, root: &Path, paths: &Vec<PathBuf>) -> Result<(), String>
         }
     }
 
+    for added in given
+        .iter()
+        .filter(|g| moved.iter().any(|m| m.starts_with(g)))
+    {
+        listed.push(config.relative_path(root, added)?);
+    }
+    // staged first, so dotty.yaml only lists the paths once they are in the index
+    let git_repo = git::open(repo)?;
     if !to_commit.is_empty() {
-        let git_repo = git::open(repo)?;
-        git::unstage_all(&git_repo)?;
         git::add_submodules(&git_repo, &submodules)?;
         git::stage_all_paths(&git_repo, &to_commit)?;
-        git::commit(&git_repo, &build_git_message(&to_commit))?;
+    }
+    // the entries of a repository without dotty.yaml are listed when first loaded, with these
+    let config_path = Path::new(config::FILE_NAME);
+    let entries_changed = record_entries(repo, config, &listed, &modes, &owners, &vendored, opts)?
+        || (repo.join(config_path).exists() && git::is_modified(&git_repo, config_path)?);
+
+    if !to_commit.is_empty() || entries_changed {
+        let mut to_stage = Vec::new();
+        let vendored_commits: Vec<(PathBuf, String, String)> = vendored
+            .iter()
+            .filter_map(|(path, url, commit)| Some((path.clone(), url.clone(), commit.clone()?)))
+            .collect();
+        if lock_vendored(repo, &vendored_commits)?
+            | (!submodules.is_empty() && refresh_lock(repo, &git_repo)?)
+        {
+            to_stage.push(PathBuf::from(lockfile::FILE_NAME));
+        }
+        if entries_changed {
+            to_stage.push(PathBuf::from(config::FILE_NAME));
+        }
+        git::stage_all_paths(&git_repo, &to_stage)?;
+        let added = match to_commit.len() {
+            0 => format!("the entries in {}", config::FILE_NAME),
+            1 => to_commit.first().unwrap().display().to_string(),
+            _ => format!("{} paths", to_commit.len()),
+        };
+        if opts.no_commit {
+            log::info!(
+                "staged {} in dotty repository {}, use dotty commit to commit",
+                added,
+                repo.display()
+            );
+        } else {
+            let paths_message = match to_commit.is_empty() {
+                true => format!("updating entries in {}", config::FILE_NAME),
+                false => build_git_message(&to_commit),
+            };
+            let message = match opts.message {
+                Some(message) => format!("{}\n\n{}", message, paths_message),
+                None => paths_message,
+            };
+            git::commit(&git_repo, &message)?;
+            log::info!(
+                "successfully added {} to dotty repository {}",
+                added,
+                repo.display()
+            );
+        }
+    }
+    let added_files: Vec<PathBuf> = git::tracked_files(&git_repo)?
+        .into_iter()
+        .filter(|file| to_commit.iter().any(|added| file.starts_with(added)))
+        .map(|file| repo.join(file))
+        .collect();
+    record_in_manifest(&added_files)?;
 
+    if let (Some(old_repo), false) = (&migrate_from, migrated.is_empty()) {
+        let old_git_repo = git::open(old_repo)?;
+        git::remove_all_paths(&old_git_repo, &migrated)?;
+        git::commit(&old_git_repo, &build_migrate_message(&migrated, repo))?;
         log::info!(
-            "successfully added {} to dotty repository {}",
-            if to_commit.len() == 1 {
-                to_commit.first().unwrap().display().to_string()
-            } else {
-                format!("{} paths", to_commit.len())
-            },
-            repo.display()
+            "successfully migrated {} paths from dotty repository {}",
+            migrated.len(),
+            old_repo.display()
         );
     }
 
-    Ok(())
+    let added: Vec<PathBuf> = to_commit.iter().map(|p| repo.join(p)).collect();
+    run_hook(repo, root, "post-add", &added)
 }
 
-pub fn restore(repo: &Path, root: &Path, symlinks: bool, overwrite: bool) -> Result<(), String> {
-    let overwrite = match overwrite {
-        true => Some(fs::create_overwrite_temp_dir("dotty-")?),
+/// Fails for paths dotty.yaml cannot hold, since it is UTF-8
+///
+/// Paths stay OsStr through git and the filesystem, but dotty.yaml, state.json and manifest.json
+/// are UTF-8, so files with other names are only managed within a directory that is listed.
+fn check_recordable(relative_path: &Path) -> Result<(), String> {
+    match relative_path.to_str() {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "cannot record {} in {} - the path is not valid UTF-8",
+            relative_path.display(),
+            config::FILE_NAME
+        )),
+    }
+}
+
+/// Lists the added paths in dotty.yaml, along with how they are restored and the modes and owners of their files
+///
+/// Takes the config loaded before adding, as loading a repository without dotty.yaml again would
+/// list what was just added file by file
+fn record_entries(
+    repo: &Path,
+    mut config: config::Config,
+    relative_paths: &[PathBuf],
+    modes: &[(PathBuf, u32)],
+    owners: &[(PathBuf, u32, u32)],
+    vendored: &[(PathBuf, String, Option<String>)],
+    opts: &AddOptions,
+) -> Result<bool, String> {
+    // loading a missing dotty.yaml now would list and save the added paths
+    let original = match repo.join(config::FILE_NAME).exists() {
+        true => Some(config::load(repo)?),
         false => None,
     };
+    for relative_path in relative_paths {
+        if !opts.is_recorded() && config.find_entry(relative_path).is_some() {
+            continue;
+        }
+        check_recordable(relative_path)?;
+        let entry = config.entry_mut(relative_path);
+        if let Some(os) = opts.os {
+            log::debug!("restricting {} to {}", relative_path.display(), os);
+            entry.os = Some(os);
+        }
+        for host in opts.hosts {
+            if !entry.hosts.contains(host) {
+                log::debug!("restricting {} to host {}", relative_path.display(), host);
+                entry.hosts.push(host.clone());
+            }
+        }
+        if let Some(target) = opts.target {
+            log::debug!(
+                "restoring {} to {}",
+                relative_path.display(),
+                target.display()
+            );
+            entry.target = Some(target.to_owned());
+        }
+        if let Some(command) = opts.pre_restore {
+            log::debug!(
+                "running {} before restoring {}",
+                command,
+                relative_path.display()
+            );
+            entry.hooks.pre_restore = Some(command.to_owned());
+        }
+        if let Some(command) = opts.post_restore {
+            log::debug!(
+                "running {} after restoring {}",
+                command,
+                relative_path.display()
+            );
+            entry.hooks.post_restore = Some(command.to_owned());
+        }
+        if opts.copy {
+            log::debug!("restoring {} as a copy", relative_path.display());
+            entry.copy = true;
+        }
+        if opts.hardlink {
+            log::debug!("restoring {} as a hard link", relative_path.display());
+            entry.hardlink = true;
+        }
+        if opts.encrypt {
+            log::debug!("requiring {} to be encrypted", relative_path.display());
+            entry.encrypt = true;
+        }
+        for tag in opts.tags {
+            if !entry.tags.contains(tag) {
+                log::debug!("tagging {} with {}", relative_path.display(), tag);
+                entry.tags.push(tag.clone());
+            }
+        }
+        if opts.as_dir {
+            log::debug!("restoring {} as a directory", relative_path.display());
+            entry.directory = true;
+        }
+        if let Some(stored_as) = opts.stored_as {
+            log::debug!(
+                "storing {} as {}",
+                relative_path.display(),
+                stored_as.display()
+            );
+            entry.source = Some(stored_as.to_owned());
+        }
+    }
+    for (relative_path, mode) in modes {
+        log::debug!("recording mode {:04o} of {}", mode, relative_path.display());
+        config.entry_mut(relative_path).mode = Some(format!("{:04o}", mode));
+    }
+    for (relative_path, uid, gid) in owners {
+        let entry = config.entry_mut(relative_path);
+        entry.owner = Some(users::user_name(*uid));
+        entry.group = Some(users::group_name(*gid));
+        log::debug!(
+            "recording owner {}:{} of {}",
+            entry.owner.as_deref().unwrap_or_default(),
+            entry.group.as_deref().unwrap_or_default(),
+            relative_path.display()
+        );
+    }
+    for (relative_path, url, _) in vendored {
+        config.vendored.insert(relative_path.clone(), url.clone());
+    }
+    if original.as_ref() == Some(&config) {
+        return Ok(false);
+    }
+    config::save(repo, &config)?;
+    Ok(true)
+}
 
-    let top_level_repo_paths = fs::read_dir(repo)?
-        .into_iter()
-        .filter(|p| {
-            p.file_name()
-                .and_then(|f| f.to_str())
-                .map(|f| !matches!(f, ".git" | ".gitmodules"))
-                .unwrap_or(true)
-        })
-        .collect();
-    let paths_to_restore: Vec<PathBuf> = flatten_paths_to_add(&top_level_repo_paths)?
-        .into_iter()
-        .map(|x| x.0)
-        .collect();
+#[derive(Default)]
+pub struct RestoreOptions {
+    pub symlinks: bool,
+    pub overwrite: bool,
+    pub pick: bool,
+    pub relabel: bool,
+    pub preview: bool,
+    pub snapshot: bool,
+    pub exclude: Vec<String>,
+    pub preserve_times: Option<fs::PreserveTimes>,
+    pub interactive: bool,
+    pub tags: Vec<String>,
+    pub locked: bool,
+    pub prune: bool,
+    pub commit: bool,
+}
 
-    for from in paths_to_restore {
-        let relative_path = path::relative_from_root(repo, &from)?;
-        let to = root.join(&relative_path);
-        let overwrite_entry = overwrite.as_ref().map(|o| o.entry(&relative_path));
-        log::debug!("restoring {} to {}", from.display(), to.display());
-        fs::restore(&from, &to, overwrite_entry.as_deref(), symlinks)?;
+pub fn restore(repo: &Path, root: &Path, opts: &RestoreOptions) -> Result<(), String> {
+    if opts.locked {
+        apply_lock(repo)?;
+    }
+    let config = config::load(repo)?;
+    let mut entries = managed_entries(repo, &config)?;
+
+    if !opts.exclude.is_empty() {
+        let excluded = dottyignore::from_patterns(root, &opts.exclude)?;
+        entries.retain(|e| {
+            let to = e.destination(root);
+            let is_excluded = excluded.is_ignored(&to);
+            if is_excluded {
+                log::debug!("excluding {}", to.display());
+            }
+            !is_excluded
+        });
+    }
+    if !opts.tags.is_empty() {
+        entries.retain(|e| is_tagged(&config, e, &opts.tags));
+    }
+
+    if opts.pick {
+        entries = pick_entries_to_restore(root, entries)?;
+    }
+
+    if opts.preview {
+        preview_restore(repo, root, &config, &entries, opts.symlinks, opts.overwrite)?;
+        if !prompt::confirm("Apply these changes?")? {
+            log::info!("restore cancelled");
+            return Ok(());
+        }
+    }
+
+    let overwrite = opts.overwrite || opts.interactive;
+    let mut plan = build_plan(
+        repo,
+        root,
+        &config,
+        &entries,
+        opts.symlinks,
+        overwrite,
+        opts.prune,
+    )?;
+    plan.preserve_times = opts.preserve_times;
+    if opts.interactive {
+        resolve_conflicts(repo, &config, &mut plan)?;
+    } else if opts.overwrite && !confirm_overwrite(&plan)? {
+        log::info!("restore cancelled");
+        return Ok(());
+    }
+
+    if opts.snapshot
+        && opts.overwrite
+        && !dry_run::skip(format_args!("snapshot {}", root.display()))
+    {
+        let snapshot = snapshot::create(root)?;
+        log::info!(
+            "created {:?} snapshot {} of {}",
+            snapshot.kind,
+            snapshot.name,
+            root.display()
+        );
+    }
+
+    let planned: Vec<PathBuf> = plan.actions.iter().map(|a| a.to.clone()).collect();
+    run_hook(repo, root, "pre-restore", &planned)?;
+    run_entry_hooks(repo, root, &config, &entries, &planned, false)?;
+
+    let deployed = execute_plan(&plan)?;
+
+    if opts.relabel {
+        match selinux::is_enforcing() {
+            true => selinux::restorecon(&deployed)?,
+            false => log::debug!("selinux is not enforcing; skipping relabel"),
+        }
+    }
+
+    run_entry_hooks(repo, root, &config, &entries, &deployed, true)?;
+    run_hook(repo, root, "post-restore", &deployed)?;
+    if opts.commit {
+        commit_staged(repo)?;
     }
 
     log::info!(
         "successfully restored dotty repository {} to {}, by {}",
         repo.display(),
         root.display(),
-        match symlinks {
+        match opts.symlinks {
             true => "creating symlinks",
             false => "copying files",
         }
@@ -107,90 +729,4729 @@ pub fn restore(repo: &Path, root: &Path, symlinks: bool, overwrite: bool) -> Res
     Ok(())
 }
 
-pub fn sync(repo: &Path, url: Option<&str>) -> Result<(), String> {
-    let git_repo = git::open(repo)?;
-    git::sync(&git_repo, url)?;
-    log::info!("successfully synced dotty repository");
+pub fn link(repo: &Path, root: &Path, paths: &[PathBuf]) -> Result<(), String> {
+    let config = config::load(repo)?;
+    let entries = select_entries(&config, root, managed_entries(repo, &config)?, paths)?;
+
+    let mut state = state::load()?;
+    let mut linked = 0;
+    for entry in entries {
+        let to = entry.destination(root);
+        match fs::deploy_status(&entry.from, &to)? {
+            fs::DeployStatus::Missing => {
+                fs::restore(&entry.from, &to, None, fs::Method::Symlink, None)?;
+                log::info!("linked {} to {}", to.display(), entry.from.display());
+                state.record(state::Deployed {
+                    repository: repo.to_owned(),
+                    checksum: state::checksum(&to)?,
+                    baseline: None,
+                    mode: fs::mode(&to).ok().map(|mode| format!("{:04o}", mode)),
+                    entry: entry.from,
+                    target: to,
+                    method: state::Method::Symlink,
+                    timestamp: chrono::Utc::now(),
+                });
+                linked += 1;
+            }
+            status => log::debug!("not linking {}, it is {}", to.display(), status),
+        }
+    }
+    if linked > 0 {
+        state::save(&state)?;
+    }
+
+    match linked {
+        0 => log::info!("there are no missing links in {}", root.display()),
+        _ => log::info!("successfully linked {} missing entries", linked),
+    }
     Ok(())
 }
 
-pub fn update(repo: &Path) -> Result<(), String> {
+/// Lists every file in the repository and its overlays that has no entry in dotty.yaml
+pub fn backfill(repo: &Path) -> Result<(), String> {
+    let mut config = config::load(repo)?;
+    let backfilled = backfill_entries(repo, &mut config)?;
+    if backfilled.is_empty() {
+        log::info!("every entry is already listed in {}", config::FILE_NAME);
+        return Ok(());
+    }
+
     let git_repo = git::open(repo)?;
+    check_nothing_else_staged(&git_repo, &[])?;
+    config::save(repo, &config)?;
+    git::stage_all_paths(&git_repo, &vec![PathBuf::from(config::FILE_NAME)])?;
+    git::commit(
+        &git_repo,
+        &format!(
+            "listing {} existing entries in {}",
+            backfilled.len(),
+            config::FILE_NAME
+        ),
+    )?;
+    log::info!(
+        "successfully listed {} entries in {}",
+        backfilled.len(),
+        config::FILE_NAME
+    );
+    Ok(())
+}
 
-    git::unstage_all(&git_repo)?;
-    let updated = git::update_submodules(&git_repo)?;
-    if updated > 0 {
-        git::commit(&git_repo, "Updated all submodules")?;
-        log::info!("successfully updated {} submodules", updated);
-    } else {
-        log::warn!("there are no submodules to update");
+/// Adds an entry for every file in the repository and its overlays that has none, returning their paths
+///
+/// Also migrates configs from before dotty.yaml listed every entry, so it must not load the config.
+pub fn backfill_entries(repo: &Path, config: &mut config::Config) -> Result<Vec<PathBuf>, String> {
+    let mut backfilled = Vec::new();
+    let layers = layer_paths(repo, config)?;
+    for source in personal_sources(repo)? {
+        for from in managed_paths(&source, &layers)? {
+            let destination = config.destination(&path::relative_from_root(&source, &from)?);
+            let relative_path = template::rendered_path(&destination);
+            if config.find_entry(&relative_path).is_some()
+                || config.find_entry(&destination).is_some()
+            {
+                continue;
+            }
+            if check_recordable(&relative_path).is_err() {
+                log::warn!(
+                    "not listing {} in {}, only UTF-8 paths can be",
+                    relative_path.display(),
+                    config::FILE_NAME
+                );
+                continue;
+            }
+            log::debug!("backfilling {}", relative_path.display());
+            config.entry_mut(&relative_path);
+            backfilled.push(relative_path);
+        }
     }
+    Ok(backfilled)
+}
 
+/// Restores the packages on this machine from now on
+pub fn enable(repo: &Path, names: &[String]) -> Result<(), String> {
+    let mut local = config::load_local(repo)?;
+    for name in names {
+        check_package_name(name)?;
+        if local.enabled.contains(name) {
+            log::info!("package {} is already enabled", name);
+            continue;
+        }
+        if !repo.join(config::Config::package_path(name)).is_dir() {
+            log::warn!(
+                "package {} does not exist in dotty repository {}",
+                name,
+                repo.display()
+            );
+        }
+        local.enabled.push(name.clone());
+    }
+    save_local(repo, &local)?;
+    log::info!(
+        "successfully enabled {}, use dotty restore to restore them",
+        names.join(", ")
+    );
     Ok(())
 }
 
-#[derive(PartialEq)]
-enum PathType {
-    File,
-    GitRepo,
+/// Stops restoring the packages on this machine, removing their symlinks
+pub fn disable(repo: &Path, root: &Path, names: &[String]) -> Result<(), String> {
+    let config = config::load(repo)?;
+    let entries = managed_entries(repo, &config)?;
+    let mut local = config::load_local(repo)?;
+    for name in names {
+        if !local.enabled.contains(name) {
+            log::info!("package {} is not enabled", name);
+            continue;
+        }
+        local.enabled.retain(|enabled| enabled != name);
+
+        let suffix = format!("/{}", name);
+        for entry in entries.iter().filter(|e| e.layer.ends_with(&suffix)) {
+            let to = entry.destination(root);
+            match fs::symlink_target(&to)? {
+                Some(target) if target == entry.from => {
+                    log::debug!("removing symlink {}", to.display());
+                    fs::remove(&to)?;
+                    fs::remove_empty_parents(&to, root)?;
+                }
+                _ => log::debug!("leaving {} in place", to.display()),
+            }
+        }
+    }
+    save_local(repo, &local)?;
+    log::info!("successfully disabled {}", names.join(", "));
+    Ok(())
+}
+
+fn check_package_name(name: &str) -> Result<(), String> {
+    match name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        true => Err(format!("invalid package name {}", name)),
+        false => Ok(()),
+    }
+}
+
+/// Saves dotty.local.yaml, keeping it out of the repository
+fn save_local(repo: &Path, local: &config::Local) -> Result<(), String> {
+    config::save_local(repo, local)?;
+    git::exclude(&git::open(repo)?, &format!("/{}", config::LOCAL_FILE_NAME))
 }
 
-fn flatten_paths_to_add(paths: &Vec<PathBuf>) -> Result<Vec<(PathBuf, PathType)>, String> {
-    let mut path_stack = Vec::new();
+pub fn relink(repo: &Path, root: &Path, old_repo: Option<&Path>) -> Result<(), String> {
+    let old_repo = old_repo.map(path::absolute).transpose()?;
+    let config = config::load(repo)?;
 
-    for path in paths {
-        if !path.exists() {
-            return Err(format!("{} does not exist", path.display()));
+    let mut relinked = 0;
+    for entry in managed_entries(repo, &config)? {
+        let to = entry.destination(root);
+        let Some(target) = fs::link_target(&to)? else {
+            continue;
+        };
+        let Ok(in_repo) = entry.from.strip_prefix(repo) else {
+            continue;
+        };
+        if target == entry.from || !target.ends_with(in_repo) {
+            continue;
         }
-        path_stack.push(path::canonicalize(path)?)
+        let moved_from = target.ancestors().nth(in_repo.components().count());
+        let moved = match (&old_repo, moved_from) {
+            (Some(old_repo), Some(moved_from)) => moved_from == old_repo,
+            (None, Some(_)) => !to.exists(),
+            _ => false,
+        };
+        if !moved {
+            log::debug!(
+                "not relinking {} which points to {}",
+                to.display(),
+                target.display()
+            );
+            continue;
+        }
+        fs::relink(&to, &entry.from)?;
+        log::info!(
+            "relinked {} from {} to {}",
+            to.display(),
+            target.display(),
+            entry.from.display()
+        );
+        relinked += 1;
     }
 
-    let mut flattened: Vec<(PathBuf, PathType)> = Vec::new();
+    match relinked {
+        0 => log::info!("there are no symlinks to relink in {}", root.display()),
+        _ => log::info!(
+            "successfully relinked {} symlinks to dotty repository {}",
+            relinked,
+            repo.display()
+        ),
+    }
+    Ok(())
+}
 
-    while let Some(path) = path_stack.pop() {
-        if path.is_dir() {
-            if git::check_open(&path) {
-                flattened.push((path, PathType::GitRepo))
-            } else {
-                path_stack.append(&mut fs::read_dir(&path)?)
+pub fn remove(repo: &Path, root: &Path, paths: &[PathBuf]) -> Result<(), String> {
+    let mut config = config::load(repo)?;
+    let entries = managed_entries(repo, &config)?;
+
+    let mut to_remove = Vec::new();
+    for path in paths {
+        let absolute = path::absolute(path)?;
+        if contains_root(&config, root, &absolute) {
+            return Err(format!(
+                "{} contains a root and cannot be removed",
+                path.display()
+            ));
+        }
+        let relative = config.relative_path(root, &absolute)?;
+        let matching: Vec<_> = entries
+            .iter()
+            .filter(|e| e.relative_path.starts_with(&relative))
+            .collect();
+        if matching.is_empty() {
+            return Err(format!("{} is not managed by dotty", path.display()));
+        }
+        for entry in matching {
+            if !is_personal(&entry.layer) {
+                return Err(format!(
+                    "{} comes from layer {} and cannot be removed",
+                    entry.relative_path.display(),
+                    entry.layer
+                ));
             }
-        } else {
-            flattened.push((path, PathType::File))
+            if git::check_open(&entry.from) {
+                return Err(format!(
+                    "{} is a submodule and cannot be removed",
+                    entry.relative_path.display()
+                ));
+            }
+            to_remove.push(entry);
         }
     }
 
-    Ok(flattened)
+    let git_repo = git::open(repo)?;
+    check_nothing_else_staged(&git_repo, &[])?;
+    let saved_config = std::fs::read_to_string(repo.join(config::FILE_NAME)).ok();
+    let mut moved = Vec::new();
+    let result = move_back_entries(repo, root, &to_remove, &mut moved).and_then(|removed| {
+        commit_removal(repo, root, paths, &git_repo, &mut config, &removed).map(|_| removed)
+    });
+    let removed = match result {
+        Ok(removed) => removed,
+        Err(err) => {
+            undo_removal(repo, &git_repo, &moved, saved_config.as_deref());
+            return Err(err);
+        }
+    };
+
+    // the files moved back are no longer deployed, so restore must not prune them
+    let mut state = state::load()?;
+    for (_, to, _) in &moved {
+        state.forget(to);
+    }
+    state::save(&state)?;
+    log::info!(
+        "successfully removed {} from dotty repository {}",
+        match removed.len() {
+            1 => removed[0].display().to_string(),
+            count => format!("{} paths", count),
+        },
+        repo.display()
+    );
+    Ok(())
 }
 
-fn move_to_dotty_repo(repo: &Path, root: &Path, path: &Path) -> Result<Option<PathBuf>, String> {
-    let relative_path = path::relative_from_root(root, path)?;
-    let to = repo.join(&relative_path);
+/// Whether the path is the root, a named root or one of their parents
+fn contains_root(config: &config::Config, root: &Path, absolute: &Path) -> bool {
+    root.starts_with(absolute)
+        || config
+            .roots
+            .values()
+            .filter_map(|named_root| path::canonicalize(named_root).ok())
+            .any(|named_root| named_root.starts_with(absolute))
+}
 
-    log::debug!(
-        "moving {} to {} and then replacing with symlink",
-        path.display(),
-        to.display()
-    );
-    Ok(match fs::move_then_symlink(path, &to)? {
-        true => Some(relative_path),
-        false => None,
-    })
+/// Moves the entries out of the repository, recording each one moved so it can be undone
+fn move_back_entries(
+    repo: &Path,
+    root: &Path,
+    entries: &[&ManagedEntry],
+    moved: &mut Vec<(PathBuf, PathBuf, bool)>,
+) -> Result<Vec<PathBuf>, String> {
+    let mut removed = Vec::new();
+    for entry in entries {
+        let to = entry.destination(root);
+        log::debug!("moving {} back to {}", entry.from.display(), to.display());
+        let linked = fs::move_back(&entry.from, &to)?;
+        moved.push((entry.from.clone(), to, linked));
+        fs::remove_empty_parents(&entry.from, repo)?;
+        removed.push(path::relative_from_root(repo, &entry.from)?);
+    }
+    Ok(removed)
 }
 
-fn build_git_message(to_commit: &Vec<PathBuf>) -> String {
-    match to_commit.len() {
-        0 => String::default(),
-        1 => format!("adding {}", to_commit.first().unwrap().display()),
-        _ => {
-            let mut msg = format!(
-                "adding {} files to {}\n\n",
-                to_commit.len(),
-                path::common_base_path(to_commit).display()
+/// Commits the removed paths along with the entries of the config that named them
+fn commit_removal(
+    repo: &Path,
+    root: &Path,
+    paths: &[PathBuf],
+    git_repo: &git2::Repository,
+    config: &mut config::Config,
+    removed: &Vec<PathBuf>,
+) -> Result<(), String> {
+    git::remove_all_paths(git_repo, removed)?;
+
+    let config_entries = config.entries.len();
+    let relative_removed = paths_removed(config, root, paths);
+    config
+        .entries
+        .retain(|e| !relative_removed.iter().any(|r| e.key().starts_with(r)));
+    if config.entries.len() != config_entries {
+        config::save(repo, config)?;
+        git::stage_all_paths(git_repo, &vec![PathBuf::from(config::FILE_NAME)])?;
+    }
+
+    git::commit(git_repo, &build_remove_message(removed))?;
+    Ok(())
+}
+
+/// Puts what remove moved back into the repository, along with the config as it was
+fn undo_removal(
+    repo: &Path,
+    git_repo: &git2::Repository,
+    moved: &[(PathBuf, PathBuf, bool)],
+    saved_config: Option<&str>,
+) {
+    for (from, to, linked) in moved.iter().rev() {
+        if let Err(err) = fs::undo_move_back(from, to, *linked) {
+            log::error!(
+                "failed to move {} back into dotty repository {} - {}",
+                to.display(),
+                repo.display(),
+                err
             );
-            for path in to_commit {
-                msg.push_str(&format!("- {}\n", path.display()));
-            }
-            msg
         }
     }
+    let restore_config = match saved_config {
+        Some(contents) => fs::write(&repo.join(config::FILE_NAME), contents),
+        None => Ok(()),
+    };
+    if let Err(err) = restore_config.and(git::unstage_all(git_repo)) {
+        log::error!(
+            "failed to undo the changes to dotty repository {} - {}",
+            repo.display(),
+            err
+        );
+    }
+}
+
+/// The given paths relative to the roots, ignoring any that are not beneath one
+fn paths_removed(config: &config::Config, root: &Path, paths: &[PathBuf]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .filter_map(|p| path::absolute(p).ok())
+        .filter_map(|p| config.relative_path(root, &p).ok())
+        .collect()
+}
+
+pub fn diff(repo: &Path, root: &Path, paths: &[PathBuf], stat: bool) -> Result<(), String> {
+    let config = config::load(repo)?;
+    let entries = select_entries(&config, root, managed_entries(repo, &config)?, paths)?;
+
+    let mut rows = Vec::new();
+    let (mut total_insertions, mut total_deletions) = (0, 0);
+    for entry in entries {
+        let to = entry.destination(root);
+        let changes = match fs::deploy_status(&entry.from, &to)? {
+            fs::DeployStatus::Linked => continue,
+            fs::DeployStatus::Missing => {
+                if !stat {
+                    println!("{} is not deployed", to.display());
+                }
+                continue;
+            }
+            fs::DeployStatus::Conflict => {
+                if !stat {
+                    println!("{} is a symlink to another path", to.display());
+                }
+                continue;
+            }
+            // a copied directory differs file by file
+            fs::DeployStatus::Exists if to.is_dir() => diff::unified_dirs(&entry.from, &to)?
+                .into_iter()
+                .map(|(relative, changes)| (entry.relative_path.join(relative), changes))
+                .collect(),
+            fs::DeployStatus::Exists => match diff::unified_files(&entry.from, &to)? {
+                Some(changes) => vec![(entry.relative_path.clone(), changes)],
+                None => continue,
+            },
+        };
+
+        for (relative_path, changes) in changes {
+            if stat {
+                let (insertions, deletions) = diff::stat(&changes);
+                total_insertions += insertions;
+                total_deletions += deletions;
+                rows.push(vec![
+                    relative_path.display().to_string(),
+                    "|".to_owned(),
+                    (insertions + deletions).to_string(),
+                    diff::paint(diff::GREEN, &"+".repeat(insertions.min(40)))
+                        + &diff::paint(diff::RED, &"-".repeat(deletions.min(40))),
+                ]);
+            } else {
+                print!("{}", diff::colorize(&changes));
+            }
+        }
+    }
+
+    if stat && !rows.is_empty() {
+        print!("{}", string::format_table(&rows));
+        println!(
+            "{} files changed, {} insertions(+), {} deletions(-)",
+            rows.len(),
+            total_insertions,
+            total_deletions
+        );
+    }
+    Ok(())
+}
+
+/// Copies edits made to deployed copies back into the repository and commits them
+pub fn harvest(
+    repo: &Path,
+    root: &Path,
+    paths: &[PathBuf],
+    allow_secrets: bool,
+) -> Result<(), String> {
+    let config = config::load(repo)?;
+    let entries = select_entries(&config, root, managed_entries(repo, &config)?, paths)?;
+    let mut state = state::load()?;
+
+    // planned before copying anything, so secrets in any of the copies stop the whole harvest
+    let mut planned = Vec::new();
+    for entry in entries {
+        let to = entry.destination(root);
+        if template::is_template(&entry.from) {
+            log::debug!("not harvesting {} rendered from a template", to.display());
+            continue;
+        }
+        let changes = match fs::deploy_status(&entry.from, &to)? {
+            fs::DeployStatus::Exists if to.is_file() => {
+                match diff::unified_files(&entry.from, &to)? {
+                    Some(changes) => changes,
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+        // the record of what restore deployed tells edits to the copy from newer commits
+        let Some(record) = state.find(&to) else {
+            log::warn!(
+                "not harvesting {} as there is no record of restoring it, use dotty diff to \
+                 compare it with {}",
+                to.display(),
+                entry.relative_path.display()
+            );
+            continue;
+        };
+        if state::checksum(&to)? == record.checksum {
+            log::info!(
+                "{} is unchanged since it was restored, use dotty restore to deploy {}",
+                to.display(),
+                entry.relative_path.display()
+            );
+            continue;
+        }
+        let base = match state::checksum(&entry.from)? == record.checksum {
+            true => None,
+            false => match baseline(repo, record) {
+                Some(base) => Some(base),
+                None => {
+                    log::warn!(
+                        "not harvesting {} which changed in the repository as well, use dotty \
+                         diff to compare them",
+                        to.display()
+                    );
+                    continue;
+                }
+            },
+        };
+        planned.push((entry, to, changes, base));
+    }
+    if planned.is_empty() {
+        log::info!("there are no edited copies in {}", root.display());
+        return Ok(());
+    }
+    let copies = planned
+        .iter()
+        .map(|(_, to, _, _)| to.clone())
+        .collect::<Vec<_>>();
+    check_secrets(repo, &copies, allow_secrets)?;
+    let git_repo = git::open(repo)?;
+    check_nothing_else_staged(&git_repo, &[])?;
+
+    let mut harvested = Vec::new();
+    for (entry, to, changes, base) in planned {
+        match base {
+            Some(base) => {
+                if !merge_into_repo(&entry.from, &to, Some(&base))? {
+                    continue;
+                }
+                log::info!("merged {} with {}", to.display(), entry.from.display());
+                fs::copy(&entry.from, &to)?;
+            }
+            None => {
+                print!("{}", diff::colorize(&changes));
+                log::debug!("harvesting {} into {}", to.display(), entry.from.display());
+                fs::copy(&to, &entry.from)?;
+            }
+        }
+        if !dry_run::is_enabled() {
+            let baseline = store_baseline(repo, &to)?;
+            if let Some(record) = state.deployed.iter_mut().find(|d| d.target == to) {
+                record.checksum = state::checksum(&to)?;
+                record.baseline = baseline;
+                record.timestamp = chrono::Utc::now();
+            }
+        }
+        harvested.push(entry.from);
+    }
+    if harvested.is_empty() {
+        return Ok(());
+    }
+    state::save(&state)?;
+    keep_baselines(repo, &state)?;
+
+    let committed = harvested
+        .iter()
+        .map(|from| path::relative_from_root(repo, from))
+        .collect::<Result<Vec<_>, _>>()?;
+    git::stage_all_paths(&git_repo, &committed)?;
+    git::commit(&git_repo, &build_update_message(&committed))?;
+    log::info!(
+        "successfully harvested {} edited copies into dotty repository {}",
+        committed.len(),
+        repo.display()
+    );
+    Ok(())
+}
+
+/// Opens the repository copy of the entry best matching the query in $VISUAL or $EDITOR, then offers to commit
+pub fn edit(repo: &Path, root: &Path, query: &str, no_commit: bool) -> Result<(), String> {
+    let config = config::load(repo)?;
+    let entry = match_entry(managed_entries(repo, &config)?, query)?;
+    let own = entry.from.strip_prefix(repo).map(|p| vec![p.to_owned()]);
+    if let (false, Ok(own)) = (no_commit, &own) {
+        check_nothing_else_staged(&git::open(repo)?, own)?;
+    }
+    let before = std::fs::read(&entry.from).ok();
+
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    if dry_run::skip(format_args!(
+        "edit {} with {}",
+        entry.from.display(),
+        editor
+    )) {
+        return Ok(());
+    }
+    log::debug!("editing {} with {}", entry.from.display(), editor);
+    // like git, the editor is run by the shell so it can have quoted arguments
+    let mut cmd = dotty_command(repo, root, "sh");
+    cmd.arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&entry.from);
+    match cmd.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => return Err(format!("{} exited with {}", editor, status)),
+        Err(err) => return Err(format!("failed to run editor {} - {}", editor, err)),
+    }
+
+    let after = std::fs::read(&entry.from).ok();
+    if before == after {
+        log::info!("{} is unchanged", entry.relative_path.display());
+        return Ok(());
+    }
+    if let (Some(before), Some(after)) = (&before, &after) {
+        if let (Ok(before), Ok(after)) = (std::str::from_utf8(before), std::str::from_utf8(after)) {
+            let name = entry.relative_path.display().to_string();
+            print!(
+                "{}",
+                diff::colorize(&diff::unified(&name, &name, before, after))
+            );
+        }
+    }
+    let to = entry.destination(root);
+    if fs::deploy_status(&entry.from, &to)? != fs::DeployStatus::Linked {
+        log::info!(
+            "{} is not linked, use dotty restore to deploy the changes",
+            to.display()
+        );
+    }
+
+    let git_repo = git::open(repo)?;
+    let Some(changed) = entry
+        .from
+        .strip_prefix(repo)
+        .ok()
+        .filter(|p| git::changed_files(&git_repo).is_ok_and(|c| c.contains(&p.to_path_buf())))
+        .map(|p| p.to_owned())
+    else {
+        log::info!(
+            "not committing {} as it is outside the dotty repository",
+            entry.from.display()
+        );
+        return Ok(());
+    };
+    let confirmed = !no_commit
+        && prompt::proceed(&format!("Commit the changes to {}?", changed.display()))
+            .unwrap_or_else(|err| {
+                log::debug!("not committing - {}", err);
+                false
+            });
+    if !confirmed {
+        log::info!("use dotty commit to commit the changes later");
+        return Ok(());
+    }
+    check_secrets(repo, &[entry.from.clone()], false)?;
+    let committed = vec![changed];
+    check_nothing_else_staged(&git_repo, &committed)?;
+    git::stage_all_paths(&git_repo, &committed)?;
+    git::commit(&git_repo, &build_update_message(&committed))?;
+    log::info!(
+        "successfully committed {} in dotty repository {}",
+        committed[0].display(),
+        repo.display()
+    );
+    Ok(())
+}
+
+/// Fails when paths other than the command's own are staged, rather than committing them along or
+/// discarding what add --no-commit staged for dotty commit
+fn check_nothing_else_staged(git_repo: &git2::Repository, own: &[PathBuf]) -> Result<(), String> {
+    let staged: Vec<PathBuf> = git::staged_files(git_repo)?
+        .into_iter()
+        .filter(|staged| !own.iter().any(|path| staged.starts_with(path)))
+        .collect();
+    if staged.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<String> = staged.iter().map(|p| p.display().to_string()).collect();
+    Err(format!(
+        "{} {} staged, commit with dotty commit or unstage first",
+        names.join(", "),
+        match staged.len() {
+            1 => "is",
+            _ => "are",
+        }
+    ))
+}
+
+/// Undoes the last commit, moving the files an add took into the repository back to the root
+///
+/// Only commits dotty made are undone, and not merges. Commits already pushed are reverted with a
+/// new commit, others are dropped. The entries moved back are those whose files the commit added
+/// and that are symlinked from the root.
+pub fn undo(repo: &Path, root: &Path) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    let Some(last) = git::last_commit(&git_repo)? else {
+        return Err(format!(
+            "there is no commit to undo in dotty repository {}",
+            repo.display()
+        ));
+    };
+    if last.is_merge {
+        return Err(format!(
+            "not undoing {}, merges cannot be undone, use git revert instead",
+            last.summary
+        ));
+    }
+    if !last.by_dotty {
+        return Err(format!(
+            "not undoing {}, it was not committed by dotty",
+            last.summary
+        ));
+    }
+    if !git::changed_files(&git_repo)?.is_empty() {
+        return Err(format!(
+            "commit or discard the changes in dotty repository {} before undoing",
+            repo.display()
+        ));
+    }
+
+    let added = git::added_between(&git_repo, last.parent.as_deref(), &last.id)?;
+    let tracked = git::tracked_files(&git_repo)?;
+    // a directory only goes away with the commit when every file in it is new
+    let is_added = |relative: &Path| {
+        let mut files = tracked
+            .iter()
+            .filter(|p| p.starts_with(relative))
+            .peekable();
+        files.peek().is_some() && files.all(|p| added.contains(p))
+    };
+    let config = config::load(repo)?;
+    let entries = managed_entries(repo, &config)?;
+    let mut to_move = Vec::new();
+    for entry in &entries {
+        let Ok(relative) = entry.from.strip_prefix(repo) else {
+            continue;
+        };
+        if !is_personal(&entry.layer) || !is_added(relative) || git::check_open(&entry.from) {
+            continue;
+        }
+        let to = entry.destination(root);
+        if fs::symlink_target(&to)?.as_ref() == Some(&entry.from) {
+            to_move.push((entry.from.clone(), to));
+        }
+    }
+
+    // symlinks into the files of the commit that are not moved back would be left dangling
+    let mut state = state::load()?;
+    let links: BTreeSet<PathBuf> = entries
+        .iter()
+        .map(|entry| entry.destination(root))
+        .chain(
+            state
+                .deployed_from(repo)
+                .map(|record| record.target.clone()),
+        )
+        .collect();
+    for link in links {
+        if to_move.iter().any(|(_, to)| *to == link) {
+            continue;
+        }
+        let Some(target) = fs::symlink_target(&link)? else {
+            continue;
+        };
+        if target.strip_prefix(repo).is_ok_and(is_added) {
+            return Err(format!(
+                "not undoing {}, {} links to {} which it added, use dotty remove first",
+                last.summary,
+                link.display(),
+                target.display()
+            ));
+        }
+    }
+
+    let confirm = format!(
+        "{} {}{}?",
+        match last.pushed {
+            true => "Revert",
+            false => "Drop",
+        },
+        last.summary,
+        match to_move.len() {
+            0 => String::new(),
+            count => format!(", moving {} paths back to {}", count, root.display()),
+        }
+    );
+    if !prompt::proceed(&confirm)? {
+        return Err("undo cancelled".to_owned());
+    }
+
+    let keep: Vec<PathBuf> = to_move
+        .iter()
+        .map(|(from, _)| path::relative_from_root(repo, from))
+        .collect::<Result<_, _>>()?;
+    match last.pushed {
+        true => {
+            git::revert_last_commit(&git_repo, &format!("reverting {}", last.summary), &keep)?;
+        }
+        false => git::drop_last_commit(&git_repo, &keep)?,
+    }
+
+    // the commit is undone, so what is left is moving the files out of the repository
+    for (from, to) in &to_move {
+        log::debug!("moving {} back to {}", from.display(), to.display());
+        if let Err(err) = fs::move_back(from, to) {
+            state::save(&state)?;
+            return Err(format!(
+                "undid {} but failed to move {} back, it is untracked in dotty repository {} - {}",
+                last.summary,
+                from.display(),
+                repo.display(),
+                err
+            ));
+        }
+        fs::remove_empty_parents(from, repo)?;
+        state.forget(to);
+    }
+    // nor is anything else the commit added, such as copies, deployed from the repository
+    state.deployed.retain(|record| {
+        record.repository != repo || !record.entry.strip_prefix(repo).is_ok_and(is_added)
+    });
+    state::save(&state)?;
+    log::info!(
+        "successfully undid {}{}",
+        last.summary,
+        match to_move.len() {
+            0 => String::new(),
+            count => format!(", moving {} paths back to {}", count, root.display()),
+        }
+    );
+    Ok(())
+}
+
+/// Checks the objects of the repository, then the managed files and deployed copies against the manifest
+///
+/// Files edited since the last verify are recorded again, as their modification time changed.
+pub fn verify(repo: &Path) -> Result<(), String> {
+    let mut problems = 0;
+    for problem in git::verify(&git::open(repo)?)? {
+        println!("{}", problem);
+        problems += 1;
+    }
+
+    let config = config::load(repo)?;
+    let mut paths: BTreeSet<PathBuf> = managed_entries(repo, &config)?
+        .into_iter()
+        .map(|entry| entry.from)
+        .filter(|from| from.is_file())
+        .collect();
+    paths.extend(
+        state::load()?
+            .deployed_from(repo)
+            .filter(|record| record.method != state::Method::Symlink)
+            .map(|record| record.target.clone()),
+    );
+    let git_repo = git::open(repo)?;
+    let mut manifest = manifest::load()?;
+    let previous = std::mem::take(&mut manifest.files);
+    manifest.files = previous
+        .into_iter()
+        .filter(|(path, _)| paths.contains(path))
+        .collect();
+    for path in &paths {
+        // repository files with their committed contents are intact, whatever the manifest says
+        let check = match path.strip_prefix(repo) {
+            Ok(relative) if git::matches_head(&git_repo, relative)? => manifest::Check::New,
+            _ => manifest.check(path)?,
+        };
+        match check {
+            manifest::Check::Unchanged => {}
+            manifest::Check::Edited | manifest::Check::New => manifest.record(path)?,
+            manifest::Check::Corrupted => {
+                println!(
+                    "{} changed without its modification time changing",
+                    path.display()
+                );
+                problems += 1;
+            }
+            manifest::Check::Truncated => {
+                println!("{} was truncated", path.display());
+                problems += 1;
+            }
+            manifest::Check::Missing if manifest.files.contains_key(path) => {
+                println!("{} is missing", path.display());
+                manifest.files.remove(path);
+                problems += 1;
+            }
+            manifest::Check::Missing => {}
+        }
+    }
+    manifest::save(&manifest)?;
+
+    match problems {
+        0 => {
+            log::info!(
+                "successfully verified dotty repository {} and {} files",
+                repo.display(),
+                paths.len()
+            );
+            Ok(())
+        }
+        _ => Err(format!(
+            "found {} problems in dotty repository {}",
+            problems,
+            repo.display()
+        )),
+    }
+}
+
+/// Records the files in the manifest, so verify tells later corruption from these changes
+fn record_in_manifest(paths: &[PathBuf]) -> Result<(), String> {
+    if paths.is_empty() || dry_run::is_enabled() {
+        return Ok(());
+    }
+    let mut manifest = manifest::load()?;
+    for path in paths {
+        if path
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_file())
+        {
+            manifest.record(path)?;
+        }
+    }
+    manifest::save(&manifest)
+}
+
+/// Shrinks the object stores of the repository and optionally of its submodules
+pub fn gc(repo: &Path, aggressive: bool, submodules: bool, prune: &str) -> Result<(), String> {
+    let mut paths = vec![repo.to_owned()];
+    if submodules {
+        for (path, _, _) in git::submodule_heads(&git::open(repo)?)? {
+            if git::check_open(&repo.join(&path)) {
+                paths.push(repo.join(path));
+            }
+        }
+    }
+    let mut rows = vec![vec![
+        "REPOSITORY".to_owned(),
+        "BEFORE".to_owned(),
+        "AFTER".to_owned(),
+    ]];
+    for path in paths {
+        log::info!("collecting garbage in {}", path.display());
+        let (before, after) = git::gc(&path, aggressive, prune)?;
+        rows.push(vec![
+            path.display().to_string(),
+            string::format_size(before),
+            string::format_size(after),
+        ]);
+    }
+    print!("{}", string::format_table(&rows));
+    Ok(())
+}
+
+/// Tags the current state of the repository to roll back to later
+pub fn snapshot_create(repo: &Path, name: Option<&str>) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    if !git::changed_files(&git_repo)?.is_empty() {
+        return Err(format!(
+            "commit the changes in dotty repository {} before taking a snapshot",
+            repo.display()
+        ));
+    }
+    let name = match name {
+        Some(name) => name.to_owned(),
+        None => chrono::Local::now()
+            .format("snapshot-%Y%m%d-%H%M%S")
+            .to_string(),
+    };
+    git::create_tag(&git_repo, &name, &format!("dotty snapshot {}", name))?;
+    log::info!(
+        "successfully took snapshot {}, roll back to it with dotty rollback",
+        name
+    );
+    println!("{}", name);
+    Ok(())
+}
+
+/// Commits the files of a snapshot or commit on top of the repository, then restores them
+pub fn rollback(
+    repo: &Path,
+    root: &Path,
+    target: &str,
+    opts: &RestoreOptions,
+) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    if !git::changed_files(&git_repo)?.is_empty() {
+        return Err(format!(
+            "commit or discard the changes in dotty repository {} before rolling back",
+            repo.display()
+        ));
+    }
+    let id = git::resolve_commit(&git_repo, target)?;
+    match git::commit_files_of(&git_repo, &id, &format!("rolling back to {}", target))? {
+        Some(_) => log::info!(
+            "rolled dotty repository {} back to {}",
+            repo.display(),
+            target
+        ),
+        None => log::info!(
+            "dotty repository {} is at {} already",
+            repo.display(),
+            target
+        ),
+    }
+    restore(repo, root, opts)
+}
+
+/// Fetches the remote branch without merging it, then optionally compares the repository to it
+///
+/// With cached, compares to the remote branch as of the last fetch instead of fetching.
+pub fn fetch(repo: &Path, url: Option<&str>, status: bool, cached: bool) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    if !cached {
+        git::fetch(&git_repo, url)?;
+    }
+    if !status {
+        return Ok(());
+    }
+    let Some(comparison) = git::compare_remote(&git_repo, url)? else {
+        return Err(format!(
+            "dotty repository {} has not been fetched yet",
+            repo.display()
+        ));
+    };
+    let fetched = match cached {
+        true => last_fetch(&git_repo)
+            .and_then(|fetched| fetched.elapsed().ok())
+            .map(|age| format!(", fetched {}", string::format_age(age)))
+            .unwrap_or_default(),
+        false => String::new(),
+    };
+    let remote = url.unwrap_or("origin");
+    match (comparison.ahead, comparison.behind) {
+        (0, 0) => println!(
+            "up to date with {}/{}{}",
+            remote, comparison.branch, fetched
+        ),
+        (ahead, behind) => println!(
+            "{} ahead, {} behind {}/{}{}",
+            ahead, behind, remote, comparison.branch, fetched
+        ),
+    }
+    if comparison.incoming.is_empty() {
+        return Ok(());
+    }
+    println!("\nincoming commits:");
+    let now = SystemTime::now();
+    let rows: Vec<Vec<String>> = comparison
+        .incoming
+        .into_iter()
+        .map(|commit| {
+            vec![
+                format!("  {}", &commit.id[..7]),
+                string::format_age(now.duration_since(commit.time).unwrap_or_default()),
+                commit.author,
+                commit.summary,
+            ]
+        })
+        .collect();
+    print!("{}", string::format_table(&rows));
+    Ok(())
+}
+
+fn last_fetch(git_repo: &git2::Repository) -> Option<SystemTime> {
+    std::fs::metadata(git_repo.path().join("FETCH_HEAD"))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Prints the commits of the repository, or only those changing the managed paths
+pub fn log(repo: &Path, root: &Path, paths: &[PathBuf], max_count: usize) -> Result<(), String> {
+    let mut changed = Vec::new();
+    if !paths.is_empty() {
+        let config = config::load(repo)?;
+        for entry in select_entries(&config, root, managed_entries(repo, &config)?, paths)? {
+            changed.push(path::relative_from_root(repo, &entry.from)?);
+        }
+    }
+    let now = SystemTime::now();
+    let mut rows = Vec::new();
+    for commit in git::log(&git::open(repo)?, &changed, max_count)? {
+        rows.push(vec![
+            commit.id[..7].to_owned(),
+            string::format_age(now.duration_since(commit.time).unwrap_or_default()),
+            commit.author,
+            commit.summary,
+        ]);
+    }
+    print!("{}", string::format_table(&rows));
+    Ok(())
+}
+
+/// Prints the repository path of the entry matching the query, the repository or the root
+pub fn path(repo: &Path, root: &Path, query: Option<&str>, show_root: bool) -> Result<(), String> {
+    let path = match query {
+        _ if show_root => root.to_owned(),
+        Some(query) => {
+            let config = config::load(repo)?;
+            match_entry(managed_entries(repo, &config)?, query)?.from
+        }
+        None => repo.to_owned(),
+    };
+    // only the path goes to stdout, as is, for cd $(dotty path)
+    let mut line = path.into_os_string().into_encoded_bytes();
+    line.push(b'\n');
+    io::stdout()
+        .write_all(&line)
+        .map_err(|err| format!("failed to print path - {}", err))
+}
+
+/// The entry matching the query exactly, by file name, as a substring or as a subsequence, prompting when several do
+fn match_entry(entries: Vec<ManagedEntry>, query: &str) -> Result<ManagedEntry, String> {
+    let query_lower = query.to_lowercase();
+    let name = |e: &ManagedEntry| e.relative_path.display().to_string().to_lowercase();
+    let is_subsequence = |name: &str| {
+        let mut chars = name.chars();
+        query_lower.chars().all(|q| chars.any(|c| c == q))
+    };
+    let tiers: [&dyn Fn(&ManagedEntry) -> bool; 4] = [
+        &|e| e.relative_path == Path::new(query),
+        &|e| {
+            e.relative_path.file_name().is_some_and(|f| {
+                let f = f.to_string_lossy().to_lowercase();
+                f == query_lower || f.trim_start_matches('.') == query_lower
+            })
+        },
+        &|e| name(e).contains(&query_lower),
+        &|e| is_subsequence(&name(e)),
+    ];
+    for matches in tiers {
+        let mut matching: Vec<ManagedEntry> =
+            entries.iter().filter(|e| matches(e)).cloned().collect();
+        match matching.len() {
+            0 => continue,
+            1 => return Ok(matching.remove(0)),
+            _ => {
+                let options = matching
+                    .iter()
+                    .map(|e| e.relative_path.display().to_string())
+                    .collect();
+                let index = prompt::select(&format!("Entries matching {}:", query), options)?;
+                return Ok(matching.remove(index));
+            }
+        }
+    }
+    Err(format!("no managed entry matches {}", query))
+}
+
+/// The entries at or beneath the given paths, or all entries when none are given
+fn select_entries(
+    config: &config::Config,
+    root: &Path,
+    entries: Vec<ManagedEntry>,
+    paths: &[PathBuf],
+) -> Result<Vec<ManagedEntry>, String> {
+    if paths.is_empty() {
+        return Ok(entries);
+    }
+    let mut selected = Vec::new();
+    for path in paths {
+        let relative = config.relative_path(root, &path::absolute(path)?)?;
+        let matching: Vec<_> = entries
+            .iter()
+            .filter(|e| e.relative_path.starts_with(&relative))
+            .cloned()
+            .collect();
+        if matching.is_empty() {
+            return Err(format!("{} is not managed by dotty", path.display()));
+        }
+        selected.extend(matching);
+    }
+    Ok(selected)
+}
+
+pub fn plan(
+    repo: &Path,
+    root: &Path,
+    output: Option<&Path>,
+    symlinks: bool,
+    overwrite: bool,
+    prune: bool,
+) -> Result<(), String> {
+    let config = config::load(repo)?;
+    let entries = managed_entries(repo, &config)?;
+    let plan = build_plan(repo, root, &config, &entries, symlinks, overwrite, prune)?;
+
+    let mut rows = vec![vec!["ACTION".to_owned(), "PATH".to_owned()]];
+    for action in &plan.actions {
+        rows.push(vec![
+            action.kind.to_string(),
+            action.to.display().to_string(),
+        ]);
+    }
+    let planned: BTreeSet<&Path> = plan.actions.iter().map(|a| a.to.as_path()).collect();
+    for entry in &entries {
+        let to = entry.destination(root);
+        if !planned.contains(to.as_path()) {
+            rows.push(vec!["skip".to_owned(), to.display().to_string()]);
+        }
+    }
+    for domain in &plan.defaults {
+        rows.push(vec!["import defaults".to_owned(), domain.to_owned()]);
+    }
+    if rows.len() > 1 {
+        print!("{}", string::format_table(&rows));
+    }
+    if plan.is_empty() {
+        println!("no changes, {} is up to date", root.display());
+    }
+
+    if let Some(output) = output {
+        plans::save(output, &plan)?;
+        log::info!("saved plan to {}", output.display());
+    }
+    Ok(())
+}
+
+pub fn apply(repo: &Path, root: &Path, plan_file: &Path) -> Result<(), String> {
+    let plan = plans::load(plan_file)?;
+    check_plan(repo, root, &plan)?;
+    execute_plan(&plan)?;
+    log::info!(
+        "successfully applied plan {} to {}",
+        plan_file.display(),
+        root.display()
+    );
+    Ok(())
+}
+
+pub fn list(repo: &Path, root: &Path, long: bool, tags: &[String]) -> Result<(), String> {
+    let config = config::load(repo)?;
+    let mut entries = managed_entries(repo, &config)?;
+    if !tags.is_empty() {
+        entries.retain(|e| is_tagged(&config, e, tags));
+    }
+
+    if !long {
+        for entry in entries {
+            println!("{}", entry.relative_path.display());
+        }
+        return Ok(());
+    }
+
+    let mut rows = vec![vec![
+        "ENTRY".to_owned(),
+        "DESTINATION".to_owned(),
+        "LAYER".to_owned(),
+        "STATUS".to_owned(),
+        "MODE".to_owned(),
+        "OS".to_owned(),
+        "HOSTS".to_owned(),
+        "TAGS".to_owned(),
+        "DESCRIPTION".to_owned(),
+    ]];
+    for entry in entries {
+        let to = entry.destination(root);
+        let config_entry = config.find_entry(&entry.relative_path);
+        rows.push(vec![
+            entry.relative_path.display().to_string(),
+            to.display().to_string(),
+            entry.layer,
+            fs::deploy_status(&entry.from, &to)?.to_string(),
+            config_entry
+                .and_then(|e| e.mode.clone())
+                .unwrap_or_default(),
+            config_entry
+                .and_then(|e| e.os.map(|os| os.to_string()))
+                .unwrap_or_default(),
+            config_entry.map(|e| e.hosts.join(",")).unwrap_or_default(),
+            config
+                .tags(&entry.relative_path)
+                .iter()
+                .map(|tag| tag.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+            config_entry
+                .and_then(|e| e.description.clone())
+                .unwrap_or_default(),
+        ]);
+    }
+    print!("{}", string::format_table(&rows));
+    Ok(())
+}
+
+pub fn status(repo: &Path, root: &Path) -> Result<(), String> {
+    print!("{}", status_report(repo, root)?);
+    Ok(())
+}
+
+/// Prints a short token for shell prompts, nothing when the repository is in sync: * for changes
+/// to commit, ⇡N and ⇣N for commits to push and pull as of the last fetch, and ? when that fetch
+/// is older than `stale`
+pub fn prompt_status(repo: &Path, stale: &str) -> Result<(), String> {
+    let stale = string::parse_duration(stale)?;
+    // prompts are drawn everywhere, so a missing repository is not worth an error
+    if !git::check_open(repo) {
+        return Ok(());
+    }
+    let state = git::prompt_state(&git::open(repo)?)?;
+    let mut token = String::new();
+    if state.dirty {
+        token.push('*');
+    }
+    if state.ahead > 0 {
+        token.push_str(&format!("⇡{}", state.ahead));
+    }
+    if state.behind > 0 {
+        token.push_str(&format!("⇣{}", state.behind));
+    }
+    let is_stale = state
+        .fetched
+        .and_then(|fetched| fetched.elapsed().ok())
+        .is_none_or(|elapsed| elapsed > stale);
+    if is_stale {
+        token.push('?');
+    }
+    if !token.is_empty() {
+        println!("{}", token);
+    }
+    Ok(())
+}
+
+fn status_report(repo: &Path, root: &Path) -> Result<String, String> {
+    let git_repo = git::open(repo)?;
+    let summary = format!("{}: {}\n", repo.display(), git::summary(&git_repo));
+
+    let mut rows = vec![vec![
+        "ENTRY".to_owned(),
+        "LAYER".to_owned(),
+        "STATUS".to_owned(),
+    ]];
+    for row in status_rows(repo, root)? {
+        rows.push(vec![row.name, row.layer, row.status]);
+    }
+    Ok(summary + &string::format_table(&rows))
+}
+
+/// Prints a tab separated line of state and path for each entry that differs from the
+/// repository, returning whether there were none
+///
+/// The states are missing, conflict, exists, modified, outdated, mode, owner, differs and untracked.
+/// Lines are sorted by path, and tabs, newlines and backslashes in paths are escaped like \t, \n
+/// and \\ so each line stays one entry.
+pub fn status_porcelain(repo: &Path, root: &Path) -> Result<bool, String> {
+    let mut lines: Vec<(String, &str)> = status_rows(repo, root)?
+        .into_iter()
+        .filter_map(|row| Some((porcelain_path(&row.name), row.drift?)))
+        .collect();
+    lines.sort();
+    for (name, drift) in &lines {
+        println!("{}\t{}", drift, name);
+    }
+    Ok(lines.is_empty())
+}
+
+fn porcelain_path(name: &str) -> String {
+    name.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+/// Modified when the copy was edited since restore deployed it, outdated when only its entry changed
+///
+/// Without a record of the restore, a copy that differs from its entry is taken as modified.
+fn copy_drift(
+    state: &state::State,
+    from: &Path,
+    to: &Path,
+) -> Result<Option<&'static str>, String> {
+    if !to.is_file() {
+        return Ok(None);
+    }
+    Ok(match state.find(to) {
+        Some(record) if state::checksum(to)? != record.checksum => Some("modified"),
+        Some(record) if record.method == state::Method::Render => None,
+        Some(_) if !fs::same_contents(from, to) => Some("outdated"),
+        Some(_) => None,
+        None if template::is_template(from) || fs::same_contents(from, to) => None,
+        None => Some("modified"),
+    })
+}
+
+struct StatusRow {
+    name: String,
+    layer: String,
+    status: String,
+    /// The porcelain state, when the entry differs from the repository
+    drift: Option<&'static str>,
+}
+
+fn status_rows(repo: &Path, root: &Path) -> Result<Vec<StatusRow>, String> {
+    let config = config::load(repo)?;
+    let state = state::load()?;
+    let mut rows = Vec::new();
+    for entry in managed_entries(repo, &config)? {
+        let to = entry.destination(root);
+        let deploy_status = fs::deploy_status(&entry.from, &to)?;
+        let mut status = deploy_status.to_string();
+        let method = match config.find_entry(&entry.relative_path) {
+            Some(e) => e.method()?,
+            None => fs::Method::Symlink,
+        };
+        let mut drift = match deploy_status {
+            fs::DeployStatus::Linked => None,
+            fs::DeployStatus::Missing => Some("missing"),
+            fs::DeployStatus::Conflict => Some("conflict"),
+            fs::DeployStatus::Exists
+                if method != fs::Method::Copy && !template::is_template(&entry.from) =>
+            {
+                Some("exists")
+            }
+            fs::DeployStatus::Exists => copy_drift(&state, &entry.from, &to)?,
+        };
+        if let (Some(mode), true) = (config.mode(&entry.relative_path)?, to.exists()) {
+            let actual = fs::mode(&to)?;
+            if actual != mode {
+                status = format!("{}, mode {:04o} instead of {:04o}", status, actual, mode);
+                drift = drift.or(Some("mode"));
+            }
+        }
+        let (owner, group) = config.ownership(&entry.relative_path);
+        if deploy_status == fs::DeployStatus::Exists && (owner.is_some() || group.is_some()) {
+            let (uid, gid) = fs::owner(&to)?;
+            if owner.is_some_and(|o| o != uid) || group.is_some_and(|g| g != gid) {
+                status = format!(
+                    "{}, owner {}:{} instead of {}:{}",
+                    status,
+                    users::user_name(uid),
+                    users::group_name(gid),
+                    users::user_name(owner.unwrap_or(uid)),
+                    users::group_name(group.unwrap_or(gid))
+                );
+                drift = drift.or(Some("owner"));
+            }
+        }
+        rows.push(StatusRow {
+            name: entry.relative_path.display().to_string(),
+            layer: entry.layer,
+            status,
+            drift,
+        });
+    }
+
+    if defaults::is_supported() {
+        for domain in &config.defaults {
+            let from = repo.join(config::Config::defaults_path(domain));
+            let differs = defaults::differs(domain, &from)?;
+            rows.push(StatusRow {
+                name: domain.to_owned(),
+                layer: "defaults".to_owned(),
+                status: match differs {
+                    true => "differs".to_owned(),
+                    false => "in sync".to_owned(),
+                },
+                drift: differs.then_some("differs"),
+            });
+        }
+    }
+    let brewfile = repo.join(config::BREWFILE);
+    if brewfile.exists() && brew::is_installed() {
+        let (status, drift) = if !brew::is_satisfied(&brewfile)? {
+            ("missing packages", Some("missing"))
+        } else if brew::has_untracked(&brewfile)? {
+            ("untracked packages", Some("untracked"))
+        } else {
+            ("in sync", None)
+        };
+        rows.push(StatusRow {
+            name: config::BREWFILE.to_owned(),
+            layer: "brew".to_owned(),
+            status: status.to_owned(),
+            drift,
+        });
+    }
+    Ok(rows)
+}
+
+pub fn exec(repo: &Path, root: &Path, command: &[String], locked: bool) -> Result<(), String> {
+    let lock = match locked {
+        true => Some(lock::acquire(repo)?),
+        false => None,
+    };
+    let Some((program, args)) = command.split_first() else {
+        return Err("no command to execute".to_owned());
+    };
+
+    let mut cmd = dotty_command(repo, root, program);
+    cmd.args(args);
+    if let Some(lock) = &lock {
+        cmd.env(lock::TOKEN_ENV, lock.token());
+    }
+
+    log::debug!("executing {}", command.join(" "));
+    if dry_run::skip(format_args!("execute {}", command.join(" "))) {
+        return Ok(());
+    }
+    match cmd.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} exited with {}", program, status)),
+        Err(err) => Err(format!("failed to execute {} - {}", program, err)),
+    }
+}
+
+/// Runs git with the arguments in the dotty repository
+pub fn git(repo: &Path, root: &Path, args: &[String]) -> Result<(), String> {
+    let mut cmd = dotty_command(repo, root, "git");
+    cmd.current_dir(repo).args(args);
+
+    log::debug!("executing git {} in {}", args.join(" "), repo.display());
+    if dry_run::skip(format_args!("execute git {}", args.join(" "))) {
+        return Ok(());
+    }
+    match cmd.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("git exited with {}", status)),
+        Err(err) => Err(format!("failed to execute git - {}", err)),
+    }
+}
+
+/// A command with the dotty context exported to its environment
+fn dotty_command(repo: &Path, root: &Path, program: impl AsRef<OsStr>) -> Command {
+    let mut cmd = Command::new(program);
+    cmd.env("DOTTY_REPOSITORY", repo)
+        .env("DOTTY_ROOT", root)
+        .env("DOTTY_PROFILE", profile::active().join(","));
+    match git::open(repo).and_then(|git_repo| git::branch_name(&git_repo)) {
+        Ok(branch) => {
+            cmd.env("DOTTY_BRANCH", branch);
+        }
+        Err(err) => log::debug!("not exporting DOTTY_BRANCH - {}", err),
+    }
+    cmd
+}
+
+/// Runs the executable hooks/<name> of the repository, if there is one
+fn run_hook(repo: &Path, root: &Path, name: &str, changed: &[PathBuf]) -> Result<(), String> {
+    let hook = repo.join(config::HOOKS_DIR).join(name);
+    let Ok(metadata) = std::fs::metadata(&hook) else {
+        return Ok(());
+    };
+    if !metadata.is_file() || fs::mode(&hook)? & 0o111 == 0 {
+        log::warn!(
+            "not running hook {} as it is not executable",
+            hook.display()
+        );
+        return Ok(());
+    }
+    if dry_run::skip(format_args!("run hook {}", hook.display())) {
+        return Ok(());
+    }
+
+    let changed: Vec<String> = changed.iter().map(|p| p.display().to_string()).collect();
+    let mut cmd = dotty_command(repo, root, &hook);
+    cmd.current_dir(repo)
+        .env("DOTTY_HOOK", name)
+        .env("DOTTY_CHANGED_PATHS", changed.join("\n"));
+    if let Ok(token) = env::var(lock::TOKEN_ENV) {
+        cmd.env(lock::TOKEN_ENV, token);
+    }
+    log::debug!("running hook {} for {} paths", name, changed.len());
+    match cmd.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("hook {} exited with {}", name, status)),
+        Err(err) => Err(format!("failed to run hook {} - {}", hook.display(), err)),
+    }
+}
+
+/// Runs the pre-restore or post-restore hooks of the dotty.yaml entries of the restored paths
+fn run_entry_hooks(
+    repo: &Path,
+    root: &Path,
+    config: &config::Config,
+    entries: &[ManagedEntry],
+    restored: &[PathBuf],
+    post: bool,
+) -> Result<(), String> {
+    let name = match post {
+        true => "post-restore",
+        false => "pre-restore",
+    };
+    let by_destination: BTreeMap<PathBuf, &ManagedEntry> = entries
+        .iter()
+        .map(|entry| (entry.destination(root), entry))
+        .collect();
+    // the restored paths are in plan order, so hooks run after those of their dependencies
+    let mut changed: Vec<(PathBuf, &str, Vec<String>)> = Vec::new();
+    for to in restored {
+        let Some(entry) = by_destination.get(to) else {
+            continue;
+        };
+        let Some(config_entry) = config.find_entry(&entry.relative_path) else {
+            continue;
+        };
+        let command = match post {
+            true => &config_entry.hooks.post_restore,
+            false => &config_entry.hooks.pre_restore,
+        };
+        let Some(command) = command else {
+            continue;
+        };
+        let key = config_entry.key();
+        match changed.iter_mut().find(|(path, _, _)| *path == key) {
+            Some((_, _, paths)) => paths.push(to.display().to_string()),
+            None => changed.push((key, command, vec![to.display().to_string()])),
+        }
+    }
+
+    for (path, command, paths) in changed {
+        if dry_run::skip(format_args!("run {} hook of {}", name, path.display())) {
+            continue;
+        }
+        log::debug!("running {} hook of {}", name, path.display());
+        let mut cmd = dotty_command(repo, root, "sh");
+        cmd.arg("-c")
+            .arg(command)
+            .current_dir(repo)
+            .env("DOTTY_HOOK", name)
+            .env("DOTTY_CHANGED_PATHS", paths.join("\n"));
+        match cmd.status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                return Err(format!(
+                    "{} hook of {} exited with {}",
+                    name,
+                    path.display(),
+                    status
+                ))
+            }
+            Err(err) => {
+                return Err(format!(
+                    "failed to run {} hook of {} - {}",
+                    name,
+                    path.display(),
+                    err
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn bootstrap(repo: &Path, root: &Path, only: &[String]) -> Result<(), String> {
+    let config = config::load(repo)?;
+    for name in only {
+        if !config.bootstrap.iter().any(|s| &s.name == name) {
+            return Err(format!(
+                "no bootstrap script named {} in {}",
+                name,
+                config::FILE_NAME
+            ));
+        }
+    }
+
+    let mut ran = 0;
+    for script in &config.bootstrap {
+        if !only.is_empty() && !only.contains(&script.name) {
+            continue;
+        }
+        if let Some(os) = script.os.filter(|os| !os.is_current()) {
+            log::debug!(
+                "skipping bootstrap script {} which is only for {}",
+                script.name,
+                os
+            );
+            continue;
+        }
+        log::info!("running bootstrap script {}", script.name);
+        ran += 1;
+        if dry_run::skip(format_args!("run {}", script.run)) {
+            continue;
+        }
+        let mut cmd = dotty_command(repo, root, "sh");
+        cmd.arg("-c").arg(&script.run).current_dir(repo);
+        match cmd.status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                return Err(format!(
+                    "bootstrap script {} exited with {}",
+                    script.name, status
+                ))
+            }
+            Err(err) => {
+                return Err(format!(
+                    "failed to run bootstrap script {} - {}",
+                    script.name, err
+                ))
+            }
+        }
+    }
+    log::info!("successfully ran {} bootstrap scripts", ran);
+    Ok(())
+}
+
+/// Commits changes to managed files once they settle, then syncs them when asked to
+///
+/// Only the repository is watched, which sees edits through symlinks and hard links but not to
+/// entries restored as copies.
+pub fn watch(
+    repo: &Path,
+    root: &Path,
+    debounce: &str,
+    sync_changes: bool,
+    allow_secrets: bool,
+    notify: bool,
+) -> Result<(), String> {
+    let debounce = string::parse_duration(debounce)?;
+    let sync_opts = SyncOptions {
+        allow_secrets,
+        ..Default::default()
+    };
+    watcher::watch(repo, debounce, |changed| {
+        log::debug!("{} paths changed in {}", changed.len(), repo.display());
+        let _turn = take_turn();
+        let result = lock::acquire(repo).and_then(|_lock| {
+            if git::changed_files(&git::open(repo)?)?.is_empty() {
+                return Ok(());
+            }
+            commit(repo, None, false, allow_secrets)?;
+            match sync_changes {
+                true => sync(repo, root, &sync_opts),
+                false => Ok(()),
+            }
+        });
+        if let Err(err) = result {
+            log::error!("{}", err);
+            if notify {
+                notify_failure(repo, "watch", &err);
+            }
+        }
+    })
+}
+
+/// Serves requests from clients and, unless debounce is none, commits changes like watch meanwhile
+pub fn daemon(
+    repo: &Path,
+    root: &Path,
+    ttl: &str,
+    debounce: Option<&str>,
+    sync_changes: bool,
+    allow_secrets: bool,
+    notify: bool,
+) -> Result<(), String> {
+    let ttl = string::parse_duration(ttl)?;
+    type StatusKey = (PathBuf, Vec<String>, Vec<Option<SystemTime>>);
+    let mut cached: Option<(StatusKey, Instant, String)> = None;
+    // nobody is at the terminal, so confirmations are only skipped for clients run with --yes
+    prompt::disable();
+
+    if let Some(debounce) = debounce {
+        string::parse_duration(debounce)?;
+        let (repo, root, debounce) = (repo.to_owned(), root.to_owned(), debounce.to_owned());
+        std::thread::spawn(move || {
+            if let Err(err) = watch(&repo, &root, &debounce, sync_changes, allow_secrets, notify) {
+                log::error!("stopped watching {} - {}", repo.display(), err);
+            }
+        });
+    }
+
+    ipc::serve(repo, |request| {
+        let result = match request {
+            ipc::Request::Status { root, profiles } => {
+                let key = (root, profiles, status_fingerprint(repo));
+                match &cached {
+                    Some((cached_key, at, report)) if *cached_key == key && at.elapsed() < ttl => {
+                        log::trace!("serving cached status");
+                        Ok(report.clone())
+                    }
+                    _ => with_client_flags(
+                        ClientFlags {
+                            profiles: key.1.clone(),
+                            ..Default::default()
+                        },
+                        || status_report(repo, &key.0),
+                    )
+                    .inspect(|report| {
+                        cached = Some((key, Instant::now(), report.clone()));
+                    }),
+                }
+            }
+            ipc::Request::Sync {
+                root,
+                url,
+                notify,
+                allow_secrets,
+                stages,
+                rebase,
+                strategy,
+                autocommit,
+                autostash,
+                ssh_key,
+                accept_new,
+                profiles,
+                assume_yes,
+            } => {
+                cached = None;
+                let flags = ClientFlags {
+                    profiles,
+                    ssh_key,
+                    accept_new,
+                    assume_yes,
+                };
+                let opts = SyncOptions {
+                    url,
+                    notify,
+                    allow_secrets,
+                    stages,
+                    rebase,
+                    strategy,
+                    autocommit,
+                    autostash,
+                    force_with_lease: false,
+                };
+                with_client_flags(flags, || {
+                    lock::acquire(repo).and_then(|_lock| sync(repo, &root, &opts))
+                })
+                .map(|_| String::new())
+            }
+            ipc::Request::Restore {
+                root,
+                symlinks,
+                overwrite,
+                relabel,
+                profiles,
+                exclude,
+                preserve_times,
+                tags,
+                locked,
+                prune,
+                ssh_key,
+                accept_new,
+                assume_yes,
+            } => {
+                cached = None;
+                let flags = ClientFlags {
+                    profiles,
+                    ssh_key,
+                    accept_new,
+                    assume_yes,
+                };
+                let opts = RestoreOptions {
+                    symlinks,
+                    overwrite,
+                    relabel,
+                    exclude,
+                    preserve_times,
+                    tags,
+                    locked,
+                    prune,
+                    ..Default::default()
+                };
+                with_client_flags(flags, || {
+                    lock::acquire(repo).and_then(|_lock| restore(repo, &root, &opts))
+                })
+                .map(|_| String::new())
+            }
+        };
+        match result {
+            Ok(output) => ipc::Response {
+                output,
+                error: None,
+            },
+            Err(err) => {
+                log::error!("{}", err);
+                ipc::Response {
+                    output: String::new(),
+                    error: Some(err),
+                }
+            }
+        }
+    })
+}
+
+/// Requests and watch commits read process-wide settings like the active profiles, so they take
+/// turns rather than see each other's
+static TURN: Mutex<()> = Mutex::new(());
+
+fn take_turn() -> MutexGuard<'static, ()> {
+    TURN.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The flags of a client a daemon request runs with
+#[derive(Default)]
+struct ClientFlags {
+    profiles: Vec<String>,
+    ssh_key: Option<PathBuf>,
+    accept_new: bool,
+    assume_yes: bool,
+}
+
+/// Runs in its turn with the flags of a client, going back to the daemon's own after
+fn with_client_flags<T>(
+    flags: ClientFlags,
+    run: impl FnOnce() -> Result<T, String>,
+) -> Result<T, String> {
+    let _turn = take_turn();
+    let own_profiles = profile::active();
+    let (own_key, own_accept_new) = (git::ssh_key(), known_hosts::accepts_new());
+    let own_assume_yes = prompt::assumes_yes();
+    let result = profile::activate(&flags.profiles).and_then(|_| {
+        if flags.ssh_key.is_some() {
+            git::set_ssh_key(flags.ssh_key);
+        }
+        known_hosts::set_accept_new(own_accept_new || flags.accept_new);
+        prompt::set_assume_yes(own_assume_yes || flags.assume_yes);
+        run()
+    });
+    git::set_ssh_key(own_key);
+    known_hosts::set_accept_new(own_accept_new);
+    prompt::set_assume_yes(own_assume_yes);
+    if let Err(err) = profile::activate(&own_profiles) {
+        log::warn!("{}", err);
+    }
+    result
+}
+
+/// Runs the request in a running daemon, returning false when there is none
+pub fn forward_to_daemon(repo: &Path, request: &ipc::Request) -> Result<bool, String> {
+    match ipc::request(repo, request)? {
+        Some(response) => {
+            print!("{}", response.output);
+            match response.error {
+                Some(err) => Err(err),
+                None => Ok(true),
+            }
+        }
+        None => Ok(false),
+    }
+}
+
+/// Changes whenever a commit, checkout or config edit could change the status
+fn status_fingerprint(repo: &Path) -> Vec<Option<SystemTime>> {
+    let git_dir = git::git_dir(repo).unwrap_or_else(|_| repo.join(".git"));
+    [
+        git_dir.join("HEAD"),
+        git_dir.join("index"),
+        git_dir.join("FETCH_HEAD"),
+        repo.join(config::FILE_NAME),
+    ]
+    .iter()
+    .map(|path| {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    })
+    .collect()
+}
+
+pub fn ignore_add(repo: &Path, pattern: &str) -> Result<(), String> {
+    dottyignore::validate(pattern)?;
+    let mut lines = dottyignore::read_lines(repo)?;
+    if lines.iter().any(|l| l == pattern) {
+        log::warn!("{} is already ignored", pattern);
+        return Ok(());
+    }
+    check_nothing_else_staged(&git::open(repo)?, &[])?;
+    lines.push(pattern.to_owned());
+    dottyignore::write_lines(repo, &lines)?;
+    commit_ignore_file(repo, &format!("ignoring {}", pattern))?;
+    log::info!("successfully added ignore pattern {}", pattern);
+    Ok(())
+}
+
+pub fn ignore_list(repo: &Path) -> Result<(), String> {
+    for pattern in dottyignore::patterns(repo)? {
+        println!("{}", pattern);
+    }
+    Ok(())
+}
+
+pub fn ignore_remove(repo: &Path, pattern: &str) -> Result<(), String> {
+    let lines = dottyignore::read_lines(repo)?;
+    let remaining: Vec<String> = lines.iter().filter(|l| *l != pattern).cloned().collect();
+    if remaining.len() == lines.len() {
+        return Err(format!("{} is not an ignore pattern", pattern));
+    }
+    check_nothing_else_staged(&git::open(repo)?, &[])?;
+    dottyignore::write_lines(repo, &remaining)?;
+    commit_ignore_file(repo, &format!("no longer ignoring {}", pattern))?;
+    log::info!("successfully removed ignore pattern {}", pattern);
+    Ok(())
+}
+
+fn commit_ignore_file(repo: &Path, message: &str) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    git::stage_all_paths(&git_repo, &vec![PathBuf::from(dottyignore::FILE_NAME)])?;
+    git::commit(&git_repo, message)?;
+    Ok(())
+}
+
+pub fn backups_list(repo: &Path) -> Result<(), String> {
+    let config = config::load(repo)?;
+    let mut rows = vec![vec![
+        "CREATED".to_owned(),
+        "KIND".to_owned(),
+        "ID".to_owned(),
+        "LOCATION".to_owned(),
+    ]];
+    for backup in backup::load_all(&backup::base_dir(config.backup_dir.as_deref())?)? {
+        rows.push(vec![
+            backup.created.format("%Y-%m-%d %H:%M:%S").to_string(),
+            "files".to_owned(),
+            backup.id,
+            backup.dir.display().to_string(),
+        ]);
+    }
+    for snapshot in snapshot::load_all()? {
+        rows.push(vec![
+            snapshot.created.format("%Y-%m-%d %H:%M:%S").to_string(),
+            format!("{:?}", snapshot.kind).to_lowercase(),
+            snapshot.name,
+            snapshot.root.display().to_string(),
+        ]);
+    }
+    print!("{}", string::format_table(&rows));
+    Ok(())
+}
+
+/// Copies the files in a backup back to the root, replacing any symlinks that took their place
+pub fn backups_restore(repo: &Path, root: &Path, id: &str) -> Result<(), String> {
+    let config = config::load(repo)?;
+    let backup = backup::find(&backup::base_dir(config.backup_dir.as_deref())?, id)?;
+
+    let mut restored = Vec::new();
+    restore_backup_dir(&backup.dir, root, &mut restored)?;
+    log::info!(
+        "successfully restored {} paths from backup {} to {}",
+        restored.len(),
+        backup.id,
+        root.display()
+    );
+    Ok(())
+}
+
+fn restore_backup_dir(
+    dir: &Path,
+    to_dir: &Path,
+    restored: &mut Vec<PathBuf>,
+) -> Result<(), String> {
+    for from in fs::read_dir(dir)? {
+        let Some(file_name) = from.file_name() else {
+            continue;
+        };
+        let to = to_dir.join(file_name);
+        let is_symlink = fs::link_target(&to)?.is_some();
+        if from.is_dir() && to.is_dir() && !is_symlink {
+            restore_backup_dir(&from, &to, restored)?;
+            continue;
+        }
+        if is_symlink {
+            fs::remove(&to)?;
+        } else if to.exists() {
+            return Err(format!("not overwriting existing {}", to.display()));
+        }
+        log::debug!("restoring {} to {}", from.display(), to.display());
+        fs::copy(&from, &to)?;
+        restored.push(to);
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct SyncOptions {
+    pub url: Option<String>,
+    pub notify: bool,
+    pub allow_secrets: bool,
+    pub stages: git::SyncStages,
+    pub rebase: bool,
+    pub strategy: git::MergeStrategy,
+    pub autocommit: bool,
+    pub autostash: bool,
+    pub force_with_lease: bool,
+}
+
+/// Pulls and pushes the dotty repository and its layers, or only one of them
+pub fn sync(repo: &Path, root: &Path, opts: &SyncOptions) -> Result<(), String> {
+    let url = opts.url.as_deref();
+    let pull_opts = git::PullOptions {
+        rebase: opts.rebase,
+        strategy: opts.strategy,
+    };
+    // a mirror being down should not stop layers and hooks from running
+    let mut mirrored = Ok(());
+    if let Err(err) = git::open(repo)
+        .and_then(|mut git_repo| {
+            if opts.autocommit && !git::changed_files(&git_repo)?.is_empty() {
+                log::info!("committing outstanding changes before syncing");
+                commit(repo, None, false, opts.allow_secrets)?;
+            }
+            if opts.stages.pushes() {
+                let unpushed = git::unpushed_files(&git_repo)?;
+                let paths: Vec<PathBuf> = unpushed.iter().map(|p| repo.join(p)).collect();
+                check_secrets(repo, &paths, opts.allow_secrets)?;
+            }
+            run_hook(repo, root, "pre-sync", &[])?;
+            if let Some(branch) = config::load_local(repo)?.branch {
+                git::switch_branch(&git_repo, url, &branch)?;
+            }
+            // a first sync may be to a repository that has not been created yet
+            if opts.stages.pushes() && !git::has_remote_branch(&git_repo) {
+                if let Some(remote_url) = git::remote_url(&git_repo, url) {
+                    create_remote(&remote_url)?;
+                }
+            }
+            let stashed = opts.autostash && opts.stages.pulls() && git::stash(&mut git_repo)?;
+            let old_head = git::head_id(&git_repo)?;
+            let synced = match opts.force_with_lease {
+                true => force_push(repo, &git_repo, url),
+                false => git::sync(&git_repo, url, opts.stages, pull_opts),
+            };
+            let synced = match synced {
+                Err(err)
+                    if opts.strategy == git::MergeStrategy::Tool && git::is_merging(&git_repo) =>
+                {
+                    log::warn!("{}", err);
+                    run_mergetool(repo, &git_repo).and_then(|_| match opts.stages.pushes() {
+                        true => git::push(&git_repo, url, false),
+                        false => Ok(()),
+                    })
+                }
+                result => result,
+            };
+            // a failed pop must not hide why the merge failed
+            let synced = match (synced, stashed) {
+                (synced, false) => synced,
+                (Ok(()), true) => git::stash_pop(&mut git_repo),
+                (Err(err), true) => match git::stash_pop(&mut git_repo) {
+                    Ok(()) => Err(err),
+                    Err(pop_err) => Err(format!("{}, and {}", err, pop_err)),
+                },
+            };
+            synced?;
+            if opts.stages.pushes() {
+                mirrored = push_mirrors(repo, &git_repo);
+            }
+            if opts.stages.pulls() {
+                apply_identity(repo, &git_repo)?;
+            }
+            match git::head_id(&git_repo)? {
+                Some(new_head) if old_head.as_ref() != Some(&new_head) => {
+                    git::changed_between(&git_repo, old_head.as_deref(), &new_head)
+                }
+                _ => Ok(Vec::new()),
+            }
+        })
+        .and_then(|changed| {
+            sync_layers(repo, opts.stages, pull_opts)?;
+            let changed: Vec<PathBuf> = changed.iter().map(|p| repo.join(p)).collect();
+            run_hook(repo, root, "post-sync", &changed)
+        })
+        .and(mirrored)
+    {
+        if opts.notify {
+            notify_failure(repo, "sync", &err);
+        }
+        return Err(err);
+    }
+    record_sync(repo)?;
+    log::info!("successfully synced dotty repository");
+    Ok(())
+}
+
+const LAST_SYNC_FILE: &str = "dotty-last-sync";
+const DEFAULT_SYNC_WARNING: &str = "7d";
+
+/// Records the time of the sync in the git directory, where it stays with this machine
+fn record_sync(repo: &Path) -> Result<(), String> {
+    let last_sync = git::open(repo)?.path().join(LAST_SYNC_FILE);
+    fs::write(&last_sync, &chrono::Utc::now().to_rfc3339())
+}
+
+/// The last successful sync, or else the last fetch for repositories synced before it was recorded
+fn last_sync(git_repo: &git2::Repository) -> Option<SystemTime> {
+    match std::fs::read_to_string(git_repo.path().join(LAST_SYNC_FILE)) {
+        Ok(contents) => chrono::DateTime::parse_from_rfc3339(contents.trim())
+            .ok()
+            .map(SystemTime::from),
+        Err(_) => last_fetch(git_repo),
+    }
+}
+
+/// Warns when a repository with a remote has not synced for longer than configured
+pub fn warn_if_stale(repo: &Path, config: &config::Config) {
+    let threshold = config
+        .sync_warning
+        .as_deref()
+        .unwrap_or(DEFAULT_SYNC_WARNING);
+    let threshold = match string::parse_duration(threshold) {
+        Ok(threshold) if threshold.is_zero() => return,
+        Ok(threshold) => threshold,
+        Err(err) => {
+            log::warn!("invalid sync_warning in {} - {}", config::FILE_NAME, err);
+            return;
+        }
+    };
+    let Ok(git_repo) = git::open(repo) else {
+        return;
+    };
+    if git::remote_url(&git_repo, None).is_none() {
+        return;
+    }
+    let Some(age) = last_sync(&git_repo).and_then(|synced| synced.elapsed().ok()) else {
+        return;
+    };
+    if age > threshold {
+        log::warn!(
+            "dotty repository {} was last synced {}, use dotty sync to catch up",
+            repo.display(),
+            string::format_age(age)
+        );
+    }
+}
+
+/// Overwrites the remote branch once confirmed, which never happens without asking
+fn force_push(repo: &Path, git_repo: &git2::Repository, url: Option<&str>) -> Result<(), String> {
+    let msg = format!(
+        "Force push {} to {}, overwriting any remote commits missing locally?",
+        repo.display(),
+        url.unwrap_or("origin")
+    );
+    match prompt::proceed(&msg)? {
+        true => git::push(git_repo, url, true),
+        false => Err("force push cancelled".to_owned()),
+    }
+}
+
+/// Pushes to every mirror, reporting each one and failing at the end if any of them failed
+fn push_mirrors(repo: &Path, git_repo: &git2::Repository) -> Result<(), String> {
+    let mut failed = Vec::new();
+    for mirror in config::load(repo)?.mirrors {
+        match git::push_mirror(git_repo, &mirror.name, &mirror.url) {
+            Ok(()) => log::info!("successfully pushed to mirror {}", mirror.name),
+            Err(err) => {
+                log::warn!("{}", err);
+                failed.push(mirror.name);
+            }
+        }
+    }
+    match failed.is_empty() {
+        true => Ok(()),
+        false => Err(format!("failed to push to mirrors {}", failed.join(", "))),
+    }
+}
+
+/// Runs the merge tool on each conflicting file, then completes the merge if none remain
+fn run_mergetool(repo: &Path, git_repo: &git2::Repository) -> Result<(), String> {
+    let tool = env::var("DOTTY_MERGETOOL")
+        .ok()
+        .filter(|tool| !tool.is_empty());
+    for path in git::conflicted_files(git_repo)? {
+        let mut cmd = match &tool {
+            Some(tool) => {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c").arg(format!("{} \"$1\"", tool)).arg("sh");
+                cmd
+            }
+            None => {
+                let mut cmd = Command::new("git");
+                cmd.args(["mergetool", "--no-prompt", "--"]);
+                cmd
+            }
+        };
+        cmd.arg(&path).current_dir(repo);
+        if dry_run::skip(format_args!("resolve conflicts in {}", path.display())) {
+            continue;
+        }
+        log::info!("resolving conflicts in {}", path.display());
+        match cmd.status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                return Err(format!(
+                    "merge tool exited with {} for {}",
+                    status,
+                    path.display()
+                ))
+            }
+            Err(err) => {
+                return Err(format!(
+                    "failed to run merge tool for {} - {}",
+                    path.display(),
+                    err
+                ))
+            }
+        }
+        // git mergetool stages what it resolves itself
+        if tool.is_some() && !diff::has_conflict_markers(&repo.join(&path))? {
+            git::stage_all_paths(git_repo, &vec![path])?;
+        }
+    }
+
+    let remaining = git::conflicted_files(git_repo)?;
+    if !remaining.is_empty() {
+        return Err(format!(
+            "conflicts remain in {}, resolve them in {} then use dotty commit",
+            remaining
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            repo.display()
+        ));
+    }
+    git::commit(git_repo, "Merge remote changes")?;
+    log::info!("resolved all conflicts and completed the merge");
+    Ok(())
+}
+
+pub fn commit(
+    repo: &Path,
+    message: Option<&str>,
+    patch: bool,
+    allow_secrets: bool,
+) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    match patch {
+        true => stage_selected_hunks(repo, &git_repo, allow_secrets)?,
+        false => {
+            let submodules = git::submodule_paths(&git_repo)?;
+            let mut to_stage = Vec::new();
+            let mut to_remove = Vec::new();
+            for path in git::changed_files(&git_repo)? {
+                let full_path = repo.join(&path);
+                if submodules.contains(&path) {
+                    log::debug!(
+                        "not committing submodule {}, use dotty update",
+                        path.display()
+                    );
+                } else if full_path.exists() {
+                    to_stage.push(path);
+                } else {
+                    to_remove.push(path);
+                }
+            }
+            let paths: Vec<PathBuf> = to_stage.iter().map(|p| repo.join(p)).collect();
+            check_secrets(repo, &paths, allow_secrets)?;
+            git::stage_all_paths(&git_repo, &to_stage)?;
+            git::remove_all_paths(&git_repo, &to_remove)?;
+        }
+    };
+
+    // what was staged before is committed along with the changes
+    let committed = git::staged_files(&git_repo)?;
+    if committed.is_empty() {
+        log::warn!("there are no changes to commit");
+        return Ok(());
+    }
+    let message = match message {
+        Some(message) => message.to_owned(),
+        None => build_update_message(&committed),
+    };
+    git::commit(&git_repo, &message)?;
+    log::info!(
+        "successfully committed {} changed paths in dotty repository {}",
+        committed.len(),
+        repo.display()
+    );
+    Ok(())
+}
+
+const DEFAULT_WARN_FILE_SIZE: &str = "1MB";
+const DEFAULT_MAX_FILE_SIZE: &str = "50MB";
+const DEFAULT_MAX_TOTAL_SIZE: &str = "100MB";
+
+/// Refuses files that are too large, or binary when configured to, unless forced
+fn check_limits(limits: &config::Limits, files: &[PathBuf], force: bool) -> Result<(), String> {
+    let size_limit = |limit: &Option<String>, default: &str| {
+        string::parse_size(limit.as_deref().unwrap_or(default))
+    };
+    let warn_file_size = size_limit(&limits.warn_file_size, DEFAULT_WARN_FILE_SIZE)?;
+    let max_file_size = size_limit(&limits.max_file_size, DEFAULT_MAX_FILE_SIZE)?;
+    let max_total_size = size_limit(&limits.max_total_size, DEFAULT_MAX_TOTAL_SIZE)?;
+
+    let mut exceeded = 0;
+    let mut total_size = 0;
+    for file in files {
+        let size = match std::fs::metadata(file) {
+            Ok(metadata) => metadata.len(),
+            Err(err) => return Err(format!("failed to read {} - {}", file.display(), err)),
+        };
+        total_size += size;
+        if size > max_file_size {
+            log::warn!(
+                "{} is {}, over the limit of {}",
+                file.display(),
+                string::format_size(size),
+                string::format_size(max_file_size)
+            );
+            exceeded += 1;
+        } else if size > warn_file_size {
+            log::warn!("{} is {}", file.display(), string::format_size(size));
+        }
+        if fs::is_binary(file)? {
+            log::warn!("{} has binary content", file.display());
+            if limits.refuse_binary {
+                exceeded += 1;
+            }
+        }
+    }
+    if total_size > max_total_size {
+        log::warn!(
+            "adding {} in total, over the limit of {}",
+            string::format_size(total_size),
+            string::format_size(max_total_size)
+        );
+        exceeded += 1;
+    }
+
+    match exceeded {
+        0 => Ok(()),
+        _ if force => Ok(()),
+        _ => Err(format!(
+            "exceeded the add limits {} times, use --force to add anyway",
+            exceeded
+        )),
+    }
+}
+
+/// Refuses to commit files that look like they contain credentials, unless allowed
+fn check_secrets(repo: &Path, paths: &[PathBuf], allow: bool) -> Result<(), String> {
+    if crypt::load_key(git::open(repo)?.path())?.is_some() {
+        log::debug!("not scanning for secrets as dotty repository is encrypted");
+        return Ok(());
+    }
+    let mut findings = Vec::new();
+    for path in paths {
+        findings.append(&mut secrets::scan(path)?);
+    }
+    report_secrets(&findings, allow)
+}
+
+/// Like check_secrets, for the staged contents of the paths and the lines the selected hunks add
+fn check_staged_secrets(
+    repo: &Path,
+    git_repo: &git2::Repository,
+    staged: &[PathBuf],
+    selected: &[(&git::FileChange, Vec<usize>)],
+    allow: bool,
+) -> Result<(), String> {
+    let mut findings = Vec::new();
+    for path in staged {
+        if let Some(contents) = git::staged_contents(git_repo, path) {
+            findings.append(&mut secrets::scan_contents(&repo.join(path), &contents));
+        }
+    }
+    for (change, indices) in selected {
+        for (line_number, line) in indices
+            .iter()
+            .flat_map(|&idx| change.hunks[idx].added_lines())
+        {
+            findings.append(&mut secrets::scan_line(
+                &repo.join(&change.path),
+                line_number,
+                &line,
+            ));
+        }
+    }
+    report_secrets(&findings, allow)
+}
+
+fn report_secrets(findings: &[secrets::Finding], allow: bool) -> Result<(), String> {
+    for finding in findings {
+        log::warn!(
+            "{}:{} looks like it contains {}",
+            finding.path.display(),
+            finding.line,
+            finding.kind
+        );
+    }
+    match findings.len() {
+        0 => Ok(()),
+        _ if allow => Ok(()),
+        found => Err(format!(
+            "found {} possible secrets, use --allow-secrets to include them anyway",
+            found
+        )),
+    }
+}
+
+/// Prompts for each hunk of each changed file, staging those selected
+fn stage_selected_hunks(
+    repo: &Path,
+    git_repo: &git2::Repository,
+    allow_secrets: bool,
+) -> Result<(), String> {
+    let staged = git::staged_files(git_repo)?;
+    let changes = git::working_changes(git_repo)?;
+    let mut selected = Vec::new();
+    let mut whole_files = Vec::new();
+    let mut removed = Vec::new();
+    for change in &changes {
+        let header = diff::paint(diff::CYAN, &change.path.display().to_string());
+        let question = match change.kind {
+            git::ChangeKind::Added => Some("Stage this new file?"),
+            git::ChangeKind::Deleted => Some("Stage this deletion?"),
+            git::ChangeKind::Modified if change.binary => Some("Stage this binary file?"),
+            git::ChangeKind::Modified => None,
+        };
+        if let Some(question) = question {
+            println!("{}", header);
+            if prompt::confirm(question)? {
+                match change.kind {
+                    git::ChangeKind::Deleted => removed.push(change.path.clone()),
+                    _ => whole_files.push(change.path.clone()),
+                }
+            }
+            continue;
+        }
+
+        let mut indices = Vec::new();
+        for (idx, hunk) in change.hunks.iter().enumerate() {
+            println!("{}", header);
+            print!("{}", diff::colorize(&hunk.text()));
+            if prompt::confirm(&format!(
+                "Stage this hunk [{}/{}]?",
+                idx + 1,
+                change.hunks.len()
+            ))? {
+                indices.push(idx);
+            }
+        }
+        if !indices.is_empty() {
+            selected.push((change, indices));
+        }
+    }
+
+    let mut scanned = staged;
+    scanned.extend(whole_files.iter().cloned());
+    check_staged_secrets(repo, git_repo, &scanned, &selected, allow_secrets)?;
+    git::stage_hunks(git_repo, &selected)?;
+    git::stage_all_paths(git_repo, &whole_files)?;
+    git::remove_all_paths(git_repo, &removed)?;
+    Ok(())
+}
+
+pub fn update(
+    repo: &Path,
+    only: &[String],
+    unpin: &[String],
+    no_commit: bool,
+) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    let mut pins = config::load(repo)?.pins;
+    for name in unpin {
+        if pins.remove(name).is_none() {
+            log::warn!("submodule {} is not pinned", name);
+        }
+    }
+
+    if !no_commit {
+        check_nothing_else_staged(&git_repo, &[])?;
+    }
+    let updated = git::update_submodules(&git_repo, only, &pins)?;
+    let locked = refresh_lock(repo, &git_repo)?;
+    if locked {
+        git::stage_all_paths(&git_repo, &vec![PathBuf::from(lockfile::FILE_NAME)])?;
+    }
+    if updated == 0 && !locked {
+        log::warn!("there are no submodules to update");
+        return Ok(());
+    }
+
+    if no_commit {
+        log::info!(
+            "staged updates to {} submodules without committing",
+            updated
+        );
+    } else if updated > 0 {
+        let message = match only.is_empty() {
+            true => "Updated all submodules".to_owned(),
+            false => format!("Updated submodules {}", only.join(", ")),
+        };
+        git::commit(&git_repo, &message)?;
+        log::info!("successfully updated {} submodules", updated);
+    } else {
+        git::commit(&git_repo, &format!("Updated {}", lockfile::FILE_NAME))?;
+        log::info!("successfully updated {}", lockfile::FILE_NAME);
+    }
+    Ok(())
+}
+
+/// Removes a submodule entirely and commits the result, along with its pin and lock
+pub fn submodule_remove(repo: &Path, selected: &str) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    check_nothing_else_staged(&git_repo, &[])?;
+    let (name, path) = git::remove_submodule(&git_repo, selected)?;
+    let mut paths = Vec::new();
+    let mut config = config::load(repo)?;
+    let path_name = path.to_string_lossy().into_owned();
+    if config.pins.remove(&name).is_some() | config.pins.remove(&path_name).is_some() {
+        config::save(repo, &config)?;
+        paths.push(PathBuf::from(config::FILE_NAME));
+    }
+    if refresh_lock(repo, &git_repo)? {
+        paths.push(PathBuf::from(lockfile::FILE_NAME));
+    }
+    git::stage_all_paths(&git_repo, &paths)?;
+    git::commit(&git_repo, &format!("Removed submodule {}", name))?;
+    log::info!("successfully removed submodule {}", name);
+    Ok(())
+}
+
+/// Replaces vendored directories with the files of the latest commit of their repositories
+pub fn revendor(repo: &Path, only: &[PathBuf]) -> Result<(), String> {
+    let config = config::load(repo)?;
+    let mut vendored: Vec<(&PathBuf, &String)> = config.vendored.iter().collect();
+    if !only.is_empty() {
+        if let Some(unknown) = only
+            .iter()
+            .find(|selected| !config.vendored.contains_key(*selected))
+        {
+            return Err(format!("{} is not vendored", unknown.display()));
+        }
+        vendored.retain(|(path, _)| only.contains(path));
+    }
+    if vendored.is_empty() {
+        log::warn!("there are no vendored directories to refresh");
+        return Ok(());
+    }
+
+    let git_repo = git::open(repo)?;
+    // revendoring replaces the whole directory, so local edits would be lost
+    let changed = git::changed_files(&git_repo)?;
+    if let Some((path, _)) = vendored
+        .iter()
+        .find(|(path, _)| changed.iter().any(|file| file.starts_with(path)))
+    {
+        return Err(format!(
+            "vendored directory {} has uncommitted changes, commit or discard them first",
+            path.display()
+        ));
+    }
+    check_nothing_else_staged(&git_repo, &[])?;
+    let checkout = vendor_checkout(&git_repo);
+    let mut revendored = Vec::new();
+    let mut commits = Vec::new();
+    for (path, url) in vendored {
+        if dry_run::skip(format_args!("revendor {} from {}", path.display(), url)) {
+            continue;
+        }
+        log::debug!("revendoring {} from {}", path.display(), url);
+        if checkout.exists() {
+            fs::remove_all(&checkout)?;
+        }
+        if let Some(commit) = git::head_id(&git::clone(&checkout, url)?)? {
+            commits.push((path.clone(), url.clone(), commit));
+        }
+        fs::remove_all(&checkout.join(".git"))?;
+        fs::remove_all(&repo.join(path))?;
+        std::fs::rename(&checkout, repo.join(path))
+            .map_err(|err| format!("failed to move {} - {}", path.display(), err))?;
+        revendored.push(path.clone());
+    }
+    git::remove_all_paths(&git_repo, &revendored)?;
+    let mut paths = revendored.clone();
+    if lock_vendored(repo, &commits)? {
+        paths.push(PathBuf::from(lockfile::FILE_NAME));
+    }
+    git::stage_all_paths(&git_repo, &paths)?;
+    if !git::has_staged_changes(&git_repo)? {
+        log::info!("vendored directories are already up to date");
+        return Ok(());
+    }
+    let message = match revendored.len() {
+        1 => format!("Revendored {}", revendored[0].display()),
+        count => format!("Revendored {} directories", count),
+    };
+    git::commit(&git_repo, &message)?;
+    log::info!("successfully revendored {} directories", revendored.len());
+    Ok(())
+}
+
+/// Replaces a submodule with the files of its checked out commit, vendored from its url
+pub fn submodule_absorb(repo: &Path, selected: &str) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    check_nothing_else_staged(&git_repo, &[])?;
+    let (path, url, commit) = git::submodule_origin(&git_repo, selected)?;
+    if dry_run::skip(format_args!("absorb submodule {}", path.display())) {
+        return Ok(());
+    }
+    let checkout = vendor_checkout(&git_repo);
+    if checkout.exists() {
+        fs::remove_all(&checkout)?;
+    }
+    fs::copy(&repo.join(&path), &checkout)?;
+    fs::remove_all(&checkout.join(".git"))?;
+    let (name, path) = match git::remove_submodule(&git_repo, selected) {
+        Ok(removed) => removed,
+        Err(err) => {
+            fs::remove_all(&checkout)?;
+            return Err(err);
+        }
+    };
+    std::fs::rename(&checkout, repo.join(&path))
+        .map_err(|err| format!("failed to move {} - {}", path.display(), err))?;
+
+    let mut config = config::load(repo)?;
+    config.pins.remove(&name);
+    config.pins.remove(path.to_string_lossy().as_ref());
+    config.vendored.insert(path.clone(), url.clone());
+    config::save(repo, &config)?;
+    let mut paths = vec![path.clone(), PathBuf::from(config::FILE_NAME)];
+    let vendored = [(path.clone(), url.clone(), commit.clone())];
+    if lock_vendored(repo, &vendored)? | refresh_lock(repo, &git_repo)? {
+        paths.push(PathBuf::from(lockfile::FILE_NAME));
+    }
+    git::stage_all_paths(&git_repo, &paths)?;
+    git::commit(
+        &git_repo,
+        &format!(
+            "Absorbed submodule {}\n\nVendored {} at {}",
+            name, url, commit
+        ),
+    )?;
+    log::info!("successfully absorbed submodule {}", name);
+    Ok(())
+}
+
+/// Replaces vendored files with a submodule of the commit they were vendored from
+pub fn submodule_extract(repo: &Path, path: &Path) -> Result<(), String> {
+    let mut config = config::load(repo)?;
+    let Some(url) = config.vendored.remove(path) else {
+        return Err(format!("{} is not vendored", path.display()));
+    };
+    let git_repo = git::open(repo)?;
+    check_nothing_else_staged(&git_repo, &[])?;
+    if dry_run::skip(format_args!("extract {} from {}", path.display(), url)) {
+        return Ok(());
+    }
+    let checkout = vendor_checkout(&git_repo);
+    if checkout.exists() {
+        fs::remove_all(&checkout)?;
+    }
+    let latest = git::head_id(&git::clone(&checkout, &url)?)?.unwrap_or_default();
+    let locked = lockfile::load(repo)?
+        .and_then(|lock| lock.vendored.into_iter().find(|pin| pin.path == path))
+        .map(|pin| pin.commit);
+    let commit = match locked {
+        Some(commit) => {
+            git::check_out(&checkout, &commit, true)?;
+            commit
+        }
+        None => {
+            log::warn!(
+                "the commit {} was vendored from is not locked, extracting the latest one",
+                path.display()
+            );
+            latest
+        }
+    };
+    git::remove_all_paths(&git_repo, &vec![path.to_owned()])?;
+    fs::remove_all(&repo.join(path))?;
+    std::fs::rename(&checkout, repo.join(path))
+        .map_err(|err| format!("failed to move {} - {}", path.display(), err))?;
+    git::add_submodules(&git_repo, &vec![path.to_owned()])?;
+
+    config::save(repo, &config)?;
+    let mut paths = vec![path.to_owned(), PathBuf::from(config::FILE_NAME)];
+    if refresh_lock(repo, &git_repo)? {
+        paths.push(PathBuf::from(lockfile::FILE_NAME));
+    }
+    git::stage_all_paths(&git_repo, &paths)?;
+    git::commit(
+        &git_repo,
+        &format!(
+            "Extracted submodule {}\n\nChecked out {} at {}",
+            path.display(),
+            url,
+            commit
+        ),
+    )?;
+    log::info!("successfully extracted submodule {}", path.display());
+    Ok(())
+}
+
+/// Where vendored repositories are cloned before replacing the files in the repository
+fn vendor_checkout(git_repo: &git2::Repository) -> PathBuf {
+    git_repo.path().join("dotty-vendor")
+}
+
+/// Records the commits vendored directories were copied from, returning whether the lock changed
+fn lock_vendored(repo: &Path, vendored: &[(PathBuf, String, String)]) -> Result<bool, String> {
+    let mut lock = lockfile::load(repo)?.unwrap_or_default();
+    let mut changed = false;
+    for (path, url, commit) in vendored {
+        let pin = lockfile::Pin {
+            path: path.clone(),
+            url: Some(url.clone()),
+            commit: commit.clone(),
+        };
+        if lock.vendored.contains(&pin) {
+            continue;
+        }
+        lock.vendored.retain(|locked| &locked.path != path);
+        lock.vendored.push(pin);
+        changed = true;
+    }
+    if !changed {
+        return Ok(false);
+    }
+    lock.vendored.sort_by(|a, b| a.path.cmp(&b.path));
+    lockfile::save(repo, &lock)?;
+    log::debug!("updated {}", lockfile::FILE_NAME);
+    Ok(true)
+}
+
+/// Records the current submodule and layer commits, returning whether the lock changed
+fn refresh_lock(repo: &Path, git_repo: &git2::Repository) -> Result<bool, String> {
+    let mut lock = lockfile::Lock::default();
+    for (path, url, commit) in git::submodule_heads(git_repo)? {
+        lock.submodules.push(lockfile::Pin { path, url, commit });
+    }
+    let config = config::load(repo)?;
+    for layer in &config.layers {
+        let layer_path = path::resolve_from(repo, &layer.path)?;
+        if !layer_path.exists() {
+            continue;
+        }
+        if let Some(commit) = git::head_id(&git::open(&layer_path)?)? {
+            lock.layers.push(lockfile::Pin {
+                path: PathBuf::from(&layer.name),
+                url: layer.url.clone(),
+                commit,
+            });
+        }
+    }
+
+    let existing = lockfile::load(repo)?;
+    // vendored commits can't be read back from the files, so they are kept while still vendored
+    lock.vendored = existing
+        .iter()
+        .flat_map(|existing| &existing.vendored)
+        .filter(|pin| config.vendored.contains_key(&pin.path))
+        .cloned()
+        .collect();
+    if existing.as_ref() == Some(&lock) || (existing.is_none() && lock == Default::default()) {
+        return Ok(false);
+    }
+    lockfile::save(repo, &lock)?;
+    log::debug!("updated {}", lockfile::FILE_NAME);
+    Ok(true)
+}
+
+/// Checks out the locked commits of submodules and read-only layers
+fn apply_lock(repo: &Path) -> Result<(), String> {
+    let Some(lock) = lockfile::load(repo)? else {
+        return Ok(());
+    };
+    for pin in &lock.submodules {
+        let path = repo.join(&pin.path);
+        if !git::check_open(&path) {
+            log::debug!("submodule {} is not checked out", pin.path.display());
+            continue;
+        }
+        if git::check_out(&path, &pin.commit, true)? {
+            log::info!(
+                "checked out submodule {} at {}",
+                pin.path.display(),
+                pin.commit
+            );
+        }
+    }
+
+    let config = config::load(repo)?;
+    for pin in &lock.layers {
+        let Some(layer) = config
+            .layers
+            .iter()
+            .find(|l| pin.path == Path::new(&l.name))
+        else {
+            log::warn!(
+                "locked layer {} is not in {}",
+                pin.path.display(),
+                config::FILE_NAME
+            );
+            continue;
+        };
+        let layer_path = path::resolve_from(repo, &layer.path)?;
+        if !layer_path.exists() {
+            log::debug!("layer {} has not been synced", layer.name);
+        } else if layer.writable {
+            if git::head_id(&git::open(&layer_path)?)?.as_deref() != Some(pin.commit.as_str()) {
+                log::warn!(
+                    "writable layer {} is not at its locked commit {}",
+                    layer.name,
+                    pin.commit
+                );
+            }
+        } else {
+            let layer_repo = git::open(&layer_path)?;
+            // the layer's branch is reset to the locked commit, which must not lose anything
+            if !git::changed_files(&layer_repo)?.is_empty()
+                || !git::is_behind(&layer_repo, &pin.commit)?
+            {
+                log::warn!(
+                    "not checking out layer {} at its locked commit {} as it has local changes",
+                    layer.name,
+                    pin.commit
+                );
+            } else if git::check_out(&layer_path, &pin.commit, false)? {
+                log::info!("checked out layer {} at {}", layer.name, pin.commit);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn notify_failure(repo: &Path, operation: &str, err: &str) {
+    let settings = match config::load(repo) {
+        Ok(config) => match config.notify {
+            Some(settings) => settings,
+            None => {
+                log::warn!(
+                    "no notify settings in {}; not sending failure notification",
+                    config::FILE_NAME
+                );
+                return;
+            }
+        },
+        Err(load_err) => {
+            log::warn!("unable to send failure notification - {}", load_err);
+            return;
+        }
+    };
+
+    let host = gethostname::gethostname().to_string_lossy().into_owned();
+    let state = git::open(repo)
+        .map(|git_repo| git::summary(&git_repo))
+        .unwrap_or_else(|open_err| open_err);
+
+    if let Some(url) = &settings.webhook {
+        let payload = serde_json::json!({
+            "operation": operation,
+            "host": host,
+            "repository": repo.display().to_string(),
+            "state": state,
+            "error": err,
+        });
+        if let Err(notify_err) = notification::webhook(url, &payload) {
+            log::warn!("{}", notify_err);
+        }
+    }
+
+    if let Some(email) = &settings.email {
+        let subject = format!("dotty {} failed on {}", operation, host);
+        let body = format!(
+            "repository: {}\nstate: {}\nerror: {}\n",
+            repo.display(),
+            state,
+            err
+        );
+        if let Err(notify_err) = notification::email(email, &subject, &body) {
+            log::warn!("{}", notify_err);
+        }
+    }
+}
+
+pub fn brew_capture(repo: &Path) -> Result<(), String> {
+    if !brew::is_installed() {
+        return Err("brew is not installed".to_owned());
+    }
+
+    let git_repo = git::open(repo)?;
+    check_nothing_else_staged(&git_repo, &[])?;
+    let relative_path = PathBuf::from(config::BREWFILE);
+    brew::dump(&repo.join(&relative_path))?;
+
+    if !git::is_modified(&git_repo, &relative_path)? {
+        log::info!("{} is already up to date", config::BREWFILE);
+        return Ok(());
+    }
+    git::stage_all_paths(&git_repo, &vec![relative_path])?;
+    git::commit(&git_repo, &format!("capturing {}", config::BREWFILE))?;
+
+    log::info!(
+        "successfully captured {} in dotty repository {}",
+        config::BREWFILE,
+        repo.display()
+    );
+    Ok(())
+}
+
+pub fn brew_apply(repo: &Path) -> Result<(), String> {
+    if !brew::is_installed() {
+        return Err("brew is not installed".to_owned());
+    }
+
+    let brewfile = repo.join(config::BREWFILE);
+    if !brewfile.exists() {
+        return Err(format!("{} does not exist", brewfile.display()));
+    }
+    brew::install(&brewfile)?;
+
+    log::info!("successfully applied {}", brewfile.display());
+    Ok(())
+}
+
+pub fn packages_diff(repo: &Path) -> Result<(), String> {
+    let mut rows = vec![vec![
+        "MANAGER".to_owned(),
+        "PACKAGE".to_owned(),
+        "STATUS".to_owned(),
+    ]];
+    for (manager, packages) in package_states(&config::load(repo)?)? {
+        for (package, installed) in packages {
+            rows.push(vec![
+                manager.to_string(),
+                package,
+                match installed {
+                    true => "installed".to_owned(),
+                    false => "missing".to_owned(),
+                },
+            ]);
+        }
+    }
+    print!("{}", string::format_table(&rows));
+    Ok(())
+}
+
+pub fn packages_install(repo: &Path) -> Result<(), String> {
+    let mut installed = 0;
+    for (manager, packages) in package_states(&config::load(repo)?)? {
+        let missing: Vec<String> = packages
+            .into_iter()
+            .filter(|(_, installed)| !installed)
+            .map(|(package, _)| package)
+            .collect();
+        if missing.is_empty() {
+            log::debug!("all {} packages are installed", manager);
+            continue;
+        }
+        log::info!("installing {} with {}", missing.join(", "), manager);
+        manager.install(&missing)?;
+        installed += missing.len();
+    }
+    log::info!("successfully installed {} packages", installed);
+    Ok(())
+}
+
+type PackageStates = Vec<(Manager, Vec<(String, bool)>)>;
+
+/// Whether each declared package is installed, for the package managers on this machine
+fn package_states(config: &config::Config) -> Result<PackageStates, String> {
+    let mut states = Vec::new();
+    for manager in Manager::ALL {
+        let declared = config.packages.get(manager);
+        if declared.is_empty() {
+            continue;
+        }
+        if !manager.is_available() {
+            log::debug!(
+                "skipping {} packages as {} is not available",
+                declared.len(),
+                manager
+            );
+            continue;
+        }
+        let installed = manager.installed()?;
+        states.push((
+            manager,
+            declared
+                .iter()
+                .map(|p| (p.clone(), installed.contains(p)))
+                .collect(),
+        ));
+    }
+    Ok(states)
+}
+
+pub fn crypt_init(repo: &Path) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    let key_path = crypt::key_path(git_repo.path());
+    if key_path.exists() {
+        return Err(format!(
+            "dotty repository {} is already encrypted",
+            repo.display()
+        ));
+    }
+    check_nothing_else_staged(&git_repo, &[])?;
+
+    crypt::Key::generate().write(&key_path)?;
+    git::configure_crypt_filter(&git_repo, &crypt_filter_command(repo)?)?;
+
+    let attributes_path = repo.join(".gitattributes");
+    if let Err(err) = std::fs::write(&attributes_path, crypt::gitattributes()) {
+        return Err(format!(
+            "failed to write {} - {}",
+            attributes_path.display(),
+            err
+        ));
+    }
+
+    let mut to_commit = git::tracked_files(&git_repo)?;
+    to_commit.push(PathBuf::from(".gitattributes"));
+    git::stage_all_paths(&git_repo, &to_commit)?;
+    git::commit(&git_repo, "encrypting repository")?;
+
+    log::warn!("commits made before encryption still contain plaintext");
+    log::info!(
+        "successfully encrypted dotty repository {}; use crypt export-key to unlock other clones",
+        repo.display()
+    );
+    Ok(())
+}
+
+pub fn crypt_export_key(repo: &Path, to: &Path) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    crypt::Key::read(&crypt::key_path(git_repo.path()))?.write(to)?;
+    log::info!("successfully exported key to {}", to.display());
+    Ok(())
+}
+
+pub fn crypt_import_key(repo: &Path, from: &Path) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    crypt::Key::read(from)?.write(&crypt::key_path(git_repo.path()))?;
+    git::configure_crypt_filter(&git_repo, &crypt_filter_command(repo)?)?;
+    git::decrypt_worktree(&git_repo)?;
+    log::info!("successfully unlocked dotty repository {}", repo.display());
+    Ok(())
+}
+
+pub fn crypt_filter(repo: &Path, encrypt: bool) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    let key = crypt::Key::read(&crypt::key_path(git_repo.path()))?;
+
+    let mut input = Vec::new();
+    if let Err(err) = io::stdin().read_to_end(&mut input) {
+        return Err(format!("failed to read stdin - {}", err));
+    }
+    let output = match encrypt {
+        true => key.encrypt(&input)?,
+        false => key.decrypt(&input)?,
+    };
+    if let Err(err) = io::stdout().write_all(&output) {
+        return Err(format!("failed to write stdout - {}", err));
+    }
+    Ok(())
+}
+
+fn crypt_filter_command(repo: &Path) -> Result<String, String> {
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => return Err(format!("failed to get dotty executable path - {}", err)),
+    };
+    Ok(format!(
+        "{} --repository {} crypt",
+        shell_quote(&exe),
+        shell_quote(repo)
+    ))
+}
+
+/// Quotes a path for the shell git runs filters with, where nothing is special within single quotes
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', "'\\''"))
+}
+
+const SCHEDULE_LABEL: &str = "com.github.keithhendry.dotty.sync";
+const SCHEDULE_UNIT: &str = "dotty-sync";
+
+/// Installs a launch agent on macOS, or a systemd user timer on Linux, that syncs the repository
+pub fn schedule_install(repo: &Path, interval: Option<&str>, at_login: bool) -> Result<(), String> {
+    check_schedule_supported()?;
+    let interval = interval.map(string::parse_duration).transpose()?;
+    if interval.is_none() && !at_login {
+        return Err("either an interval or at login must be specified".to_owned());
+    }
+
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => return Err(format!("failed to get dotty executable path - {}", err)),
+    };
+    let program_args = vec![
+        exe.display().to_string(),
+        "--repository".to_owned(),
+        repo.display().to_string(),
+        "sync".to_owned(),
+        "--notify".to_owned(),
+    ];
+    let installed = match launchd::is_supported() {
+        true => launchd::install(SCHEDULE_LABEL, &program_args, interval, at_login)?,
+        false => systemd::install(
+            SCHEDULE_UNIT,
+            "Sync the dotty repository",
+            &program_args,
+            interval,
+            at_login,
+        )?,
+    };
+
+    log::info!(
+        "successfully scheduled sync of dotty repository {} with {}",
+        repo.display(),
+        installed.display()
+    );
+    Ok(())
+}
+
+pub fn schedule_status() -> Result<(), String> {
+    check_schedule_supported()?;
+    let (installed, state) = match launchd::is_supported() {
+        true => (
+            launchd::agent_path(SCHEDULE_LABEL)?,
+            match launchd::is_loaded(SCHEDULE_LABEL) {
+                true => "loaded",
+                false => "not loaded",
+            },
+        ),
+        false => (
+            systemd::timer_path(SCHEDULE_UNIT)?,
+            match systemd::is_active(SCHEDULE_UNIT) {
+                true => "active",
+                false => "not active",
+            },
+        ),
+    };
+    match installed.exists() {
+        true => println!("{} is installed and {}", installed.display(), state),
+        false => println!("scheduled sync is not installed"),
+    }
+    Ok(())
+}
+
+pub fn schedule_remove() -> Result<(), String> {
+    check_schedule_supported()?;
+    let removed = match launchd::is_supported() {
+        true => launchd::remove(SCHEDULE_LABEL)?,
+        false => systemd::remove(SCHEDULE_UNIT)?,
+    };
+    match removed {
+        true => log::info!("successfully removed scheduled sync"),
+        false => log::warn!("scheduled sync is not installed"),
+    }
+    Ok(())
+}
+
+fn check_schedule_supported() -> Result<(), String> {
+    match launchd::is_supported() || systemd::is_supported() {
+        true => Ok(()),
+        false => Err("scheduled sync is only supported on macOS and Linux".to_owned()),
+    }
+}
+
+#[derive(PartialEq)]
+enum PathType {
+    File,
+    Dir,
+    GitRepo,
+}
+
+/// The directories themselves, to be moved whole instead of file by file
+fn whole_dirs_to_add(paths: &[PathBuf]) -> Result<Vec<(PathBuf, PathType)>, String> {
+    let mut dirs = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            return Err(format!("{} is not a directory", path.display()));
+        }
+        let path = path::canonicalize(path)?;
+        match git::check_open(&path) {
+            true => dirs.push((path, PathType::GitRepo)),
+            false => dirs.push((path, PathType::Dir)),
+        }
+    }
+    Ok(dirs)
+}
+
+fn flatten_paths_to_add(
+    paths: &Vec<PathBuf>,
+    ignore: Option<&dottyignore::Rules>,
+) -> Result<Vec<(PathBuf, PathType)>, String> {
+    timings::time(Phase::Scan, || {
+        let mut path_stack = Vec::new();
+
+        for path in paths {
+            if !path.exists() {
+                return Err(format!("{} does not exist", path.display()));
+            }
+            path_stack.push((
+                path::canonicalize(path)?,
+                dottyignore::GitignoreChain::default(),
+            ))
+        }
+
+        let mut flattened: Vec<(PathBuf, PathType)> = Vec::new();
+
+        while let Some((path, gitignores)) = path_stack.pop() {
+            if path.is_dir() {
+                if git::check_open(&path) {
+                    flattened.push((path, PathType::GitRepo))
+                } else {
+                    let gitignores = gitignores.descend(&path);
+                    for child in fs::read_dir(&path)? {
+                        match ignore {
+                            Some(rules) if rules.is_ignored(&child) => {
+                                log::debug!("ignoring {}", child.display())
+                            }
+                            _ if gitignores.is_ignored(&child) => {
+                                log::debug!("ignoring {} matched by .gitignore", child.display())
+                            }
+                            _ => path_stack.push((child, gitignores.clone())),
+                        }
+                    }
+                }
+            } else if path.is_file() {
+                flattened.push((path, PathType::File))
+            } else {
+                log::debug!("skipping {} which is not a regular file", path.display())
+            }
+        }
+
+        Ok(flattened)
+    })
+}
+
+#[derive(Clone)]
+struct ManagedEntry {
+    from: PathBuf,
+    relative_path: PathBuf,
+    layer: String,
+    target: Option<PathBuf>,
+}
+
+impl ManagedEntry {
+    /// Where the entry is restored, beneath the root unless its dotty.yaml entry has a target
+    fn destination(&self, root: &Path) -> PathBuf {
+        root.join(self.target.as_ref().unwrap_or(&self.relative_path))
+    }
+}
+
+/// Whether the dotty.yaml entry of the managed entry, or of one of its parents, has any of the tags
+fn is_tagged(config: &config::Config, entry: &ManagedEntry, tags: &[String]) -> bool {
+    config
+        .tags(&entry.relative_path)
+        .iter()
+        .any(|tag| tags.contains(tag))
+}
+
+const PERSONAL_LAYER: &str = "personal";
+
+/// The personal repository and all of its profile and host overlays, active or not
+fn personal_sources(repo: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut sources = vec![repo.to_owned()];
+    for overlays in [
+        config::PACKAGES_DIR,
+        config::PROFILES_DIR,
+        config::HOSTS_DIR,
+    ] {
+        let dir = repo.join(overlays);
+        if dir.is_dir() {
+            sources.extend(fs::read_dir(&dir)?.into_iter().filter(|p| p.is_dir()));
+        }
+    }
+    Ok(sources)
+}
+
+/// Whether the source is the personal repository or one of its package, profile or host overlays
+fn is_personal(layer: &str) -> bool {
+    layer == PERSONAL_LAYER
+        || layer.starts_with(&format!("{}/", PERSONAL_LAYER))
+        || layer.starts_with(&format!("{}:", PERSONAL_LAYER))
+        || layer.starts_with(&format!("{}@", PERSONAL_LAYER))
+}
+
+/// Where the synced layers are checked out, which may be inside the repository
+fn layer_paths(repo: &Path, config: &config::Config) -> Result<Vec<PathBuf>, String> {
+    let mut paths = Vec::new();
+    for layer in &config.layers {
+        let layer_path = path::resolve_from(repo, &layer.path)?;
+        if layer_path.exists() {
+            paths.push(path::canonicalize(&layer_path)?);
+        }
+    }
+    Ok(paths)
+}
+
+/// The files to restore from the repository, leaving out the layers checked out inside it
+fn managed_paths(repo: &Path, layers: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let repo_path = path::canonicalize(repo)?;
+    let nested: Vec<&PathBuf> = layers
+        .iter()
+        .filter(|layer| layer.starts_with(&repo_path) && **layer != repo_path)
+        .collect();
+    let top_level_repo_paths = fs::read_dir(repo)?
+        .into_iter()
+        .filter(|p| {
+            p.file_name()
+                .and_then(|f| f.to_str())
+                .map(|f| {
+                    !matches!(
+                        f,
+                        ".git"
+                            | ".gitmodules"
+                            | config::FILE_NAME
+                            | config::LOCAL_FILE_NAME
+                            | config::DEFAULTS_DIR
+                            | config::BREWFILE
+                            | config::PACKAGES_DIR
+                            | config::PROFILES_DIR
+                            | config::HOSTS_DIR
+                            | config::HOOKS_DIR
+                            | lockfile::FILE_NAME
+                            | dottyignore::FILE_NAME
+                    )
+                })
+                .unwrap_or(true)
+        })
+        .collect();
+    let mut paths: Vec<PathBuf> = flatten_paths_to_add(&top_level_repo_paths, None)?
+        .into_iter()
+        .map(|x| x.0)
+        .filter(|p| !nested.iter().any(|layer| p.starts_with(layer)))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn managed_entries(repo: &Path, config: &config::Config) -> Result<Vec<ManagedEntry>, String> {
+    timings::time(Phase::Plan, || {
+        let mut layers = Vec::new();
+        for layer in &config.layers {
+            let layer_path = path::resolve_from(repo, &layer.path)?;
+            if !layer_path.exists() {
+                log::warn!(
+                    "layer {} has not been synced to {}",
+                    layer.name,
+                    layer_path.display()
+                );
+                continue;
+            }
+            layers.push((layer.name.clone(), layer_path));
+        }
+        layers.push((PERSONAL_LAYER.to_owned(), repo.to_owned()));
+
+        let profiles = profile::active();
+        let hostname = profile::hostname();
+        let enabled = config::load_local(repo)?.enabled;
+        let mut sources = Vec::new();
+        for (name, layer_path) in layers {
+            sources.push((name.clone(), layer_path.clone()));
+            for package in &enabled {
+                let package_path = layer_path.join(config::Config::package_path(package));
+                if package_path.is_dir() {
+                    sources.push((format!("{}/{}", name, package), package_path));
+                }
+            }
+            for profile_name in &profiles {
+                let profile_path = layer_path.join(config::Config::profile_path(profile_name));
+                if profile_path.is_dir() {
+                    sources.push((format!("{}:{}", name, profile_name), profile_path));
+                }
+            }
+            let host_path = layer_path.join(config::Config::host_path(&hostname));
+            if host_path.is_dir() {
+                sources.push((format!("{}@{}", name, hostname), host_path));
+            }
+        }
+        for profile_name in &profiles {
+            let suffix = format!(":{}", profile_name);
+            if !sources.iter().any(|(name, _)| name.ends_with(&suffix)) {
+                log::warn!("profile {} does not exist in any layer", profile_name);
+            }
+        }
+        for package in &enabled {
+            let suffix = format!("/{}", package);
+            if !sources.iter().any(|(name, _)| name.ends_with(&suffix)) {
+                log::warn!("package {} does not exist in any layer", package);
+            }
+        }
+
+        let layer_paths = layer_paths(repo, config)?;
+        let mut entries: BTreeMap<PathBuf, ManagedEntry> = BTreeMap::new();
+        for (name, source_path) in sources {
+            for from in managed_paths(&source_path, &layer_paths)? {
+                let destination =
+                    config.destination(&path::relative_from_root(&source_path, &from)?);
+                let relative_path = template::rendered_path(&destination);
+                if let Some((root_name, _)) = config
+                    .named_root(&relative_path)
+                    .filter(|(root_name, _)| !config.roots.contains_key(root_name))
+                {
+                    log::warn!(
+                        "not restoring {} as there is no root {} in {}",
+                        from.display(),
+                        root_name,
+                        config::FILE_NAME
+                    );
+                    continue;
+                }
+                if is_personal(&name)
+                    && config.find_entry(&relative_path).is_none()
+                    && config.find_entry(&destination).is_none()
+                {
+                    log::warn!(
+                        "{} is not listed in {}, use dotty backfill to manage it",
+                        from.display(),
+                        config::FILE_NAME
+                    );
+                    continue;
+                }
+                let (from, relative_path) = match config.directory_entry(&relative_path) {
+                    Some(dir) if entries.get(&dir.key()).is_some_and(|e| e.layer == name) => {
+                        continue
+                    }
+                    Some(dir) => (source_path.join(dir.source()), dir.key()),
+                    None => (from, relative_path),
+                };
+                if let Some(overridden) = entries.get(&relative_path) {
+                    log::trace!(
+                        "{} from layer {} overrides layer {}",
+                        relative_path.display(),
+                        name,
+                        overridden.layer
+                    );
+                }
+                let target = config
+                    .target(&relative_path)
+                    .map(|target| path::expand_home(&target))
+                    .transpose()?;
+                entries.insert(
+                    relative_path.clone(),
+                    ManagedEntry {
+                        from,
+                        relative_path,
+                        layer: name.clone(),
+                        target,
+                    },
+                );
+            }
+        }
+        let personal_sources = personal_sources(repo)?;
+        for entry in &config.entries {
+            let stored = config.stored_path(&entry.key());
+            let template = PathBuf::from(format!("{}.{}", stored.display(), template::EXTENSION));
+            if !personal_sources
+                .iter()
+                .any(|dir| dir.join(&stored).exists() || dir.join(&template).exists())
+            {
+                log::warn!(
+                    "{} is listed in {} but missing from the repository",
+                    entry.path.display(),
+                    config::FILE_NAME
+                );
+            }
+        }
+        Ok(entries.into_values().collect())
+    })
+}
+
+fn export_defaults(repo: &Path, domains: &[String]) -> Result<Vec<PathBuf>, String> {
+    if !defaults::is_supported() {
+        return Err("macOS defaults can only be added on macOS".to_owned());
+    }
+
+    let mut config = config::load(repo)?;
+    let mut exported = Vec::new();
+    for domain in domains {
+        let relative_path = config::Config::defaults_path(domain);
+        log::debug!(
+            "exporting defaults domain {} to {}",
+            domain,
+            relative_path.display()
+        );
+        defaults::export(domain, &repo.join(&relative_path))?;
+        if !config.defaults.contains(domain) {
+            config.defaults.push(domain.to_owned());
+        }
+        exported.push(relative_path);
+    }
+    config::save(repo, &config)?;
+    exported.push(PathBuf::from(config::FILE_NAME));
+    Ok(exported)
+}
+
+fn import_defaults(repo: &Path, domains: &[String]) -> Result<(), String> {
+    if !defaults::is_supported() {
+        log::debug!("skipping {} macOS defaults domains", domains.len());
+        return Ok(());
+    }
+
+    for domain in domains {
+        let from = repo.join(config::Config::defaults_path(domain));
+        log::debug!(
+            "importing defaults domain {} from {}",
+            domain,
+            from.display()
+        );
+        defaults::import(domain, &from)?;
+    }
+    Ok(())
+}
+
+fn build_plan(
+    repo: &Path,
+    root: &Path,
+    config: &config::Config,
+    entries: &[ManagedEntry],
+    symlinks: bool,
+    overwrite: bool,
+    prune: bool,
+) -> Result<Plan, String> {
+    timings::time(Phase::Plan, || {
+        let entries = order_entries(config, entries)?;
+        let mut variables = template::variables(repo, config)?;
+        let ignore = dottyignore::load(repo, root)?;
+        let mut actions = Vec::new();
+        let mut conflicts = Vec::new();
+        let state = state::load()?;
+        let mut deployed = BTreeSet::new();
+        let mut dirs = BTreeSet::from([root.to_owned()]);
+        for entry in &entries {
+            let to = entry.destination(root);
+            let config_entry = config.find_entry(&entry.relative_path);
+            if config_entry.is_some_and(|e| !e.is_current()) {
+                log::debug!("skipping {} which is not for this machine", to.display());
+                continue;
+            }
+            if ignore.is_ignored(&to) {
+                log::debug!("skipping ignored {}", to.display());
+                continue;
+            }
+            if let Some(parent) = to.parent() {
+                dirs.insert(parent.to_owned());
+            }
+            deployed.insert(to.clone());
+
+            if config_entry.is_some_and(|e| e.encrypt) && is_locked(&entry.from)? {
+                return Err(format!(
+                    "{} is still encrypted, unlock the repository with dotty crypt import-key",
+                    entry.from.display()
+                ));
+            }
+            let method = match config_entry {
+                Some(e) => e.method()?,
+                None => fs::Method::Symlink,
+            };
+            let copy = method == fs::Method::Copy;
+            let hardlink = symlinks && method == fs::Method::Hardlink;
+            let symlinks = symlinks && method == fs::Method::Symlink;
+            let mode = config.mode(&entry.relative_path)?;
+            let drifted = |path: &Path| match mode {
+                Some(mode) => fs::mode(path).map(|m| m != mode),
+                None => Ok(false),
+            };
+            let (owner, group) = config.ownership(&entry.relative_path);
+            // symlinks and hard links share the owner of the file in the repository
+            let disowned = |path: &Path| match (owner, group) {
+                (None, None) => Ok(false),
+                _ => fs::owner(path).map(|(uid, gid)| {
+                    owner.is_some_and(|o| o != uid) || group.is_some_and(|g| g != gid)
+                }),
+            };
+            let kind = if template::is_template(&entry.from) {
+                let rendered = template::render(repo, &entry.from, &mut variables)?;
+                match fs::deploy_status(&entry.from, &to)? {
+                    _ if is_rendered(&to, &rendered) && (drifted(&to)? || disowned(&to)?) => {
+                        Kind::Chmod
+                    }
+                    _ if is_rendered(&to, &rendered) => continue,
+                    fs::DeployStatus::Missing => Kind::Render,
+                    _ if overwrite => Kind::Render,
+                    _ => {
+                        conflicts.push(to.display().to_string());
+                        continue;
+                    }
+                }
+            } else {
+                match fs::deploy_status(&entry.from, &to)? {
+                    fs::DeployStatus::Exists if copy && fs::same_contents(&entry.from, &to) => {
+                        match drifted(&to)? || disowned(&to)? {
+                            true => Kind::Chmod,
+                            false => continue,
+                        }
+                    }
+                    fs::DeployStatus::Linked
+                        if symlinks || (hardlink && fs::is_hard_link(&entry.from, &to)) =>
+                    {
+                        match drifted(&entry.from)? {
+                            true => Kind::Chmod,
+                            false => continue,
+                        }
+                    }
+                    fs::DeployStatus::Linked => Kind::Replace,
+                    fs::DeployStatus::Missing if symlinks => Kind::Link,
+                    fs::DeployStatus::Missing if hardlink => Kind::Hardlink,
+                    fs::DeployStatus::Missing => Kind::Copy,
+                    fs::DeployStatus::Exists if copy && is_mergeable(repo, &state, entry, &to)? => {
+                        Kind::Merge
+                    }
+                    fs::DeployStatus::Exists if overwrite => Kind::Backup,
+                    fs::DeployStatus::Conflict if overwrite => Kind::Replace,
+                    fs::DeployStatus::Exists | fs::DeployStatus::Conflict => {
+                        conflicts.push(to.display().to_string());
+                        continue;
+                    }
+                }
+            };
+            actions.push(Action {
+                kind,
+                from: Some(entry.from.clone()),
+                source: Some(fs::fingerprint(&entry.from)?),
+                target: fs::fingerprint(&to)?,
+                to,
+                copy,
+                hardlink,
+                mode,
+                owner,
+                group,
+            });
+        }
+        if !conflicts.is_empty() {
+            return Err(format!(
+                "not overwriting existing {} without --overwrite",
+                conflicts.join(", ")
+            ));
+        }
+
+        let mut pruned = BTreeSet::new();
+        // restore did not record these, so they may not be its to remove
+        for dir in dirs.iter().filter(|_| prune) {
+            for link in fs::dangling_symlinks(dir, repo)? {
+                if !deployed.contains(&link) {
+                    pruned.insert(link);
+                }
+            }
+        }
+        // entries removed from the repository since restore deployed them, unless changed since
+        for record in state.deployed_from(repo) {
+            if record.entry.exists()
+                || deployed.contains(&record.target)
+                || pruned.contains(&record.target)
+            {
+                continue;
+            }
+            let unchanged = match record.method {
+                state::Method::Symlink => {
+                    fs::link_target(&record.target)?.is_some_and(|link| link == record.entry)
+                }
+                _ => {
+                    record.checksum.is_some() && state::checksum(&record.target)? == record.checksum
+                }
+            };
+            if unchanged {
+                pruned.insert(record.target.clone());
+            }
+        }
+        for to in pruned {
+            actions.push(Action {
+                kind: Kind::Prune,
+                from: None,
+                source: None,
+                target: fs::fingerprint(&to)?,
+                to,
+                copy: false,
+                hardlink: false,
+                mode: None,
+                owner: None,
+                group: None,
+            });
+        }
+
+        Ok(Plan {
+            version: plans::VERSION,
+            repository: repo.to_owned(),
+            root: root.to_owned(),
+            head: git::head_id(&git::open(repo)?)?,
+            symlinks,
+            preserve_times: None,
+            actions,
+            defaults: config.defaults.clone(),
+        })
+    })
+}
+
+/// Whether `to` is a file already holding the rendered template
+fn is_rendered(to: &Path, rendered: &str) -> bool {
+    std::fs::symlink_metadata(to).is_ok_and(|m| m.is_file())
+        && std::fs::read_to_string(to).is_ok_and(|contents| contents == rendered)
+}
+
+/// Whether the file in the repository still holds ciphertext, as the repository has no key
+fn is_locked(from: &Path) -> Result<bool, String> {
+    if !from.is_file() {
+        return Ok(false);
+    }
+    match std::fs::read(from) {
+        Ok(contents) => Ok(crypt::is_encrypted(&contents)),
+        Err(err) => Err(format!("failed to read {} - {}", from.display(), err)),
+    }
+}
+
+/// The package of the packages directory an entry comes from, if any
+fn entry_package(entry: &ManagedEntry) -> Option<&str> {
+    entry.layer.split_once('/').map(|(_, package)| package)
+}
+
+/// Orders entries after the entries their dotty.yaml entry, or their package, depends on
+fn order_entries<'a>(
+    config: &config::Config,
+    entries: &'a [ManagedEntry],
+) -> Result<Vec<&'a ManagedEntry>, String> {
+    let keys: Vec<Option<PathBuf>> = entries
+        .iter()
+        .map(|entry| config.find_entry(&entry.relative_path).map(|e| e.key()))
+        .collect();
+    let mut edges = Vec::new();
+    for (dependent, entry) in entries.iter().enumerate() {
+        if let Some(package) = entry_package(entry) {
+            for depends in config.package_depends.get(package).into_iter().flatten() {
+                let mut found = false;
+                for (dependency, other) in entries.iter().enumerate() {
+                    if entry_package(other) == Some(depends.as_str()) {
+                        found = true;
+                        edges.push((dependency, dependent));
+                    }
+                }
+                if !found {
+                    log::warn!(
+                        "package {} depends on package {} which is not enabled",
+                        package,
+                        depends
+                    );
+                }
+            }
+        }
+
+        let Some(config_entry) = config.find_entry(&entry.relative_path) else {
+            continue;
+        };
+        for depends in &config_entry.depends {
+            let mut found = false;
+            for (dependency, other) in entries.iter().enumerate() {
+                if other.relative_path.starts_with(depends) {
+                    found = true;
+                    // the files of one dotty.yaml entry share its dependencies, not each other
+                    if keys[dependency] != keys[dependent] {
+                        edges.push((dependency, dependent));
+                    }
+                }
+            }
+            if !found {
+                log::warn!(
+                    "{} depends on {} which is not managed by dotty",
+                    config_entry.path.display(),
+                    depends.display()
+                );
+            }
+        }
+    }
+
+    match graph::topological_order(entries.len(), &edges) {
+        Ok(order) => Ok(order.into_iter().map(|i| &entries[i]).collect()),
+        Err(cycle) => Err(format!(
+            "entries have a dependency cycle {}",
+            cycle
+                .iter()
+                .map(|&i| entries[i].relative_path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        )),
+    }
+}
+
+fn check_plan(repo: &Path, root: &Path, plan: &Plan) -> Result<(), String> {
+    if plan.repository != repo || plan.root != root {
+        return Err(format!(
+            "plan was created for dotty repository {} and root {}",
+            plan.repository.display(),
+            plan.root.display()
+        ));
+    }
+    let head = git::head_id(&git::open(repo)?)?;
+    if head != plan.head {
+        return Err(format!(
+            "refusing to apply plan, dotty repository {} moved to {} since it was created",
+            repo.display(),
+            head.as_deref().unwrap_or("no commits")
+        ));
+    }
+
+    let mut changed = Vec::new();
+    for action in &plan.actions {
+        let source = action.from.as_deref().map(fs::fingerprint).transpose()?;
+        if source != action.source || fs::fingerprint(&action.to)? != action.target {
+            changed.push(action.to.display().to_string());
+        }
+    }
+    if !changed.is_empty() {
+        return Err(format!(
+            "refusing to apply plan, {} changed since it was created",
+            changed.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Asks before the plan replaces existing files or symlinks that are not already deployed
+fn confirm_overwrite(plan: &Plan) -> Result<bool, String> {
+    let mut overwritten = Vec::new();
+    for action in &plan.actions {
+        let Some(from) = &action.from else {
+            continue;
+        };
+        if matches!(
+            action.kind,
+            Kind::Backup | Kind::Replace | Kind::Render | Kind::Merge
+        ) && matches!(
+            fs::deploy_status(from, &action.to)?,
+            fs::DeployStatus::Exists | fs::DeployStatus::Conflict
+        ) {
+            overwritten.push(&action.to);
+        }
+    }
+    if overwritten.is_empty() {
+        return Ok(true);
+    }
+    for path in &overwritten {
+        println!("{}", path.display());
+    }
+    prompt::proceed(&format!(
+        "Overwrite these {} paths, moving them to the backup directory?",
+        overwritten.len()
+    ))
+}
+
+/// Asks what to do with each existing file the plan would replace, updating its actions
+fn resolve_conflicts(repo: &Path, config: &config::Config, plan: &mut Plan) -> Result<(), String> {
+    let mut variables = None;
+    let mut actions = Vec::new();
+    for mut action in plan.actions.drain(..) {
+        let Some(from) = action.from.clone() else {
+            actions.push(action);
+            continue;
+        };
+        let status = fs::deploy_status(&from, &action.to)?;
+        let is_conflict = match action.kind {
+            Kind::Render => action.target != fs::Fingerprint::Missing,
+            Kind::Backup | Kind::Replace => status != fs::DeployStatus::Linked,
+            _ => false,
+        };
+        if !is_conflict {
+            actions.push(action);
+            continue;
+        }
+
+        let is_file = status == fs::DeployStatus::Exists && action.to.is_file();
+        if action.kind == Kind::Render {
+            let variables = match &mut variables {
+                Some(variables) => variables,
+                None => variables.insert(template::variables(repo, config)?),
+            };
+            let rendered = template::render(repo, &from, variables)?;
+            if let Ok(existing) = std::fs::read_to_string(&action.to) {
+                let name = action.to.display().to_string();
+                print!(
+                    "{}",
+                    diff::colorize(&diff::unified(&name, &name, &existing, &rendered))
+                );
+            }
+        } else if is_file && from.is_file() {
+            if let Some(changes) = diff::unified_files(&action.to, &from)? {
+                print!("{}", diff::colorize(&changes));
+            }
+        } else if let Some(target) = fs::link_target(&action.to)? {
+            println!(
+                "{} is a symlink to {}",
+                action.to.display(),
+                target.display()
+            );
+        }
+
+        let mut choices = vec![("overwrite", Some(action.kind)), ("skip", None)];
+        if is_file && action.kind != Kind::Render {
+            choices.push(("keep both", Some(Kind::Keep)));
+            if from.is_file() {
+                choices.push(("merge", Some(Kind::Merge)));
+            }
+        }
+        let choice = prompt::select(
+            &format!(
+                "{} already exists, what should be done?",
+                action.to.display()
+            ),
+            choices.iter().map(|(name, _)| name.to_string()).collect(),
+        )?;
+        match choices[choice].1 {
+            Some(kind) => {
+                action.kind = kind;
+                actions.push(action);
+            }
+            None => log::info!("skipping {}", action.to.display()),
+        }
+    }
+    plan.actions = actions;
+    Ok(())
+}
+
+/// Where keep both moves an existing file, next to it
+fn kept_path(to: &Path) -> PathBuf {
+    let mut kept = to.as_os_str().to_owned();
+    kept.push(".orig");
+    let mut count = 1;
+    let mut path = PathBuf::from(&kept);
+    while path.exists() {
+        count += 1;
+        let mut numbered = kept.clone();
+        numbered.push(format!(".{}", count));
+        path = PathBuf::from(numbered);
+    }
+    path
+}
+
+/// Merges the existing file into the repository file, or when they conflict writes both, with
+/// conflict markers between them, beside the existing file until the markers are resolved there
+///
+/// With the contents restore deployed as the base, only the lines both changed differently conflict.
+/// Returns whether the merge was clean, or resolved.
+fn merge_into_repo(from: &Path, existing: &Path, base: Option<&str>) -> Result<bool, String> {
+    let mut merge_path = existing.as_os_str().to_owned();
+    merge_path.push(".merge");
+    let merge_path = PathBuf::from(merge_path);
+    if merge_path.is_file() {
+        if diff::has_conflict_markers(&merge_path)? {
+            log::warn!(
+                "resolve the conflict markers in {} first",
+                merge_path.display()
+            );
+            return Ok(false);
+        }
+        log::debug!("merging the resolved {}", merge_path.display());
+        fs::copy(&merge_path, from)?;
+        fs::remove(&merge_path)?;
+        return Ok(true);
+    }
+
+    let read = |path: &Path| match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(err) => Err(format!("failed to merge {} - {}", path.display(), err)),
+    };
+    let (local, dotty) = (read(existing)?, read(from)?);
+    let (merged, conflicted) = match base {
+        Some(base) => diff::merge3("local", "dotty", base, &local, &dotty),
+        None => {
+            let merged = diff::merge("local", "dotty", &local, &dotty);
+            let conflicted = merged.lines().any(|line| line.starts_with("<<<<<<< "));
+            (merged, conflicted)
+        }
+    };
+    if !conflicted {
+        fs::write(from, &merged)?;
+        return Ok(true);
+    }
+    // the repository keeps its version until the conflict is resolved
+    fs::write(&merge_path, &merged)?;
+    log::warn!(
+        "resolve the conflict markers in {}, then restore or harvest {} again",
+        merge_path.display(),
+        existing.display()
+    );
+    Ok(false)
+}
+
+/// Whether both the deployed copy and the entry changed since restore, which recorded their base
+fn is_mergeable(
+    repo: &Path,
+    state: &state::State,
+    entry: &ManagedEntry,
+    to: &Path,
+) -> Result<bool, String> {
+    let Some(record) = state.find(to).filter(|record| record.baseline.is_some()) else {
+        return Ok(false);
+    };
+    Ok(state::checksum(to)? != record.checksum
+        && state::checksum(&entry.from)? != record.checksum
+        && baseline(repo, record).is_some())
+}
+
+/// The contents restore deployed, while they are still in the object database
+fn baseline(repo: &Path, record: &state::Deployed) -> Option<String> {
+    let id = record.baseline.as_deref()?;
+    let contents = git::read_blob(&git::open(repo).ok()?, id)?;
+    String::from_utf8(contents).ok()
+}
+
+/// Keeps the baselines of the copies deployed from the repository reachable in git
+fn keep_baselines(repo: &Path, state: &state::State) -> Result<(), String> {
+    if dry_run::is_enabled() {
+        return Ok(());
+    }
+    let ids = state
+        .deployed_from(repo)
+        .filter_map(|record| record.baseline.clone())
+        .collect::<Vec<_>>();
+    git::keep_blobs(&git::open(repo)?, &ids)
+}
+
+/// Keeps the deployed contents of a copy as the base of later merges, except in encrypted repositories
+fn store_baseline(repo: &Path, path: &Path) -> Result<Option<String>, String> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let git_repo = git::open(repo)?;
+    if crypt::load_key(git_repo.path())?.is_some() {
+        return Ok(None);
+    }
+    git::write_blob(&git_repo, path).map(Some)
+}
+
+/// Returns the deployed paths
+fn execute_plan(plan: &Plan) -> Result<Vec<PathBuf>, String> {
+    let backup = match plan.actions.iter().any(|a| match a.kind {
+        Kind::Backup | Kind::Replace | Kind::Merge => true,
+        Kind::Render => a.target != fs::Fingerprint::Missing,
+        _ => false,
+    }) {
+        true => {
+            let config = config::load(&plan.repository)?;
+            Some(backup::create(&backup::base_dir(
+                config.backup_dir.as_deref(),
+            )?)?)
+        }
+        false => None,
+    };
+    let mut variables = match plan.actions.iter().any(|a| a.kind == Kind::Render) {
+        true => template::variables(&plan.repository, &config::load(&plan.repository)?)?,
+        false => BTreeMap::new(),
+    };
+
+    let mut state = state::load()?;
+    let mut deployed = Vec::new();
+    // the state is saved with whatever was deployed before an action failed
+    let mut execute = || -> Result<(), String> {
+        for action in &plan.actions {
+            execute_action(plan, action, backup.as_ref(), &mut variables, &mut state)?;
+            deployed.push(action.to.clone());
+        }
+        Ok(())
+    };
+    let result = execute();
+    // copies brought in line with their entry outside of restore, e.g. by a merge, are a new base
+    for record in state.deployed.iter_mut().filter(|record| {
+        record.repository == plan.repository && record.method == state::Method::Copy
+    }) {
+        let checksum = state::checksum(&record.target)?;
+        if !dry_run::is_enabled()
+            && checksum != record.checksum
+            && checksum == state::checksum(&record.entry)?
+        {
+            record.baseline = store_baseline(&plan.repository, &record.target)?;
+            record.checksum = checksum;
+        }
+    }
+    state.deployed.retain(|record| {
+        record.repository != plan.repository || record.target.symlink_metadata().is_ok()
+    });
+    state::save(&state)?;
+    keep_baselines(&plan.repository, &state)?;
+    result?;
+    record_in_manifest(&deployed)?;
+
+    if !plan.defaults.is_empty() {
+        import_defaults(&plan.repository, &plan.defaults)?;
+    }
+    Ok(deployed)
+}
+
+fn execute_action(
+    plan: &Plan,
+    action: &Action,
+    backup: Option<&fs::OverwriteDir>,
+    variables: &mut BTreeMap<String, String>,
+    state: &mut state::State,
+) -> Result<(), String> {
+    let from = match (action.kind, &action.from) {
+        (Kind::Prune, _) => {
+            log::info!("pruning {}", action.to.display());
+            fs::remove(&action.to)?;
+            state.forget(&action.to);
+            return Ok(());
+        }
+        (_, Some(from)) => from,
+        (_, None) => return Err(format!("no entry to restore to {}", action.to.display())),
+    };
+    let overwrite_entry = match action.kind {
+        Kind::Backup | Kind::Replace | Kind::Render | Kind::Merge => backup.as_ref().map(|b| {
+            b.entry(
+                action
+                    .to
+                    .strip_prefix(&plan.root)
+                    .or_else(|_| action.to.strip_prefix("/"))
+                    .unwrap_or(&action.to),
+            )
+        }),
+        Kind::Keep => Some(kept_path(&action.to)),
+        _ => None,
+    };
+    let method = match (plan.symlinks, action.copy, action.hardlink) {
+        (true, false, true) => fs::Method::Hardlink,
+        (true, false, false) => fs::Method::Symlink,
+        _ => fs::Method::Copy,
+    };
+    if action.kind == Kind::Merge {
+        let base = state
+            .find(&action.to)
+            .and_then(|record| baseline(&plan.repository, record));
+        if !merge_into_repo(from, &action.to, base.as_deref())? {
+            log::warn!(
+                "leaving {} as it is until the conflict is resolved",
+                action.to.display()
+            );
+            return Ok(());
+        }
+    }
+    match action.kind {
+        Kind::Chmod => {}
+        Kind::Render => {
+            log::debug!("rendering {} to {}", from.display(), action.to.display());
+            let rendered = template::render(&plan.repository, from, variables)?;
+            fs::write_rendered(&rendered, &action.to, overwrite_entry.as_deref())?;
+        }
+        _ => {
+            log::debug!("restoring {} to {}", from.display(), action.to.display());
+            fs::restore(
+                from,
+                &action.to,
+                overwrite_entry.as_deref(),
+                method,
+                plan.preserve_times,
+            )?;
+        }
+    }
+    let copied = method == fs::Method::Copy || template::is_template(from);
+    if let Some(mode) = action.mode {
+        match copied {
+            true => fs::set_mode(&action.to, mode)?,
+            false => fs::set_mode(from, mode)?,
+        }
+    }
+    if copied && (action.owner.is_some() || action.group.is_some()) {
+        fs::set_owner(&action.to, action.owner, action.group)?;
+    }
+    if !dry_run::is_enabled() {
+        state.record(state::Deployed {
+            repository: plan.repository.clone(),
+            entry: from.clone(),
+            target: action.to.clone(),
+            method: match (template::is_template(from), method) {
+                (true, _) => state::Method::Render,
+                (false, fs::Method::Symlink) => state::Method::Symlink,
+                (false, fs::Method::Hardlink) => state::Method::Hardlink,
+                (false, fs::Method::Copy) => state::Method::Copy,
+            },
+            mode: fs::mode(&action.to)
+                .ok()
+                .map(|mode| format!("{:04o}", mode)),
+            checksum: state::checksum(&action.to)?,
+            baseline: match copied && !template::is_template(from) {
+                true => store_baseline(&plan.repository, &action.to)?,
+                false => None,
+            },
+            timestamp: chrono::Utc::now(),
+        });
+    }
+    Ok(())
+}
+
+fn preview_restore(
+    repo: &Path,
+    root: &Path,
+    config: &config::Config,
+    entries: &[ManagedEntry],
+    symlinks: bool,
+    overwrite: bool,
+) -> Result<(), String> {
+    let mut variables = template::variables(repo, config)?;
+    for entry in entries {
+        let to = entry.destination(root);
+        if template::is_template(&entry.from) {
+            let rendered = template::render(repo, &entry.from, &mut variables)?;
+            let (color, action) = match fs::deploy_status(&entry.from, &to)? {
+                _ if is_rendered(&to, &rendered) => continue,
+                fs::DeployStatus::Missing => (diff::GREEN, "render"),
+                _ if overwrite => (diff::YELLOW, "back up and render"),
+                _ => (diff::RED, "conflict, requires --overwrite"),
+            };
+            println!(
+                "{} {}",
+                diff::paint(color, &format!("{:>32}", action)),
+                to.display()
+            );
+            if let Ok(existing) = std::fs::read_to_string(&to) {
+                let name = to.display().to_string();
+                print!(
+                    "{}",
+                    diff::colorize(&diff::unified(&name, &name, &existing, &rendered))
+                );
+            }
+            continue;
+        }
+        let method = match config.find_entry(&entry.relative_path) {
+            Some(e) => e.method()?,
+            None => fs::Method::Symlink,
+        };
+        let copy = method == fs::Method::Copy;
+        let hardlink = symlinks && method == fs::Method::Hardlink;
+        let symlinks = symlinks && method == fs::Method::Symlink;
+        let (color, action) = match fs::deploy_status(&entry.from, &to)? {
+            fs::DeployStatus::Exists if copy && fs::same_contents(&entry.from, &to) => continue,
+            fs::DeployStatus::Linked if symlinks => continue,
+            fs::DeployStatus::Linked if hardlink && fs::is_hard_link(&entry.from, &to) => continue,
+            fs::DeployStatus::Linked if hardlink => {
+                (diff::YELLOW, "replace symlink with hard link")
+            }
+            fs::DeployStatus::Linked => (diff::YELLOW, "replace symlink with copy"),
+            fs::DeployStatus::Missing if symlinks => (diff::GREEN, "create symlink"),
+            fs::DeployStatus::Missing if hardlink => (diff::GREEN, "create hard link"),
+            fs::DeployStatus::Missing => (diff::GREEN, "copy"),
+            fs::DeployStatus::Exists if overwrite => (diff::YELLOW, "back up and replace"),
+            fs::DeployStatus::Conflict if overwrite => (diff::YELLOW, "replace symlink"),
+            fs::DeployStatus::Exists | fs::DeployStatus::Conflict => {
+                (diff::RED, "conflict, requires --overwrite")
+            }
+        };
+        println!(
+            "{} {}",
+            diff::paint(color, &format!("{:>32}", action)),
+            to.display()
+        );
+
+        let existing_file = std::fs::symlink_metadata(&to)
+            .map(|m| m.is_file())
+            .unwrap_or(false);
+        if existing_file && entry.from.is_file() {
+            match diff::unified_files(&to, &entry.from)? {
+                Some(changes) => print!("{}", diff::colorize(&changes)),
+                None => println!("{:>32} contents are identical", ""),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn sync_layers(
+    repo: &Path,
+    stages: git::SyncStages,
+    pull_opts: git::PullOptions,
+) -> Result<(), String> {
+    let config = config::load(repo)?;
+    for layer in &config.layers {
+        let layer_path = path::resolve_from(repo, &layer.path)?;
+        if (!layer.writable || !layer_path.exists()) && !stages.pulls() {
+            continue;
+        }
+        if !layer_path.exists() {
+            match &layer.url {
+                Some(url)
+                    if dry_run::skip(format_args!("clone layer {} from {}", layer.name, url)) => {}
+                Some(url) => {
+                    git::clone_recurse(&layer_path, url, None, false)?;
+                }
+                None => {
+                    return Err(format!(
+                        "layer {} does not exist at {} and has no url",
+                        layer.name,
+                        layer_path.display()
+                    ))
+                }
+            }
+        } else {
+            let layer_repo = git::open(&layer_path)?;
+            if layer.writable {
+                git::sync(&layer_repo, layer.url.as_deref(), stages, pull_opts)?;
+            } else {
+                git::pull(&layer_repo, layer.url.as_deref(), pull_opts)?;
+            }
+        }
+        log::info!("successfully synced layer {}", layer.name);
+    }
+    Ok(())
+}
+
+/// Directories beneath the root whose children are offered individually
+const PICK_NESTED_DIRS: &[&str] = &[".config"];
+
+/// Directories beneath the root that are never worth offering
+const PICK_SKIPPED_DIRS: &[&str] = &[".cache", ".local", ".Trash", ".npm", ".cargo", ".rustup"];
+
+/// Prompts for the unmanaged dotfiles in the root to add
+fn pick_paths_to_add(repo: &Path, root: &Path) -> Result<Vec<PathBuf>, String> {
+    let ignore = dottyignore::load(repo, root)?;
+    let is_dotfile = |p: &Path| {
+        p.file_name()
+            .is_some_and(|f| f.as_encoded_bytes().starts_with(b"."))
+    };
+    let mut candidates = Vec::new();
+    for path in fs::read_dir(root)? {
+        let name = path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .unwrap_or_default();
+        if !is_dotfile(&path) || PICK_SKIPPED_DIRS.contains(&name) {
+            continue;
+        }
+        if PICK_NESTED_DIRS.contains(&name) && path.is_dir() {
+            candidates.append(&mut fs::read_dir(&path)?);
+        } else {
+            candidates.push(path);
+        }
+    }
+    // copies are managed without a symlink, and directories with managed files cannot be added
+    let config = config::load(repo)?;
+    let is_managed = |path: &Path| {
+        config.relative_path(root, path).is_ok_and(|relative| {
+            config.find_entry(&relative).is_some()
+                || config
+                    .entries
+                    .iter()
+                    .any(|e| e.key().starts_with(&relative))
+        })
+    };
+    candidates.retain(|path| {
+        !repo.starts_with(path)
+            && !ignore.is_ignored(path)
+            && path::relative_from_root(root, path).is_ok_and(|p| check_recordable(&p).is_ok())
+            && !matches!(fs::symlink_target(path), Ok(Some(target)) if target.starts_with(repo))
+            && !is_managed(path)
+    });
+    candidates.sort();
+
+    let options = candidates
+        .iter()
+        .map(|p| {
+            path::relative_from_root(root, p)
+                .unwrap_or_else(|_| p.clone())
+                .display()
+                .to_string()
+        })
+        .collect();
+    let selected = prompt::multi_select("Select the paths to add:", options)?;
+    log::debug!("picked {} of {} paths", selected.len(), candidates.len());
+    Ok(candidates
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| selected.contains(i))
+        .map(|(_, p)| p)
+        .collect())
+}
+
+fn pick_entries_to_restore(
+    root: &Path,
+    entries: Vec<ManagedEntry>,
+) -> Result<Vec<ManagedEntry>, String> {
+    let mut options = Vec::new();
+    for entry in &entries {
+        let status = fs::deploy_status(&entry.from, &entry.destination(root))?;
+        options.push(format!("{} ({})", entry.relative_path.display(), status));
+    }
+
+    let selected = prompt::multi_select("Select the entries to restore:", options)?;
+    log::debug!("picked {} of {} entries", selected.len(), entries.len());
+    Ok(entries
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| selected.contains(i))
+        .map(|(_, e)| e)
+        .collect())
+}
+
+/// Checks the repository path given with --as, returning it along with the single path it stores
+fn check_stored_as(stored_as: &Path, paths: &[PathBuf]) -> Result<(PathBuf, PathBuf), String> {
+    let [added] = paths else {
+        return Err("--as can only be used when adding a single path".to_owned());
+    };
+    if stored_as.as_os_str().is_empty()
+        || !stored_as
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+    {
+        return Err(format!(
+            "{} must be a relative path within the repository",
+            stored_as.display()
+        ));
+    }
+    Ok((path::canonicalize(added)?, stored_as.to_owned()))
+}
+
+/// Where a path at or beneath the added path is stored when it is added under another path
+fn stored_path(added: &Path, stored_as: &Path, path: &Path) -> Result<PathBuf, String> {
+    match path.strip_prefix(added) {
+        Ok(rest) if rest.as_os_str().is_empty() => Ok(stored_as.to_owned()),
+        Ok(rest) => Ok(stored_as.join(rest)),
+        Err(_) => Err(format!(
+            "{} is not beneath {}",
+            path.display(),
+            added.display()
+        )),
+    }
+}
+
+/// Moves, or copies, the path beneath the target, returning its path relative to the repository
+fn move_to_dotty_repo(
+    repo: &Path,
+    target: &Path,
+    config: &config::Config,
+    root: &Path,
+    path: &Path,
+    stored_as: Option<&Path>,
+    method: fs::Method,
+) -> Result<Option<PathBuf>, String> {
+    let to = match stored_as {
+        Some(stored_as) => target.join(stored_as),
+        None => target.join(config.relative_path(root, path)?),
+    };
+    let relative_path = path::relative_from_root(repo, &to)?;
+
+    match method {
+        fs::Method::Copy => {
+            log::debug!("copying {} to {}", path.display(), to.display());
+            fs::copy(path, &to)?;
+            return Ok(Some(relative_path));
+        }
+        fs::Method::Hardlink if fs::is_hard_link(path, &to) => return Ok(None),
+        fs::Method::Hardlink => {
+            log::debug!("hard linking {} to {}", to.display(), path.display());
+            fs::hard_link(path, &to)?;
+            return Ok(Some(relative_path));
+        }
+        fs::Method::Symlink => {}
+    }
+
+    log::debug!(
+        "moving {} to {} and then replacing with symlink",
+        path.display(),
+        to.display()
+    );
+    if !fs::move_then_symlink(path, &to)? {
+        return Ok(None);
+    }
+    // so restore prunes the symlink once the entry leaves the repository again
+    let mut state = state::load()?;
+    state.record(state::Deployed {
+        repository: repo.to_owned(),
+        checksum: state::checksum(path)?,
+        baseline: None,
+        mode: fs::mode(path).ok().map(|mode| format!("{:04o}", mode)),
+        entry: to,
+        target: path.to_owned(),
+        method: state::Method::Symlink,
+        timestamp: chrono::Utc::now(),
+    });
+    state::save(&state)?;
+    Ok(Some(relative_path))
+}
+
+/// Commits what add --no-commit staged, leaving other changes in the repository as they are
+fn commit_staged(repo: &Path) -> Result<(), String> {
+    let git_repo = git::open(repo)?;
+    let staged = git::staged_files(&git_repo)?;
+    if staged.is_empty() {
+        log::info!("there are no staged changes to commit");
+        return Ok(());
+    }
+    // like add, the message lists the added paths rather than dotty.yaml
+    let added: Vec<PathBuf> = staged
+        .iter()
+        .filter(|path| *path != Path::new(config::FILE_NAME))
+        .cloned()
+        .collect();
+    let message = match added.is_empty() {
+        true => format!("updating entries in {}", config::FILE_NAME),
+        false => build_git_message(&added),
+    };
+    git::commit(&git_repo, &message)?;
+    log::info!(
+        "successfully committed {} staged paths in dotty repository {}",
+        staged.len(),
+        repo.display()
+    );
+    Ok(())
+}
+
+fn build_git_message(to_commit: &Vec<PathBuf>) -> String {
+    match to_commit.len() {
+        0 => String::default(),
+        1 => format!("adding {}", to_commit.first().unwrap().display()),
+        _ => {
+            let mut msg = format!(
+                "adding {} files to {}\n\n",
+                to_commit.len(),
+                path::common_base_path(to_commit).display()
+            );
+            for path in to_commit {
+                msg.push_str(&format!("- {}\n", path.display()));
+            }
+            msg
+        }
+    }
+}
+
+fn build_remove_message(removed: &Vec<PathBuf>) -> String {
+    match removed.len() {
+        1 => format!("removing {}", removed.first().unwrap().display()),
+        _ => {
+            let mut msg = format!(
+                "removing {} files from {}\n\n",
+                removed.len(),
+                path::common_base_path(removed).display()
+            );
+            for path in removed {
+                msg.push_str(&format!("- {}\n", path.display()));
+            }
+            msg
+        }
+    }
+}
+
+fn build_update_message(committed: &Vec<PathBuf>) -> String {
+    match committed.len() {
+        1 => format!("updating {}", committed.first().unwrap().display()),
+        _ => {
+            let mut msg = format!("updating {} files\n\n", committed.len());
+            for path in committed {
+                msg.push_str(&format!("- {}\n", path.display()));
+            }
+            msg
+        }
+    }
+}
+
+fn build_migrate_message(migrated: &Vec<PathBuf>, repo: &Path) -> String {
+    let mut msg = format!(
+        "migrating {} paths to {}\n\n",
+        migrated.len(),
+        repo.display()
+    );
+    for path in migrated {
+        msg.push_str(&format!("- {}\n", path.display()));
+    }
+    msg
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    /// A root with an empty dotty repository in it, removed when dropped
+    struct Root(PathBuf);
+
+    impl Root {
+        fn new(name: &str) -> Root {
+            // state.json and manifest.json go beside the roots rather than in the real state dir
+            let tmp = std::env::temp_dir();
+            std::env::set_var(
+                "XDG_STATE_HOME",
+                tmp.join(format!("dotty-state-{}", std::process::id())),
+            );
+            let root = tmp.join(format!("dotty-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(&root).unwrap();
+            let repo = git2::Repository::init(root.join(".dotty")).unwrap();
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "dotty").unwrap();
+            config.set_str("user.email", "dotty@localhost").unwrap();
+            Root(root)
+        }
+
+        fn repo(&self) -> PathBuf {
+            self.0.join(".dotty")
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn non_utf8(name: &[u8]) -> PathBuf {
+        PathBuf::from(OsStr::from_bytes(name))
+    }
+
+    #[test]
+    fn porcelain_path_escapes_separators_and_backslashes() {
+        assert_eq!(porcelain_path(".config/foo bar"), ".config/foo bar");
+        assert_eq!(porcelain_path("a\tb\nc"), "a\\tb\\nc");
+        assert_eq!(porcelain_path("a\\tb"), "a\\\\tb");
+    }
+
+    #[test]
+    fn check_recordable_rejects_non_utf8_paths() {
+        assert!(check_recordable(Path::new(".config/foo")).is_ok());
+        assert!(check_recordable(&non_utf8(b".conf\xffig")).is_err());
+    }
+
+    #[test]
+    fn add_leaves_non_utf8_entries_in_place() {
+        let root = Root::new("add-non-utf8");
+        let file = root.0.join(non_utf8(b".rc\xff"));
+        std::fs::write(&file, "contents").unwrap();
+
+        let result = add(
+            &root.repo(),
+            &root.0,
+            &[file.clone()],
+            &AddOptions::default(),
+        );
+
+        assert!(result.is_err());
+        assert!(!file.symlink_metadata().unwrap().file_type().is_symlink());
+        assert!(!root.repo().join(non_utf8(b".rc\xff")).exists());
+    }
+
+    #[test]
+    fn add_keeps_non_utf8_files_of_directories() {
+        let root = Root::new("add-non-utf8-dir");
+        let dir = root.0.join(".config");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join(non_utf8(b"na\xefve")), "contents").unwrap();
+        let opts = AddOptions {
+            as_dir: true,
+            ..AddOptions::default()
+        };
+
+        add(&root.repo(), &root.0, &[dir.clone()], &opts).unwrap();
+
+        let stored = root.repo().join(".config").join(non_utf8(b"na\xefve"));
+        assert_eq!(std::fs::read_to_string(stored).unwrap(), "contents");
+        assert_eq!(
+            std::fs::read_link(&dir).unwrap(),
+            root.repo().join(".config")
+        );
+        let tracked = git::tracked_files(&git::open(&root.repo()).unwrap()).unwrap();
+        assert!(tracked.contains(&Path::new(".config").join(non_utf8(b"na\xefve"))));
+        let config = config::load(&root.repo()).unwrap();
+        assert!(config.find_entry(Path::new(".config")).is_some());
+    }
 }
@@ -0,0 +1,846 @@
+use crate::utils::dry_run;
+use crate::utils::fs::Method;
+use crate::utils::git;
+use crate::utils::packages::Manager;
+use crate::utils::path;
+use crate::utils::profile;
+use crate::utils::users;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const FILE_NAME: &str = "dotty.yaml";
+pub const LOCAL_FILE_NAME: &str = "dotty.local.yaml";
+pub const DEFAULTS_DIR: &str = ".defaults";
+pub const BREWFILE: &str = "Brewfile";
+pub const PROFILES_DIR: &str = "profiles";
+pub const HOSTS_DIR: &str = "hosts";
+pub const HOOKS_DIR: &str = "hooks";
+pub const PACKAGES_DIR: &str = "packages";
+pub const ROOTS_DIR: &str = "roots";
+
+/// The dotty.yaml schema version, configs from before versioning have none and are version 1
+pub const VERSION: u32 = 2;
+
+/// Upgrades the dotty.yaml of a repository in place from one version to the next
+type Migration = fn(&Path, &mut Mapping) -> Result<(), String>;
+
+/// The migration from each version to the next, starting with version 1
+const MIGRATIONS: [Migration; VERSION as usize - 1] = [list_entries];
+
+/// Version 2 only manages listed entries, so lists those version 1 managed by being in the repository
+fn list_entries(repo: &Path, mapping: &mut Mapping) -> Result<(), String> {
+    if !repo.is_dir() {
+        return Ok(());
+    }
+    let mut config: Config = match serde_yaml::from_value(Value::Mapping(mapping.clone())) {
+        Ok(config) => config,
+        Err(err) => return Err(format!("failed to parse config - {}", err)),
+    };
+    let listed = crate::cmds::backfill_entries(repo, &mut config)?;
+    if listed.is_empty() {
+        return Ok(());
+    }
+    log::debug!("listing {} existing entries", listed.len());
+    match serde_yaml::to_value(&config.entries) {
+        Ok(entries) => {
+            mapping.insert(Value::from("entries"), entries);
+            Ok(())
+        }
+        Err(err) => Err(format!("failed to serialize entries - {}", err)),
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Config {
+    #[serde(default = "current_version")]
+    pub version: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify: Option<Notify>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Layer>,
+
+    #[serde(default)]
+    pub entries: Vec<Entry>,
+
+    /// macOS preference domains exported to the defaults directory
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub defaults: Vec<String>,
+
+    /// Values available to templates, overriding the built in variables
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+
+    /// Guardrails checked before adding files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<Limits>,
+
+    /// Packages to install, per package manager
+    #[serde(default, skip_serializing_if = "Packages::is_empty")]
+    pub packages: Packages,
+
+    /// Packages of the packages directory by name with the packages restored before them, e.g.
+    /// nvim: [fonts]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub package_depends: BTreeMap<String, Vec<String>>,
+
+    /// Setup scripts run in order by bootstrap
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bootstrap: Vec<Script>,
+
+    /// Where restore moves the files it replaces, default ~/.local/share/dotty/backups
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup_dir: Option<PathBuf>,
+
+    /// Roots besides the default one by name, e.g. etc: /etc, with their entries stored in roots/<name>
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub roots: BTreeMap<String, PathBuf>,
+
+    /// The identity dotty commits as and how it signs them, instead of the git config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Identity>,
+
+    /// Remotes sync also pushes to after pushing to origin, which stays the one pulled from
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<Mirror>,
+
+    /// The private key for ssh remotes, tried after ssh-agent. Default is the usual keys in ~/.ssh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_key: Option<PathBuf>,
+
+    /// Retries of fetches, pushes and clones that fail with network errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<Retry>,
+
+    /// The proxy for https remotes, e.g. http://proxy.example.com:8080. Default is the git config
+    /// http.proxy or the http_proxy and https_proxy variables
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+
+    /// Hosts DOTTY_GIT_TOKEN is sent to over https, e.g. git.example.com. It is sent nowhere else
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub token_hosts: Vec<String>,
+
+    /// Self-hosted GitLab hosts, e.g. gitlab.example.com, which are sent GITLAB_TOKEN for clones
+    /// and to create repositories. Only gitlab.com is recognized without being listed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gitlab_hosts: Vec<String>,
+
+    /// Submodules by name or path pinned to a tag, branch or commit instead of following the
+    /// default branch of their remote
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pins: BTreeMap<String, String>,
+
+    /// Repositories added with add --vendor, stored as plain files, by path with the url
+    /// revendor refreshes them from
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vendored: BTreeMap<PathBuf, String>,
+
+    /// How long since the last sync before other commands warn about it, e.g. "14d". Default 7d,
+    /// and "0" turns the warning off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync_warning: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Retry {
+    /// Attempts in total, default 3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+
+    /// The wait before the first retry, doubling after each one, e.g. "5s". Default 1s
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<String>,
+}
+
+/// Another remote kept in step with origin, e.g. a self hosted copy
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Mirror {
+    /// The git remote name, e.g. gitea
+    pub name: String,
+
+    pub url: String,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Identity {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+
+    /// Signs commits with gpg or ssh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sign: Option<Signing>,
+
+    /// The gpg key id, or the path to the ssh key, to sign with. Default is the git user.signingkey
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Signing {
+    Gpg,
+    Ssh,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Limits {
+    /// Warns about files larger than this, e.g. "1MB"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warn_file_size: Option<String>,
+
+    /// Refuses files larger than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<String>,
+
+    /// Refuses to add more than this at once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_total_size: Option<String>,
+
+    /// Refuses binary files instead of warning about them
+    #[serde(default)]
+    pub refuse_binary: bool,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Packages {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub brew: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub apt: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pacman: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cargo: Vec<String>,
+}
+
+impl Packages {
+    pub fn is_empty(&self) -> bool {
+        Manager::ALL.iter().all(|m| self.get(*m).is_empty())
+    }
+
+    pub fn get(&self, manager: Manager) -> &[String] {
+        match manager {
+            Manager::Brew => &self.brew,
+            Manager::Apt => &self.apt,
+            Manager::Pacman => &self.pacman,
+            Manager::Cargo => &self.cargo,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Script {
+    pub name: String,
+
+    /// A shell command, run from the dotty repository
+    pub run: String,
+
+    /// Only runs the script on this operating system
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<Os>,
+}
+
+/// A shared repository whose entries are restored beneath the personal repository
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Layer {
+    pub name: String,
+
+    /// The local checkout, relative to the dotty repository unless absolute
+    pub path: PathBuf,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    /// Pushes local commits when syncing, otherwise the layer is only pulled
+    #[serde(default)]
+    pub writable: bool,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Notify {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<Email>,
+}
+
+/// The password is only read from DOTTY_SMTP_PASSWORD, as dotty.yaml is committed in plaintext
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Email {
+    pub smtp_host: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smtp_port: Option<u16>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Entry {
+    pub path: PathBuf,
+
+    /// The named root the path is relative to, instead of the default root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
+    /// Entries, or directories of entries, that must be restored before this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends: Vec<PathBuf>,
+
+    /// Only restores the entry on this operating system
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<Os>,
+
+    /// Only restores the entry on these machines
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+
+    /// Where the entry is restored instead of beneath the root, e.g. "/etc/hosts" or "~/Library/foo"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+
+    /// Restores a copy instead of a symlink, for tools that do not follow symlinks
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub copy: bool,
+
+    /// Restores a hard link to the file in the repository, for tools that replace symlinks
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hardlink: bool,
+
+    /// Restores a single symlink to the whole directory, so new files in it are tracked
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub directory: bool,
+
+    /// Where the entry is stored in the repository, when not at its path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+
+    /// Permission bits in octal, e.g. "0600", reapplied on restore as git only tracks the executable bit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+
+    /// The user owning the file, reapplied to copies when restoring with enough privileges
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+
+    /// The group owning the file, reapplied along with the owner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+
+    /// Refuses to restore the entry while the repository is locked, or to add it unencrypted
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypt: bool,
+
+    /// Shell commands run from the repository around restoring the entry
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Hooks {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_restore: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_restore: Option<String>,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.pre_restore.is_none() && self.post_restore.is_none()
+    }
+}
+
+impl Entry {
+    pub fn mode(&self) -> Result<Option<u32>, String> {
+        let Some(mode) = &self.mode else {
+            return Ok(None);
+        };
+        match u32::from_str_radix(mode, 8) {
+            Ok(bits) if bits <= 0o7777 => Ok(Some(bits)),
+            _ => Err(format!(
+                "invalid mode {} for {} in {}",
+                mode,
+                self.path.display(),
+                FILE_NAME
+            )),
+        }
+    }
+
+    /// The entry's path among the entries of every root, beneath roots/<name> for a named root
+    pub fn key(&self) -> PathBuf {
+        match &self.root {
+            Some(root) => Config::root_path(root).join(&self.path),
+            None => self.path.clone(),
+        }
+    }
+
+    /// The entry's path within the repository
+    pub fn source(&self) -> PathBuf {
+        self.source.clone().unwrap_or_else(|| self.key())
+    }
+
+    /// How the entry is restored when restoring symlinks
+    pub fn method(&self) -> Result<Method, String> {
+        match (self.copy, self.hardlink) {
+            (true, true) => Err(format!(
+                "{} in {} cannot be both a copy and a hard link",
+                self.path.display(),
+                FILE_NAME
+            )),
+            (true, false) => Ok(Method::Copy),
+            (false, true) => Ok(Method::Hardlink),
+            (false, false) => Ok(Method::Symlink),
+        }
+    }
+
+    /// Whether the entry's os and hosts conditions match this machine
+    pub fn is_current(&self) -> bool {
+        self.os.is_none_or(|os| os.is_current())
+            && (self.hosts.is_empty() || self.hosts.contains(&profile::hostname()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Os {
+    Linux,
+    Macos,
+    Windows,
+}
+
+impl Os {
+    pub fn is_current(&self) -> bool {
+        match self {
+            Os::Linux => cfg!(target_os = "linux"),
+            Os::Macos => cfg!(target_os = "macos"),
+            Os::Windows => cfg!(target_os = "windows"),
+        }
+    }
+}
+
+impl fmt::Display for Os {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Os::Linux => "linux",
+            Os::Macos => "macos",
+            Os::Windows => "windows",
+        })
+    }
+}
+
+/// Values specific to this machine, which are never committed
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Local {
+    /// Template variables, overriding those in dotty.yaml
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+
+    /// Packages of the packages directory restored on this machine
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub enabled: Vec<String>,
+
+    /// The branch this machine syncs, instead of whichever one is checked out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+}
+
+impl Config {
+    pub fn defaults_path(domain: &str) -> PathBuf {
+        Path::new(DEFAULTS_DIR).join(format!("{}.plist", domain))
+    }
+
+    /// The overlay directory for a profile, relative to the repository
+    pub fn profile_path(name: &str) -> PathBuf {
+        Path::new(PROFILES_DIR).join(name)
+    }
+
+    /// The directory of a package of entries, relative to the repository
+    pub fn package_path(name: &str) -> PathBuf {
+        Path::new(PACKAGES_DIR).join(name)
+    }
+
+    /// The overlay directory for a machine, relative to the repository
+    pub fn host_path(hostname: &str) -> PathBuf {
+        Path::new(HOSTS_DIR).join(hostname)
+    }
+
+    /// Where the entries of a named root are stored, relative to the repository
+    pub fn root_path(name: &str) -> PathBuf {
+        Path::new(ROOTS_DIR).join(name)
+    }
+
+    pub fn find_entry(&self, path: &Path) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .filter(|e| path.starts_with(e.key()))
+            .max_by_key(|e| e.key().components().count())
+    }
+
+    /// The tags of the entry of the path along with those of the entries of its parents
+    pub fn tags(&self, path: &Path) -> Vec<&String> {
+        let mut tags = Vec::new();
+        for entry in self.entries.iter().filter(|e| path.starts_with(e.key())) {
+            for tag in &entry.tags {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+        }
+        tags
+    }
+
+    /// The path relative to the default root, or beneath roots/<name> for the named root containing it
+    pub fn relative_path(&self, root: &Path, absolute: &Path) -> Result<PathBuf, String> {
+        let mut named = None;
+        for (name, named_root) in &self.roots {
+            let named_root = path::canonicalize(named_root)?;
+            let depth = named_root.components().count();
+            if absolute.starts_with(&named_root)
+                && (!absolute.starts_with(root) || depth > root.components().count())
+                && named.as_ref().is_none_or(|(_, _, d)| depth > *d)
+            {
+                named = Some((name, named_root, depth));
+            }
+        }
+        match named {
+            Some((name, named_root, _)) => {
+                Ok(Config::root_path(name).join(path::relative_from_root(&named_root, absolute)?))
+            }
+            None => path::relative_from_root(root, absolute),
+        }
+    }
+
+    /// The path beneath the root that a path stored in the repository is restored to
+    pub fn destination(&self, stored: &Path) -> PathBuf {
+        self.entries
+            .iter()
+            .find_map(|e| Some((e, stored.strip_prefix(e.source.as_ref()?).ok()?)))
+            .map(|(e, rest)| match rest.as_os_str().is_empty() {
+                true => e.key(),
+                false => e.key().join(rest),
+            })
+            .unwrap_or_else(|| stored.to_owned())
+    }
+
+    /// Where a path is restored to by an entry with a target or beneath a named root, relative paths are beneath the root
+    pub fn target(&self, path: &Path) -> Option<PathBuf> {
+        let targeted = self
+            .entries
+            .iter()
+            .filter(|e| e.target.is_some() && path.starts_with(e.key()))
+            .max_by_key(|e| e.key().components().count())
+            .and_then(|e| {
+                let rest = path.strip_prefix(e.key()).ok()?;
+                let target = e.target.as_ref()?;
+                Some(match rest.as_os_str().is_empty() {
+                    true => target.clone(),
+                    false => target.join(rest),
+                })
+            });
+        targeted.or_else(|| {
+            let (name, rest) = self.named_root(path)?;
+            Some(self.roots.get(&name)?.join(rest))
+        })
+    }
+
+    /// The name of the root a path beneath roots/<name> belongs to, and the path relative to it
+    pub fn named_root(&self, path: &Path) -> Option<(String, PathBuf)> {
+        let mut components = path.strip_prefix(ROOTS_DIR).ok()?.components();
+        let name = components.next()?.as_os_str().to_str()?.to_owned();
+        Some((name, components.as_path().to_owned()))
+    }
+
+    /// The entry for exactly this path, added if there is none yet
+    pub fn entry_mut(&mut self, path: &Path) -> &mut Entry {
+        match self.entries.iter().position(|e| e.key() == path) {
+            Some(index) => &mut self.entries[index],
+            None => {
+                let entry = match self.named_root(path) {
+                    Some((name, rest)) if self.roots.contains_key(&name) => Entry {
+                        path: rest,
+                        root: Some(name),
+                        ..Default::default()
+                    },
+                    _ => Entry {
+                        path: path.to_owned(),
+                        ..Default::default()
+                    },
+                };
+                self.entries.push(entry);
+                self.entries.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// The recorded permission bits of the entry for exactly this path
+    pub fn mode(&self, path: &Path) -> Result<Option<u32>, String> {
+        match self.entries.iter().find(|e| e.key() == path) {
+            Some(entry) => entry.mode(),
+            None => Ok(None),
+        }
+    }
+
+    /// The recorded user and group ids of the entry for exactly this path
+    ///
+    /// Users and groups missing on this machine are left out with a warning, keeping the owner.
+    pub fn ownership(&self, path: &Path) -> (Option<u32>, Option<u32>) {
+        let Some(entry) = self.entries.iter().find(|e| e.key() == path) else {
+            return (None, None);
+        };
+        let resolve = |name: Option<&str>, id: fn(&str) -> Result<u32, String>| match name
+            .map(id)
+            .transpose()
+        {
+            Ok(id) => id,
+            Err(err) => {
+                log::warn!("leaving the owner of {} as it is - {}", path.display(), err);
+                None
+            }
+        };
+        (
+            resolve(entry.owner.as_deref(), users::uid),
+            resolve(entry.group.as_deref(), users::gid),
+        )
+    }
+
+    /// Where a path beneath the root is stored in the repository, the inverse of destination
+    pub fn stored_path(&self, path: &Path) -> PathBuf {
+        self.entries
+            .iter()
+            .find_map(|e| Some((e.source.as_ref()?, path.strip_prefix(e.key()).ok()?)))
+            .map(|(source, rest)| match rest.as_os_str().is_empty() {
+                true => source.clone(),
+                false => source.join(rest),
+            })
+            .unwrap_or_else(|| path.to_owned())
+    }
+
+    /// The entry managing the path as part of a whole directory
+    pub fn directory_entry(&self, path: &Path) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|e| e.directory && path.starts_with(e.key()))
+    }
+}
+
+fn current_version() -> u32 {
+    VERSION
+}
+
+pub fn load(repo: &Path) -> Result<Config, String> {
+    let config_path = repo.join(FILE_NAME);
+    let mut value: Value = read(&config_path)?;
+    // repositories from before dotty.yaml have their entries listed like version 1 configs
+    if value.is_null() {
+        value = Value::Mapping(Mapping::new());
+    }
+
+    let version = match value.get("version") {
+        None => 1,
+        Some(version) => match version.as_u64().and_then(|v| u32::try_from(v).ok()) {
+            Some(version) if version > 0 => version,
+            _ => {
+                return Err(format!(
+                    "invalid version {:?} in config {}",
+                    version,
+                    config_path.display()
+                ))
+            }
+        },
+    };
+    if version > VERSION {
+        return Err(format!(
+            "config {} has version {} but this dotty only reads up to version {}, upgrade dotty",
+            config_path.display(),
+            version,
+            VERSION
+        ));
+    }
+    if version < VERSION {
+        migrate(repo, &mut value, version)?;
+    }
+
+    match serde_yaml::from_value(value) {
+        Ok(config) => Ok(config),
+        Err(err) => Err(format!(
+            "failed to parse config {} - {}",
+            config_path.display(),
+            err
+        )),
+    }
+}
+
+/// Upgrades the config to the current version, keeping the original in the git directory
+fn migrate(repo: &Path, value: &mut Value, version: u32) -> Result<(), String> {
+    let config_path = repo.join(FILE_NAME);
+    let Some(mapping) = value.as_mapping_mut() else {
+        return Err(format!(
+            "failed to migrate config {} - it is not a mapping",
+            config_path.display()
+        ));
+    };
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+        log::debug!(
+            "migrating config {} from version {} to {}",
+            config_path.display(),
+            from + 1,
+            from + 2
+        );
+        migration(repo, mapping)?;
+    }
+    mapping.insert(Value::from("version"), Value::from(VERSION));
+    if !config_path.exists() {
+        // nothing to keep for repositories without a dotty.yaml until there are entries to list
+        if mapping.get("entries").is_some() {
+            write(&config_path, value)?;
+            log::warn!(
+                "listed the entries of dotty repository {} in config {}",
+                repo.display(),
+                config_path.display()
+            );
+        }
+        return Ok(());
+    }
+
+    let backup_path = git::git_dir(repo)
+        .unwrap_or_else(|_| repo.join(".git"))
+        .join(format!("{}.v{}", FILE_NAME, version));
+    if !dry_run::skip(format_args!(
+        "back up config {} to {}",
+        config_path.display(),
+        backup_path.display()
+    )) {
+        if let Err(err) = fs::copy(&config_path, &backup_path) {
+            return Err(format!(
+                "failed to back up config {} to {} - {}",
+                config_path.display(),
+                backup_path.display(),
+                err
+            ));
+        }
+    }
+    write(&config_path, value)?;
+    log::warn!(
+        "migrated config {} from version {} to {}, the original is at {}",
+        config_path.display(),
+        version,
+        VERSION,
+        backup_path.display()
+    );
+    Ok(())
+}
+
+/// Writes the config, keeping keys of the existing dotty.yaml that this version does not know
+pub fn save(repo: &Path, config: &Config) -> Result<(), String> {
+    let config_path = repo.join(FILE_NAME);
+    let mut value = match serde_yaml::to_value(config) {
+        Ok(value) => value,
+        Err(err) => return Err(format!("failed to serialize config - {}", err)),
+    };
+    let existing: Value = read(&config_path)?;
+    // the keys that survive parsing the existing config are the ones it knows
+    if let Ok(known) =
+        serde_yaml::from_value::<Config>(existing.clone()).and_then(serde_yaml::to_value)
+    {
+        keep_unknown(&existing, &known, &mut value);
+    }
+    write(&config_path, &value)
+}
+
+fn keep_unknown(existing: &Value, known: &Value, value: &mut Value) {
+    let (Some(existing), Some(value)) = (existing.as_mapping(), value.as_mapping_mut()) else {
+        return;
+    };
+    for (key, existing_value) in existing {
+        match known.get(key) {
+            None if !value.contains_key(key) => {
+                log::trace!("keeping unknown config key {:?}", key);
+                value.insert(key.clone(), existing_value.clone());
+            }
+            Some(known_value) => {
+                if let Some(new_value) = value.get_mut(key) {
+                    keep_unknown(existing_value, known_value, new_value);
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+pub fn load_local(repo: &Path) -> Result<Local, String> {
+    read(&repo.join(LOCAL_FILE_NAME))
+}
+
+pub fn save_local(repo: &Path, local: &Local) -> Result<(), String> {
+    write(&repo.join(LOCAL_FILE_NAME), local)
+}
+
+fn read<T: DeserializeOwned + Default>(config_path: &Path) -> Result<T, String> {
+    if !config_path.exists() {
+        log::trace!("no config found at {}", config_path.display());
+        return Ok(T::default());
+    }
+
+    let contents = match fs::read_to_string(config_path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            return Err(format!(
+                "failed to read config {} - {}",
+                config_path.display(),
+                err
+            ))
+        }
+    };
+    match serde_yaml::from_str(&contents) {
+        Ok(config) => Ok(config),
+        Err(err) => Err(format!(
+            "failed to parse config {} - {}",
+            config_path.display(),
+            err
+        )),
+    }
+}
+
+fn write<T: Serialize>(config_path: &Path, config: &T) -> Result<(), String> {
+    if dry_run::skip(format_args!("write {}", config_path.display())) {
+        return Ok(());
+    }
+    let contents = match serde_yaml::to_string(config) {
+        Ok(contents) => contents,
+        Err(err) => return Err(format!("failed to serialize config - {}", err)),
+    };
+    match fs::write(config_path, contents) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!(
+            "failed to write config {} - {}",
+            config_path.display(),
+            err
+        )),
+    }
+}
@@ -0,0 +1,77 @@
+use crate::utils::dry_run;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const FILE_NAME: &str = "dotty.lock";
+
+/// The exact commits of submodules, layers and vendored directories, committed to the repository
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Lock {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub submodules: Vec<Pin>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Pin>,
+
+    /// The commits vendored directories were copied from
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vendored: Vec<Pin>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Pin {
+    /// The submodule or vendored path relative to the repository, or the layer name
+    pub path: PathBuf,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    pub commit: String,
+}
+
+pub fn load(repo: &Path) -> Result<Option<Lock>, String> {
+    let lock_path = repo.join(FILE_NAME);
+    if !lock_path.exists() {
+        log::trace!("no lock found at {}", lock_path.display());
+        return Ok(None);
+    }
+
+    let contents = match fs::read_to_string(&lock_path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            return Err(format!(
+                "failed to read lock {} - {}",
+                lock_path.display(),
+                err
+            ))
+        }
+    };
+    match serde_yaml::from_str(&contents) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(err) => Err(format!(
+            "failed to parse lock {} - {}",
+            lock_path.display(),
+            err
+        )),
+    }
+}
+
+pub fn save(repo: &Path, lock: &Lock) -> Result<(), String> {
+    if dry_run::skip(format_args!("write {}", repo.join(FILE_NAME).display())) {
+        return Ok(());
+    }
+    let lock_path = repo.join(FILE_NAME);
+    let contents = match serde_yaml::to_string(lock) {
+        Ok(contents) => contents,
+        Err(err) => return Err(format!("failed to serialize lock - {}", err)),
+    };
+    match fs::write(&lock_path, contents) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!(
+            "failed to write lock {} - {}",
+            lock_path.display(),
+            err
+        )),
+    }
+}
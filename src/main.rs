@@ -1,11 +1,37 @@
 mod cmds;
+mod config;
+mod lockfile;
+mod plan;
 mod utils;
 
 use clap::{ArgAction, Parser, ValueEnum};
-use cmds::{add, clone, init, restore, sync, update};
+use cmds::{
+    add, apply, backfill, backups_list, backups_restore, bootstrap, brew_apply, brew_capture,
+    clone, commit, crypt_export_key, crypt_filter, crypt_import_key, crypt_init, daemon, diff,
+    disable, edit, enable, exec, fetch, forward_to_daemon, gc, git, harvest, ignore_add,
+    ignore_list, ignore_remove, init, link, list, log, packages_diff, packages_install, path, plan,
+    prompt_status, relink, remove, restore, revendor, rollback, schedule_install, schedule_remove,
+    schedule_status, snapshot_create, status, status_porcelain, submodule_absorb,
+    submodule_extract, submodule_remove, sync, undo, update, verify, warn_if_stale, watch,
+    AddOptions, RestoreOptions, SyncOptions,
+};
 use simplelog::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+use utils::dry_run;
+use utils::fs::PreserveTimes;
+use utils::git::{MergeStrategy, SyncStages};
+use utils::ipc;
+use utils::known_hosts;
+use utils::lock;
+use utils::logger::{self, LogFilter};
 use utils::path;
+use utils::profile;
+use utils::prompt;
+use utils::string;
+use utils::sudo;
+use utils::timings;
 
 #[derive(Parser)]
 #[clap(about, version, author)]
@@ -27,10 +53,68 @@ struct Opts {
     #[clap(short = 'R', long, env = "DOTTY_ROOT")]
     root: Option<PathBuf>,
 
+    /// Per module log levels, e.g. "dotty::git=trace,dotty::fs=warn". Overrides verbosity for matching modules
+    #[clap(long, env = "DOTTY_LOG")]
+    log_filter: Option<LogFilter>,
+
+    /// Profiles to overlay on the shared entries, later ones taking precedence. Can be used multiple times
+    #[clap(long = "profile", env = "DOTTY_PROFILE", value_delimiter = ',')]
+    profiles: Vec<String>,
+
+    /// Reports the time spent in each phase of the command, adding up the phases of submodules
+    /// updated in parallel
+    #[clap(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "human"
+    )]
+    timings: Option<TimingsFormat>,
+
+    /// Logs every filesystem and git change that would be made without making it
+    #[clap(long, default_value = "false")]
+    dry_run: bool,
+
+    /// Fails instead of prompting, e.g. for template variables without a value
+    #[clap(long, default_value = "false")]
+    no_input: bool,
+
+    /// The private key for ssh remotes, tried after ssh-agent. Overrides ssh_key in dotty.yaml
+    #[clap(long, env = "DOTTY_SSH_KEY")]
+    ssh_key: Option<PathBuf>,
+
+    /// Trusts the host keys of ssh remotes that are not in ~/.ssh/known_hosts or
+    /// /etc/ssh/ssh_known_hosts yet, recording them in ~/.ssh/known_hosts
+    #[clap(
+        long,
+        env = "DOTTY_ACCEPT_NEW",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    accept_new: bool,
+
+    /// Proceeds with destructive operations, such as overwriting files, without confirming
+    #[clap(
+        short,
+        long,
+        env = "DOTTY_ASSUME_YES",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    yes: bool,
+
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum TimingsFormat {
+    /// A human readable table
+    Human,
+
+    /// A JSON object
+    Json,
+}
+
 #[derive(Parser)]
 enum SubCommand {
     /// Initializes a new dotty repository
@@ -41,27 +125,232 @@ enum SubCommand {
     Add(Add),
     /// Restores files to the root
     Restore(Restore),
+    /// Lists the entries managed by the dotty repository
+    List(List),
+    /// Shows the repository state and the layer and deploy status of each entry
+    Status(Status),
+    /// Prints a short token for shell prompts when the repository has changes to commit, push or
+    /// pull, reading only local state so it is fast
+    PromptStatus(PromptStatus),
     /// Syncs the dotty repository with the remote
     Sync(Sync),
+    /// Fetches and merges the remote into the dotty repository without pushing
+    Pull(Pull),
+    /// Pushes the dotty repository to the remote without pulling first
+    Push(Push),
     /// Updates the submodules in the dotty repository
     Update(Update),
+    /// Refreshes directories added with add --vendor from the repositories they came from
+    Revendor(Revendor),
+    /// Schedules a background sync of the dotty repository
+    Schedule(Schedule),
+    /// Captures or applies the Homebrew Brewfile in the dotty repository
+    Brew(Brew),
+    /// Lists or recovers the files replaced by restores, and the snapshots taken before them
+    Backups(Backups),
+    /// Encrypts the contents of the dotty repository at rest
+    Crypt(Crypt),
+    /// Runs a command with the dotty repository, root and branch exported
+    Exec(Exec),
+    /// Runs git in the dotty repository, e.g. dotty git -- log --oneline
+    Git(Git),
+    /// Serves status, sync and restore requests from a long running process, committing changes like watch
+    Daemon(Daemon),
+    /// Commits changes to managed files as they are made, optionally syncing them
+    Watch(Watch),
+    /// Computes the actions restore would take, optionally saving them to a plan file
+    Plan(Plan),
+    /// Applies a saved plan, refusing if anything changed since it was computed
+    Apply(Apply),
+    /// Creates symlinks for repository entries missing from the root, leaving existing paths alone
+    Link(Link),
+    /// Lists the files already in the dotty repository in dotty.yaml, which decides what is managed
+    Backfill(Backfill),
+    /// Points symlinks into a previous location of the dotty repository at its current location
+    Relink(Relink),
+    /// Restores packages of the packages directory on this machine
+    Enable(PackageNames),
+    /// Stops restoring packages on this machine, removing their symlinks
+    Disable(PackageNames),
+    /// Manages the patterns in .dottyignore that add skips
+    Ignore(Ignore),
+    /// Manages the git submodules of the dotty repository, such as plugins
+    Submodule(Submodule),
+    /// Commits changes to managed files in the dotty repository
+    Commit(Commit),
+    /// Stops managing paths, moving them back from the dotty repository to the root
+    #[clap(alias = "forget")]
+    Remove(Remove),
+    /// Shows how deployed copies differ from the dotty repository
+    Diff(Diff),
+    /// Opens the repository copy of a managed entry in $EDITOR
+    Edit(Edit),
+    /// Copies edits made to deployed copies back into the dotty repository and commits them
+    Harvest(Harvest),
+    /// Checks the dotty repository, managed files and deployed copies for corruption
+    #[clap(alias = "fsck")]
+    Verify(Verify),
+    /// Prunes unreachable objects and repacks the dotty repository to reclaim space
+    Gc(Gc),
+    /// Tags the current state of the dotty repository to roll back to later
+    Snapshot(SnapshotCmd),
+    /// Returns the dotty repository to the files of a snapshot or commit and restores them
+    Rollback(Rollback),
+    /// Undoes the last commit dotty made, moving the files of an add back out of the dotty repository
+    Undo(Undo),
+    /// Fetches the remote branch without merging it, optionally showing what sync would bring in
+    Fetch(Fetch),
+    /// Shows the commit history of the dotty repository or of managed paths
+    #[clap(alias = "history")]
+    Log(Log),
+    /// Prints the path of the dotty repository or of a managed entry in it, e.g. for cd $(dotty path)
+    Path(PathCmd),
+    /// Runs the setup scripts declared in dotty.yaml
+    Bootstrap(Bootstrap),
+    /// Installs the packages declared in dotty.yaml with the local package managers
+    Packages(Packages),
 }
 
 #[derive(Parser)]
-struct Init {}
+struct Init {
+    /// The repository url to sync to, created on GitHub or GitLab when it does not exist
+    #[clap()]
+    url: Option<String>,
+
+    /// The author name of the commits dotty makes. Default is the git user.name
+    #[clap(long)]
+    name: Option<String>,
+
+    /// The author email of the commits dotty makes. Default is the git user.email
+    #[clap(long)]
+    email: Option<String>,
+
+    /// Signs the commits dotty makes with gpg or ssh
+    #[clap(long, value_enum)]
+    sign: Option<config::Signing>,
+
+    /// The gpg key id, or the path to the ssh key, to sign with. Default is the git user.signingkey
+    #[clap(long, requires = "sign")]
+    signing_key: Option<String>,
+}
 
 #[derive(Parser)]
 struct Clone {
     /// The repository url to clone
     #[clap()]
     url: String,
+
+    /// Checks out and syncs this branch instead of the remote default
+    #[clap(short, long)]
+    branch: Option<String>,
+
+    /// Fetches only the latest commit of every submodule, as shallow = true in .gitmodules does
+    /// for a single one
+    #[clap(long, default_value = "false")]
+    shallow_submodules: bool,
+
+    /// Checks out the commits of submodules and read-only layers pinned in dotty.lock
+    #[clap(long, default_value = "false")]
+    locked: bool,
 }
 
 #[derive(Parser)]
 struct Add {
-    /// The paths to the files or directories
+    /// The paths to the files or directories, which must be valid UTF-8 to be listed in dotty.yaml
+    ///
+    /// Files with other names are kept in git within an added directory, but their modes, owners
+    /// and deployed state are not recorded.
     #[clap()]
     paths: Vec<PathBuf>,
+
+    /// macOS preference domains to export, e.g. com.apple.dock
+    #[clap(long = "defaults")]
+    defaults_domains: Vec<String>,
+
+    /// Moves paths that are symlinks into this other dotty repository
+    #[clap(long)]
+    migrate_from: Option<PathBuf>,
+
+    /// Adds the paths to this package of the packages directory, enabling it on this machine
+    #[clap(long, conflicts_with_all = ["into_profile", "host"])]
+    into_package: Option<String>,
+
+    /// Adds the paths to the overlay of this profile instead of the shared entries
+    #[clap(long)]
+    into_profile: Option<String>,
+
+    /// Adds the paths to the overlay of this machine, see DOTTY_HOST
+    #[clap(long, conflicts_with = "into_profile")]
+    host: bool,
+
+    /// Only restores the paths on this operating system
+    #[clap(long, value_enum)]
+    os: Option<config::Os>,
+
+    /// Only restores the paths on this machine, by hostname. Can be used multiple times
+    #[clap(long = "on-host", value_name = "HOSTNAME")]
+    on_hosts: Vec<String>,
+
+    /// Restores the path here instead of beneath the root, e.g. /etc/hosts or ~/Library/foo
+    #[clap(long)]
+    target: Option<PathBuf>,
+
+    /// A shell command run from the repository before restoring the paths
+    #[clap(long, value_name = "COMMAND")]
+    pre_restore: Option<String>,
+
+    /// A shell command run from the repository after restoring the paths
+    #[clap(long, value_name = "COMMAND")]
+    post_restore: Option<String>,
+
+    /// Commits files that look like they contain credentials
+    #[clap(long, default_value = "false")]
+    allow_secrets: bool,
+
+    /// Adds files over the size and binary limits in dotty.yaml
+    #[clap(short, long, default_value = "false")]
+    force: bool,
+
+    /// Copies the paths into the repository, leaving the originals in place, and restores them as copies
+    #[clap(long, default_value = "false")]
+    copy: bool,
+
+    /// Stages the paths without committing them, see dotty commit and restore --commit
+    #[clap(long, default_value = "false")]
+    no_commit: bool,
+
+    /// The commit message, followed by the list of added paths
+    #[clap(short, long, conflicts_with = "no_commit")]
+    message: Option<String>,
+
+    /// Picks unmanaged dotfiles in the root to add, with fuzzy search
+    #[clap(short, long, default_value = "false")]
+    interactive: bool,
+
+    /// Moves each directory whole and symlinks it, so new files in it are tracked
+    #[clap(long, default_value = "false", conflicts_with = "copy")]
+    as_dir: bool,
+
+    /// Stores the path at this location in the repository, restoring it back to where it was
+    #[clap(long = "as", value_name = "REPO_PATH")]
+    stored_as: Option<PathBuf>,
+
+    /// Hard links the files into the repository, leaving the originals in place, and restores them as hard links
+    #[clap(long, default_value = "false", conflicts_with_all = ["copy", "as_dir"])]
+    hardlink: bool,
+
+    /// Refuses to add the paths unless the repository is encrypted, see dotty crypt init
+    #[clap(long, default_value = "false")]
+    encrypt: bool,
+
+    /// Tags the paths in dotty.yaml, e.g. shell. Can be used multiple times
+    #[clap(long = "tag", value_name = "TAG")]
+    tags: Vec<String>,
+
+    /// Stores git repositories as plain files instead of submodules, recording their origin in
+    /// dotty.yaml for dotty revendor
+    #[clap(long, default_value = "false")]
+    vendor: bool,
 }
 
 #[derive(Parser)]
@@ -73,6 +362,58 @@ struct Restore {
     /// Overwrites existing files/symlinks
     #[clap(short, long, default_value = "false")]
     overwrite: bool,
+
+    /// Interactively pick which entries to restore
+    #[clap(short, long, default_value = "false")]
+    pick: bool,
+
+    /// Applies exactly the actions of a plan saved by dotty plan, see dotty apply
+    #[clap(long, conflicts_with_all = ["overwrite", "pick", "interactive", "diff", "exclude", "tags", "commit"])]
+    plan_file: Option<PathBuf>,
+
+    /// Asks whether to overwrite, skip, keep both or merge each existing file, showing its diff
+    #[clap(short, long, default_value = "false", conflicts_with = "overwrite")]
+    interactive: bool,
+
+    /// Runs restorecon on the restored paths when SELinux is enforcing
+    #[clap(long, default_value = "false")]
+    relabel: bool,
+
+    /// Previews the changes, with diffs of existing files, before confirming
+    #[clap(short, long, default_value = "false")]
+    diff: bool,
+
+    /// Takes a btrfs, ZFS or APFS snapshot of the root before overwriting
+    #[clap(long, default_value = "false", requires = "overwrite")]
+    snapshot: bool,
+
+    /// Skips entries matching this glob, relative to the root, e.g. .config/alacritty
+    #[clap(short = 'x', long)]
+    exclude: Vec<String>,
+
+    /// Keeps the timestamps of the repository files on copies, modification times unless given
+    #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "modified")]
+    preserve_times: Option<PreserveTimes>,
+
+    /// Only restores entries with this tag in dotty.yaml. Can be used multiple times
+    #[clap(long = "tag", value_name = "TAG")]
+    tags: Vec<String>,
+
+    /// Checks out the commits of submodules and read-only layers pinned in dotty.lock first
+    #[clap(long, default_value = "false")]
+    locked: bool,
+
+    /// Also removes dangling symlinks into the repository that restore did not create
+    #[clap(long, default_value = "false")]
+    prune: bool,
+
+    /// Commits what add --no-commit staged once the restore succeeds
+    #[clap(long, default_value = "false")]
+    commit: bool,
+
+    /// Retries changes that are denied permission with sudo, e.g. for entries in a root of /etc
+    #[clap(long, default_value = "false")]
+    sudo: bool,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
@@ -84,15 +425,540 @@ enum RestoreMode {
     Files,
 }
 
+#[derive(Parser)]
+struct List {
+    /// Shows destination, deploy status, tags and description for each entry
+    #[clap(short, long, default_value = "false")]
+    long: bool,
+
+    /// Only lists entries with this tag in dotty.yaml. Can be used multiple times
+    #[clap(long = "tag", value_name = "TAG")]
+    tags: Vec<String>,
+}
+
+#[derive(Parser)]
+struct Status {
+    /// Prints a tab separated state and path for each entry that differs, for scripts. Exits
+    /// with 1 when there are any
+    #[clap(long, default_value = "false")]
+    porcelain: bool,
+}
+
+#[derive(Parser)]
+struct PromptStatus {
+    /// How long ago the last fetch can be before ? is shown, e.g. 12h or 7d
+    #[clap(long, default_value = "1d")]
+    stale: String,
+}
+
 #[derive(Parser)]
 struct Sync {
     /// The repository url to sync to
     #[clap()]
     url: Option<String>,
+
+    /// Sends a notification on failure using the notify settings in dotty.yaml
+    #[clap(short, long, default_value = "false")]
+    notify: bool,
+
+    /// Commits files that look like they contain credentials
+    #[clap(long, default_value = "false")]
+    allow_secrets: bool,
+
+    /// Only fetches and merges the remote, like pull
+    #[clap(long, default_value = "false")]
+    no_push: bool,
+
+    /// Rebases local commits onto the remote instead of merging, merging anyway on conflict
+    #[clap(long, default_value = "false", overrides_with = "merge")]
+    rebase: bool,
+
+    /// Merges the remote, overriding an earlier --rebase
+    #[clap(long, default_value = "false", overrides_with = "rebase")]
+    merge: bool,
+
+    /// How conflicting changes to the same file are resolved
+    #[clap(long, value_enum, default_value = "manual")]
+    strategy: MergeStrategy,
+
+    /// Commits outstanding changes with a generated message before syncing
+    #[clap(long, default_value = "false", conflicts_with = "autostash")]
+    autocommit: bool,
+
+    /// Stashes outstanding changes before syncing and pops them afterwards
+    #[clap(long, default_value = "false")]
+    autostash: bool,
+
+    /// Overwrites the remote branch with the local one instead of pulling, unless it changed since the last fetch
+    #[clap(long, default_value = "false", conflicts_with_all = ["no_push", "rebase", "autostash"])]
+    force_with_lease: bool,
+}
+
+#[derive(Parser)]
+struct Pull {
+    /// The repository url to pull from
+    #[clap()]
+    url: Option<String>,
+
+    /// Sends a notification on failure using the notify settings in dotty.yaml
+    #[clap(short, long, default_value = "false")]
+    notify: bool,
+
+    /// Rebases local commits onto the remote instead of merging, merging anyway on conflict
+    #[clap(long, default_value = "false", overrides_with = "merge")]
+    rebase: bool,
+
+    /// Merges the remote, overriding an earlier --rebase
+    #[clap(long, default_value = "false", overrides_with = "rebase")]
+    merge: bool,
+
+    /// How conflicting changes to the same file are resolved
+    #[clap(long, value_enum, default_value = "manual")]
+    strategy: MergeStrategy,
+}
+
+#[derive(Parser)]
+struct Push {
+    /// The repository url to push to
+    #[clap()]
+    url: Option<String>,
+
+    /// Sends a notification on failure using the notify settings in dotty.yaml
+    #[clap(short, long, default_value = "false")]
+    notify: bool,
+
+    /// Pushes files that look like they contain credentials
+    #[clap(long, default_value = "false")]
+    allow_secrets: bool,
+
+    /// Overwrites the remote branch unless it changed since the last fetch
+    #[clap(long, default_value = "false")]
+    force_with_lease: bool,
+}
+
+#[derive(Parser)]
+struct Update {
+    /// Only updates this submodule, by name or path. Can be used multiple times
+    #[clap(long)]
+    only: Vec<String>,
+
+    /// Updates this pinned submodule to the default branch of its remote instead. Can be used
+    /// multiple times
+    #[clap(long)]
+    unpin: Vec<String>,
+
+    /// Stages the updated submodules without committing them
+    #[clap(long, default_value = "false")]
+    no_commit: bool,
+}
+
+#[derive(Parser)]
+struct Revendor {
+    /// The vendored directories to refresh, relative to the repository. Default is all of them
+    #[clap()]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct Schedule {
+    #[clap(subcommand)]
+    subcmd: Option<ScheduleCommand>,
+}
+
+#[derive(Parser)]
+enum ScheduleCommand {
+    /// Installs a launch agent on macOS, or a systemd user timer on Linux, that runs dotty sync
+    Install(ScheduleInstall),
+    /// Shows whether the scheduled sync is installed, which is the default
+    Status,
+    /// Removes the scheduled sync
+    Remove,
+}
+
+#[derive(Parser)]
+struct ScheduleInstall {
+    /// How often to sync, e.g. 30m, 6h or 1d
+    #[clap(short, long)]
+    interval: Option<String>,
+
+    /// Syncs at login
+    #[clap(short, long, default_value = "false")]
+    at_login: bool,
+}
+
+#[derive(Parser)]
+struct Backups {
+    #[clap(subcommand)]
+    subcmd: Option<BackupsCommand>,
+}
+
+#[derive(Parser)]
+enum BackupsCommand {
+    /// Lists the files replaced by restores and the snapshots taken before them. The default
+    List,
+    /// Copies the files replaced by a restore back to the root
+    Restore(BackupsRestore),
+}
+
+#[derive(Parser)]
+struct BackupsRestore {
+    /// The ID of the backup, see dotty backups list
+    #[clap()]
+    id: String,
+}
+
+#[derive(Parser)]
+struct Brew {
+    #[clap(subcommand)]
+    subcmd: BrewCommand,
+}
+
+#[derive(Parser)]
+enum BrewCommand {
+    /// Dumps the installed packages into the Brewfile and commits it
+    Capture,
+    /// Installs the packages in the Brewfile
+    Apply,
+}
+
+#[derive(Parser)]
+struct Packages {
+    #[clap(subcommand)]
+    subcmd: PackagesCommand,
+}
+
+#[derive(Parser)]
+enum PackagesCommand {
+    /// Installs the declared packages that are missing
+    Install,
+    /// Lists the declared packages and whether they are installed
+    Diff,
+}
+
+#[derive(Parser)]
+struct Exec {
+    /// Holds the repository lock while the command runs
+    #[clap(short, long, default_value = "false")]
+    locked: bool,
+
+    /// The command and its arguments, after --
+    #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+#[derive(Parser)]
+struct Git {
+    /// The git arguments, after --
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<String>,
+}
+
+#[derive(Parser)]
+struct Diff {
+    /// The paths in the root to compare. Default is all entries
+    #[clap()]
+    paths: Vec<PathBuf>,
+
+    /// Shows a summary of changed lines per file instead of the diff
+    #[clap(long, default_value = "false")]
+    stat: bool,
 }
 
 #[derive(Parser)]
-struct Update {}
+struct Edit {
+    /// The entry to edit, matched against the managed paths, e.g. zshrc
+    #[clap()]
+    query: String,
+
+    /// Leaves the changes uncommitted instead of offering to commit them
+    #[clap(long, default_value = "false")]
+    no_commit: bool,
+}
+
+#[derive(Parser)]
+struct Harvest {
+    /// The deployed paths to harvest. Default is all entries
+    #[clap()]
+    paths: Vec<PathBuf>,
+
+    /// Commits copies that look like they contain credentials
+    #[clap(long, default_value = "false")]
+    allow_secrets: bool,
+}
+
+#[derive(Parser)]
+struct Verify {}
+
+#[derive(Parser)]
+struct Gc {
+    /// Recomputes every delta, which is slower but packs tighter
+    #[clap(long, default_value = "false")]
+    aggressive: bool,
+
+    /// Also shrinks the object stores of submodules
+    #[clap(long, default_value = "false")]
+    submodules: bool,
+
+    /// Only prunes unreachable objects older than this, in any format git gc --prune takes, e.g. now
+    #[clap(long, default_value = "2.weeks.ago")]
+    prune: String,
+}
+
+#[derive(Parser)]
+struct SnapshotCmd {
+    /// The name of the snapshot. Default is snapshot- with the date and time
+    #[clap()]
+    name: Option<String>,
+}
+
+#[derive(Parser)]
+struct Rollback {
+    /// The snapshot, tag or commit to roll back to
+    #[clap()]
+    target: String,
+
+    /// Restore mode
+    #[clap(short, long, value_enum, default_value = "symlinks")]
+    mode: RestoreMode,
+
+    /// Overwrites existing files/symlinks
+    #[clap(short, long, default_value = "false")]
+    overwrite: bool,
+}
+
+#[derive(Parser)]
+struct Undo {}
+
+#[derive(Parser)]
+struct Fetch {
+    /// The repository url to fetch from
+    #[clap()]
+    url: Option<String>,
+
+    /// Shows the commits ahead and behind the remote branch, listing the incoming ones
+    #[clap(long, default_value = "false")]
+    status: bool,
+
+    /// Compares to the remote branch as of the last fetch, without fetching
+    #[clap(long, default_value = "false", requires = "status")]
+    cached: bool,
+}
+
+#[derive(Parser)]
+struct Log {
+    /// The paths in the root to show the history of. Default is the whole repository
+    #[clap()]
+    paths: Vec<PathBuf>,
+
+    /// The number of commits to show
+    #[clap(short = 'n', long, default_value = "20")]
+    max_count: usize,
+}
+
+#[derive(Parser)]
+struct PathCmd {
+    /// The entry to print the repository path of, matched like edit. Default is the repository itself
+    #[clap(conflicts_with = "show_root")]
+    query: Option<String>,
+
+    /// Prints the root instead
+    #[clap(long = "root", default_value = "false")]
+    show_root: bool,
+}
+
+#[derive(Parser)]
+struct Bootstrap {
+    /// Only runs this script, by name. Can be used multiple times
+    #[clap(long)]
+    only: Vec<String>,
+}
+
+#[derive(Parser)]
+struct Remove {
+    /// The paths in the root to stop managing
+    #[clap(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct Commit {
+    /// The commit message. Default lists the changed paths
+    #[clap(short, long)]
+    message: Option<String>,
+
+    /// Interactively selects the hunks to commit
+    #[clap(short, long, default_value = "false")]
+    patch: bool,
+
+    /// Commits files that look like they contain credentials
+    #[clap(long, default_value = "false")]
+    allow_secrets: bool,
+}
+
+#[derive(Parser)]
+struct Ignore {
+    #[clap(subcommand)]
+    subcmd: IgnoreCommand,
+}
+
+#[derive(Parser)]
+enum IgnoreCommand {
+    /// Adds a gitignore style pattern, e.g. 'nvim/**/spell/*'
+    Add(IgnorePattern),
+    /// Lists the ignore patterns
+    List,
+    /// Removes an ignore pattern
+    Remove(IgnorePattern),
+}
+
+#[derive(Parser)]
+struct IgnorePattern {
+    #[clap()]
+    pattern: String,
+}
+
+#[derive(Parser)]
+struct Submodule {
+    #[clap(subcommand)]
+    subcmd: SubmoduleCommand,
+}
+
+#[derive(Parser)]
+enum SubmoduleCommand {
+    /// Removes a submodule from .gitmodules, the git config and the working tree, then commits
+    Remove(SubmodulePath),
+    /// Replaces a submodule with plain files of its checked out commit, like add --vendor
+    Absorb(SubmodulePath),
+    /// Replaces vendored files with a submodule of the commit they were vendored from
+    Extract(VendoredPath),
+}
+
+#[derive(Parser)]
+struct SubmodulePath {
+    /// The path or name of the submodule
+    #[clap()]
+    path: String,
+}
+
+#[derive(Parser)]
+struct VendoredPath {
+    /// The vendored directory, relative to the repository
+    #[clap()]
+    path: PathBuf,
+}
+
+#[derive(Parser)]
+struct Link {
+    /// The paths in the root to link. Default is all entries
+    #[clap()]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct Backfill {}
+
+#[derive(Parser)]
+struct PackageNames {
+    /// The names of the packages, e.g. nvim for packages/nvim
+    #[clap(required = true)]
+    names: Vec<String>,
+}
+
+#[derive(Parser)]
+struct Relink {
+    /// The previous location of the dotty repository. Default is any location of a dangling symlink
+    #[clap(long)]
+    from: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct Plan {
+    /// The file to save the plan to
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    /// The mode the plan restores in
+    #[clap(short, long, value_enum, default_value = "symlinks")]
+    mode: RestoreMode,
+
+    /// Plans to move existing files to a backup directory and replace them
+    #[clap(long, default_value = "false")]
+    overwrite: bool,
+
+    /// Also plans to remove dangling symlinks into the repository that restore did not create
+    #[clap(long, default_value = "false")]
+    prune: bool,
+}
+
+#[derive(Parser)]
+struct Apply {
+    /// The plan file created by dotty plan
+    #[clap()]
+    plan: PathBuf,
+}
+
+#[derive(Parser)]
+struct Daemon {
+    /// How long a cached status is served before it is recomputed, e.g. 2s or 1m
+    #[clap(long, default_value = "2s")]
+    ttl: String,
+
+    /// Only serves requests, without committing changes to managed files like watch
+    #[clap(long, default_value = "false")]
+    no_watch: bool,
+
+    #[clap(flatten)]
+    watch: Watch,
+}
+
+#[derive(Parser)]
+struct Watch {
+    /// How long changes have to settle before they are committed, e.g. 2s or 1m
+    #[clap(long, default_value = "2s")]
+    debounce: String,
+
+    /// Syncs with the remote after each commit
+    #[clap(long, default_value = "false")]
+    sync: bool,
+
+    /// Commits files that look like they contain credentials
+    #[clap(long, default_value = "false")]
+    allow_secrets: bool,
+
+    /// Sends a notification when committing or syncing fails, using the notify settings in
+    /// dotty.yaml
+    #[clap(short, long, default_value = "false")]
+    notify: bool,
+}
+
+#[derive(Parser)]
+struct Crypt {
+    #[clap(subcommand)]
+    subcmd: CryptCommand,
+}
+
+#[derive(Parser)]
+enum CryptCommand {
+    /// Generates a key and encrypts every file committed from now on
+    Init,
+    /// Exports the key to unlock other clones of the dotty repository
+    ExportKey(KeyPath),
+    /// Imports a key and decrypts the files in the dotty repository
+    ImportKey(KeyPath),
+    /// Encrypts stdin to stdout, used as the git clean filter
+    #[clap(hide = true)]
+    Clean,
+    /// Decrypts stdin to stdout, used as the git smudge filter
+    #[clap(hide = true)]
+    Smudge,
+}
+
+#[derive(Parser)]
+struct KeyPath {
+    /// The path to the key file
+    #[clap()]
+    path: PathBuf,
+}
 
 fn init_logger(opts: &Opts) {
     let level = match opts.verbose {
@@ -107,7 +973,7 @@ fn init_logger(opts: &Opts) {
         } else {
             log::LevelFilter::Off
         })
-        .set_target_level(if opts.verbose >= 3 {
+        .set_target_level(if opts.verbose >= 3 || opts.log_filter.is_some() {
             log::LevelFilter::Error
         } else {
             log::LevelFilter::Off
@@ -116,12 +982,143 @@ fn init_logger(opts: &Opts) {
         .set_time_level(log::LevelFilter::Off)
         .build();
 
-    if let Err(err) = TermLogger::init(level, config, TerminalMode::Mixed, ColorChoice::Auto) {
+    // logs go to stderr, leaving stdout to the output of commands like path, list and verify
+    let result = match &opts.log_filter {
+        Some(filter) => logger::init(
+            filter.clone(),
+            level,
+            TermLogger::new(
+                log::LevelFilter::Trace,
+                config,
+                TerminalMode::Stderr,
+                ColorChoice::Auto,
+            ),
+        ),
+        None => TermLogger::init(level, config, TerminalMode::Stderr, ColorChoice::Auto)
+            .map_err(|err| err.to_string()),
+    };
+    if let Err(err) = result {
         panic!("failed to initialize logger - {}", err);
     }
 }
 
-fn run(opts: &Opts) -> Result<(), String> {
+/// The options of the sync, pull and push subcommands
+fn sync_options(subcmd: &SubCommand) -> Option<SyncOptions> {
+    match subcmd {
+        SubCommand::Sync(sync_cmd) => Some(SyncOptions {
+            url: sync_cmd.url.clone(),
+            notify: sync_cmd.notify,
+            allow_secrets: sync_cmd.allow_secrets,
+            stages: match (sync_cmd.no_push, sync_cmd.force_with_lease) {
+                (true, _) => SyncStages::Pull,
+                (false, true) => SyncStages::Push,
+                (false, false) => SyncStages::Both,
+            },
+            rebase: sync_cmd.rebase,
+            strategy: sync_cmd.strategy,
+            autocommit: sync_cmd.autocommit,
+            autostash: sync_cmd.autostash,
+            force_with_lease: sync_cmd.force_with_lease,
+        }),
+        SubCommand::Pull(pull_cmd) => Some(SyncOptions {
+            url: pull_cmd.url.clone(),
+            notify: pull_cmd.notify,
+            stages: SyncStages::Pull,
+            rebase: pull_cmd.rebase,
+            strategy: pull_cmd.strategy,
+            ..Default::default()
+        }),
+        SubCommand::Push(push_cmd) => Some(SyncOptions {
+            url: push_cmd.url.clone(),
+            notify: push_cmd.notify,
+            allow_secrets: push_cmd.allow_secrets,
+            stages: SyncStages::Push,
+            force_with_lease: push_cmd.force_with_lease,
+            ..Default::default()
+        }),
+        _ => None,
+    }
+}
+
+/// Only non-interactive commands are handed to a running daemon
+///
+/// The daemon never prompts, as with --no-input, and uses the profiles, ssh key, accept-new and
+/// --yes of the client.
+fn daemon_request(subcmd: &SubCommand, root: &Path, assume_yes: bool) -> Option<ipc::Request> {
+    match subcmd {
+        SubCommand::Status(Status { porcelain: false }) => Some(ipc::Request::Status {
+            root: root.to_owned(),
+            profiles: profile::active(),
+        }),
+        SubCommand::Sync(_) | SubCommand::Pull(_) | SubCommand::Push(_) => {
+            // merge tools and force push confirmations need the terminal
+            let opts = sync_options(subcmd)
+                .filter(|opts| opts.strategy != MergeStrategy::Tool && !opts.force_with_lease)?;
+            Some(ipc::Request::Sync {
+                root: root.to_owned(),
+                url: opts.url,
+                notify: opts.notify,
+                allow_secrets: opts.allow_secrets,
+                stages: opts.stages,
+                rebase: opts.rebase,
+                strategy: opts.strategy,
+                autocommit: opts.autocommit,
+                autostash: opts.autostash,
+                ssh_key: utils::git::ssh_key(),
+                accept_new: known_hosts::accepts_new(),
+                profiles: profile::active(),
+                assume_yes,
+            })
+        }
+        SubCommand::Restore(restore_cmd)
+            if !restore_cmd.pick
+                && restore_cmd.plan_file.is_none()
+                && !restore_cmd.interactive
+                && (!restore_cmd.overwrite || assume_yes)
+                && !restore_cmd.diff
+                && !restore_cmd.snapshot
+                && !restore_cmd.commit
+                && !restore_cmd.sudo =>
+        {
+            Some(ipc::Request::Restore {
+                root: root.to_owned(),
+                symlinks: restore_cmd.mode == RestoreMode::Symlinks,
+                overwrite: restore_cmd.overwrite,
+                relabel: restore_cmd.relabel,
+                profiles: profile::active(),
+                exclude: restore_cmd.exclude.clone(),
+                preserve_times: restore_cmd.preserve_times,
+                tags: restore_cmd.tags.clone(),
+                locked: restore_cmd.locked,
+                prune: restore_cmd.prune,
+                ssh_key: utils::git::ssh_key(),
+                accept_new: known_hosts::accepts_new(),
+                assume_yes,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Whether the command warns about a repository that has not synced in a while
+fn warns_when_stale(subcmd: &SubCommand) -> bool {
+    !matches!(
+        subcmd,
+        SubCommand::Init(_)
+            | SubCommand::Clone(_)
+            | SubCommand::Sync(_)
+            | SubCommand::Pull(_)
+            | SubCommand::Push(_)
+            | SubCommand::PromptStatus(_)
+            | SubCommand::Status(Status { porcelain: true })
+            | SubCommand::Daemon(_)
+            | SubCommand::Watch(_)
+            | SubCommand::Schedule(_)
+    )
+}
+
+fn run(opts: &Opts) -> Result<ExitCode, String> {
+    profile::activate(&opts.profiles)?;
     let repo = path::canonicalize(&opts.repository)?;
     let root = path::canonicalize(&path::get_root(opts.root.as_deref(), &repo)?)?;
     log::debug!(
@@ -129,25 +1126,307 @@ fn run(opts: &Opts) -> Result<(), String> {
         repo.display(),
         root.display()
     );
-    match &opts.subcmd {
-        SubCommand::Init(_) => init(&repo),
-        SubCommand::Clone(clone_cmd) => clone(&repo, &clone_cmd.url),
-        SubCommand::Add(add_cmd) => add(&repo, &root, &add_cmd.paths),
+    // an invalid or missing dotty.yaml is reported by the commands that need it
+    let config = config::load(&repo).unwrap_or_default();
+    if let Some(ssh_key) = opts.ssh_key.as_ref().or(config.ssh_key.as_ref()) {
+        utils::git::set_ssh_key(Some(path::absolute(&path::expand_home(ssh_key)?)?));
+    }
+    if let Some(proxy) = &config.proxy {
+        utils::git::set_proxy(proxy.to_owned());
+    }
+    utils::git::set_token_hosts(config.token_hosts.clone());
+    utils::hosting::set_gitlab_hosts(config.gitlab_hosts.clone());
+    if let Some(retry) = &config.retry {
+        let backoff = retry.backoff.as_deref().unwrap_or("1s");
+        utils::git::set_retry(
+            retry.attempts.unwrap_or(3),
+            string::parse_duration(backoff)?,
+        );
+    }
+    if warns_when_stale(&opts.subcmd) {
+        warn_if_stale(&repo, &config);
+    }
+    if let Some(request) = daemon_request(&opts.subcmd, &root, opts.yes).filter(|_| !opts.dry_run) {
+        if forward_to_daemon(&repo, &request)? {
+            return Ok(ExitCode::SUCCESS);
+        }
+    }
+    let _lock = match &opts.subcmd {
+        SubCommand::Add(_)
+        | SubCommand::Restore(_)
+        | SubCommand::Sync(_)
+        | SubCommand::Pull(_)
+        | SubCommand::Push(_)
+        | SubCommand::Update(_)
+        | SubCommand::Revendor(_)
+        | SubCommand::Apply(_)
+        | SubCommand::Link(_)
+        | SubCommand::Relink(_)
+        | SubCommand::Backfill(_)
+        | SubCommand::Enable(_)
+        | SubCommand::Disable(_)
+        | SubCommand::Ignore(Ignore {
+            subcmd: IgnoreCommand::Add(_) | IgnoreCommand::Remove(_),
+        })
+        | SubCommand::Brew(Brew {
+            subcmd: BrewCommand::Capture,
+        })
+        | SubCommand::Crypt(Crypt {
+            subcmd: CryptCommand::Init | CryptCommand::ImportKey(_),
+        })
+        | SubCommand::Commit(_)
+        | SubCommand::Edit(_)
+        | SubCommand::Harvest(_)
+        | SubCommand::Undo(_)
+        | SubCommand::Fetch(_)
+        | SubCommand::Gc(_)
+        | SubCommand::Snapshot(_)
+        | SubCommand::Rollback(_)
+        | SubCommand::Submodule(_)
+        | SubCommand::Remove(_) => Some(lock::acquire(&repo)?),
+        _ => None,
+    };
+    let result = match &opts.subcmd {
+        SubCommand::Init(init_cmd) => init(
+            &repo,
+            init_cmd.url.as_deref(),
+            config::Identity {
+                name: init_cmd.name.clone(),
+                email: init_cmd.email.clone(),
+                sign: init_cmd.sign,
+                signing_key: init_cmd.signing_key.clone(),
+            },
+        ),
+        SubCommand::Clone(clone_cmd) => clone(
+            &repo,
+            &clone_cmd.url,
+            clone_cmd.branch.as_deref(),
+            clone_cmd.shallow_submodules,
+            clone_cmd.locked,
+        ),
+        SubCommand::Add(add_cmd) => add(
+            &repo,
+            &root,
+            &add_cmd.paths,
+            &AddOptions {
+                defaults_domains: &add_cmd.defaults_domains,
+                migrate_from: add_cmd.migrate_from.as_deref(),
+                into_package: add_cmd.into_package.as_deref(),
+                into_profile: add_cmd.into_profile.as_deref(),
+                into_host: add_cmd.host,
+                os: add_cmd.os,
+                hosts: &add_cmd.on_hosts,
+                target: add_cmd.target.as_deref(),
+                pre_restore: add_cmd.pre_restore.as_deref(),
+                post_restore: add_cmd.post_restore.as_deref(),
+                allow_secrets: add_cmd.allow_secrets,
+                force: add_cmd.force,
+                copy: add_cmd.copy,
+                no_commit: add_cmd.no_commit,
+                message: add_cmd.message.as_deref(),
+                interactive: add_cmd.interactive,
+                as_dir: add_cmd.as_dir,
+                stored_as: add_cmd.stored_as.as_deref(),
+                hardlink: add_cmd.hardlink,
+                encrypt: add_cmd.encrypt,
+                tags: &add_cmd.tags,
+                vendor: add_cmd.vendor,
+            },
+        ),
+        SubCommand::Restore(Restore {
+            plan_file: Some(plan_file),
+            ..
+        }) => apply(&repo, &root, plan_file),
         SubCommand::Restore(restore_cmd) => restore(
             &repo,
             &root,
-            restore_cmd.mode == RestoreMode::Symlinks,
-            restore_cmd.overwrite,
+            &RestoreOptions {
+                symlinks: restore_cmd.mode == RestoreMode::Symlinks,
+                overwrite: restore_cmd.overwrite,
+                pick: restore_cmd.pick,
+                interactive: restore_cmd.interactive,
+                relabel: restore_cmd.relabel,
+                preview: restore_cmd.diff,
+                snapshot: restore_cmd.snapshot,
+                exclude: restore_cmd.exclude.clone(),
+                preserve_times: restore_cmd.preserve_times,
+                tags: restore_cmd.tags.clone(),
+                locked: restore_cmd.locked,
+                prune: restore_cmd.prune,
+                commit: restore_cmd.commit,
+            },
         ),
-        SubCommand::Sync(sync_cmd) => sync(&repo, sync_cmd.url.as_deref()),
-        SubCommand::Update(_) => update(&repo),
-    }
+        SubCommand::List(list_cmd) => list(&repo, &root, list_cmd.long, &list_cmd.tags),
+        SubCommand::Status(Status { porcelain: true }) => {
+            return status_porcelain(&repo, &root).map(|clean| match clean {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::from(DRIFT_EXIT_CODE),
+            })
+        }
+        SubCommand::Status(_) => status(&repo, &root),
+        SubCommand::PromptStatus(prompt_cmd) => prompt_status(&repo, &prompt_cmd.stale),
+        SubCommand::Sync(_) | SubCommand::Pull(_) | SubCommand::Push(_) => sync(
+            &repo,
+            &root,
+            &sync_options(&opts.subcmd).unwrap_or_default(),
+        ),
+        SubCommand::Update(update_cmd) => update(
+            &repo,
+            &update_cmd.only,
+            &update_cmd.unpin,
+            update_cmd.no_commit,
+        ),
+        SubCommand::Revendor(revendor_cmd) => revendor(&repo, &revendor_cmd.paths),
+        SubCommand::Schedule(schedule_cmd) => match &schedule_cmd.subcmd {
+            Some(ScheduleCommand::Install(install_cmd)) => {
+                schedule_install(&repo, install_cmd.interval.as_deref(), install_cmd.at_login)
+            }
+            None | Some(ScheduleCommand::Status) => schedule_status(),
+            Some(ScheduleCommand::Remove) => schedule_remove(),
+        },
+        SubCommand::Brew(brew_cmd) => match brew_cmd.subcmd {
+            BrewCommand::Capture => brew_capture(&repo),
+            BrewCommand::Apply => brew_apply(&repo),
+        },
+        SubCommand::Backups(backups_cmd) => match &backups_cmd.subcmd {
+            None | Some(BackupsCommand::List) => backups_list(&repo),
+            Some(BackupsCommand::Restore(restore_cmd)) => {
+                backups_restore(&repo, &root, &restore_cmd.id)
+            }
+        },
+        SubCommand::Crypt(crypt_cmd) => match &crypt_cmd.subcmd {
+            CryptCommand::Init => crypt_init(&repo),
+            CryptCommand::ExportKey(key_cmd) => crypt_export_key(&repo, &key_cmd.path),
+            CryptCommand::ImportKey(key_cmd) => crypt_import_key(&repo, &key_cmd.path),
+            CryptCommand::Clean => crypt_filter(&repo, true),
+            CryptCommand::Smudge => crypt_filter(&repo, false),
+        },
+        SubCommand::Exec(exec_cmd) => exec(&repo, &root, &exec_cmd.command, exec_cmd.locked),
+        SubCommand::Git(git_cmd) => git(&repo, &root, &git_cmd.args),
+        SubCommand::Daemon(daemon_cmd) => daemon(
+            &repo,
+            &root,
+            &daemon_cmd.ttl,
+            Some(daemon_cmd.watch.debounce.as_str()).filter(|_| !daemon_cmd.no_watch),
+            daemon_cmd.watch.sync,
+            daemon_cmd.watch.allow_secrets,
+            daemon_cmd.watch.notify,
+        ),
+        SubCommand::Watch(watch_cmd) => watch(
+            &repo,
+            &root,
+            &watch_cmd.debounce,
+            watch_cmd.sync,
+            watch_cmd.allow_secrets,
+            watch_cmd.notify,
+        ),
+        SubCommand::Plan(plan_cmd) => plan(
+            &repo,
+            &root,
+            plan_cmd.output.as_deref(),
+            plan_cmd.mode == RestoreMode::Symlinks,
+            plan_cmd.overwrite,
+            plan_cmd.prune,
+        ),
+        SubCommand::Apply(apply_cmd) => apply(&repo, &root, &apply_cmd.plan),
+        SubCommand::Link(link_cmd) => link(&repo, &root, &link_cmd.paths),
+        SubCommand::Backfill(_) => backfill(&repo),
+        SubCommand::Enable(packages_cmd) => enable(&repo, &packages_cmd.names),
+        SubCommand::Disable(packages_cmd) => disable(&repo, &root, &packages_cmd.names),
+        SubCommand::Relink(relink_cmd) => relink(&repo, &root, relink_cmd.from.as_deref()),
+        SubCommand::Diff(diff_cmd) => diff(&repo, &root, &diff_cmd.paths, diff_cmd.stat),
+        SubCommand::Edit(edit_cmd) => edit(&repo, &root, &edit_cmd.query, edit_cmd.no_commit),
+        SubCommand::Harvest(harvest_cmd) => {
+            harvest(&repo, &root, &harvest_cmd.paths, harvest_cmd.allow_secrets)
+        }
+        SubCommand::Verify(_) => verify(&repo),
+        SubCommand::Gc(gc_cmd) => gc(&repo, gc_cmd.aggressive, gc_cmd.submodules, &gc_cmd.prune),
+        SubCommand::Snapshot(snapshot_cmd) => snapshot_create(&repo, snapshot_cmd.name.as_deref()),
+        SubCommand::Rollback(rollback_cmd) => rollback(
+            &repo,
+            &root,
+            &rollback_cmd.target,
+            &RestoreOptions {
+                symlinks: rollback_cmd.mode == RestoreMode::Symlinks,
+                overwrite: rollback_cmd.overwrite,
+                ..Default::default()
+            },
+        ),
+        SubCommand::Undo(_) => undo(&repo, &root),
+        SubCommand::Fetch(fetch_cmd) => fetch(
+            &repo,
+            fetch_cmd.url.as_deref(),
+            fetch_cmd.status,
+            fetch_cmd.cached,
+        ),
+        SubCommand::Log(log_cmd) => log(&repo, &root, &log_cmd.paths, log_cmd.max_count),
+        SubCommand::Path(path_cmd) => {
+            path(&repo, &root, path_cmd.query.as_deref(), path_cmd.show_root)
+        }
+        SubCommand::Bootstrap(bootstrap_cmd) => bootstrap(&repo, &root, &bootstrap_cmd.only),
+        SubCommand::Packages(packages_cmd) => match packages_cmd.subcmd {
+            PackagesCommand::Install => packages_install(&repo),
+            PackagesCommand::Diff => packages_diff(&repo),
+        },
+        SubCommand::Remove(remove_cmd) => remove(&repo, &root, &remove_cmd.paths),
+        SubCommand::Commit(commit_cmd) => commit(
+            &repo,
+            commit_cmd.message.as_deref(),
+            commit_cmd.patch,
+            commit_cmd.allow_secrets,
+        ),
+        SubCommand::Ignore(ignore_cmd) => match &ignore_cmd.subcmd {
+            IgnoreCommand::Add(pattern_cmd) => ignore_add(&repo, &pattern_cmd.pattern),
+            IgnoreCommand::List => ignore_list(&repo),
+            IgnoreCommand::Remove(pattern_cmd) => ignore_remove(&repo, &pattern_cmd.pattern),
+        },
+        SubCommand::Submodule(submodule_cmd) => match &submodule_cmd.subcmd {
+            SubmoduleCommand::Remove(path_cmd) => submodule_remove(&repo, &path_cmd.path),
+            SubmoduleCommand::Absorb(path_cmd) => submodule_absorb(&repo, &path_cmd.path),
+            SubmoduleCommand::Extract(path_cmd) => submodule_extract(&repo, &path_cmd.path),
+        },
+    };
+    result.map(|_| ExitCode::SUCCESS)
 }
 
-fn main() {
+/// The exit code of status --porcelain when entries differ from the repository
+const DRIFT_EXIT_CODE: u8 = 1;
+const ERROR_EXIT_CODE: u8 = 2;
+
+fn main() -> ExitCode {
     let opts: Opts = Opts::parse();
     init_logger(&opts);
-    if let Err(err) = run(&opts) {
-        log::error!("{}", err);
+    if opts.timings.is_some() {
+        timings::enable();
+    }
+    if opts.dry_run {
+        dry_run::enable();
+    }
+    if opts.no_input {
+        prompt::disable();
+    }
+    if opts.yes {
+        prompt::assume_yes();
+    }
+    if opts.accept_new {
+        known_hosts::set_accept_new(true);
+    }
+    if let SubCommand::Restore(Restore { sudo: true, .. }) = opts.subcmd {
+        sudo::enable();
+    }
+    let start = Instant::now();
+    let exit_code = match run(&opts) {
+        Ok(exit_code) => exit_code,
+        Err(err) => {
+            log::error!("{}", err);
+            ExitCode::from(ERROR_EXIT_CODE)
+        }
+    };
+    if let Some(format) = opts.timings {
+        eprint!(
+            "{}",
+            timings::report(start.elapsed(), format == TimingsFormat::Json)
+        );
     }
+    exit_code
 }
@@ -0,0 +1,156 @@
+use crate::utils::fs::{Fingerprint, PreserveTimes};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const VERSION: u32 = 1;
+
+/// The actions restore would take, along with the state they were computed from
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Plan {
+    pub version: u32,
+    pub repository: PathBuf,
+    pub root: PathBuf,
+
+    /// The repository commit the plan was computed from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head: Option<String>,
+
+    pub symlinks: bool,
+
+    /// The timestamps copies keep from the repository
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preserve_times: Option<PreserveTimes>,
+
+    pub actions: Vec<Action>,
+
+    /// macOS preference domains imported after the actions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub defaults: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Creates a symlink where nothing exists
+    Link,
+
+    /// Copies where nothing exists
+    Copy,
+
+    /// Creates a hard link where nothing exists
+    Hardlink,
+
+    /// Replaces an existing symlink
+    Replace,
+
+    /// Moves an existing file to the backup directory before deploying
+    Backup,
+
+    /// Writes a rendered template, backing up anything it replaces
+    Render,
+
+    /// Removes a dangling symlink into the repository, or an unchanged entry removed from it
+    Prune,
+
+    /// Reapplies the recorded permission bits to an otherwise deployed entry
+    Chmod,
+
+    /// Moves an existing file aside, next to the deployed entry
+    Keep,
+
+    /// Merges an existing file into the repository with conflict markers, then backs it up and deploys
+    Merge,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Link => "create symlink",
+            Kind::Copy => "copy",
+            Kind::Hardlink => "create hard link",
+            Kind::Replace => "replace symlink",
+            Kind::Backup => "back up and replace",
+            Kind::Render => "render",
+            Kind::Prune => "prune",
+            Kind::Chmod => "set permissions",
+            Kind::Keep => "keep both",
+            Kind::Merge => "merge and replace",
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Action {
+    pub kind: Kind,
+
+    /// The entry in the repository, absent for prunes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<PathBuf>,
+
+    pub to: PathBuf,
+
+    /// The state of the entry in the repository when planned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Fingerprint>,
+
+    /// The state of the path in the root when planned
+    pub target: Fingerprint,
+
+    /// Deploys a copy even in a symlink plan
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub copy: bool,
+
+    /// Deploys a hard link in a symlink plan
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hardlink: bool,
+
+    /// Permission bits set on the deployed copy, or on the repository file a symlink points to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+
+    /// The user id given ownership of a deployed copy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<u32>,
+
+    /// The group id given ownership of a deployed copy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<u32>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty() && self.defaults.is_empty()
+    }
+}
+
+pub fn load(path: &Path) -> Result<Plan, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) => return Err(format!("failed to read plan {} - {}", path.display(), err)),
+    };
+    let plan: Plan = match serde_json::from_str(&contents) {
+        Ok(plan) => plan,
+        Err(err) => return Err(format!("failed to parse plan {} - {}", path.display(), err)),
+    };
+    if plan.version != VERSION {
+        return Err(format!(
+            "plan {} has unsupported version {}",
+            path.display(),
+            plan.version
+        ));
+    }
+    Ok(plan)
+}
+
+pub fn save(path: &Path, plan: &Plan) -> Result<(), String> {
+    let contents = match serde_json::to_string_pretty(plan) {
+        Ok(contents) => contents,
+        Err(err) => return Err(format!("failed to serialize plan - {}", err)),
+    };
+    match fs::write(path, contents + "\n") {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("failed to write plan {} - {}", path.display(), err)),
+    }
+}
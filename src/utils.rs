@@ -1,4 +1,34 @@
+pub mod backup;
+pub mod brew;
+pub mod crypt;
+pub mod defaults;
+pub mod diff;
+pub mod dottyignore;
+pub mod dry_run;
 pub mod fs;
 pub mod git;
+pub mod graph;
+pub mod hosting;
+pub mod ipc;
+pub mod known_hosts;
+pub mod launchd;
+pub mod lock;
+pub mod logger;
+pub mod manifest;
+pub mod notification;
+pub mod packages;
 pub mod path;
+pub mod platform;
+pub mod profile;
+pub mod prompt;
+pub mod secrets;
+pub mod selinux;
+pub mod snapshot;
+pub mod state;
 pub mod string;
+pub mod sudo;
+pub mod systemd;
+pub mod template;
+pub mod timings;
+pub mod users;
+pub mod watcher;
@@ -0,0 +1,68 @@
+use super::fs::{self, OverwriteDir};
+use super::path;
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+
+/// The files a restore replaced, beneath their paths relative to the root
+pub struct Backup {
+    pub id: String,
+    pub dir: PathBuf,
+    pub created: DateTime<Utc>,
+}
+
+/// The configured backup directory, or the default beneath the data directory
+pub fn base_dir(configured: Option<&Path>) -> Result<PathBuf, String> {
+    match configured {
+        Some(dir) => path::canonicalize(dir),
+        None => Ok(path::data_dir()?.join("backups")),
+    }
+}
+
+/// A new backup named after the current time
+pub fn create(base: &Path) -> Result<OverwriteDir, String> {
+    let id = Utc::now().format("%Y%m%d%H%M%S").to_string();
+    let mut dir = base.join(&id);
+    let mut count = 1;
+    while dir.exists() {
+        count += 1;
+        dir = base.join(format!("{}-{}", id, count));
+    }
+    fs::create_overwrite_dir(&dir)
+}
+
+/// The backups in the directory, oldest first
+pub fn load_all(base: &Path) -> Result<Vec<Backup>, String> {
+    if !base.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut backups = Vec::new();
+    for dir in fs::read_dir(base)? {
+        let Some(id) = dir.file_name().and_then(|f| f.to_str()) else {
+            continue;
+        };
+        let created = match std::fs::metadata(&dir).and_then(|m| m.modified()) {
+            Ok(modified) => DateTime::<Utc>::from(modified),
+            Err(err) => {
+                return Err(format!(
+                    "unable to get modified time of {} - {}",
+                    dir.display(),
+                    err
+                ))
+            }
+        };
+        backups.push(Backup {
+            id: id.to_owned(),
+            dir: dir.clone(),
+            created,
+        });
+    }
+    backups.sort_by_key(|b| b.created);
+    Ok(backups)
+}
+
+pub fn find(base: &Path, id: &str) -> Result<Backup, String> {
+    load_all(base)?
+        .into_iter()
+        .find(|b| b.id == id)
+        .ok_or_else(|| format!("no backup {} in {}", id, base.display()))
+}
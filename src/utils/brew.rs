@@ -0,0 +1,71 @@
+use super::dry_run;
+use std::path::Path;
+use std::process::{Command, Output};
+
+pub fn is_installed() -> bool {
+    Command::new("brew")
+        .arg("--version")
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+pub fn dump(brewfile: &Path) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "dump installed packages to {}",
+        brewfile.display()
+    )) {
+        return Ok(());
+    }
+    let output = bundle(&["dump", "--force"], brewfile)?;
+    check_success(&output, "dump", brewfile)
+}
+
+pub fn install(brewfile: &Path) -> Result<(), String> {
+    if dry_run::skip(format_args!("install packages from {}", brewfile.display())) {
+        return Ok(());
+    }
+    let output = bundle(&["install"], brewfile)?;
+    check_success(&output, "install", brewfile)
+}
+
+pub fn is_satisfied(brewfile: &Path) -> Result<bool, String> {
+    Ok(bundle(&["check", "--no-upgrade"], brewfile)?
+        .status
+        .success())
+}
+
+pub fn has_untracked(brewfile: &Path) -> Result<bool, String> {
+    let output = bundle(&["cleanup"], brewfile)?;
+    Ok(!String::from_utf8_lossy(&output.stdout).trim().is_empty())
+}
+
+fn bundle(args: &[&str], brewfile: &Path) -> Result<Output, String> {
+    log::trace!(
+        "brew bundle {} --file {}",
+        args.join(" "),
+        brewfile.display()
+    );
+    match Command::new("brew")
+        .arg("bundle")
+        .args(args)
+        .arg("--file")
+        .arg(brewfile)
+        .output()
+    {
+        Ok(output) => Ok(output),
+        Err(err) => Err(format!("failed to run brew bundle - {}", err)),
+    }
+}
+
+fn check_success(output: &Output, action: &str, brewfile: &Path) -> Result<(), String> {
+    if !output.status.success() {
+        return Err(format!(
+            "brew bundle {} failed for {} - {}",
+            action,
+            brewfile.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
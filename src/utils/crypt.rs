@@ -0,0 +1,138 @@
+use crate::config;
+use crate::utils::dry_run;
+use crate::utils::platform;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const FILTER: &str = "dotty-crypt";
+
+const MAGIC: &[u8] = b"\0DOTTYCRYPT\0";
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const PLAINTEXT_PATHS: [&str; 3] = [".gitattributes", ".gitmodules", config::FILE_NAME];
+
+pub struct Key {
+    cipher: [u8; KEY_LEN],
+    mac: [u8; KEY_LEN],
+}
+
+impl Key {
+    pub fn generate() -> Key {
+        let mut key = Key {
+            cipher: [0; KEY_LEN],
+            mac: [0; KEY_LEN],
+        };
+        rand::thread_rng().fill_bytes(&mut key.cipher);
+        rand::thread_rng().fill_bytes(&mut key.mac);
+        key
+    }
+
+    pub fn read(path: &Path) -> Result<Key, String> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) => return Err(format!("failed to read key {} - {}", path.display(), err)),
+        };
+        if bytes.len() != KEY_LEN * 2 {
+            return Err(format!("{} is not a valid dotty key", path.display()));
+        }
+        let mut key = Key {
+            cipher: [0; KEY_LEN],
+            mac: [0; KEY_LEN],
+        };
+        key.cipher.copy_from_slice(&bytes[..KEY_LEN]);
+        key.mac.copy_from_slice(&bytes[KEY_LEN..]);
+        Ok(key)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        if dry_run::skip(format_args!("write key {}", path.display())) {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            if let Err(err) = fs::create_dir_all(parent) {
+                return Err(format!(
+                    "failed to create directory {} - {}",
+                    parent.display(),
+                    err
+                ));
+            }
+        }
+        let bytes = [self.cipher, self.mac].concat();
+        if let Err(err) = fs::write(path, bytes)
+            .and_then(|_| platform::permissions(path, 0o600))
+            .and_then(|permissions| fs::set_permissions(path, permissions))
+        {
+            return Err(format!("failed to write key {} - {}", path.display(), err));
+        }
+        Ok(())
+    }
+
+    /// Encrypts deterministically, so unchanged files produce identical blobs
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        if is_encrypted(plaintext) {
+            return Ok(plaintext.to_owned());
+        }
+
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.mac)
+            .map_err(|err| format!("invalid key - {}", err))?;
+        mac.update(plaintext);
+        let digest = mac.finalize().into_bytes();
+        let nonce = XNonce::from_slice(&digest[..NONCE_LEN]);
+
+        let cipher = XChaCha20Poly1305::new_from_slice(&self.cipher)
+            .map_err(|err| format!("invalid key - {}", err))?;
+        let ciphertext = cipher
+            .encrypt(nonce, plaintext)
+            .map_err(|err| format!("failed to encrypt - {}", err))?;
+
+        Ok([MAGIC, nonce.as_slice(), &ciphertext].concat())
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        if !is_encrypted(data) || data.len() < MAGIC.len() + NONCE_LEN {
+            return Ok(data.to_owned());
+        }
+
+        let (nonce, ciphertext) = data[MAGIC.len()..].split_at(NONCE_LEN);
+        let cipher = XChaCha20Poly1305::new_from_slice(&self.cipher)
+            .map_err(|err| format!("invalid key - {}", err))?;
+        cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "failed to decrypt - the key does not match".to_owned())
+    }
+}
+
+pub fn key_path(git_dir: &Path) -> PathBuf {
+    git_dir.join(FILTER).join("key")
+}
+
+pub fn load_key(git_dir: &Path) -> Result<Option<Key>, String> {
+    let path = key_path(git_dir);
+    match path.exists() {
+        true => Ok(Some(Key::read(&path)?)),
+        false => Ok(None),
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn should_encrypt(path: &Path) -> bool {
+    path.to_str()
+        .map(|p| !PLAINTEXT_PATHS.contains(&p))
+        .unwrap_or(true)
+}
+
+pub fn gitattributes() -> String {
+    let mut attributes = format!("* filter={}\n", FILTER);
+    for path in PLAINTEXT_PATHS {
+        attributes.push_str(&format!("{} !filter\n", path));
+    }
+    attributes
+}
@@ -0,0 +1,92 @@
+use super::dry_run;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+pub fn is_supported() -> bool {
+    cfg!(target_os = "macos")
+}
+
+pub fn read(domain: &str) -> Result<String, String> {
+    log::trace!("exporting defaults domain {}", domain);
+    let output = match Command::new("defaults")
+        .args(["export", domain, "-"])
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => return Err(format!("failed to run defaults export - {}", err)),
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "failed to export defaults domain {} - {}",
+            domain,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub fn export(domain: &str, to: &Path) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "export defaults {} to {}",
+        domain,
+        to.display()
+    )) {
+        return Ok(());
+    }
+    let plist = read(domain)?;
+    if let Some(parent) = to.parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            return Err(format!(
+                "failed to create directory {} - {}",
+                parent.display(),
+                err
+            ));
+        }
+    }
+    match fs::write(to, plist) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("failed to write {} - {}", to.display(), err)),
+    }
+}
+
+pub fn import(domain: &str, from: &Path) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "import defaults {} from {}",
+        domain,
+        from.display()
+    )) {
+        return Ok(());
+    }
+    log::trace!(
+        "importing defaults domain {} from {}",
+        domain,
+        from.display()
+    );
+    let output = match Command::new("defaults")
+        .arg("import")
+        .arg(domain)
+        .arg(from)
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => return Err(format!("failed to run defaults import - {}", err)),
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "failed to import defaults domain {} from {} - {}",
+            domain,
+            from.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+pub fn differs(domain: &str, from: &Path) -> Result<bool, String> {
+    let stored = match fs::read_to_string(from) {
+        Ok(stored) => stored,
+        Err(err) => return Err(format!("failed to read {} - {}", from.display(), err)),
+    };
+    Ok(read(domain)? != stored)
+}
@@ -0,0 +1,338 @@
+use similar::{capture_diff_slices, Algorithm, ChangeTag, DiffTag, TextDiff};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io::IsTerminal;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+pub const RED: &str = "31";
+pub const GREEN: &str = "32";
+pub const YELLOW: &str = "33";
+pub const CYAN: &str = "36";
+
+pub fn paint(color: &str, text: &str) -> String {
+    match std::io::stdout().is_terminal() {
+        true => format!("\x1b[{}m{}\x1b[0m", color, text),
+        false => text.to_owned(),
+    }
+}
+
+/// Returns a unified diff turning `old` into `new`, or None when the contents match
+pub fn unified_files(old: &Path, new: &Path) -> Result<Option<String>, String> {
+    let old_contents = read(old)?;
+    let new_contents = read(new)?;
+    if old_contents == new_contents {
+        return Ok(None);
+    }
+
+    match (
+        String::from_utf8(old_contents),
+        String::from_utf8(new_contents),
+    ) {
+        (Ok(old_text), Ok(new_text)) => Ok(Some(unified(
+            &old.display().to_string(),
+            &new.display().to_string(),
+            &old_text,
+            &new_text,
+        ))),
+        _ => Ok(Some(format!(
+            "binary files {} and {} differ\n",
+            old.display(),
+            new.display()
+        ))),
+    }
+}
+
+/// Like unified_files, for each file that differs beneath two directories, by relative path
+pub fn unified_dirs(old: &Path, new: &Path) -> Result<Vec<(PathBuf, String)>, String> {
+    let mut names = BTreeSet::new();
+    for dir in [old, new] {
+        if dir.is_dir() {
+            names.extend(read_names(dir)?);
+        }
+    }
+    let mut changes = Vec::new();
+    for name in names {
+        let (old_path, new_path) = (old.join(&name), new.join(&name));
+        if old_path.is_dir() || new_path.is_dir() {
+            for (relative, diff) in unified_dirs(&old_path, &new_path)? {
+                changes.push((Path::new(&name).join(relative), diff));
+            }
+            continue;
+        }
+        let diff = match (old_path.is_file(), new_path.is_file()) {
+            (true, true) => unified_files(&old_path, &new_path)?,
+            (true, false) => Some(unified_text(&old_path, &read(&old_path)?, "/dev/null", b"")),
+            (false, true) => Some(unified_text("/dev/null", b"", &new_path, &read(&new_path)?)),
+            (false, false) => None,
+        };
+        if let Some(diff) = diff {
+            changes.push((PathBuf::from(name), diff));
+        }
+    }
+    Ok(changes)
+}
+
+pub fn unified(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(old_name, new_name)
+        .to_string()
+}
+
+/// Combines both versions, with conflict markers around the lines that differ
+pub fn merge(ours_name: &str, theirs_name: &str, ours: &str, theirs: &str) -> String {
+    let mut merged = String::new();
+    let mut ours_lines = String::new();
+    let mut theirs_lines = String::new();
+    for change in TextDiff::from_lines(ours, theirs).iter_all_changes() {
+        let mut line = change.value().to_owned();
+        if change.missing_newline() {
+            line.push('\n');
+        }
+        match change.tag() {
+            ChangeTag::Equal => {
+                push_conflict(
+                    &mut merged,
+                    (ours_name, &mut ours_lines),
+                    (theirs_name, &mut theirs_lines),
+                );
+                merged.push_str(&line);
+            }
+            ChangeTag::Delete => ours_lines.push_str(&line),
+            ChangeTag::Insert => theirs_lines.push_str(&line),
+        }
+    }
+    push_conflict(
+        &mut merged,
+        (ours_name, &mut ours_lines),
+        (theirs_name, &mut theirs_lines),
+    );
+    merged
+}
+
+/// Combines the changes both versions made to `base`, with conflict markers where they overlap
+///
+/// Returns the merged text and whether it has conflicts.
+pub fn merge3(
+    ours_name: &str,
+    theirs_name: &str,
+    base: &str,
+    ours: &str,
+    theirs: &str,
+) -> (String, bool) {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let ours: Vec<&str> = ours.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = theirs.split_inclusive('\n').collect();
+    let ours_changes = changes(&base, &ours);
+    let theirs_changes = changes(&base, &theirs);
+
+    let mut merged = String::new();
+    let mut conflicted = false;
+    let (mut o, mut t, mut pos) = (0, 0, 0);
+    loop {
+        let start = match (ours_changes.get(o), theirs_changes.get(t)) {
+            (Some(a), Some(b)) => a.0.start.min(b.0.start),
+            (Some(a), None) => a.0.start,
+            (None, Some(b)) => b.0.start,
+            (None, None) => break,
+        };
+        // changes touching or overlapping each other are merged as one chunk
+        let (o_from, t_from) = (o, t);
+        let mut end = start;
+        loop {
+            if let Some((old, _)) = ours_changes.get(o).filter(|(old, _)| old.start <= end) {
+                end = end.max(old.end);
+                o += 1;
+            } else if let Some((old, _)) = theirs_changes.get(t).filter(|(old, _)| old.start <= end)
+            {
+                end = end.max(old.end);
+                t += 1;
+            } else {
+                break;
+            }
+        }
+        merged.push_str(&base[pos..start].concat());
+        let ours_chunk = chunk(&base, &ours, &ours_changes[o_from..o], start, end);
+        let theirs_chunk = chunk(&base, &theirs, &theirs_changes[t_from..t], start, end);
+        if o_from == o || ours_chunk == theirs_chunk {
+            merged.push_str(&theirs_chunk);
+        } else if t_from == t {
+            merged.push_str(&ours_chunk);
+        } else {
+            let mut ours_lines = ours_chunk;
+            let mut theirs_lines = theirs_chunk;
+            for lines in [&mut ours_lines, &mut theirs_lines] {
+                if !lines.is_empty() && !lines.ends_with('\n') {
+                    lines.push('\n');
+                }
+            }
+            push_conflict(
+                &mut merged,
+                (ours_name, &mut ours_lines),
+                (theirs_name, &mut theirs_lines),
+            );
+            conflicted = true;
+        }
+        pos = end;
+    }
+    merged.push_str(&base[pos..].concat());
+    (merged, conflicted)
+}
+
+/// The ranges of base lines a version replaced, with the lines that replaced them
+fn changes(base: &[&str], version: &[&str]) -> Vec<(Range<usize>, Range<usize>)> {
+    capture_diff_slices(Algorithm::Myers, base, version)
+        .iter()
+        .map(|op| op.as_tag_tuple())
+        .filter(|(tag, _, _)| *tag != DiffTag::Equal)
+        .map(|(_, old, new)| (old, new))
+        .collect()
+}
+
+/// The lines of a version covering base lines `start..end`, which only the changes differ in
+fn chunk(
+    base: &[&str],
+    version: &[&str],
+    changes: &[(Range<usize>, Range<usize>)],
+    start: usize,
+    end: usize,
+) -> String {
+    match (changes.first(), changes.last()) {
+        (Some((first_old, first_new)), Some((last_old, last_new))) => {
+            let from = first_new.start - (first_old.start - start);
+            let to = last_new.end + (end - last_old.end);
+            version[from..to].concat()
+        }
+        _ => base[start..end].concat(),
+    }
+}
+
+fn push_conflict(merged: &mut String, ours: (&str, &mut String), theirs: (&str, &mut String)) {
+    if ours.1.is_empty() && theirs.1.is_empty() {
+        return;
+    }
+    merged.push_str(&format!(
+        "<<<<<<< {}\n{}=======\n{}>>>>>>> {}\n",
+        ours.0, ours.1, theirs.1, theirs.0
+    ));
+    ours.1.clear();
+    theirs.1.clear();
+}
+
+/// Whether the file still has lines starting conflict markers, like those merge writes
+pub fn has_conflict_markers(path: &Path) -> Result<bool, String> {
+    let contents = read(path)?;
+    Ok(String::from_utf8_lossy(&contents)
+        .lines()
+        .any(|line| line.starts_with("<<<<<<< ") || line.starts_with(">>>>>>> ")))
+}
+
+pub fn colorize(diff: &str) -> String {
+    let mut colored = String::new();
+    for line in diff.lines() {
+        let line = if line.starts_with("+++") || line.starts_with("---") {
+            line.to_owned()
+        } else if line.starts_with('+') {
+            paint(GREEN, line)
+        } else if line.starts_with('-') {
+            paint(RED, line)
+        } else if line.starts_with("@@") {
+            paint(CYAN, line)
+        } else {
+            line.to_owned()
+        };
+        colored.push_str(&line);
+        colored.push('\n');
+    }
+    colored
+}
+
+/// Counts the inserted and deleted lines of a unified diff, only within its hunks
+pub fn stat(diff: &str) -> (usize, usize) {
+    let mut insertions = 0;
+    let mut deletions = 0;
+    // the old and new lines left in the current hunk, as its header gives them
+    let (mut old_left, mut new_left) = (0, 0);
+    for line in diff.lines() {
+        if old_left == 0 && new_left == 0 {
+            if let Some((old_lines, new_lines)) = hunk_lines(line) {
+                (old_left, new_left) = (old_lines, new_lines);
+            }
+            continue;
+        }
+        match line.as_bytes().first() {
+            Some(b'+') => {
+                insertions += 1;
+                new_left -= 1.min(new_left);
+            }
+            Some(b'-') => {
+                deletions += 1;
+                old_left -= 1.min(old_left);
+            }
+            Some(b'\\') => {}
+            _ => {
+                old_left -= 1.min(old_left);
+                new_left -= 1.min(new_left);
+            }
+        }
+    }
+    (insertions, deletions)
+}
+
+/// The old and new line counts of a hunk header such as "@@ -1,3 +1,4 @@"
+fn hunk_lines(line: &str) -> Option<(usize, usize)> {
+    let mut ranges = line.strip_prefix("@@ -")?.split(' ');
+    let count = |range: &str| match range.split_once(',') {
+        Some((_, count)) => count.parse().ok(),
+        None => Some(1),
+    };
+    let old_lines = count(ranges.next()?)?;
+    let new_lines = count(ranges.next()?.strip_prefix('+')?)?;
+    Some((old_lines, new_lines))
+}
+
+/// A unified diff of a file that only one side has
+fn unified_text(
+    old_name: impl AsRef<Path>,
+    old: &[u8],
+    new_name: impl AsRef<Path>,
+    new: &[u8],
+) -> String {
+    let (old_name, new_name) = (old_name.as_ref().display(), new_name.as_ref().display());
+    match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+        (Ok(old_text), Ok(new_text)) => unified(
+            &old_name.to_string(),
+            &new_name.to_string(),
+            old_text,
+            new_text,
+        ),
+        _ => format!("binary files {} and {} differ\n", old_name, new_name),
+    }
+}
+
+/// The names in a directory, but for .git
+fn read_names(dir: &Path) -> Result<Vec<OsString>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => return Err(format!("failed to read {} - {}", dir.display(), err)),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        match entry {
+            Ok(entry) if entry.file_name() != ".git" => names.push(entry.file_name()),
+            Ok(_) => {}
+            Err(err) => return Err(format!("failed to read {} - {}", dir.display(), err)),
+        }
+    }
+    Ok(names)
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    match fs::read(path) {
+        Ok(contents) => Ok(contents),
+        Err(err) => Err(format!("failed to read {} - {}", path.display(), err)),
+    }
+}
@@ -0,0 +1,133 @@
+use super::dry_run;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+pub const FILE_NAME: &str = ".dottyignore";
+
+/// Gitignore style patterns from the repository and the root, matched against paths in the root
+pub struct Rules {
+    base: PathBuf,
+    matcher: Gitignore,
+}
+
+impl Rules {
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        path.starts_with(&self.base)
+            && self
+                .matcher
+                .matched_path_or_any_parents(path, path.is_dir())
+                .is_ignore()
+    }
+}
+
+/// The .gitignore files found while walking down into a directory
+#[derive(Clone, Default)]
+pub struct GitignoreChain(Vec<Rc<Gitignore>>);
+
+impl GitignoreChain {
+    /// Adds the rules of the directory's .gitignore, if it has one
+    pub fn descend(&self, dir: &Path) -> GitignoreChain {
+        let path = dir.join(".gitignore");
+        if !path.is_file() {
+            return self.clone();
+        }
+        let (matcher, err) = Gitignore::new(&path);
+        if let Some(err) = err {
+            log::warn!("ignoring invalid patterns in {} - {}", path.display(), err);
+        }
+        let mut chain = self.clone();
+        chain.0.push(Rc::new(matcher));
+        chain
+    }
+
+    /// Whether the deepest .gitignore with a matching pattern ignores the path
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let is_dir = path.is_dir();
+        self.0
+            .iter()
+            .rev()
+            .map(|matcher| matcher.matched(path, is_dir))
+            .find(|m| !m.is_none())
+            .is_some_and(|m| m.is_ignore())
+    }
+}
+
+pub fn load(repo: &Path, base: &Path) -> Result<Rules, String> {
+    let mut builder = GitignoreBuilder::new(base);
+    for dir in [repo, base] {
+        for pattern in patterns(dir)? {
+            if let Err(err) = builder.add_line(None, &pattern) {
+                return Err(format!(
+                    "invalid pattern {} in {} - {}",
+                    pattern,
+                    dir.join(FILE_NAME).display(),
+                    err
+                ));
+            }
+        }
+    }
+    build(base, builder)
+}
+
+/// Rules from the given patterns alone, matched against paths in the base
+pub fn from_patterns(base: &Path, patterns: &[String]) -> Result<Rules, String> {
+    let mut builder = GitignoreBuilder::new(base);
+    for pattern in patterns {
+        if let Err(err) = builder.add_line(None, pattern) {
+            return Err(format!("invalid pattern {} - {}", pattern, err));
+        }
+    }
+    build(base, builder)
+}
+
+fn build(base: &Path, builder: GitignoreBuilder) -> Result<Rules, String> {
+    match builder.build() {
+        Ok(matcher) => Ok(Rules {
+            base: base.to_owned(),
+            matcher,
+        }),
+        Err(err) => Err(format!("failed to build ignore rules - {}", err)),
+    }
+}
+
+pub fn validate(pattern: &str) -> Result<(), String> {
+    match GitignoreBuilder::new("/").add_line(None, pattern) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("invalid pattern {} - {}", pattern, err)),
+    }
+}
+
+/// The patterns in the directory's ignore file without comments or blank lines
+pub fn patterns(dir: &Path) -> Result<Vec<String>, String> {
+    Ok(read_lines(dir)?
+        .into_iter()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .collect())
+}
+
+pub fn read_lines(dir: &Path) -> Result<Vec<String>, String> {
+    let path = dir.join(FILE_NAME);
+    match fs::read_to_string(&path) {
+        Ok(contents) => Ok(contents.lines().map(|l| l.to_owned()).collect()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(format!("failed to read {} - {}", path.display(), err)),
+    }
+}
+
+pub fn write_lines(repo: &Path, lines: &[String]) -> Result<(), String> {
+    if dry_run::skip(format_args!("write {}", repo.join(FILE_NAME).display())) {
+        return Ok(());
+    }
+    let path = repo.join(FILE_NAME);
+    let mut contents = lines.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+    match fs::write(&path, contents) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("failed to write {} - {}", path.display(), err)),
+    }
+}
@@ -0,0 +1,20 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Logs the operation and returns true when it should not be performed
+pub fn skip(operation: impl Display) -> bool {
+    if is_enabled() {
+        log::warn!("dry run: would {}", operation);
+    }
+    is_enabled()
+}
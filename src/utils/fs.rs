@@ -1,76 +1,180 @@
-use super::string::random_string;
-use std::env;
-use std::fs;
-use std::io::ErrorKind;
-use std::os::unix::fs as unix_fs;
+use super::dry_run;
+use super::path;
+use super::platform;
+use super::selinux;
+use super::sudo;
+use super::timings::{self, Phase};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::fs::{self, File, FileTimes};
+use std::io::{ErrorKind, Read};
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 pub fn move_then_symlink(from: &Path, to: &Path) -> Result<bool, String> {
-    if to.exists() {
-        if let Some(metadata) = symlink_metadata(from)? {
-            log::trace!("{} already exists", to.display());
+    timings::time(Phase::Filesystem, || {
+        if to.exists() {
+            if let Some(metadata) = symlink_metadata(from)? {
+                log::trace!("{} already exists", to.display());
 
-            if metadata.is_symlink() {
-                if let Ok(resolved_to) = fs::canonicalize(from) {
-                    if resolved_to == to {
-                        log::trace!("{} points to {} already", to.display(), from.display());
-                        return Ok(false);
+                if metadata.is_symlink() {
+                    if let Ok(resolved_to) = fs::canonicalize(from) {
+                        if resolved_to == to {
+                            log::trace!("{} points to {} already", to.display(), from.display());
+                            return Ok(false);
+                        }
                     }
                 }
             }
+
+            return Err(format!("{} already exists in repo", to.display()));
         }
 
-        return Err(format!("{} already exists in repo", to.display()));
-    }
+        rename(from, to)?;
+        symlink(to, from)?;
 
-    rename(from, to)?;
-    symlink(to, from)?;
+        Ok(true)
+    })
+}
+
+/// The timestamps a copy keeps from the file it was copied from
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PreserveTimes {
+    /// Keeps modification times
+    Modified,
+    /// Keeps modification and access times
+    All,
+}
 
-    Ok(true)
+/// How an entry in the repository is deployed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    Symlink,
+    Copy,
+    Hardlink,
 }
 
 pub fn restore(
     from: &Path,
     to: &Path,
     overwrite: Option<&Path>,
-    symlinks: bool,
+    method: Method,
+    times: Option<PreserveTimes>,
 ) -> Result<(), String> {
-    if !from.exists() {
-        return Err(format!("{} does not exist", from.display()));
-    }
-    if let Some(metadata) = symlink_metadata(to)? {
-        log::trace!("{} already exists", to.display());
+    timings::time(Phase::Filesystem, || {
+        if !from.exists() {
+            return Err(format!("{} does not exist", from.display()));
+        }
+        let symlinks = method == Method::Symlink;
+        if method == Method::Hardlink && is_hard_link(from, to) {
+            log::trace!(
+                "{} is already a hard link to {}",
+                to.display(),
+                from.display()
+            );
+            return Ok(());
+        }
+        if let Some(metadata) = symlink_metadata(to)? {
+            log::trace!("{} already exists", to.display());
 
-        if metadata.is_symlink() {
-            let resolved_to = match fs::canonicalize(to) {
-                Ok(resolved) => resolved,
-                Err(err) => return Err(format!("failed to resolve {} - {}", from.display(), err)),
-            };
+            if metadata.is_symlink() {
+                let resolved_to = match fs::canonicalize(to) {
+                    Ok(resolved) => resolved,
+                    Err(err) => {
+                        return Err(format!("failed to resolve {} - {}", from.display(), err))
+                    }
+                };
 
-            if resolved_to == from {
-                if symlinks {
-                    log::trace!("{} correctly points to {}", to.display(), from.display());
-                    return Ok(());
-                } else {
-                    log::warn!("replacing symlink {} with {}", to.display(), from.display());
+                if resolved_to == from {
+                    if symlinks {
+                        log::trace!("{} correctly points to {}", to.display(), from.display());
+                        return Ok(());
+                    } else {
+                        log::warn!("replacing symlink {} with {}", to.display(), from.display());
+                        remove(to)?
+                    }
+                } else if overwrite.is_some() {
+                    log::warn!(
+                        "removing existing symlink {} to {}",
+                        to.display(),
+                        resolved_to.display()
+                    );
                     remove(to)?
+                } else {
+                    return Err(format!(
+                        "not overwriting symlink {} to {}",
+                        to.display(),
+                        resolved_to.display()
+                    ));
                 }
-            } else if overwrite.is_some() {
-                log::warn!(
-                    "removing existing symlink {} to {}",
-                    to.display(),
-                    resolved_to.display()
-                );
-                remove(to)?
             } else {
-                return Err(format!(
-                    "not overwriting symlink {} to {}",
-                    to.display(),
-                    resolved_to.display()
-                ));
+                match overwrite {
+                    Some(move_existing_to) => {
+                        log::warn!(
+                            "moving existing {} to {}",
+                            to.display(),
+                            move_existing_to.display()
+                        );
+                        rename(to, move_existing_to)?;
+                    }
+                    None => return Err(format!("not overwriting existing file {}", to.display())),
+                }
             }
-        } else {
+        }
+        match method {
+            Method::Symlink => symlink(from, to),
+            Method::Copy => copy_with_times(from, to, times),
+            Method::Hardlink => hard_link(from, to),
+        }
+    })
+}
+
+/// Whether `to` is the same file as `from`, rather than a symlink to or a copy of it
+pub fn is_hard_link(from: &Path, to: &Path) -> bool {
+    match (fs::symlink_metadata(from), fs::symlink_metadata(to)) {
+        (Ok(a), Ok(b)) => a.is_file() && platform::same_file(&a, &b),
+        _ => false,
+    }
+}
+
+pub fn hard_link(original: &Path, link: &Path) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "create hard link {} to {}",
+        link.display(),
+        original.display()
+    )) {
+        return Ok(());
+    }
+    log::trace!(
+        "creating hard link {} to {}",
+        link.display(),
+        original.display()
+    );
+    create_parent_dir(link)?;
+    match fs::hard_link(original, link) {
+        Ok(_) => Ok(()),
+        Err(err) if sudo::applies(&err) => {
+            sudo::run("ln", &[original.as_os_str(), link.as_os_str()])
+        }
+        Err(err) => Err(format!(
+            "failed to create hard link {} to {} - {}",
+            link.display(),
+            original.display(),
+            err
+        )),
+    }
+}
+
+/// Writes rendered contents to `to`, moving anything already there to `overwrite`
+pub fn write_rendered(contents: &str, to: &Path, overwrite: Option<&Path>) -> Result<(), String> {
+    timings::time(Phase::Filesystem, || {
+        if let Some(metadata) = symlink_metadata(to)? {
             match overwrite {
+                Some(_) if metadata.is_symlink() => {
+                    log::warn!("removing existing symlink {}", to.display());
+                    remove(to)?
+                }
                 Some(move_existing_to) => {
                     log::warn!(
                         "moving existing {} to {}",
@@ -82,53 +186,276 @@ pub fn restore(
                 None => return Err(format!("not overwriting existing file {}", to.display())),
             }
         }
+        if dry_run::skip(format_args!("write {}", to.display())) {
+            return Ok(());
+        }
+        log::trace!("write {}", to.display());
+        create_parent_dir(to)?;
+        match fs::write(to, contents) {
+            Ok(_) => Ok(()),
+            Err(err) if sudo::applies(&err) => sudo::write(to, contents.as_bytes()),
+            Err(err) => Err(format!("failed to write {} - {}", to.display(), err)),
+        }
+    })
+}
+
+pub fn write(path: &Path, contents: &str) -> Result<(), String> {
+    if dry_run::skip(format_args!("write {}", path.display())) {
+        return Ok(());
     }
-    if symlinks {
-        symlink(from, to)
-    } else {
-        copy(from, to)
+    match fs::write(path, contents) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("failed to write {} - {}", path.display(), err)),
+    }
+}
+
+/// Replaces the symlink at `to` with `from`, undoing move_then_symlink
+/// Moves `from` back to `to`, returning whether `to` was a symlink to it
+pub fn move_back(from: &Path, to: &Path) -> Result<bool, String> {
+    timings::time(Phase::Filesystem, || {
+        let linked = match symlink_metadata(to)? {
+            Some(metadata) if metadata.is_symlink() => match fs::canonicalize(to) {
+                Ok(resolved_to) if resolved_to == from => {
+                    remove(to)?;
+                    true
+                }
+                _ => {
+                    return Err(format!(
+                        "{} is not a symlink to {}",
+                        to.display(),
+                        from.display()
+                    ))
+                }
+            },
+            Some(_) => return Err(format!("not overwriting existing file {}", to.display())),
+            None => false,
+        };
+        rename(from, to)?;
+        Ok(linked)
+    })
+}
+
+/// Undoes move_back, relinking `to` when it was a symlink
+pub fn undo_move_back(from: &Path, to: &Path, linked: bool) -> Result<(), String> {
+    match linked {
+        true => move_then_symlink(to, from).map(|_| ()),
+        false => timings::time(Phase::Filesystem, || rename(to, from)),
+    }
+}
+
+/// Removes the now empty parent directories of `path`, stopping at `base`
+pub fn remove_empty_parents(path: &Path, base: &Path) -> Result<(), String> {
+    let mut dir = path.parent();
+    while let Some(parent) = dir {
+        if parent == base || !parent.starts_with(base) || !is_empty(parent)? {
+            break;
+        }
+        remove_dir(parent)?;
+        dir = parent.parent();
+    }
+    Ok(())
+}
+
+pub fn symlink_target(path: &Path) -> Result<Option<PathBuf>, String> {
+    match symlink_metadata(path)? {
+        Some(metadata) if metadata.is_symlink() => match fs::canonicalize(path) {
+            Ok(target) => Ok(Some(target)),
+            Err(err) => Err(format!("failed to resolve {} - {}", path.display(), err)),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// The path a symlink points to, whether or not it still exists
+pub fn link_target(path: &Path) -> Result<Option<PathBuf>, String> {
+    match symlink_metadata(path)? {
+        Some(metadata) if metadata.is_symlink() => match fs::read_link(path) {
+            Ok(target) => Ok(Some(match path.parent() {
+                Some(parent) => path::normalize(&parent.join(target)),
+                None => target,
+            })),
+            Err(err) => Err(format!("failed to read link {} - {}", path.display(), err)),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Points the symlink at `original` instead
+pub fn relink(link: &Path, original: &Path) -> Result<(), String> {
+    remove(link)?;
+    symlink(original, link)
+}
+
+pub fn migrate(link: &Path, from: &Path, to: &Path) -> Result<(), String> {
+    timings::time(Phase::Filesystem, || {
+        if to.exists() {
+            return Err(format!("{} already exists in repo", to.display()));
+        }
+
+        copy(from, to)?;
+        remove_all(from)?;
+        remove(link)?;
+        symlink(to, link)
+    })
+}
+
+/// Enough of a path's metadata to tell whether it changed
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Fingerprint {
+    Missing,
+    Symlink { target: PathBuf },
+    File { len: u64, modified: SystemTime },
+    Dir { modified: SystemTime },
+}
+
+pub fn fingerprint(path: &Path) -> Result<Fingerprint, String> {
+    let metadata = match symlink_metadata(path)? {
+        Some(metadata) => metadata,
+        None => return Ok(Fingerprint::Missing),
+    };
+    if metadata.is_symlink() {
+        return match fs::read_link(path) {
+            Ok(target) => Ok(Fingerprint::Symlink { target }),
+            Err(err) => Err(format!("failed to read link {} - {}", path.display(), err)),
+        };
+    }
+    let modified = match metadata.modified() {
+        Ok(modified) => modified,
+        Err(err) => {
+            return Err(format!(
+                "unable to get modified time of {} - {}",
+                path.display(),
+                err
+            ))
+        }
+    };
+    match metadata.is_dir() {
+        true => Ok(Fingerprint::Dir { modified }),
+        false => Ok(Fingerprint::File {
+            len: metadata.len(),
+            modified,
+        }),
+    }
+}
+
+/// Symlinks directly within the directory that point into the base but no longer resolve
+pub fn dangling_symlinks(dir: &Path, base: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut dangling = Vec::new();
+    if !dir.is_dir() {
+        return Ok(dangling);
+    }
+    for link in read_dir(dir)? {
+        if let Ok(target) = fs::read_link(&link) {
+            let target = path::normalize(&dir.join(target));
+            if target.starts_with(base) && !link.exists() {
+                dangling.push(link);
+            }
+        }
     }
+    Ok(dangling)
 }
 
-pub struct OverwriteTempDir {
-    temp_dir: PathBuf,
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeployStatus {
+    Linked,
+    Missing,
+    Exists,
+    Conflict,
 }
 
-impl Drop for OverwriteTempDir {
+impl std::fmt::Display for DeployStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DeployStatus::Linked => "linked",
+            DeployStatus::Missing => "missing",
+            DeployStatus::Exists => "exists",
+            DeployStatus::Conflict => "conflict",
+        })
+    }
+}
+
+pub fn deploy_status(from: &Path, to: &Path) -> Result<DeployStatus, String> {
+    match symlink_metadata(to)? {
+        None => Ok(DeployStatus::Missing),
+        Some(metadata) if metadata.is_symlink() => match fs::canonicalize(to) {
+            Ok(resolved_to) if resolved_to == from => Ok(DeployStatus::Linked),
+            _ => Ok(DeployStatus::Conflict),
+        },
+        Some(_) if is_hard_link(from, to) => Ok(DeployStatus::Linked),
+        Some(_) => Ok(DeployStatus::Exists),
+    }
+}
+
+/// A directory for overwritten files, removed again if nothing was moved into it
+pub struct OverwriteDir {
+    dir: PathBuf,
+}
+
+impl Drop for OverwriteDir {
     fn drop(&mut self) {
-        if let Ok(true) = is_empty(&self.temp_dir) {
-            let _ = remove_dir(&self.temp_dir);
+        if let Ok(true) = is_empty(&self.dir) {
+            let _ = remove_dir(&self.dir);
         }
     }
 }
 
-impl OverwriteTempDir {
+impl OverwriteDir {
     pub fn entry(&self, path: &Path) -> PathBuf {
-        self.temp_dir.join(path)
+        self.dir.join(path)
     }
 }
 
-pub fn create_overwrite_temp_dir(prefix: &str) -> Result<OverwriteTempDir, String> {
-    let name = prefix.to_owned() + &random_string(7);
-    let temp_dir = env::temp_dir().join(name);
-    if let Err(err) = fs::create_dir(&temp_dir) {
+pub fn create_overwrite_dir(dir: &Path) -> Result<OverwriteDir, String> {
+    let dir = dir.to_owned();
+    if dry_run::skip(format_args!("create backup directory {}", dir.display())) {
+        return Ok(OverwriteDir { dir });
+    }
+    if let Err(err) = fs::create_dir_all(&dir) {
         return Err(format!(
-            "failed to create temp dir {} - {}",
-            temp_dir.display(),
+            "failed to create backup directory {} - {}",
+            dir.display(),
             err
         ));
     }
-    log::trace!("created overwrite temp dir {}", temp_dir.display());
-    Ok(OverwriteTempDir { temp_dir })
+    log::trace!("created overwrite dir {}", dir.display());
+    Ok(OverwriteDir { dir })
 }
 
 pub fn remove_dir(dir: &Path) -> Result<(), String> {
+    if dry_run::skip(format_args!("remove directory {}", dir.display())) {
+        return Ok(());
+    }
     match fs::remove_dir(dir) {
         Ok(_) => Ok(()),
         Err(err) => Err(format!("failed to remove {} - {}", dir.display(), err)),
     }
 }
 
+/// Whether the start of the file contains a NUL byte, as git decides
+pub fn is_binary(path: &Path) -> Result<bool, String> {
+    let mut buffer = [0; 8000];
+    let read = fs::File::open(path).and_then(|mut file| file.read(&mut buffer));
+    match read {
+        Ok(len) => Ok(buffer[..len].contains(&0)),
+        Err(err) => Err(format!("failed to read {} - {}", path.display(), err)),
+    }
+}
+
+/// Whether both paths are files with the same contents
+pub fn same_contents(a: &Path, b: &Path) -> bool {
+    match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+        (Ok(a_metadata), Ok(b_metadata))
+            if a_metadata.is_file()
+                && b_metadata.is_file()
+                && a_metadata.len() == b_metadata.len() =>
+        {
+            matches!((fs::read(a), fs::read(b)), (Ok(a), Ok(b)) if a == b)
+        }
+        _ => false,
+    }
+}
+
 pub fn is_empty(dir: &Path) -> Result<bool, String> {
     match dir.read_dir() {
         Ok(mut read_dir) => Ok(read_dir.next().is_none()),
@@ -159,75 +486,220 @@ pub fn read_dir(dir: &Path) -> Result<Vec<PathBuf>, String> {
 }
 
 fn rename(from: &Path, to: &Path) -> Result<(), String> {
+    if dry_run::skip(format_args!("move {} to {}", from.display(), to.display())) {
+        return Ok(());
+    }
     log::trace!("rename {} to {}", from.display(), to.display());
     create_parent_dir(to)?;
-    if let Err(err) = fs::rename(from, to) {
-        return Err(format!(
+    match fs::rename(from, to) {
+        Ok(_) => Ok(()),
+        Err(err) if sudo::applies(&err) => sudo::run("mv", &[from.as_os_str(), to.as_os_str()]),
+        Err(err) => Err(format!(
             "failed to move {} to {} - {}",
             from.display(),
             to.display(),
             err
-        ));
+        )),
+    }
+}
+
+/// The permission bits of the path, following symlinks
+pub fn mode(path: &Path) -> Result<u32, String> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(platform::mode(&metadata)),
+        Err(err) => Err(format!(
+            "unable to get permissions of {} - {}",
+            path.display(),
+            err
+        )),
+    }
+}
+
+/// The user and group ids owning the path, following symlinks
+pub fn owner(path: &Path) -> Result<(u32, u32), String> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(platform::owner(&metadata)),
+        Err(err) => Err(format!(
+            "unable to get owner of {} - {}",
+            path.display(),
+            err
+        )),
+    }
+}
+
+/// Changes the owner, warning instead when that needs privileges and sudo is not enabled
+pub fn set_owner(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<(), String> {
+    let owner = format!(
+        "{}:{}",
+        uid.map(|uid| uid.to_string()).unwrap_or_default(),
+        gid.map(|gid| gid.to_string()).unwrap_or_default()
+    );
+    if dry_run::skip(format_args!(
+        "change owner of {} to {}",
+        path.display(),
+        owner
+    )) {
+        return Ok(());
+    }
+    log::trace!("change owner of {} to {}", path.display(), owner);
+    match platform::chown(path, uid, gid) {
+        Ok(_) => Ok(()),
+        Err(err) if sudo::applies(&err) => {
+            sudo::run("chown", &[OsStr::new(&owner), path.as_os_str()])
+        }
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            log::warn!(
+                "not changing owner of {} to {} without privileges, see restore --sudo",
+                path.display(),
+                owner
+            );
+            Ok(())
+        }
+        Err(err) => Err(format!(
+            "failed to change owner of {} - {}",
+            path.display(),
+            err
+        )),
     }
-    Ok(())
 }
 
-fn copy(from: &Path, to: &Path) -> Result<(), String> {
+pub fn set_mode(path: &Path, mode: u32) -> Result<(), String> {
+    if dry_run::skip(format_args!("set mode {:04o} on {}", mode, path.display())) {
+        return Ok(());
+    }
+    log::trace!("set mode {:04o} on {}", mode, path.display());
+    match platform::permissions(path, mode)
+        .and_then(|permissions| fs::set_permissions(path, permissions))
+    {
+        Ok(_) => Ok(()),
+        Err(err) if sudo::applies(&err) => sudo::run(
+            "chmod",
+            &[OsStr::new(&format!("{:o}", mode)), path.as_os_str()],
+        ),
+        Err(err) => Err(format!(
+            "failed to set permissions of {} - {}",
+            path.display(),
+            err
+        )),
+    }
+}
+
+pub fn copy(from: &Path, to: &Path) -> Result<(), String> {
+    copy_with_times(from, to, None)
+}
+
+fn copy_with_times(from: &Path, to: &Path, times: Option<PreserveTimes>) -> Result<(), String> {
+    if dry_run::skip(format_args!("copy {} to {}", from.display(), to.display())) {
+        return Ok(());
+    }
     log::trace!("copy {} to {}", from.display(), to.display());
     create_parent_dir(to)?;
-    if let Err(err) = copy_recursively(from, to) {
-        return Err(format!(
+    match copy_recursively(from, to, times) {
+        Ok(_) => {
+            if let Err(err) = selinux::apply_default_context(to) {
+                log::warn!("{}", err);
+            }
+            Ok(())
+        }
+        Err(err) if sudo::applies(&err) => {
+            let flags = match times {
+                Some(_) => "-Rp",
+                None => "-R",
+            };
+            // cp copies a directory into the destination when it exists, so copy its contents
+            let source = match from.is_dir() {
+                true => from.join("."),
+                false => from.to_owned(),
+            };
+            sudo::run(
+                "cp",
+                &[OsStr::new(flags), source.as_os_str(), to.as_os_str()],
+            )
+        }
+        Err(err) => Err(format!(
             "failed to copy {} to {} - {}",
             from.display(),
             to.display(),
             err
-        ));
+        )),
     }
-    Ok(())
 }
 
-fn copy_recursively(source: &Path, destination: &Path) -> std::io::Result<()> {
+fn copy_recursively(
+    source: &Path,
+    destination: &Path,
+    times: Option<PreserveTimes>,
+) -> std::io::Result<()> {
+    // read before copying, which updates the access time
+    let metadata = match times {
+        Some(_) => Some(fs::metadata(source)?),
+        None => None,
+    };
     if source.is_file() {
         fs::copy(source, destination)?;
     } else {
         fs::create_dir_all(destination)?;
         for entry in fs::read_dir(source)? {
             let entry = entry?;
-            copy_recursively(&entry.path(), &destination.join(entry.file_name()))?;
+            copy_recursively(&entry.path(), &destination.join(entry.file_name()), times)?;
+        }
+    }
+    if let (Some(times), Some(metadata)) = (times, metadata) {
+        let mut file_times = FileTimes::new().set_modified(metadata.modified()?);
+        if times == PreserveTimes::All {
+            file_times = file_times.set_accessed(metadata.accessed()?);
         }
+        File::open(destination)?.set_times(file_times)?;
     }
     Ok(())
 }
 
 fn create_parent_dir(path: &Path) -> Result<(), String> {
     if let Some(parent) = path.parent() {
-        if let Err(err) = fs::create_dir_all(parent) {
-            return Err(format!(
-                "failed to create directory {} - {}",
-                parent.display(),
-                err
-            ));
+        match fs::create_dir_all(parent) {
+            Ok(_) => {}
+            Err(err) if sudo::applies(&err) => {
+                sudo::run("mkdir", &[OsStr::new("-p"), parent.as_os_str()])?
+            }
+            Err(err) => {
+                return Err(format!(
+                    "failed to create directory {} - {}",
+                    parent.display(),
+                    err
+                ))
+            }
         }
     }
     Ok(())
 }
 
 fn symlink(original: &Path, link: &Path) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "create symlink {} to {}",
+        link.display(),
+        original.display()
+    )) {
+        return Ok(());
+    }
     log::trace!(
         "creating symlink {} to {}",
         link.display(),
         original.display()
     );
     create_parent_dir(link)?;
-    if let Err(err) = unix_fs::symlink(original, link) {
-        return Err(format!(
+    match platform::symlink(original, link) {
+        Ok(_) => Ok(()),
+        Err(err) if sudo::applies(&err) => sudo::run(
+            "ln",
+            &[OsStr::new("-s"), original.as_os_str(), link.as_os_str()],
+        ),
+        Err(err) => Err(format!(
             "failed to create symlink {} to {} - {}",
             link.display(),
             original.display(),
             err
-        ));
-    };
-    Ok(())
+        )),
+    }
 }
 
 fn symlink_metadata(path: &Path) -> Result<Option<fs::Metadata>, String> {
@@ -242,8 +714,27 @@ fn symlink_metadata(path: &Path) -> Result<Option<fs::Metadata>, String> {
     }
 }
 
-fn remove(path: &Path) -> Result<(), String> {
-    match fs::remove_file(path) {
+pub fn remove(path: &Path) -> Result<(), String> {
+    if dry_run::skip(format_args!("delete {}", path.display())) {
+        return Ok(());
+    }
+    match platform::remove_link(path) {
+        Ok(_) => Ok(()),
+        Err(err) if sudo::applies(&err) => sudo::run("rm", &[path.as_os_str()]),
+        Err(err) => Err(format!("unable to delete {} - {}", path.display(), err)),
+    }
+}
+
+/// Deletes a file, or a directory with everything in it
+pub fn remove_all(path: &Path) -> Result<(), String> {
+    if dry_run::skip(format_args!("delete {}", path.display())) {
+        return Ok(());
+    }
+    let result = match path.is_dir() {
+        true => fs::remove_dir_all(path),
+        false => fs::remove_file(path),
+    };
+    match result {
         Ok(_) => Ok(()),
         Err(err) => Err(format!("unable to delete {} - {}", path.display(), err)),
     }
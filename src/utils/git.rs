@@ -1,11 +1,84 @@
+use super::crypt::{self, Key};
+use super::dry_run;
+use super::hosting::{self, Provider};
+use super::known_hosts;
+use super::platform;
+use super::timings::{self, Phase};
 use git2::build::{CheckoutBuilder, RepoBuilder};
 use git2::{
-    AnnotatedCommit, AutotagOption, Commit, Config, Cred, CredentialType, ErrorCode, FetchOptions,
-    Index, Oid, PushOptions, Reference, Remote, RemoteCallbacks, RemoteUpdateFlags, Repository,
-    ResetType, SubmoduleUpdateOptions,
+    AnnotatedCommit, ApplyLocation, AutotagOption, BranchType, CertificateCheckStatus, Commit,
+    Config, ConfigLevel, Cred, CredentialType, Delta, Diff, DiffOptions, ErrorClass, ErrorCode,
+    FetchOptions, FileFavor, FileMode, Index, IndexEntry, IndexTime, MergeOptions, ObjectType, Oid,
+    Patch, ProxyOptions, PushOptions, RebaseOptions, Reference, Remote, RemoteCallbacks,
+    RemoteUpdateFlags, Repository, ResetType, StashFlags, Status, StatusOptions,
+    SubmoduleUpdateOptions, Tree,
 };
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Default private keys tried in ~/.ssh when no key is configured and ssh-agent has none
+const DEFAULT_SSH_KEYS: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// Submodules fetched at the same time, which is bound by the network rather than the cpu
+const SUBMODULE_JOBS: usize = 8;
+
+/// The ref keeping the contents restore deployed reachable, as the base of merges
+const BASELINES_REF: &str = "refs/dotty/baselines";
+
+/// Ends the message of every commit dotty makes, telling them apart from those made with git
+const TRAILER: &str = "Committed-by: dotty";
+
+static SSH_KEY: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+static PROXY: RwLock<Option<String>> = RwLock::new(None);
+
+static TOKEN_HOSTS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+static RETRY_ATTEMPTS: AtomicU32 = AtomicU32::new(3);
+static RETRY_BACKOFF_MILLIS: AtomicU64 = AtomicU64::new(1000);
+
+/// Sets the private key used for ssh remotes after trying ssh-agent
+pub fn set_ssh_key(path: Option<PathBuf>) {
+    if let Some(path) = &path {
+        log::trace!("using ssh key {}", path.display());
+    }
+    if let Ok(mut key) = SSH_KEY.write() {
+        *key = path;
+    }
+}
+
+pub fn ssh_key() -> Option<PathBuf> {
+    SSH_KEY.read().ok().and_then(|key| key.clone())
+}
+
+/// Sets the proxy for https remotes instead of the one in the git config or environment
+pub fn set_proxy(url: String) {
+    log::trace!("using proxy {}", url);
+    if let Ok(mut proxy) = PROXY.write() {
+        *proxy = Some(url);
+    }
+}
+
+/// Sets the hosts DOTTY_GIT_TOKEN is sent to
+pub fn set_token_hosts(hosts: Vec<String>) {
+    if let Ok(mut token_hosts) = TOKEN_HOSTS.write() {
+        *token_hosts = hosts;
+    }
+}
+
+/// Sets how many times network operations are attempted, waiting twice as long after each failure
+pub fn set_retry(attempts: u32, backoff: Duration) {
+    RETRY_ATTEMPTS.store(attempts.max(1), Ordering::Relaxed);
+    RETRY_BACKOFF_MILLIS.store(backoff.as_millis() as u64, Ordering::Relaxed);
+}
 
 pub fn init_or_open(path: &Path) -> Result<Repository, String> {
     git_helper(
@@ -28,35 +101,57 @@ pub fn init_or_open(path: &Path) -> Result<Repository, String> {
     )
 }
 
-pub fn clone_recurse(path: &Path, url: &str) -> Result<Repository, String> {
+/// Clones with submodules, checking out the branch instead of the remote default when given
+///
+/// Submodules are fetched with only their latest commit when shallow, or when shallow = true is
+/// set for them in .gitmodules.
+pub fn clone_recurse(
+    path: &Path,
+    url: &str,
+    branch: Option<&str>,
+    shallow_submodules: bool,
+) -> Result<Repository, String> {
     git_helper(
         || {
             log::debug!("cloning git repository {} into {}", url, path.display());
 
-            let mut fetch_opts = FetchOptions::new();
-            fetch_opts.remote_callbacks(create_callbacks());
-
-            let mut builder = RepoBuilder::new();
-            builder.fetch_options(fetch_opts);
-
-            let repo = builder.clone(url, path)?;
+            let repo = network(|| {
+                let mut builder = RepoBuilder::new();
+                builder.fetch_options(fetch_options(false));
+                if let Some(branch) = branch {
+                    builder.branch(branch);
+                }
+                builder.clone(url, path)
+            })?;
 
             log::debug!("initializing submodules in {}", path.display());
 
-            let mut checkout_builder = CheckoutBuilder::new();
-            checkout_builder.force();
-
-            let mut fetch_opts = FetchOptions::new();
-            fetch_opts.remote_callbacks(create_callbacks());
+            update_submodules_recursive(&repo, true, shallow_submodules)?;
 
-            let mut opts = SubmoduleUpdateOptions::new();
-            opts.checkout(checkout_builder);
-            opts.fetch(fetch_opts);
-            opts.allow_fetch(true);
+            Ok(repo)
+        },
+        |err| {
+            format!(
+                "failed to clone git repository {} into {} - {}",
+                url,
+                path.display(),
+                err
+            )
+        },
+    )
+}
 
-            update_submodules_recursive(&repo, true, &mut opts)?;
+/// Clones without submodules, e.g. to copy the files of a repository
+pub fn clone(path: &Path, url: &str) -> Result<Repository, String> {
+    git_helper(
+        || {
+            log::debug!("cloning git repository {} into {}", url, path.display());
 
-            Ok(repo)
+            network(|| {
+                let mut builder = RepoBuilder::new();
+                builder.fetch_options(fetch_options(false));
+                builder.clone(url, path)
+            })
         },
         |err| {
             format!(
@@ -77,6 +172,11 @@ pub fn open(path: &Path) -> Result<Repository, String> {
     )
 }
 
+/// The git directory of the repository, which is not .git in worktrees and submodules
+pub fn git_dir(path: &Path) -> Result<PathBuf, String> {
+    Ok(open(path)?.path().to_owned())
+}
+
 pub fn check_open(path: &Path) -> bool {
     match Repository::open(path) {
         Ok(_) => {
@@ -87,7 +187,48 @@ pub fn check_open(path: &Path) -> bool {
     }
 }
 
+/// Ignores the pattern in this clone only, via info/exclude
+pub fn exclude(repo: &Repository, pattern: &str) -> Result<(), String> {
+    let exclude_path = repo.path().join("info").join("exclude");
+    let mut contents = fs::read_to_string(&exclude_path).unwrap_or_default();
+    if contents.lines().any(|line| line == pattern) {
+        return Ok(());
+    }
+    if dry_run::skip(format_args!(
+        "exclude {} in {}",
+        pattern,
+        repo.path().display()
+    )) {
+        return Ok(());
+    }
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(pattern);
+    contents.push('\n');
+    if let Some(parent) = exclude_path.parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            return Err(format!(
+                "failed to create directory {} - {}",
+                parent.display(),
+                err
+            ));
+        }
+    }
+    match fs::write(&exclude_path, contents) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!(
+            "failed to write {} - {}",
+            exclude_path.display(),
+            err
+        )),
+    }
+}
+
 pub fn unstage_all(repo: &Repository) -> Result<(), String> {
+    if dry_run::skip(format_args!("reset the index of {}", repo.path().display())) {
+        return Ok(());
+    }
     git_helper(
         || {
             if let Some(latest_commit) = find_last_commit(repo)? {
@@ -111,6 +252,13 @@ pub fn unstage_all(repo: &Repository) -> Result<(), String> {
 }
 
 pub fn stage_all_paths(repo: &Repository, paths: &Vec<PathBuf>) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "stage {} paths in {}",
+        paths.len(),
+        repo.path().display()
+    )) {
+        return Ok(());
+    }
     log::debug!(
         "staging {} paths in git repository {}",
         paths.len(),
@@ -118,10 +266,11 @@ pub fn stage_all_paths(repo: &Repository, paths: &Vec<PathBuf>) -> Result<(), St
     );
     git_helper(
         || {
+            let key = load_crypt_key(repo)?;
             let mut index = repo.index()?;
             for path in paths {
                 log::trace!("staging path {}", path.display());
-                stage_path_recursive(&mut index, path)?;
+                stage_path_recursive(repo, &mut index, path, key.as_ref())?;
             }
             index.write()
         },
@@ -136,7 +285,295 @@ pub fn stage_all_paths(repo: &Repository, paths: &Vec<PathBuf>) -> Result<(), St
     )
 }
 
+pub fn remove_all_paths(repo: &Repository, paths: &Vec<PathBuf>) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "remove {} paths from {}",
+        paths.len(),
+        repo.path().display()
+    )) {
+        return Ok(());
+    }
+    log::debug!(
+        "removing {} paths from git repository {}",
+        paths.len(),
+        repo.path().display()
+    );
+    git_helper(
+        || {
+            let mut index = repo.index()?;
+            for path in paths {
+                log::trace!("removing path {}", path.display());
+                index.remove_all([path.as_path()], None)?;
+            }
+            index.write()
+        },
+        |err| {
+            format!(
+                "failed to remove {} paths from git repository {} - {}",
+                paths.len(),
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+pub fn tracked_files(repo: &Repository) -> Result<Vec<PathBuf>, String> {
+    git_helper(
+        || {
+            Ok(repo
+                .index()?
+                .iter()
+                .filter(|entry| entry.mode != 0o160000)
+                .map(|entry| platform::path_from_bytes(&entry.path))
+                .collect())
+        },
+        |err| {
+            format!(
+                "failed to list files in git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+pub fn configure_crypt_filter(repo: &Repository, command: &str) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "configure the {} filter in {}",
+        crypt::FILTER,
+        repo.path().display()
+    )) {
+        return Ok(());
+    }
+    git_helper(
+        || {
+            let mut config = repo.config()?;
+            let filter = format!("filter.{}", crypt::FILTER);
+            config.set_str(&format!("{}.clean", filter), &format!("{} clean", command))?;
+            config.set_str(
+                &format!("{}.smudge", filter),
+                &format!("{} smudge", command),
+            )?;
+            config.set_bool(&format!("{}.required", filter), true)
+        },
+        |err| {
+            format!(
+                "failed to configure encryption filter in git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+pub fn decrypt_worktree(repo: &Repository) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "decrypt the working tree of {}",
+        repo.path().display()
+    )) {
+        return Ok(());
+    }
+    git_helper(
+        || decrypt_checkout(repo),
+        |err| {
+            format!(
+                "failed to decrypt git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+pub fn is_modified(repo: &Repository, path: &Path) -> Result<bool, String> {
+    git_helper(
+        || {
+            let status = repo.status_file(path)?;
+            match load_crypt_key(repo)? {
+                Some(key) if status == Status::WT_MODIFIED => {
+                    Ok(!is_unchanged_encrypted(repo, &key, path)?)
+                }
+                _ => Ok(!status.is_empty()),
+            }
+        },
+        |err| {
+            format!(
+                "failed to get status of {} in git repository {} - {}",
+                path.display(),
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// The paths whose staged contents differ from the last commit
+pub fn staged_files(repo: &Repository) -> Result<Vec<PathBuf>, String> {
+    git_helper(
+        || {
+            let head_tree = match find_last_commit(repo)? {
+                Some(commit) => Some(commit.tree()?),
+                None => None,
+            };
+            let diff = repo.diff_tree_to_index(head_tree.as_ref(), None, None)?;
+            Ok(diff
+                .deltas()
+                .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+                .map(Path::to_path_buf)
+                .collect())
+        },
+        |err| {
+            format!(
+                "failed to get staged changes in git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// The staged contents of the file, or none when it is not in the index
+pub fn staged_contents(repo: &Repository, path: &Path) -> Option<Vec<u8>> {
+    let entry = repo.index().ok()?.get_path(path, 0)?;
+    repo.find_blob(entry.id)
+        .ok()
+        .map(|blob| blob.content().to_vec())
+}
+
+/// The paths of the submodules of the repository, not of their own submodules
+pub fn submodule_paths(repo: &Repository) -> Result<Vec<PathBuf>, String> {
+    git_helper(
+        || {
+            Ok(repo
+                .submodules()?
+                .iter()
+                .map(|submodule| submodule.path().to_owned())
+                .collect())
+        },
+        |err| {
+            format!(
+                "failed to list submodules of git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Whether the index differs from the last commit
+pub fn has_staged_changes(repo: &Repository) -> Result<bool, String> {
+    git_helper(
+        || {
+            let head_tree = match find_last_commit(repo)? {
+                Some(commit) => Some(commit.tree()?),
+                None => None,
+            };
+            let diff = repo.diff_tree_to_index(head_tree.as_ref(), None, None)?;
+            Ok(diff.deltas().len() > 0)
+        },
+        |err| {
+            format!(
+                "failed to get staged changes in git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Whether a merge with conflicts to resolve by hand is in progress
+pub fn is_merging(repo: &Repository) -> bool {
+    repo.find_reference("MERGE_HEAD").is_ok()
+}
+
+/// The files with unresolved conflicts in the index, rereading it as other tools may have changed it
+pub fn conflicted_files(repo: &Repository) -> Result<Vec<PathBuf>, String> {
+    git_helper(
+        || {
+            let mut index = repo.index()?;
+            index.read(true)?;
+            let mut conflicted = Vec::new();
+            for conflict in index.conflicts()? {
+                let conflict = conflict?;
+                if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+                    conflicted.push(platform::path_from_bytes(&entry.path));
+                }
+            }
+            Ok(conflicted)
+        },
+        |err| {
+            format!(
+                "failed to list conflicts in git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Stashes the uncommitted changes, including untracked files, returning whether there were any
+pub fn stash(repo: &mut Repository) -> Result<bool, String> {
+    if dry_run::skip(format_args!("stash changes in {}", repo.path().display())) {
+        return Ok(false);
+    }
+    let path = repo.path().to_owned();
+    git_helper(
+        || {
+            if load_crypt_key(repo)?.is_some() {
+                return Err(git2::Error::from_str(
+                    "stashing is not supported in encrypted repositories",
+                ));
+            }
+            let sig = repo.signature()?;
+            match repo.stash_save(&sig, "dotty autostash", Some(StashFlags::INCLUDE_UNTRACKED)) {
+                Ok(id) => {
+                    log::debug!("stashed changes as {}", id);
+                    Ok(true)
+                }
+                Err(err) if err.code() == ErrorCode::NotFound => Ok(false),
+                Err(err) => Err(err),
+            }
+        },
+        |err| {
+            format!(
+                "failed to stash changes in git repository {} - {}",
+                path.display(),
+                err
+            )
+        },
+    )
+}
+
+/// Applies and drops the latest stash, which is kept when it conflicts
+pub fn stash_pop(repo: &mut Repository) -> Result<(), String> {
+    if dry_run::skip(format_args!("pop stash in {}", repo.path().display())) {
+        return Ok(());
+    }
+    let path = repo.path().to_owned();
+    git_helper(
+        || {
+            log::debug!("popping stash");
+            repo.stash_pop(0, None)
+        },
+        |err| {
+            format!(
+                "failed to pop stashed changes in git repository {}, they are kept in git stash - {}",
+                path.display(),
+                err
+            )
+        },
+    )
+}
+
 pub fn commit(repo: &Repository, message: &str) -> Result<Oid, String> {
+    if dry_run::skip(format_args!(
+        "commit in {} with message {}",
+        repo.path().display(),
+        message
+    )) {
+        return Ok(Oid::zero());
+    }
     log::debug!(
         "creating commit in git repository {} with message {}",
         repo.path().display(),
@@ -149,18 +586,18 @@ pub fn commit(repo: &Repository, message: &str) -> Result<Oid, String> {
             let oid = index.write_tree()?;
             let tree = repo.find_tree(oid)?;
             let maybe_parent = find_last_commit(repo)?;
-            let parents = match maybe_parent {
-                Some(ref parent) => vec![parent],
-                None => vec![],
+            // concludes a merge whose conflicts were resolved by hand
+            let merge_head = match repo.find_reference("MERGE_HEAD") {
+                Ok(reference) => Some(reference.peel_to_commit()?),
+                Err(err) if err.code() == ErrorCode::NotFound => None,
+                Err(err) => return Err(err),
             };
-            repo.commit(
-                Some("HEAD"),
-                &signature,
-                &signature,
-                message,
-                &tree,
-                &parents,
-            )
+            let parents: Vec<&Commit> = maybe_parent.iter().chain(merge_head.iter()).collect();
+            let oid = create_commit(repo, &signature, message, &tree, &parents)?;
+            if merge_head.is_some() {
+                repo.cleanup_state()?;
+            }
+            Ok(oid)
         },
         |err| {
             format!(
@@ -173,6 +610,13 @@ pub fn commit(repo: &Repository, message: &str) -> Result<Oid, String> {
 }
 
 pub fn add_submodules(repo: &Repository, submodules: &Vec<PathBuf>) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "add {} submodules to {}",
+        submodules.len(),
+        repo.path().display()
+    )) {
+        return Ok(());
+    }
     log::debug!(
         "adding {} submodules to git repository {}",
         submodules.len(),
@@ -180,7 +624,7 @@ pub fn add_submodules(repo: &Repository, submodules: &Vec<PathBuf>) -> Result<()
     );
     for path in submodules {
         log::trace!("adding submodule {}", path.display());
-        let submodule_repo = open(path)?;
+        let submodule_repo = open(&repo.workdir().unwrap_or(Path::new("")).join(path))?;
         let url = get_origin_url(&submodule_repo)?;
         if let Err(err) = repo
             .submodule(&url, path, true)
@@ -197,47 +641,43 @@ pub fn add_submodules(repo: &Repository, submodules: &Vec<PathBuf>) -> Result<()
     Ok(())
 }
 
-pub fn sync(repo: &Repository, url: Option<&str>) -> Result<(), String> {
+/// How conflicting changes to the same file are resolved when merging the remote
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeStrategy {
+    /// Leaves conflict markers in the files to resolve and commit by hand
+    #[default]
+    Manual,
+    /// Keeps the local side of each conflicting hunk
+    Ours,
+    /// Keeps the remote side of each conflicting hunk
+    Theirs,
+    /// Runs $DOTTY_MERGETOOL, or the configured git mergetool, on each conflicting file
+    Tool,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PullOptions {
+    pub rebase: bool,
+    pub strategy: MergeStrategy,
+}
+
+/// Fetches and merges the current branch, or rebases local commits onto it when possible
+pub fn pull(repo: &Repository, url: Option<&str>, opts: PullOptions) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "pull {} from {}",
+        repo.path().display(),
+        url.unwrap_or("origin")
+    )) {
+        return Ok(());
+    }
     git_helper(
         || {
-            if !repo.statuses(None)?.is_empty() {
-                return Err(git2::Error::from_str(&format!(
-                    "there are unstaged changes in {}",
-                    repo.path().display()
-                )));
-            }
+            check_clean(repo)?;
             let branch_name = get_branch_name(repo)?;
             let mut remote = get_remote(repo, url)?;
 
-            log::debug!(
-                "fetching branch {} from remote {}",
-                branch_name,
-                remote.url().unwrap_or("unknown")
-            );
-
-            let mut fetch_opts = FetchOptions::new();
-            fetch_opts.remote_callbacks(create_callbacks());
-            remote.fetch(&[&branch_name], Some(&mut fetch_opts), None)?;
-
-            if let Ok(fetch_head) = repo.find_reference("FETCH_HEAD") {
-                let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
-                log::debug!("merging remote commit {}", fetch_commit.id());
-                merge(
-                    repo,
-                    &branch_name,
-                    fetch_commit,
-                    remote.url().unwrap_or("unknown"),
-                )?;
-            }
-
-            log::debug!("pushing branch {}", branch_name);
-
-            let mut push_opts = PushOptions::new();
-            push_opts.remote_callbacks(create_callbacks());
-            remote.push(
-                &[format!("refs/heads/{0}:refs/heads/{0}", branch_name)],
-                Some(&mut push_opts),
-            )?;
+            fetch_and_merge(repo, &mut remote, &branch_name, opts)?;
 
             remote.disconnect()?;
 
@@ -252,7 +692,7 @@ pub fn sync(repo: &Repository, url: Option<&str>) -> Result<(), String> {
         },
         |err| {
             format!(
-                "failed to sync changes in git repository {} - {}",
+                "failed to pull changes in git repository {} - {}",
                 repo.path().display(),
                 err
             )
@@ -260,70 +700,1589 @@ pub fn sync(repo: &Repository, url: Option<&str>) -> Result<(), String> {
     )
 }
 
-pub fn update_submodules(repo: &Repository) -> Result<i32, String> {
+/// The url given, or otherwise that of origin
+pub fn remote_url(repo: &Repository, url: Option<&str>) -> Option<String> {
+    url.map(str::to_owned).or_else(|| {
+        repo.find_remote("origin")
+            .ok()
+            .and_then(|remote| remote.url().map(str::to_owned))
+    })
+}
+
+/// Points origin at the url, adding it when missing
+pub fn set_remote(repo: &Repository, url: &str) -> Result<(), String> {
     git_helper(
-        || {
-            let mut updated: i32 = 0;
+        || get_remote(repo, Some(url)).map(|_| ()),
+        |err| {
+            format!(
+                "failed to set remote of git repository {} to {} - {}",
+                repo.path().display(),
+                url,
+                err
+            )
+        },
+    )
+}
 
-            for mut submodule in repo.submodules()? {
-                log::debug!(
-                    "updating submodule {}",
-                    submodule.name().unwrap_or("unknown")
-                );
+/// Whether the current branch has been fetched from or pushed to origin before
+pub fn has_remote_branch(repo: &Repository) -> bool {
+    get_branch_name(repo)
+        .and_then(|branch| repo.find_reference(&format!("refs/remotes/origin/{}", branch)))
+        .is_ok()
+}
+
+/// Removes a submodule by name or path from .gitmodules, the git config, the index and the
+/// working tree, along with its repository in .git/modules, returning its name and path
+pub fn remove_submodule(repo: &Repository, selected: &str) -> Result<(String, PathBuf), String> {
+    git_helper(
+        || {
+            let submodule = find_submodule(repo, selected)?;
+            let name = submodule.name().unwrap_or(selected).to_owned();
+            let path = submodule.path().to_owned();
+            let status = repo.submodule_status(&name, git2::SubmoduleIgnore::None)?;
+            if status.intersects(
+                git2::SubmoduleStatus::WD_INDEX_MODIFIED
+                    | git2::SubmoduleStatus::WD_WD_MODIFIED
+                    | git2::SubmoduleStatus::WD_UNTRACKED,
+            ) {
+                return Err(git2::Error::from_str(&format!(
+                    "submodule {} has uncommitted changes",
+                    name
+                )));
+            }
+            if dry_run::skip(format_args!("remove submodule {}", name)) {
+                return Ok((name, path));
+            }
+            let workdir = workdir(repo)?;
+            let gitmodules_path = workdir.join(".gitmodules");
+            let mut gitmodules = Config::open(&gitmodules_path)?;
+            remove_config_section(&mut gitmodules, &gitmodules_path, &name)?;
+            remove_config_section(
+                &mut repo.config()?.open_level(ConfigLevel::Local)?,
+                &repo.path().join("config"),
+                &name,
+            )?;
+
+            let mut index = repo.index()?;
+            index.remove_path(&path)?;
+            if gitmodules.entries(None)?.next().is_none() {
+                log::trace!("removing empty {}", gitmodules_path.display());
+                delete_path(&gitmodules_path)?;
+                index.remove_path(Path::new(".gitmodules"))?;
+            } else {
+                index.add_path(Path::new(".gitmodules"))?;
+            }
+            index.write()?;
+
+            log::debug!("deleting submodule {} at {}", name, path.display());
+            delete_path(&workdir.join(&path))?;
+            let module_dir = repo.path().join("modules").join(&name);
+            if module_dir.exists() {
+                delete_path(&module_dir)?;
+            }
+            Ok((name, path))
+        },
+        |err| {
+            format!(
+                "failed to remove submodule {} from git repository {} - {}",
+                selected,
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
 
-                let submodule_repo = submodule.open()?;
-                let mut remote = get_remote(&submodule_repo, None)?;
+/// Removes the entries of a submodule, and then its header which libgit2 leaves behind
+fn remove_config_section(
+    config: &mut Config,
+    config_path: &Path,
+    submodule: &str,
+) -> Result<(), git2::Error> {
+    let prefix = format!("submodule.{}.", submodule);
+    let mut names = Vec::new();
+    let mut entries = config.entries(None)?;
+    while let Some(entry) = entries.next() {
+        if let Some(name) = entry?.name().filter(|name| name.starts_with(&prefix)) {
+            names.push(name.to_owned());
+        }
+    }
+    drop(entries);
+    for name in names {
+        log::trace!("removing config {}", name);
+        config.remove(&name)?;
+    }
 
-                remote.connect(git2::Direction::Fetch)?;
+    let header = format!("[submodule \"{}\"]", submodule);
+    let contents = fs::read_to_string(config_path).unwrap_or_default();
+    let mut remaining = String::new();
+    for line in contents.lines().filter(|line| line.trim() != header) {
+        remaining.push_str(line);
+        remaining.push('\n');
+    }
+    if remaining.len() != contents.len() {
+        fs::write(config_path, remaining).map_err(|err| {
+            git2::Error::from_str(&format!(
+                "could not write {} - {}",
+                config_path.display(),
+                err
+            ))
+        })?;
+    }
+    Ok(())
+}
 
-                let mut fetch_opts = FetchOptions::new();
-                fetch_opts.remote_callbacks(create_callbacks());
+fn delete_path(path: &Path) -> Result<(), git2::Error> {
+    let deleted = match path.is_dir() {
+        true => fs::remove_dir_all(path),
+        false => fs::remove_file(path),
+    };
+    deleted.map_err(|err| {
+        git2::Error::from_str(&format!("could not delete {} - {}", path.display(), err))
+    })
+}
 
-                let default_branch_buf = remote.default_branch()?;
-                let default_branch_ref_name =
-                    default_branch_buf.as_str().unwrap_or("refs/heads/main");
+/// Fetches every branch and tag, then checks out the tag, branch or commit detached
+fn checkout_pin(
+    repo: &Repository,
+    remote: &mut Remote,
+    shallow: bool,
+    pin: &str,
+) -> Result<(), git2::Error> {
+    log::trace!("using pin {} for submodule {}", pin, repo.path().display());
+    network(|| {
+        remote.fetch(
+            &[
+                "+refs/heads/*:refs/remotes/origin/*",
+                "+refs/tags/*:refs/tags/*",
+            ],
+            Some(&mut fetch_options(shallow)),
+            None,
+        )
+    })?;
+    let commit = match [
+        format!("refs/tags/{}", pin),
+        format!("refs/remotes/origin/{}", pin),
+    ]
+    .iter()
+    .find_map(|name| repo.find_reference(name).ok())
+    {
+        Some(reference) => reference.peel_to_commit()?,
+        None => repo.revparse_single(pin)?.peel_to_commit()?,
+    };
+    repo.set_head_detached(commit.id())?;
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))
+}
 
-                log::trace!(
-                    "using branch {} for submodule {}",
-                    default_branch_ref_name,
-                    submodule.name().unwrap_or("unknown")
-                );
+/// Checks out the branch, creating it from the remote one when there is no local branch yet
+pub fn switch_branch(repo: &Repository, url: Option<&str>, branch: &str) -> Result<(), String> {
+    if get_branch_name(repo).is_ok_and(|current| current == branch) {
+        return Ok(());
+    }
+    if dry_run::skip(format_args!(
+        "switch {} to branch {}",
+        repo.path().display(),
+        branch
+    )) {
+        return Ok(());
+    }
+    git_helper(
+        || {
+            check_clean(repo)?;
+            let local = match repo.find_branch(branch, BranchType::Local) {
+                Ok(local) => local,
+                Err(err) if err.code() == ErrorCode::NotFound => {
+                    log::debug!("fetching branch {} to create it locally", branch);
+                    let mut remote = get_remote(repo, url)?;
+                    network(|| remote.fetch(&[branch], Some(&mut fetch_options(false)), None))?;
+                    remote.disconnect()?;
+                    let commit = repo.find_reference("FETCH_HEAD")?.peel_to_commit()?;
+                    repo.branch(branch, &commit, false)?
+                }
+                Err(err) => return Err(err),
+            };
+            let reference = local.into_reference();
+            let mut checkout_builder = CheckoutBuilder::new();
+            checkout_builder.safe();
+            repo.checkout_tree(
+                &reference.peel(ObjectType::Tree)?,
+                Some(&mut checkout_builder),
+            )?;
+            repo.set_head(reference.name().unwrap_or_default())?;
+            log::info!("switched to branch {}", branch);
+            Ok(())
+        },
+        |err| {
+            format!(
+                "failed to switch git repository {} to branch {} - {}",
+                repo.path().display(),
+                branch,
+                err
+            )
+        },
+    )
+}
+
+/// The stages of a sync to run, pulling before pushing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncStages {
+    #[default]
+    Both,
+    Pull,
+    Push,
+}
+
+impl SyncStages {
+    pub fn pulls(self) -> bool {
+        self != SyncStages::Push
+    }
+
+    pub fn pushes(self) -> bool {
+        self != SyncStages::Pull
+    }
+}
+
+/// Pulls and then pushes the current branch, or only one of them
+pub fn sync(
+    repo: &Repository,
+    url: Option<&str>,
+    stages: SyncStages,
+    opts: PullOptions,
+) -> Result<(), String> {
+    if stages.pulls() {
+        pull(repo, url, opts)?;
+    }
+    if stages.pushes() {
+        push(repo, url, false)?;
+    }
+    Ok(())
+}
+
+/// Whether the url is another remote than origin, which is fetched without updating origin's
+/// remote tracking branches
+fn is_other_remote(repo: &Repository, url: Option<&str>) -> bool {
+    url.is_some_and(|url| {
+        repo.find_remote("origin")
+            .ok()
+            .and_then(|origin| origin.url().map(|origin_url| origin_url != url))
+            .unwrap_or(true)
+    })
+}
+
+/// The remote for the url, anonymous when it is not origin's
+fn remote_for<'a>(repo: &'a Repository, url: Option<&str>) -> Result<Remote<'a>, git2::Error> {
+    match url {
+        Some(url) if is_other_remote(repo, Some(url)) => repo.remote_anonymous(url),
+        _ => get_remote(repo, url),
+    }
+}
+
+/// The commit of the branch as last fetched from the url, or from origin when there is none
+fn fetched_branch(
+    repo: &Repository,
+    url: Option<&str>,
+    branch: &str,
+) -> Result<Option<Oid>, git2::Error> {
+    let Some(url) = url.filter(|&url| is_other_remote(repo, Some(url))) else {
+        return match repo.find_reference(&format!("refs/remotes/origin/{}", branch)) {
+            Ok(reference) => Ok(Some(reference.peel_to_commit()?.id())),
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err),
+        };
+    };
+    // another remote is only fetched into FETCH_HEAD, which records the url
+    let branch_ref = format!("refs/heads/{}", branch);
+    let mut fetched = None;
+    match repo.fetchhead_foreach(|ref_name, remote_url, oid, _| {
+        if ref_name == branch_ref && remote_url == url.as_bytes() {
+            fetched = Some(*oid);
+        }
+        true
+    }) {
+        Err(err) if err.code() != ErrorCode::NotFound => return Err(err),
+        _ => {}
+    }
+    Ok(fetched)
+}
+
+/// Fails the push unless the remote branch is still at the commit last fetched from it
+///
+/// Checked when the remote lists what the push updates, so nothing can move the branch between
+/// the check and the push.
+fn check_lease(callbacks: &mut RemoteCallbacks, branch: &str, expected: Option<Oid>) {
+    let branch_ref = format!("refs/heads/{}", branch);
+    callbacks.push_negotiation(move |updates| {
+        for update in updates
+            .iter()
+            .filter(|u| u.dst_refname() == Some(&branch_ref))
+        {
+            let actual = Some(update.src()).filter(|oid| !oid.is_zero());
+            log::debug!(
+                "remote {} is at {:?}, expected {:?}",
+                branch_ref,
+                actual,
+                expected
+            );
+            if actual != expected {
+                return Err(git2::Error::from_str(&format!(
+                    "the remote {} changed since it was last fetched, pull first",
+                    branch_ref
+                )));
+            }
+        }
+        Ok(())
+    });
+}
+
+/// Pushes the current branch, or overwrites it when it is still where it was last fetched from
+pub fn push(repo: &Repository, url: Option<&str>, force_with_lease: bool) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "{}push {} to {}",
+        if force_with_lease { "force " } else { "" },
+        repo.path().display(),
+        url.unwrap_or("origin")
+    )) {
+        return Ok(());
+    }
+    git_helper(
+        || {
+            // the lease is what was last fetched from the url, before pushing changes its remote
+            let lease = match force_with_lease {
+                true => Some(fetched_branch(repo, url, &get_branch_name(repo)?)?),
+                false => None,
+            };
+            push_branch(repo, &mut remote_for(repo, url)?, lease)
+        },
+        |err| {
+            format!(
+                "failed to push changes in git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Pushes the current branch to a mirror, adding or updating its named remote
+pub fn push_mirror(repo: &Repository, name: &str, url: &str) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "push {} to mirror {}",
+        repo.path().display(),
+        name
+    )) {
+        return Ok(());
+    }
+    git_helper(
+        || push_branch(repo, &mut get_named_remote(repo, name, Some(url))?, None),
+        |err| format!("failed to push to mirror {} at {} - {}", name, url, err),
+    )
+}
+
+/// Pushes the current branch, overwriting the remote one when it is at the leased commit
+fn push_branch(
+    repo: &Repository,
+    remote: &mut Remote,
+    lease: Option<Option<Oid>>,
+) -> Result<(), git2::Error> {
+    let branch_name = get_branch_name(repo)?;
+    let mut refspec = format!("refs/heads/{0}:refs/heads/{0}", branch_name);
+    if lease.is_some() {
+        refspec.insert(0, '+');
+    }
+
+    log::debug!("pushing branch {}", branch_name);
+
+    network(|| {
+        let mut callbacks = create_callbacks();
+        if let Some(expected) = lease {
+            check_lease(&mut callbacks, &branch_name, expected);
+        }
+        callbacks.push_update_reference(|reference, status| match status {
+            Some(status) => Err(git2::Error::from_str(&format!(
+                "the remote rejected {} - {}",
+                reference, status
+            ))),
+            None => Ok(()),
+        });
+        let mut push_opts = PushOptions::new();
+        push_opts.remote_callbacks(callbacks);
+        push_opts.proxy_options(proxy_options());
+        remote.push(&[refspec.as_str()], Some(&mut push_opts))
+    })?;
+
+    remote.disconnect()?;
+
+    remote.update_tips(
+        None,
+        RemoteUpdateFlags::UPDATE_FETCHHEAD,
+        AutotagOption::Unspecified,
+        None,
+    )?;
+
+    Ok(())
+}
 
-                remote.fetch(
-                    &[default_branch_ref_name] as &[&str],
-                    Some(&mut fetch_opts),
-                    None,
-                )?;
+/// Updates every submodule, or only those whose name or path is given
+///
+/// Submodules follow the default branch of their remote unless pinned to a tag, branch or commit
+/// by name or path.
+pub fn update_submodules(
+    repo: &Repository,
+    only: &[String],
+    pins: &BTreeMap<String, String>,
+) -> Result<i32, String> {
+    if dry_run::skip(format_args!(
+        "update submodules in {}",
+        repo.path().display()
+    )) {
+        return Ok(0);
+    }
+    git_helper(
+        || {
+            let mut updated: i32 = 0;
+
+            let mut submodules = repo.submodules()?;
+            if !only.is_empty() {
+                let is_selected = |submodule: &git2::Submodule, selected: &str| {
+                    submodule.name() == Some(selected)
+                        || submodule.path() == Path::new(selected.trim_end_matches('/'))
+                };
+                if let Some(unknown) = only
+                    .iter()
+                    .find(|selected| !submodules.iter().any(|s| is_selected(s, selected)))
+                {
+                    return Err(git2::Error::from_str(&format!(
+                        "there is no submodule {}",
+                        unknown
+                    )));
+                }
+                submodules.retain(|s| only.iter().any(|selected| is_selected(s, selected)));
+            }
 
-                let fetch_head = submodule_repo.find_reference("FETCH_HEAD")?;
-                let fetch_commit = submodule_repo.reference_to_annotated_commit(&fetch_head)?;
+            let workdir = repo
+                .workdir()
+                .ok_or_else(|| git2::Error::from_str("repository has no working directory"))?;
+            let jobs: Vec<(String, PathBuf, Option<String>)> = submodules
+                .iter()
+                .map(|submodule| {
+                    let name = submodule.name().unwrap_or("unknown").to_owned();
+                    let pin = pins
+                        .get(&name)
+                        .or_else(|| pins.get(submodule.path().to_string_lossy().as_ref()))
+                        .cloned();
+                    (name, workdir.join(submodule.path()), pin)
+                })
+                .collect();
 
-                let mut branch_reference =
-                    submodule_repo.find_reference(default_branch_ref_name)?;
-                fast_forward(&submodule_repo, &mut branch_reference, &fetch_commit)?;
+            // each submodule is fetched in its own repository, only the index is shared
+            let results = parallel(jobs, |(name, path, pin)| {
+                log::debug!("updating submodule {}", name);
+                let result = Repository::open(&path)
+                    .and_then(|submodule_repo| update_submodule(&submodule_repo, pin.as_deref()));
+                (name, result)
+            });
 
-                submodule.add_to_index(false)?;
+            for (name, result) in results {
+                result.map_err(|err| {
+                    git2::Error::from_str(&format!("submodule {} - {}", name, err.message()))
+                })?;
+                repo.find_submodule(&name)?.add_to_index(false)?;
                 updated += 1;
+            }
+
+            if updated > 0 {
+                repo.index()?.write()?;
+            }
+
+            Ok(updated)
+        },
+        |err| {
+            format!(
+                "failed to update submodules in git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+pub fn branch_name(repo: &Repository) -> Result<String, String> {
+    git_helper(
+        || get_branch_name(repo),
+        |err| {
+            format!(
+                "failed to get branch of git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+pub fn head_id(repo: &Repository) -> Result<Option<String>, String> {
+    git_helper(
+        || find_last_commit(repo).map(|commit| commit.map(|c| c.id().to_string())),
+        |err| {
+            format!(
+                "failed to find head of git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// The last commit of the current branch
+pub struct LastCommit {
+    pub id: String,
+    pub parent: Option<String>,
+    pub summary: String,
+    /// Whether the message ends with the trailer dotty adds to its commits
+    pub by_dotty: bool,
+    pub is_merge: bool,
+    /// Whether the remote branch has the commit, as of the last fetch
+    pub pushed: bool,
+}
 
-                remote.disconnect()?;
+pub fn last_commit(repo: &Repository) -> Result<Option<LastCommit>, String> {
+    git_helper(
+        || {
+            let Some(commit) = find_last_commit(repo)? else {
+                return Ok(None);
+            };
+            let pushed = match repo
+                .find_reference(&format!("refs/remotes/origin/{}", get_branch_name(repo)?))
+            {
+                Ok(reference) => {
+                    let remote = reference.peel_to_commit()?.id();
+                    remote == commit.id() || repo.graph_descendant_of(remote, commit.id())?
+                }
+                Err(err) if err.code() == ErrorCode::NotFound => false,
+                Err(err) => return Err(err),
+            };
+            Ok(Some(LastCommit {
+                id: commit.id().to_string(),
+                parent: commit.parent_ids().next().map(|id| id.to_string()),
+                summary: commit.summary().unwrap_or_default().to_owned(),
+                by_dotty: commit
+                    .message()
+                    .is_some_and(|message| message.trim_end().ends_with(TRAILER)),
+                is_merge: commit.parent_count() > 1,
+                pushed,
+            }))
+        },
+        |err| {
+            format!(
+                "failed to find last commit of git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Drops the last commit, resetting the index and working tree to its parent
+///
+/// Without a parent the branch is unborn again. Files beneath `keep` are left in the working tree,
+/// untracked, for the caller to move.
+pub fn drop_last_commit(repo: &Repository, keep: &[PathBuf]) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "drop last commit in {}",
+        repo.path().display()
+    )) {
+        return Ok(());
+    }
+    git_helper(
+        || {
+            let head = repo.head()?;
+            let commit = head.peel_to_commit()?;
+            let parent = match commit.parent_count() {
+                0 => None,
+                _ => Some(commit.parent(0)?),
+            };
+            let parent_tree = parent.as_ref().map(|parent| parent.tree()).transpose()?;
+            reset_files(repo, &commit, parent_tree.as_ref(), keep)?;
+            match parent {
+                Some(parent) => head
+                    .resolve()?
+                    .set_target(parent.id(), "dotty: undo")
+                    .map(|_| ()),
+                None => head.resolve()?.delete(),
+            }
+        },
+        |err| {
+            format!(
+                "failed to drop last commit of git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Commits the inverse of the last commit, leaving the files beneath `keep` in the working tree
+pub fn revert_last_commit(
+    repo: &Repository,
+    message: &str,
+    keep: &[PathBuf],
+) -> Result<Oid, String> {
+    if dry_run::skip(format_args!(
+        "revert last commit in {}",
+        repo.path().display()
+    )) {
+        return Ok(Oid::zero());
+    }
+    git_helper(
+        || {
+            let commit = repo.head()?.peel_to_commit()?;
+            let mut reverted = repo.revert_commit(&commit, &commit, 0, None)?;
+            let tree = repo.find_tree(reverted.write_tree_to(repo)?)?;
+            reset_files(repo, &commit, Some(&tree), keep)
+        },
+        |err| {
+            format!(
+                "failed to revert last commit of git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )?;
+    commit(repo, message)
+}
+
+/// Changes the index and the working tree from the files of `commit` to those of `target`
+///
+/// Files beneath `keep` stay in the working tree as they are, untracked when `target` lacks them.
+fn reset_files(
+    repo: &Repository,
+    commit: &Commit,
+    target: Option<&Tree>,
+    keep: &[PathBuf],
+) -> Result<(), git2::Error> {
+    let workdir = workdir(repo)?;
+    let diff = repo.diff_tree_to_tree(Some(&commit.tree()?), target, None)?;
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    let mut checked_out = 0;
+    for delta in diff.deltas() {
+        let Some(path) = delta.new_file().path().or(delta.old_file().path()) else {
+            continue;
+        };
+        if keep.iter().any(|kept| path.starts_with(kept))
+            || delta.old_file().mode() == FileMode::Commit
+            || delta.new_file().mode() == FileMode::Commit
+        {
+            continue;
+        }
+        if delta.status() != Delta::Deleted {
+            checkout.path(path);
+            checked_out += 1;
+            continue;
+        }
+        let path = workdir.join(path);
+        let _ = fs::remove_file(&path);
+        for dir in path.ancestors().skip(1).take_while(|dir| *dir != workdir) {
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+    if let (Some(target), true) = (target, checked_out > 0) {
+        repo.checkout_tree(target.as_object(), Some(&mut checkout))?;
+    }
+    let mut index = repo.index()?;
+    match target {
+        Some(target) => index.read_tree(target)?,
+        None => index.clear()?,
+    }
+    index.write()
+}
+
+/// Whether the file has the contents committed at HEAD, hashing it rather than trusting the index
+pub fn matches_head(repo: &Repository, path: &Path) -> Result<bool, String> {
+    git_helper(
+        || {
+            let tree = match repo.head() {
+                Ok(head) => head.peel_to_tree()?,
+                Err(err) if err.code() == ErrorCode::UnbornBranch => return Ok(false),
+                Err(err) => return Err(err),
+            };
+            let Ok(entry) = tree.get_path(path) else {
+                return Ok(false);
+            };
+            let Ok(contents) = fs::read(workdir(repo)?.join(path)) else {
+                return Ok(false);
+            };
+            let contents = match load_crypt_key(repo)? {
+                Some(key) => key
+                    .encrypt(&contents)
+                    .map_err(|e| git2::Error::from_str(&e))?,
+                None => contents,
+            };
+            Ok(Oid::hash_object(ObjectType::Blob, &contents)? == entry.id())
+        },
+        |err| {
+            format!(
+                "failed to compare {} with HEAD in git repository {} - {}",
+                path.display(),
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Reflog entries git gc keeps however old they are, so stashes are never lost
+const GC_KEPT_REFLOGS: &[(&str, &str)] = &[
+    ("gc.refs/stash.reflogExpire", "never"),
+    ("gc.refs/stash.reflogExpireUnreachable", "never"),
+];
+
+/// Prunes unreachable objects older than `prune`, e.g. 2.weeks.ago, and repacks with git gc
+///
+/// The reflog expires as git gc expires it by default, keeping dropped commits recoverable for a
+/// while. Returns the size of the object store before and after.
+pub fn gc(path: &Path, aggressive: bool, prune: &str) -> Result<(u64, u64), String> {
+    let objects = open(path)?.path().join("objects");
+    let before = dir_size(&objects);
+    if dry_run::skip(format_args!("collect garbage in {}", path.display())) {
+        return Ok((before, before));
+    }
+    let prune = format!("--prune={}", prune);
+    let mut args = vec!["gc", prune.as_str(), "--quiet"];
+    if aggressive {
+        args.push("--aggressive");
+    }
+    run_git(path, GC_KEPT_REFLOGS, &args)?;
+    Ok((before, dir_size(&objects)))
+}
+
+fn run_git(path: &Path, configs: &[(&str, &str)], args: &[&str]) -> Result<(), String> {
+    log::debug!("executing git {} in {}", args.join(" "), path.display());
+    let mut cmd = Command::new("git");
+    cmd.arg("-C").arg(path);
+    for (key, value) in configs {
+        cmd.arg("-c").arg(format!("{}={}", key, value));
+    }
+    let output = cmd
+        .args(args)
+        .output()
+        .map_err(|err| format!("failed to execute git - {}", err))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(format!(
+            "git {} failed in {} - {}",
+            args[0],
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return 0;
+    };
+    read_dir
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+            _ => entry.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+        })
+        .sum()
+}
+
+/// Reads every object to check its hash, and checks everything the last commit references exists
+///
+/// Returns the problems found.
+pub fn verify(repo: &Repository) -> Result<Vec<String>, String> {
+    git2::opts::strict_hash_verification(true);
+    git_helper(
+        || {
+            let odb = repo.odb()?;
+            let mut ids = Vec::new();
+            odb.foreach(|id| {
+                ids.push(*id);
+                true
+            })?;
+            let mut problems = Vec::new();
+            for id in ids {
+                if let Err(err) = odb.read(id) {
+                    problems.push(format!("object {} is corrupt - {}", id, err.message()));
+                }
+            }
+            if let Some(commit) = find_last_commit(repo)? {
+                commit
+                    .tree()?
+                    .walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+                        // submodule commits live in their own repositories
+                        if entry.kind() != Some(ObjectType::Commit) && !odb.exists(entry.id()) {
+                            problems.push(format!(
+                                "{}{} is missing object {}",
+                                dir,
+                                entry.name().unwrap_or_default(),
+                                entry.id()
+                            ));
+                        }
+                        git2::TreeWalkResult::Ok
+                    })?;
+            }
+            Ok(problems)
+        },
+        |err| {
+            format!(
+                "failed to verify git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Tags the last commit with an annotated tag
+pub fn create_tag(repo: &Repository, name: &str, message: &str) -> Result<(), String> {
+    if dry_run::skip(format_args!("tag {} in {}", name, repo.path().display())) {
+        return Ok(());
+    }
+    git_helper(
+        || {
+            if !Reference::is_valid_name(&format!("refs/tags/{}", name)) {
+                return Err(git2::Error::from_str("invalid tag name"));
+            }
+            let target = repo.head()?.peel(ObjectType::Commit)?;
+            repo.tag(name, &target, &repo.signature()?, message, false)
+                .map(|_| ())
+        },
+        |err| {
+            format!(
+                "failed to create tag {} in git repository {} - {}",
+                name,
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// The id of the commit a tag, branch or commit id names
+pub fn resolve_commit(repo: &Repository, name: &str) -> Result<String, String> {
+    git_helper(
+        || {
+            Ok(repo
+                .revparse_single(name)?
+                .peel_to_commit()?
+                .id()
+                .to_string())
+        },
+        |err| {
+            format!(
+                "failed to find {} in git repository {} - {}",
+                name,
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Commits the files of an earlier commit on top of the last one, keeping the history in between
+///
+/// Returns none when the files are the same already.
+pub fn commit_files_of(repo: &Repository, id: &str, message: &str) -> Result<Option<Oid>, String> {
+    let unchanged = git_helper(
+        || {
+            check_clean(repo)?;
+            let tree = repo.find_commit(Oid::from_str(id)?)?.tree()?;
+            if find_last_commit(repo)?.is_some_and(|head| head.tree_id() == tree.id()) {
+                return Ok(true);
+            }
+            if dry_run::skip(format_args!(
+                "check out files of {} in {}",
+                id,
+                repo.path().display()
+            )) {
+                return Ok(false);
+            }
+            repo.checkout_tree(tree.as_object(), Some(CheckoutBuilder::new().force()))?;
+            let mut index = repo.index()?;
+            index.read_tree(&tree)?;
+            index.write()?;
+            Ok(false)
+        },
+        |err| {
+            format!(
+                "failed to check out files of {} in git repository {} - {}",
+                id,
+                repo.path().display(),
+                err
+            )
+        },
+    )?;
+    match unchanged {
+        true => Ok(None),
+        false => commit(repo, message).map(Some),
+    }
+}
+
+/// A commit in the history of the current branch
+pub struct LogEntry {
+    pub id: String,
+    pub time: SystemTime,
+    pub author: String,
+    pub summary: String,
+}
+
+/// The most recent commits of the current branch, only those changing one of the paths if any are given
+pub fn log(
+    repo: &Repository,
+    paths: &[PathBuf],
+    max_count: usize,
+) -> Result<Vec<LogEntry>, String> {
+    git_helper(
+        || {
+            let mut entries = Vec::new();
+            if find_last_commit(repo)?.is_none() {
+                return Ok(entries);
+            }
+            let mut revwalk = repo.revwalk()?;
+            revwalk.push_head()?;
+            revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+            for id in revwalk {
+                if entries.len() >= max_count {
+                    break;
+                }
+                let commit = repo.find_commit(id?)?;
+                if !paths.is_empty() && !changes_any(&commit, paths)? {
+                    continue;
+                }
+                entries.push(LogEntry::from(&commit));
+            }
+            Ok(entries)
+        },
+        |err| {
+            format!(
+                "failed to read history of git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+impl From<&Commit<'_>> for LogEntry {
+    fn from(commit: &Commit) -> Self {
+        LogEntry {
+            id: commit.id().to_string(),
+            time: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(commit.time().seconds().max(0) as u64),
+            author: commit.author().name().unwrap_or_default().to_owned(),
+            summary: commit.summary().unwrap_or_default().to_owned(),
+        }
+    }
+}
+
+/// Fetches the current branch into its remote tracking branch, leaving the branch itself alone
+pub fn fetch(repo: &Repository, url: Option<&str>) -> Result<(), String> {
+    if dry_run::skip(format_args!("fetch {}", repo.path().display())) {
+        return Ok(());
+    }
+    git_helper(
+        || {
+            let branch = get_branch_name(repo)?;
+            let mut remote = remote_for(repo, url)?;
+            log::debug!(
+                "fetching branch {} from remote {}",
+                branch,
+                remote.url().unwrap_or("unknown")
+            );
+            // origin's remote tracking branch must not end up with another remote's commits
+            let refspec = match is_other_remote(repo, url) {
+                true => format!("refs/heads/{}", branch),
+                false => format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch),
+            };
+            network(|| remote.fetch(&[&refspec], Some(&mut fetch_options(false)), None))?;
+            remote.disconnect()
+        },
+        |err| {
+            format!(
+                "failed to fetch git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// The current branch compared to the remote branch, as of the last fetch
+pub struct RemoteComparison {
+    pub branch: String,
+    pub ahead: usize,
+    pub behind: usize,
+    /// The commits on the remote branch not merged yet, newest first
+    pub incoming: Vec<LogEntry>,
+}
+
+/// Compares the current branch to the branch last fetched from the url or origin, or none when it
+/// was never fetched
+pub fn compare_remote(
+    repo: &Repository,
+    url: Option<&str>,
+) -> Result<Option<RemoteComparison>, String> {
+    git_helper(
+        || {
+            let branch = get_branch_name(repo)?;
+            let Some(remote) = fetched_branch(repo, url, &branch)? else {
+                return Ok(None);
+            };
+            let local = find_last_commit(repo)?.map(|commit| commit.id());
+            let (ahead, behind) = match local {
+                Some(local) => repo.graph_ahead_behind(local, remote)?,
+                None => (0, 0),
+            };
+            let mut revwalk = repo.revwalk()?;
+            revwalk.push(remote)?;
+            if let Some(local) = local {
+                revwalk.hide(local)?;
+            }
+            revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+            let mut incoming = Vec::new();
+            for id in revwalk {
+                incoming.push(LogEntry::from(&repo.find_commit(id?)?));
+            }
+            Ok(Some(RemoteComparison {
+                branch,
+                ahead,
+                behind: match local {
+                    Some(_) => behind,
+                    None => incoming.len(),
+                },
+                incoming,
+            }))
+        },
+        |err| {
+            format!(
+                "failed to compare git repository {} to its remote - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Whether the commit changed any of the paths compared to its first parent
+fn changes_any(commit: &Commit, paths: &[PathBuf]) -> Result<bool, git2::Error> {
+    let tree = commit.tree()?;
+    let parent_tree = match commit.parent_count() {
+        0 => None,
+        _ => Some(commit.parent(0)?.tree()?),
+    };
+    let id = |tree: Option<&Tree>, path: &Path| {
+        tree.and_then(|tree| tree.get_path(path).ok())
+            .map(|entry| entry.id())
+    };
+    Ok(paths
+        .iter()
+        .any(|path| id(Some(&tree), path) != id(parent_tree.as_ref(), path)))
+}
+
+/// The path, url and checked out commit of a submodule by name or path
+pub fn submodule_origin(
+    repo: &Repository,
+    selected: &str,
+) -> Result<(PathBuf, String, String), String> {
+    git_helper(
+        || {
+            let submodule = find_submodule(repo, selected)?;
+            let url = submodule
+                .url()
+                .ok_or_else(|| git2::Error::from_str("submodule has no url"))?;
+            let commit = submodule
+                .workdir_id()
+                .or(submodule.index_id())
+                .ok_or_else(|| git2::Error::from_str("submodule has no commit"))?;
+            Ok((
+                submodule.path().to_owned(),
+                url.to_owned(),
+                commit.to_string(),
+            ))
+        },
+        |err| {
+            format!(
+                "failed to get submodule {} of git repository {} - {}",
+                selected,
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// The checked out commit of every submodule, recursively, as path, url and commit
+pub fn submodule_heads(
+    repo: &Repository,
+) -> Result<Vec<(PathBuf, Option<String>, String)>, String> {
+    fn add_heads(
+        repo: &Repository,
+        prefix: &Path,
+        heads: &mut Vec<(PathBuf, Option<String>, String)>,
+    ) -> Result<(), git2::Error> {
+        for submodule in repo.submodules()? {
+            let path = prefix.join(submodule.path());
+            let Some(commit) = submodule.workdir_id().or(submodule.index_id()) else {
+                log::debug!("submodule {} has no commit", path.display());
+                continue;
+            };
+            heads.push((
+                path.clone(),
+                submodule.url().map(|u| u.to_owned()),
+                commit.to_string(),
+            ));
+            if let Ok(submodule_repo) = submodule.open() {
+                add_heads(&submodule_repo, &path, heads)?;
+            }
+        }
+        Ok(())
+    }
+
+    let mut heads = Vec::new();
+    git_helper(
+        || add_heads(repo, Path::new(""), &mut heads),
+        |err| {
+            format!(
+                "failed to get submodules of git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )?;
+    Ok(heads)
+}
+
+/// Checks out the commit, detaching HEAD or else resetting the current branch to it.
+/// Returns false when it is already checked out
+pub fn check_out(path: &Path, commit: &str, detach: bool) -> Result<bool, String> {
+    if dry_run::skip(format_args!("check out {} in {}", commit, path.display())) {
+        return Ok(false);
+    }
+    let repo = open(path)?;
+    git_helper(
+        || {
+            let oid = Oid::from_str(commit)?;
+            if find_last_commit(&repo)?.map(|c| c.id()) == Some(oid) {
+                return Ok(false);
+            }
+            check_clean(&repo)?;
+            let target = repo.find_commit(oid)?;
+            if detach {
+                repo.set_head_detached(oid)?;
+                repo.checkout_head(Some(CheckoutBuilder::default().force()))?;
+            } else {
+                repo.reset(target.as_object(), ResetType::Hard, None)?;
+            }
+            Ok(true)
+        },
+        |err| {
+            format!(
+                "failed to check out {} in git repository {} - {}",
+                commit,
+                path.display(),
+                err
+            )
+        },
+    )
+}
+
+/// Whether checking out the commit only moves the repository forward, keeping its own commits
+pub fn is_behind(repo: &Repository, commit: &str) -> Result<bool, String> {
+    git_helper(
+        || {
+            let oid = Oid::from_str(commit)?;
+            Ok(match find_last_commit(repo)? {
+                Some(head) => head.id() == oid || repo.graph_descendant_of(oid, head.id())?,
+                None => true,
+            })
+        },
+        |err| {
+            format!(
+                "failed to compare {} with git repository {} - {}",
+                commit,
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+pub struct Hunk {
+    pub header: String,
+    old_start: u32,
+    old_lines: u32,
+    new_start: u32,
+    new_lines: u32,
+    body: Vec<u8>,
+}
+
+impl Hunk {
+    pub fn text(&self) -> String {
+        self.header.clone() + &String::from_utf8_lossy(&self.body)
+    }
+
+    /// The lines the hunk adds, with their line numbers in the changed file
+    pub fn added_lines(&self) -> Vec<(usize, String)> {
+        let mut line_number = self.new_start as usize;
+        let mut added = Vec::new();
+        for line in String::from_utf8_lossy(&self.body).lines() {
+            match line.as_bytes().first() {
+                Some(b'+') => {
+                    added.push((line_number, line[1..].to_owned()));
+                    line_number += 1;
+                }
+                Some(b' ') => line_number += 1,
+                _ => {}
+            }
+        }
+        added
+    }
+}
+
+pub struct FileChange {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    pub binary: bool,
+    pub hunks: Vec<Hunk>,
+}
+
+/// Files, other than submodules, that differ between the index and the working tree
+pub fn working_changes(repo: &Repository) -> Result<Vec<FileChange>, String> {
+    git_helper(
+        || {
+            if load_crypt_key(repo)?.is_some() {
+                return Err(git2::Error::from_str(
+                    "hunks cannot be selected in an encrypted repository",
+                ));
+            }
+            let mut opts = DiffOptions::new();
+            opts.include_untracked(true)
+                .recurse_untracked_dirs(true)
+                .show_untracked_content(true);
+            let diff = repo.diff_index_to_workdir(None, Some(&mut opts))?;
+
+            let mut changes = Vec::new();
+            for idx in 0..diff.deltas().len() {
+                let Some(patch) = Patch::from_diff(&diff, idx)? else {
+                    continue;
+                };
+                let delta = patch.delta();
+                if delta.old_file().mode() == FileMode::Commit
+                    || delta.new_file().mode() == FileMode::Commit
+                {
+                    continue;
+                }
+                let kind = match delta.status() {
+                    Delta::Added | Delta::Untracked => ChangeKind::Added,
+                    Delta::Deleted => ChangeKind::Deleted,
+                    _ => ChangeKind::Modified,
+                };
+                let path = delta
+                    .new_file()
+                    .path()
+                    .or(delta.old_file().path())
+                    .unwrap_or(Path::new(""))
+                    .to_owned();
+                let binary = delta.flags().is_binary();
+
+                let mut hunks = Vec::new();
+                for hunk_idx in 0..patch.num_hunks() {
+                    let (hunk, line_count) = patch.hunk(hunk_idx)?;
+                    let mut body = Vec::new();
+                    for line_idx in 0..line_count {
+                        let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+                        if matches!(line.origin(), ' ' | '+' | '-') {
+                            body.push(line.origin() as u8);
+                        }
+                        body.extend_from_slice(line.content());
+                    }
+                    hunks.push(Hunk {
+                        header: String::from_utf8_lossy(hunk.header()).into_owned(),
+                        old_start: hunk.old_start(),
+                        old_lines: hunk.old_lines(),
+                        new_start: hunk.new_start(),
+                        new_lines: hunk.new_lines(),
+                        body,
+                    });
+                }
+                changes.push(FileChange {
+                    path,
+                    kind,
+                    binary,
+                    hunks,
+                });
+            }
+            Ok(changes)
+        },
+        |err| {
+            format!(
+                "failed to diff git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Applies the selected hunks of each modified file to the index
+pub fn stage_hunks(
+    repo: &Repository,
+    selected: &[(&FileChange, Vec<usize>)],
+) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "stage {} files with selected hunks in {}",
+        selected.len(),
+        repo.path().display()
+    )) {
+        return Ok(());
+    }
+    let mut patch = Vec::new();
+    for (change, indices) in selected {
+        if indices.is_empty() {
+            continue;
+        }
+        let path = platform::path_bytes(&change.path);
+        patch.extend_from_slice(b"diff --git a/");
+        patch.extend_from_slice(&path);
+        patch.extend_from_slice(b" b/");
+        patch.extend_from_slice(&path);
+        patch.push(b'\n');
+        for prefix in [&b"--- a/"[..], &b"+++ b/"[..]] {
+            patch.extend_from_slice(prefix);
+            patch.extend_from_slice(&path);
+            patch.push(b'\n');
+        }
+
+        // Skipped hunks no longer shift the lines of the hunks after them
+        let mut skipped: i64 = 0;
+        for (idx, hunk) in change.hunks.iter().enumerate() {
+            if !indices.contains(&idx) {
+                skipped += hunk.new_lines as i64 - hunk.old_lines as i64;
+                continue;
+            }
+            patch.extend_from_slice(
+                format!(
+                    "@@ -{},{} +{},{} @@\n",
+                    hunk.old_start,
+                    hunk.old_lines,
+                    hunk.new_start as i64 - skipped,
+                    hunk.new_lines
+                )
+                .as_bytes(),
+            );
+            patch.extend_from_slice(&hunk.body);
+        }
+    }
+    if patch.is_empty() {
+        return Ok(());
+    }
+
+    git_helper(
+        || {
+            let diff = Diff::from_buffer(&patch)?;
+            repo.apply(&diff, ApplyLocation::Index, None)
+        },
+        |err| {
+            format!(
+                "failed to stage hunks in git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Paths with uncommitted changes, relative to the repository
+/// Files added or modified by commits on this branch that are not on origin yet
+pub fn unpushed_files(repo: &Repository) -> Result<Vec<PathBuf>, String> {
+    git_helper(
+        || {
+            let head = match repo.head() {
+                Ok(head) => head.peel_to_tree()?,
+                Err(err) if err.code() == ErrorCode::UnbornBranch => return Ok(Vec::new()),
+                Err(err) => return Err(err),
+            };
+            let pushed = match repo
+                .find_reference(&format!("refs/remotes/origin/{}", get_branch_name(repo)?))
+            {
+                Ok(reference) => Some(reference.peel_to_tree()?),
+                Err(err) if err.code() == ErrorCode::NotFound => None,
+                Err(err) => return Err(err),
+            };
+            diff_files(repo, pushed.as_ref(), &head)
+        },
+        |err| {
+            format!(
+                "failed to get unpushed changes in git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// What a shell prompt shows, read from the index and refs without touching the network
+#[derive(Debug, Default)]
+pub struct PromptState {
+    /// Whether there are changes dotty commit would commit
+    pub dirty: bool,
+    /// Commits not pushed to the remote branch, as of the last fetch
+    pub ahead: usize,
+    /// Commits on the remote branch not merged yet, as of the last fetch
+    pub behind: usize,
+    pub fetched: Option<SystemTime>,
+}
+
+pub fn prompt_state(repo: &Repository) -> Result<PromptState, String> {
+    git_helper(
+        || {
+            let mut status_opts = StatusOptions::new();
+            status_opts
+                .include_untracked(true)
+                .recurse_untracked_dirs(false)
+                .include_ignored(false)
+                .exclude_submodules(true);
+            let key = load_crypt_key(repo)?;
+            let mut dirty = false;
+            for entry in repo.statuses(Some(&mut status_opts))?.iter() {
+                let unchanged = match &key {
+                    Some(key) if entry.status() == Status::WT_MODIFIED => {
+                        let path = entry.path().unwrap_or_default();
+                        is_unchanged_encrypted(repo, key, Path::new(path))?
+                    }
+                    _ => false,
+                };
+                if !unchanged {
+                    dirty = true;
+                    break;
+                }
+            }
+
+            let (ahead, behind) = match (repo.head(), get_branch_name(repo)) {
+                (Ok(head), Ok(branch)) => {
+                    match repo.find_reference(&format!("refs/remotes/origin/{}", branch)) {
+                        Ok(remote) => match (head.target(), remote.target()) {
+                            (Some(local), Some(remote)) => {
+                                repo.graph_ahead_behind(local, remote)?
+                            }
+                            _ => (0, 0),
+                        },
+                        Err(err) if err.code() == ErrorCode::NotFound => (0, 0),
+                        Err(err) => return Err(err),
+                    }
+                }
+                _ => (0, 0),
+            };
+
+            Ok(PromptState {
+                dirty,
+                ahead,
+                behind,
+                fetched: fs::metadata(repo.path().join("FETCH_HEAD"))
+                    .and_then(|metadata| metadata.modified())
+                    .ok(),
+            })
+        },
+        |err| {
+            format!(
+                "failed to get state of git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Files added between two commits, or in `new` when there is no `old` commit
+pub fn added_between(
+    repo: &Repository,
+    old: Option<&str>,
+    new: &str,
+) -> Result<Vec<PathBuf>, String> {
+    git_helper(
+        || {
+            let old_tree = match old {
+                Some(old) => Some(repo.find_commit(Oid::from_str(old)?)?.tree()?),
+                None => None,
+            };
+            let new_tree = repo.find_commit(Oid::from_str(new)?)?.tree()?;
+            let diff = repo.diff_tree_to_tree(old_tree.as_ref(), Some(&new_tree), None)?;
+            Ok(diff
+                .deltas()
+                .filter(|delta| delta.status() == Delta::Added)
+                .filter_map(|delta| delta.new_file().path().map(Path::to_path_buf))
+                .collect())
+        },
+        |err| {
+            format!(
+                "failed to get files added between commits in git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
 
-                remote.update_tips(
-                    None,
-                    RemoteUpdateFlags::UPDATE_FETCHHEAD,
-                    AutotagOption::Unspecified,
-                    None,
-                )?;
-            }
+/// Files added or modified between two commits, or in `new` when there is no `old` commit
+pub fn changed_between(
+    repo: &Repository,
+    old: Option<&str>,
+    new: &str,
+) -> Result<Vec<PathBuf>, String> {
+    git_helper(
+        || {
+            let old_tree = match old {
+                Some(old) => Some(repo.find_commit(Oid::from_str(old)?)?.tree()?),
+                None => None,
+            };
+            let new_tree = repo.find_commit(Oid::from_str(new)?)?.tree()?;
+            diff_files(repo, old_tree.as_ref(), &new_tree)
+        },
+        |err| {
+            format!(
+                "failed to get changes between commits in git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
 
-            if updated > 0 {
-                repo.index()?.write()?;
-            }
+fn diff_files(
+    repo: &Repository,
+    old: Option<&Tree>,
+    new: &Tree,
+) -> Result<Vec<PathBuf>, git2::Error> {
+    let diff = repo.diff_tree_to_tree(old, Some(new), None)?;
+    Ok(diff
+        .deltas()
+        .filter(|delta| {
+            delta.status() != Delta::Deleted && delta.new_file().mode() != FileMode::Commit
+        })
+        .filter_map(|delta| delta.new_file().path().map(Path::to_path_buf))
+        .collect())
+}
 
-            Ok(updated)
-        },
+pub fn changed_files(repo: &Repository) -> Result<Vec<PathBuf>, String> {
+    git_helper(
+        || changed_paths(repo).map(|paths| paths.into_iter().map(PathBuf::from).collect()),
         |err| {
             format!(
-                "failed to update submodules in git repository {} - {}",
+                "failed to get changes in git repository {} - {}",
                 repo.path().display(),
                 err
             )
@@ -331,12 +2290,63 @@ pub fn update_submodules(repo: &Repository) -> Result<i32, String> {
     )
 }
 
+pub fn summary(repo: &Repository) -> String {
+    let branch = get_branch_name(repo).unwrap_or_else(|_| "unknown".to_owned());
+    let head = match find_last_commit(repo) {
+        Ok(Some(commit)) => commit.id().to_string(),
+        _ => "no commits".to_owned(),
+    };
+    let changes = changed_paths(repo).map(|c| c.len()).unwrap_or_default();
+    format!(
+        "branch {} at {} with {} uncommitted changes",
+        branch, head, changes
+    )
+}
+
 fn git_helper<G, E, A>(git_func: G, err_func: E) -> Result<A, String>
 where
     G: FnOnce() -> Result<A, git2::Error>,
     E: FnOnce(git2::Error) -> String,
 {
-    git_func().map_err(err_func)
+    timings::time(Phase::Git, git_func).map_err(err_func)
+}
+
+fn check_clean(repo: &Repository) -> Result<(), git2::Error> {
+    if !changed_paths(repo)?.is_empty() {
+        return Err(git2::Error::from_str(&format!(
+            "there are unstaged changes in {}",
+            repo.path().display()
+        )));
+    }
+    Ok(())
+}
+
+fn fetch_and_merge(
+    repo: &Repository,
+    remote: &mut Remote,
+    branch_name: &str,
+    opts: PullOptions,
+) -> Result<(), git2::Error> {
+    log::debug!(
+        "fetching branch {} from remote {}",
+        branch_name,
+        remote.url().unwrap_or("unknown")
+    );
+
+    network(|| remote.fetch(&[branch_name], Some(&mut fetch_options(false)), None))?;
+
+    if let Ok(fetch_head) = repo.find_reference("FETCH_HEAD") {
+        let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
+        log::debug!("merging remote commit {}", fetch_commit.id());
+        merge(
+            repo,
+            branch_name,
+            fetch_commit,
+            remote.url().unwrap_or("unknown"),
+            opts,
+        )?;
+    }
+    Ok(())
 }
 
 fn get_origin_url(repo: &Repository) -> Result<String, String> {
@@ -360,10 +2370,18 @@ fn get_origin_url(repo: &Repository) -> Result<String, String> {
 }
 
 fn get_remote<'a>(repo: &'a Repository, url: Option<&str>) -> Result<Remote<'a>, git2::Error> {
+    get_named_remote(repo, "origin", url)
+}
+
+fn get_named_remote<'a>(
+    repo: &'a Repository,
+    name: &str,
+    url: Option<&str>,
+) -> Result<Remote<'a>, git2::Error> {
     match url {
         Some(url) => {
-            log::trace!("using remote {}", url);
-            if let Ok(remote) = repo.find_remote("origin") {
+            log::trace!("using remote {} at {}", name, url);
+            if let Ok(remote) = repo.find_remote(name) {
                 if let Some(remote_url) = remote.url() {
                     return match remote_url.eq(url) {
                         true => {
@@ -372,28 +2390,222 @@ fn get_remote<'a>(repo: &'a Repository, url: Option<&str>) -> Result<Remote<'a>,
                         }
                         false => {
                             log::trace!("remote {} does not match; overwriting", remote_url);
-                            repo.remote_set_url("origin", url)?;
-                            repo.find_remote("origin")
+                            repo.remote_set_url(name, url)?;
+                            repo.find_remote(name)
                         }
                     };
                 }
             }
-            repo.remote("origin", url)
+            repo.remote(name, url)
+        }
+        None => repo.find_remote(name),
+    }
+}
+
+/// Times a fetch, push or clone, retrying it with exponential backoff on network errors
+fn network<T>(mut operation: impl FnMut() -> Result<T, git2::Error>) -> Result<T, git2::Error> {
+    let attempts = RETRY_ATTEMPTS.load(Ordering::Relaxed);
+    let mut backoff = Duration::from_millis(RETRY_BACKOFF_MILLIS.load(Ordering::Relaxed));
+    let mut attempt = 1;
+    loop {
+        match timings::time(Phase::Network, &mut operation) {
+            Err(err) if attempt < attempts && is_transient(&err) => {
+                log::warn!(
+                    "{}, retrying in {}s ({}/{})",
+                    err.message(),
+                    backoff.as_secs_f32(),
+                    attempt,
+                    attempts - 1
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
         }
-        None => repo.find_remote("origin"),
     }
 }
 
+/// Runs the job for every item on a bounded pool of threads, returning the results in order
+///
+/// The threads time their own phases, so waiting for them is not timed.
+fn parallel<T: Send, R: Send>(items: Vec<T>, job: impl Fn(T) -> R + Sync) -> Vec<R> {
+    let count = items.len();
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let results = Mutex::new((0..count).map(|_| None).collect::<Vec<Option<R>>>());
+    timings::untimed(|| {
+        thread::scope(|scope| {
+            for _ in 0..count.min(SUBMODULE_JOBS) {
+                scope.spawn(|| loop {
+                    let Some((index, item)) = queue.lock().ok().and_then(|mut queue| queue.next())
+                    else {
+                        break;
+                    };
+                    let result = job(item);
+                    if let Ok(mut results) = results.lock() {
+                        results[index] = Some(result);
+                    }
+                });
+            }
+        })
+    });
+    results
+        .into_inner()
+        .unwrap_or_else(|err| err.into_inner())
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Connection failures and timeouts, but not rejected credentials, certificates or pushes
+fn is_transient(err: &git2::Error) -> bool {
+    matches!(
+        err.class(),
+        ErrorClass::Net | ErrorClass::Os | ErrorClass::Ssh | ErrorClass::Ssl
+    ) && !matches!(err.code(), ErrorCode::Auth | ErrorCode::Certificate)
+}
+
+/// Options for one attempt at a fetch, as the credential callbacks only offer each credential once
+fn fetch_options<'a>(shallow: bool) -> FetchOptions<'a> {
+    let mut fetch_opts = FetchOptions::new();
+    fetch_opts.remote_callbacks(create_callbacks());
+    fetch_opts.proxy_options(proxy_options());
+    if shallow {
+        fetch_opts.depth(1);
+    }
+    fetch_opts
+}
+
+/// The configured proxy, or otherwise http.proxy and the http_proxy and https_proxy variables
+fn proxy_options<'a>() -> ProxyOptions<'a> {
+    let mut proxy_opts = ProxyOptions::new();
+    match PROXY.read().ok().and_then(|proxy| proxy.clone()) {
+        Some(url) => proxy_opts.url(&url),
+        None => proxy_opts.auto(),
+    };
+    proxy_opts
+}
+
 fn create_callbacks<'a>() -> RemoteCallbacks<'a> {
     let mut callbacks = RemoteCallbacks::new();
+    // libgit2 asks again after each rejected credential, so each one is only offered once
+    let mut tried_agent = false;
+    let mut ssh_keys = ssh_keys().into_iter();
+    let mut tried_token = false;
+    let mut tried_helper = false;
     callbacks.credentials(
-        |url: &str, username_from_url: Option<&str>, _cred: CredentialType| {
-            Cred::credential_helper(&Config::open_default()?, url, username_from_url)
+        move |url: &str, username_from_url: Option<&str>, allowed: CredentialType| {
+            let username = username_from_url
+                .map(str::to_owned)
+                .or_else(|| username_from(url))
+                .unwrap_or_else(|| "git".to_owned());
+            if allowed.contains(CredentialType::USERNAME) {
+                return Cred::username(&username);
+            }
+            if allowed.contains(CredentialType::SSH_KEY) {
+                if !tried_agent && std::env::var_os("SSH_AUTH_SOCK").is_some() {
+                    tried_agent = true;
+                    log::trace!("trying ssh-agent for {}", url);
+                    return Cred::ssh_key_from_agent(&username);
+                }
+                if let Some(key) = ssh_keys.next() {
+                    log::trace!("trying ssh key {} for {}", key.display(), url);
+                    let passphrase = std::env::var("DOTTY_SSH_PASSPHRASE").ok();
+                    return Cred::ssh_key(&username, None, &key, passphrase.as_deref());
+                }
+            }
+            if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && !tried_token {
+                tried_token = true;
+                if let Some((username, token)) = https_token(url, username_from_url) {
+                    log::trace!("trying token from the environment for {}", url);
+                    return Cred::userpass_plaintext(&username, &token);
+                }
+            }
+            if !tried_helper {
+                tried_helper = true;
+                return Cred::credential_helper(&Config::open_default()?, url, username_from_url);
+            }
+            Err(git2::Error::from_str(&format!(
+                "no credentials were accepted for {}, see --ssh-key",
+                url
+            )))
         },
     );
+    // https certificates are left to libgit2
+    callbacks.certificate_check(|cert, host| {
+        let Some(hostkey) = cert.as_hostkey() else {
+            return Ok(CertificateCheckStatus::CertificatePassthrough);
+        };
+        match (hostkey.hostkey_type(), hostkey.hostkey()) {
+            (Some(key_type), Some(key)) => known_hosts::check(host, key_type.name(), key)
+                .map(|_| CertificateCheckStatus::CertificateOk)
+                .map_err(|err| git2::Error::from_str(&err)),
+            _ => Ok(CertificateCheckStatus::CertificatePassthrough),
+        }
+    });
     callbacks
 }
 
+/// The configured key, or otherwise the default keys that exist
+fn ssh_keys() -> Vec<PathBuf> {
+    if let Some(key) = ssh_key() {
+        return vec![key];
+    }
+    let Ok(ssh_dir) = super::path::expand_home(Path::new("~/.ssh")) else {
+        return Vec::new();
+    };
+    DEFAULT_SSH_KEYS
+        .iter()
+        .map(|name| ssh_dir.join(name))
+        .filter(|key| key.exists())
+        .collect()
+}
+
+/// DOTTY_GIT_TOKEN for the configured token hosts, otherwise GITHUB_TOKEN or GITLAB_TOKEN for their
+/// own hosts only, and never over plain http
+fn https_token(url: &str, username: Option<&str>) -> Option<(String, String)> {
+    let host = https_host(url)?;
+    if let Some(token) = std::env::var("DOTTY_GIT_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+    {
+        let allowed = TOKEN_HOSTS.read().is_ok_and(|hosts| {
+            hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(&host))
+        });
+        match allowed {
+            true => return Some((username.unwrap_or("x-access-token").to_owned(), token)),
+            false => log::debug!("not sending DOTTY_GIT_TOKEN to {}, see token_hosts", host),
+        }
+    }
+    let hosted = hosting::parse(url)?;
+    let token = hosting::token(hosted.provider)?;
+    let default_username = match hosted.provider {
+        Provider::GitHub => "x-access-token",
+        Provider::GitLab => "oauth2",
+    };
+    Some((username.unwrap_or(default_username).to_owned(), token))
+}
+
+/// The host of an https url, without the user or port
+fn https_host(url: &str) -> Option<String> {
+    let rest = url.strip_prefix("https://")?;
+    let authority = rest.split('/').next()?;
+    let host = authority.rsplit('@').next()?.split(':').next()?;
+    Some(host.to_lowercase()).filter(|host| !host.is_empty())
+}
+
+/// The user of ssh://user@host/path and user@host:path urls
+fn username_from(url: &str) -> Option<String> {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', ':']).next()?;
+    authority
+        .split_once('@')
+        .map(|(user, _)| user.to_owned())
+        .filter(|user| !user.is_empty())
+}
+
 fn find_last_commit(repo: &Repository) -> Result<Option<Commit<'_>>, git2::Error> {
     match repo.head() {
         Ok(head) => Ok(Some(head.resolve()?.peel_to_commit()?)),
@@ -416,22 +2628,33 @@ fn get_branch_name(repo: &Repository) -> Result<String, git2::Error> {
         })
 }
 
-fn stage_path_recursive(index: &mut Index, path: &Path) -> Result<(), git2::Error> {
-    if path.is_dir() {
-        if Repository::open(path).is_ok() {
+fn stage_path_recursive(
+    repo: &Repository,
+    index: &mut Index,
+    path: &Path,
+    key: Option<&Key>,
+) -> Result<(), git2::Error> {
+    // paths are relative to the working tree, which need not be the current directory
+    let full_path = workdir(repo)?.join(path);
+    if full_path.is_dir() {
+        if Repository::open(&full_path).is_ok() {
             log::trace!("staging git submodule {}", path.display());
             index.add_path(path)?;
             return Ok(());
         }
 
         log::trace!("staging dir contents {}", path.display());
-        match fs::read_dir(path) {
+        match fs::read_dir(&full_path) {
             Ok(entries) => {
                 for entry_res in entries {
                     match entry_res {
                         Ok(entry) => {
-                            let path = entry.path();
-                            stage_path_recursive(index, &path)?;
+                            let path = path.join(entry.file_name());
+                            if repo.is_path_ignored(&path)? {
+                                log::trace!("not staging ignored {}", path.display());
+                                continue;
+                            }
+                            stage_path_recursive(repo, index, &path, key)?;
                         }
                         Err(err) => {
                             return Err(git2::Error::from_str(&format!(
@@ -452,8 +2675,187 @@ fn stage_path_recursive(index: &mut Index, path: &Path) -> Result<(), git2::Erro
             }
         }
     } else {
-        log::trace!("staging file {}", path.display());
-        index.add_path(path)?;
+        match key {
+            Some(key) if crypt::should_encrypt(path) => stage_encrypted(repo, index, path, key)?,
+            _ => {
+                log::trace!("staging file {}", path.display());
+                index.add_path(path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn stage_encrypted(
+    repo: &Repository,
+    index: &mut Index,
+    path: &Path,
+    key: &Key,
+) -> Result<(), git2::Error> {
+    log::trace!("staging encrypted file {}", path.display());
+    let full_path = workdir(repo)?.join(path);
+    let (plaintext, metadata) = match fs::read(&full_path)
+        .and_then(|plaintext| Ok((plaintext, fs::metadata(&full_path)?)))
+    {
+        Ok(read) => read,
+        Err(err) => {
+            return Err(git2::Error::from_str(&format!(
+                "could not read {} - {}",
+                full_path.display(),
+                err
+            )))
+        }
+    };
+    let ciphertext = key
+        .encrypt(&plaintext)
+        .map_err(|e| git2::Error::from_str(&e))?;
+    let entry = IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode: match platform::mode(&metadata) & 0o111 {
+            0 => 0o100644,
+            _ => 0o100755,
+        },
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id: Oid::zero(),
+        flags: 0,
+        flags_extended: 0,
+        path: platform::path_bytes(path),
+    };
+    index.add_frombuffer(&entry, &ciphertext)
+}
+
+fn load_crypt_key(repo: &Repository) -> Result<Option<Key>, git2::Error> {
+    crypt::load_key(repo.path()).map_err(|e| git2::Error::from_str(&e))
+}
+
+fn workdir(repo: &Repository) -> Result<&Path, git2::Error> {
+    repo.workdir().ok_or_else(|| {
+        git2::Error::from_str(&format!(
+            "git repository {} has no working directory",
+            repo.path().display()
+        ))
+    })
+}
+
+fn changed_paths(repo: &Repository) -> Result<Vec<String>, git2::Error> {
+    let key = load_crypt_key(repo)?;
+    let mut changed = Vec::new();
+    for entry in repo.statuses(None)?.iter() {
+        // the default options include ignored files like dotty.local.yaml
+        if entry.status() == Status::IGNORED {
+            continue;
+        }
+        let path = entry.path().unwrap_or_default().to_owned();
+        if let Some(key) = &key {
+            if entry.status() == Status::WT_MODIFIED
+                && is_unchanged_encrypted(repo, key, Path::new(&path))?
+            {
+                continue;
+            }
+        }
+        changed.push(path);
+    }
+    Ok(changed)
+}
+
+/// Encrypted blobs never match the plaintext working copy, so compare against the re-encrypted file
+fn is_unchanged_encrypted(repo: &Repository, key: &Key, path: &Path) -> Result<bool, git2::Error> {
+    if !crypt::should_encrypt(path) {
+        return Ok(false);
+    }
+    let index = repo.index()?;
+    let Some(entry) = index.get_path(path, 0) else {
+        return Ok(false);
+    };
+    let Ok(plaintext) = fs::read(workdir(repo)?.join(path)) else {
+        return Ok(false);
+    };
+    let ciphertext = key
+        .encrypt(&plaintext)
+        .map_err(|e| git2::Error::from_str(&e))?;
+    Ok(Oid::hash_object(ObjectType::Blob, &ciphertext)? == entry.id)
+}
+
+/// Stores the contents of the file in the object database, see keep_blobs to keep them from gc
+pub fn write_blob(repo: &Repository, path: &Path) -> Result<String, String> {
+    match repo.blob_path(path) {
+        Ok(id) => Ok(id.to_string()),
+        Err(err) => Err(format!("failed to store {} - {}", path.display(), err)),
+    }
+}
+
+/// Keeps the blobs reachable from a commit under refs/dotty/baselines, so gc does not prune them
+///
+/// The commit has no parent, blobs no longer listed become unreachable with the commit before it.
+pub fn keep_blobs(repo: &Repository, ids: &[String]) -> Result<(), String> {
+    git_helper(
+        || {
+            let mut builder = repo.treebuilder(None)?;
+            for id in ids {
+                let oid = Oid::from_str(id)?;
+                if repo.find_blob(oid).is_ok() {
+                    builder.insert(id, oid, 0o100644)?;
+                }
+            }
+            let existing = repo.find_reference(BASELINES_REF).ok();
+            if builder.is_empty() {
+                if let Some(mut reference) = existing {
+                    reference.delete()?;
+                }
+                return Ok(());
+            }
+            let tree = repo.find_tree(builder.write()?)?;
+            let unchanged = existing
+                .and_then(|reference| reference.peel_to_tree().ok())
+                .is_some_and(|kept| kept.id() == tree.id());
+            if !unchanged {
+                let sig = repo.signature()?;
+                let oid = repo.commit(None, &sig, &sig, "dotty: baselines", &tree, &[])?;
+                repo.reference(BASELINES_REF, oid, true, "dotty: baselines")?;
+            }
+            Ok(())
+        },
+        |err| {
+            format!(
+                "failed to keep the baselines in {} - {}",
+                BASELINES_REF, err
+            )
+        },
+    )
+}
+
+/// The contents of the blob, or none once it is no longer in the object database
+pub fn read_blob(repo: &Repository, id: &str) -> Option<Vec<u8>> {
+    let id = Oid::from_str(id).ok()?;
+    repo.find_blob(id).ok().map(|blob| blob.content().to_vec())
+}
+
+fn decrypt_checkout(repo: &Repository) -> Result<(), git2::Error> {
+    let Some(key) = load_crypt_key(repo)? else {
+        return Ok(());
+    };
+    let workdir = workdir(repo)?;
+    for entry in repo.index()?.iter() {
+        let path = workdir.join(platform::path_from_bytes(&entry.path));
+        let Ok(data) = fs::read(&path) else {
+            continue;
+        };
+        if crypt::is_encrypted(&data) {
+            log::trace!("decrypting {}", path.display());
+            let plaintext = key.decrypt(&data).map_err(|e| git2::Error::from_str(&e))?;
+            if let Err(err) = fs::write(&path, plaintext) {
+                return Err(git2::Error::from_str(&format!(
+                    "could not write {} - {}",
+                    path.display(),
+                    err
+                )));
+            }
+        }
     }
     Ok(())
 }
@@ -463,6 +2865,7 @@ fn merge(
     branch: &str,
     fetch_commit: AnnotatedCommit,
     remote_url: &str,
+    opts: PullOptions,
 ) -> Result<(), git2::Error> {
     let analysis = repo.merge_analysis(&[&fetch_commit])?;
 
@@ -470,10 +2873,15 @@ fn merge(
         log::trace!("doing a fast forward");
         let mut reference = repo.find_reference(&format!("refs/heads/{}", branch))?;
         fast_forward(repo, &mut reference, &fetch_commit)?;
+    } else if analysis.0.is_normal()
+        && opts.rebase
+        && rebase_onto(repo, branch, &fetch_commit, opts.strategy)?
+    {
+        log::trace!("rebased local commits");
     } else if analysis.0.is_normal() {
         log::trace!("doing a normal merge");
         let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
-        normal_merge(repo, &head_commit, &fetch_commit, remote_url)?;
+        normal_merge(repo, &head_commit, &fetch_commit, remote_url, opts.strategy)?;
     } else {
         log::trace!("no merge needed");
     }
@@ -496,16 +2904,77 @@ fn fast_forward(
     )?;
     repo.set_head(&name)?;
     repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    decrypt_checkout(repo)?;
     Ok(())
 }
 
+/// Rebases the local commits onto the remote commit in memory, leaving everything as it was on conflict
+fn rebase_onto(
+    repo: &Repository,
+    branch: &str,
+    upstream: &AnnotatedCommit,
+    strategy: MergeStrategy,
+) -> Result<bool, git2::Error> {
+    if repo.config()?.get_bool("commit.gpgsign").unwrap_or(false) {
+        log::debug!("not rebasing as rebased commits are not signed, merging instead");
+        return Ok(false);
+    }
+    let local = repo.reference_to_annotated_commit(&repo.head()?)?;
+    log::debug!("rebase {} onto {}", local.id(), upstream.id());
+    let mut opts = RebaseOptions::new();
+    opts.inmemory(true);
+    // the local commits being replayed are their side of a rebase
+    opts.merge_options(merge_options(match strategy {
+        MergeStrategy::Ours => MergeStrategy::Theirs,
+        MergeStrategy::Theirs => MergeStrategy::Ours,
+        strategy => strategy,
+    }));
+    let mut rebase = repo.rebase(Some(&local), Some(upstream), None, Some(&mut opts))?;
+    let sig = repo.signature()?;
+    let mut rebased = upstream.id();
+    while let Some(operation) = rebase.next() {
+        let operation = operation?;
+        if rebase.inmemory_index()?.has_conflicts() {
+            log::warn!(
+                "conflict rebasing commit {}, merging instead",
+                operation.id()
+            );
+            rebase.abort()?;
+            return Ok(false);
+        }
+        match rebase.commit(None, &sig, None) {
+            Ok(id) => rebased = id,
+            Err(err) if err.code() == ErrorCode::Applied => {
+                log::trace!("commit {} is already upstream", operation.id())
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    rebase.finish(Some(&sig))?;
+
+    let mut reference = repo.find_reference(&format!("refs/heads/{}", branch))?;
+    reference.set_target(
+        rebased,
+        &format!("Rebase: Setting {} onto id: {}", branch, upstream.id()),
+    )?;
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    decrypt_checkout(repo)?;
+    Ok(true)
+}
+
 fn normal_merge(
     repo: &Repository,
     local: &AnnotatedCommit,
     remote: &AnnotatedCommit,
     remote_url: &str,
+    strategy: MergeStrategy,
 ) -> Result<(), git2::Error> {
-    log::debug!("merge {} into {}", remote.id(), local.id());
+    log::debug!(
+        "merge {} into {} with strategy {:?}",
+        remote.id(),
+        local.id(),
+        strategy
+    );
     let local_commit = repo.find_commit(local.id())?;
     let remote_commit = repo.find_commit(remote.id())?;
     let ancestor = repo
@@ -515,54 +2984,380 @@ fn normal_merge(
         &ancestor,
         &local_commit.tree()?,
         &remote_commit.tree()?,
-        None,
+        Some(&merge_options(strategy)),
     )?;
     if idx.has_conflicts() {
-        repo.checkout_index(Some(&mut idx), None)?;
-        return Err(git2::Error::from_str("merge conficts detected"));
+        let mut conflicted = Vec::new();
+        for conflict in idx.conflicts()? {
+            let conflict = conflict?;
+            if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+                conflicted.push(String::from_utf8_lossy(&entry.path).into_owned());
+            }
+        }
+        repo.checkout_index(
+            Some(&mut idx),
+            Some(
+                CheckoutBuilder::new()
+                    .allow_conflicts(true)
+                    .conflict_style_merge(true),
+            ),
+        )?;
+        decrypt_checkout(repo)?;
+        repo.reference(
+            "MERGE_HEAD",
+            remote.id(),
+            true,
+            &format!("Merge {}", remote_url),
+        )?;
+        return Err(git2::Error::from_str(&format!(
+            "merge conficts detected in {}, resolve the conflict markers in {} then use dotty commit",
+            conflicted.join(", "),
+            workdir(repo)?.display()
+        )));
     }
     let result_tree = repo.find_tree(idx.write_tree_to(repo)?)?;
     let sig = repo.signature()?;
-    let _merge_commit = repo.commit(
-        Some("HEAD"),
-        &sig,
+    create_commit(
+        repo,
         &sig,
         &format!("Merge {}", remote_url),
         &result_tree,
         &[&local_commit, &remote_commit],
     )?;
     repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    decrypt_checkout(repo)?;
     Ok(())
 }
 
+/// Writes the identity commits are made as, and how they are signed, to the repository git config
+pub fn set_identity(
+    repo: &Repository,
+    name: Option<&str>,
+    email: Option<&str>,
+    signing_format: Option<&str>,
+    signing_key: Option<&str>,
+) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "set the commit identity of {}",
+        repo.path().display()
+    )) {
+        return Ok(());
+    }
+    git_helper(
+        || {
+            let mut config = repo.config()?.open_level(git2::ConfigLevel::Local)?;
+            if let Some(name) = name {
+                config.set_str("user.name", name)?;
+            }
+            if let Some(email) = email {
+                config.set_str("user.email", email)?;
+            }
+            if let Some(format) = signing_format {
+                log::debug!("signing commits with {}", format);
+                config.set_bool("commit.gpgsign", true)?;
+                config.set_str("gpg.format", format)?;
+            }
+            if let Some(key) = signing_key {
+                config.set_str("user.signingkey", key)?;
+            }
+            Ok(())
+        },
+        |err| {
+            format!(
+                "failed to set the commit identity of git repository {} - {}",
+                repo.path().display(),
+                err
+            )
+        },
+    )
+}
+
+/// Commits to HEAD with the dotty trailer, signing the commit when commit.gpgsign is set like git does
+fn create_commit(
+    repo: &Repository,
+    sig: &git2::Signature,
+    message: &str,
+    tree: &Tree,
+    parents: &[&Commit],
+) -> Result<Oid, git2::Error> {
+    let message = &format!("{}\n\n{}", message.trim_end(), TRAILER);
+    let config = repo.config()?;
+    if !config.get_bool("commit.gpgsign").unwrap_or(false) {
+        return repo.commit(Some("HEAD"), sig, sig, message, tree, parents);
+    }
+    let buffer = repo.commit_create_buffer(sig, sig, message, tree, parents)?;
+    let content = buffer
+        .as_str()
+        .ok_or_else(|| git2::Error::from_str("commit is not valid UTF-8"))?;
+    let signature = sign(&config, content.as_bytes())?;
+    let oid = repo.commit_signed(content, &signature, None)?;
+
+    // commit_signed leaves updating the branch to the caller
+    let head = repo.find_reference("HEAD")?;
+    let branch = match head.symbolic_target() {
+        Some(branch) => branch.to_owned(),
+        None => "HEAD".to_owned(),
+    };
+    let summary = message.lines().next().unwrap_or_default();
+    repo.reference(&branch, oid, true, &format!("commit: {}", summary))?;
+    Ok(oid)
+}
+
+/// Signs the data with gpg or ssh-keygen, as set by gpg.format
+fn sign(config: &Config, data: &[u8]) -> Result<String, git2::Error> {
+    let format = config
+        .get_string("gpg.format")
+        .unwrap_or_else(|_| "openpgp".to_owned());
+    let key = config.get_string("user.signingkey").ok();
+    let mut cmd = match format.as_str() {
+        "ssh" => {
+            let Some(key) = key else {
+                return Err(git2::Error::from_str(
+                    "signing with ssh needs user.signingkey to be set",
+                ));
+            };
+            let program = config
+                .get_string("gpg.ssh.program")
+                .unwrap_or_else(|_| "ssh-keygen".to_owned());
+            let mut cmd = Command::new(program);
+            cmd.args(["-Y", "sign", "-n", "git", "-f"]).arg(key);
+            cmd
+        }
+        "openpgp" => {
+            let program = config
+                .get_string("gpg.program")
+                .unwrap_or_else(|_| "gpg".to_owned());
+            let mut cmd = Command::new(program);
+            cmd.args(["--status-fd=2", "-bsa"]);
+            if let Some(key) = key {
+                cmd.args(["-u", &key]);
+            }
+            cmd
+        }
+        format => {
+            return Err(git2::Error::from_str(&format!(
+                "unsupported gpg.format {}",
+                format
+            )))
+        }
+    };
+    log::trace!("signing commit with {}", format);
+    let result = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(data)?;
+            }
+            child.wait_with_output()
+        });
+    match result {
+        Ok(output) if output.status.success() => {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+        Ok(output) => Err(git2::Error::from_str(&format!(
+            "failed to sign commit - {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+        Err(err) => Err(git2::Error::from_str(&format!(
+            "failed to sign commit - {}",
+            err
+        ))),
+    }
+}
+
+fn merge_options(strategy: MergeStrategy) -> MergeOptions {
+    let mut opts = MergeOptions::new();
+    match strategy {
+        MergeStrategy::Manual | MergeStrategy::Tool => {}
+        MergeStrategy::Ours => {
+            opts.file_favor(FileFavor::Ours);
+        }
+        MergeStrategy::Theirs => {
+            opts.file_favor(FileFavor::Theirs);
+        }
+    }
+    opts
+}
+
+fn find_submodule<'a>(
+    repo: &'a Repository,
+    selected: &str,
+) -> Result<git2::Submodule<'a>, git2::Error> {
+    repo.submodules()?
+        .into_iter()
+        .find(|submodule| {
+            submodule.name() == Some(selected)
+                || submodule.path() == Path::new(selected.trim_end_matches('/'))
+        })
+        .ok_or_else(|| git2::Error::from_str(&format!("there is no submodule {}", selected)))
+}
+
+/// Fetches a submodule and checks out its pin or fast-forwards its default branch
+fn update_submodule(submodule_repo: &Repository, pin: Option<&str>) -> Result<(), git2::Error> {
+    let mut remote = get_remote(submodule_repo, None)?;
+
+    network(|| {
+        remote
+            .connect_auth(
+                git2::Direction::Fetch,
+                Some(create_callbacks()),
+                Some(proxy_options()),
+            )
+            .map(|_| ())
+    })?;
+
+    let shallow = submodule_repo.is_shallow();
+    if let Some(pin) = pin {
+        checkout_pin(submodule_repo, &mut remote, shallow, pin)?;
+        return remote.disconnect();
+    }
+
+    let default_branch_buf = remote.default_branch()?;
+    let default_branch_ref_name = default_branch_buf.as_str().unwrap_or("refs/heads/main");
+
+    log::trace!(
+        "using branch {} for submodule {}",
+        default_branch_ref_name,
+        submodule_repo.path().display()
+    );
+
+    network(|| {
+        remote.fetch(
+            &[default_branch_ref_name] as &[&str],
+            Some(&mut fetch_options(shallow)),
+            None,
+        )
+    })?;
+
+    let fetch_head = submodule_repo.find_reference("FETCH_HEAD")?;
+    let fetch_commit = submodule_repo.reference_to_annotated_commit(&fetch_head)?;
+
+    // clones that fell back from a shallow fetch have no local branches
+    let mut branch_reference = match submodule_repo.find_reference(default_branch_ref_name) {
+        Err(err) if err.code() == ErrorCode::NotFound => submodule_repo.reference(
+            default_branch_ref_name,
+            fetch_commit.id(),
+            false,
+            "creating default branch",
+        )?,
+        result => result?,
+    };
+    fast_forward(submodule_repo, &mut branch_reference, &fetch_commit)?;
+
+    remote.disconnect()?;
+
+    remote.update_tips(
+        None,
+        RemoteUpdateFlags::UPDATE_FETCHHEAD,
+        AutotagOption::Unspecified,
+        None,
+    )
+}
+
 fn update_submodules_recursive(
     repo: &Repository,
     init: bool,
-    opts: &mut SubmoduleUpdateOptions,
+    shallow: bool,
 ) -> Result<(), git2::Error> {
     fn add_subrepos(
         repo: &Repository,
-        repos: &mut Vec<Repository>,
+        repos: &mut Vec<PathBuf>,
         init: bool,
-        opts: &mut SubmoduleUpdateOptions,
+        shallow: bool,
     ) -> Result<(), git2::Error> {
+        let mut jobs = Vec::new();
         for mut subm in repo.submodules()? {
+            // initializing writes to the shared config, so it happens before the parallel fetches
+            if init {
+                subm.init(false)?;
+            }
+            let name = subm.name().unwrap_or("unknown").to_owned();
+            let shallow = shallow || is_shallow_submodule(repo, &name);
+            jobs.push((name, shallow));
+        }
+
+        let parent = repo.path();
+        let results = parallel(jobs, |(name, shallow)| {
             log::trace!(
-                "updating submodule {} (init: {}, in: {})",
-                subm.name().unwrap_or("unknown"),
+                "updating submodule {} (init: {}, shallow: {}, in: {})",
+                name,
                 init,
-                repo.path().display(),
+                shallow,
+                parent.display(),
             );
-            subm.update(init, Some(opts))?;
-            repos.push(subm.open()?);
+            let result =
+                Repository::open(parent).and_then(|repo| update_subrepo(&repo, &name, shallow));
+            (name, result)
+        });
+        for (name, result) in results {
+            let path = result.map_err(|err| {
+                git2::Error::from_str(&format!("submodule {} - {}", name, err.message()))
+            })?;
+            repos.push(path);
         }
         Ok(())
     }
 
+    fn update_subrepo(
+        repo: &Repository,
+        name: &str,
+        shallow: bool,
+    ) -> Result<PathBuf, git2::Error> {
+        let mut subm = repo.find_submodule(name)?;
+        let mut update = |shallow| subm.update(false, Some(&mut submodule_update_options(shallow)));
+        // shallow fetches are not retried, as servers may not hand out a recorded commit that
+        // is no longer a branch tip and local remotes do not support them at all
+        let updated = match shallow {
+            true => timings::time(Phase::Network, || update(true)),
+            false => network(|| update(false)),
+        };
+        match updated {
+            Err(err) if shallow => {
+                log::warn!(
+                    "failed to fetch only the latest commit of submodule {}, fetching all of it - {}",
+                    name,
+                    err.message()
+                );
+                network(|| update(false))?;
+            }
+            result => result?,
+        }
+        subm.open()?
+            .workdir()
+            .map(Path::to_path_buf)
+            .ok_or_else(|| git2::Error::from_str("submodule has no working directory"))
+    }
+
     let mut repos = Vec::new();
-    add_subrepos(repo, &mut repos, init, opts)?;
-    while let Some(repo) = repos.pop() {
-        add_subrepos(&repo, &mut repos, init, opts)?;
+    add_subrepos(repo, &mut repos, init, shallow)?;
+    while let Some(path) = repos.pop() {
+        add_subrepos(&Repository::open(path)?, &mut repos, init, shallow)?;
     }
     Ok(())
 }
+
+fn submodule_update_options<'a>(shallow: bool) -> SubmoduleUpdateOptions<'a> {
+    let mut checkout_builder = CheckoutBuilder::new();
+    checkout_builder.force();
+
+    let mut opts = SubmoduleUpdateOptions::new();
+    opts.checkout(checkout_builder);
+    opts.fetch(fetch_options(shallow));
+    opts.allow_fetch(true);
+    opts
+}
+
+/// Whether .gitmodules sets shallow = true for the submodule, like git clone --recurse-submodules
+fn is_shallow_submodule(repo: &Repository, name: &str) -> bool {
+    repo.workdir()
+        .and_then(|workdir| Config::open(&workdir.join(".gitmodules")).ok())
+        .and_then(|gitmodules| {
+            gitmodules
+                .get_bool(&format!("submodule.{}.shallow", name))
+                .ok()
+        })
+        .unwrap_or(false)
+}
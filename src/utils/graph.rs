@@ -0,0 +1,51 @@
+use std::collections::BTreeSet;
+
+/// Orders nodes so each comes after the nodes it depends on, otherwise keeping their
+/// original order. Edges are `(dependency, dependent)`. Returns a cycle, each node
+/// depending on the next, if there is one
+pub fn topological_order(nodes: usize, edges: &[(usize, usize)]) -> Result<Vec<usize>, Vec<usize>> {
+    let mut dependents = vec![Vec::new(); nodes];
+    let mut dependencies = vec![Vec::new(); nodes];
+    let mut remaining = vec![0; nodes];
+    for &(dependency, dependent) in edges {
+        dependents[dependency].push(dependent);
+        dependencies[dependent].push(dependency);
+        remaining[dependent] += 1;
+    }
+
+    let mut ready: BTreeSet<usize> = (0..nodes).filter(|&n| remaining[n] == 0).collect();
+    let mut order = Vec::with_capacity(nodes);
+    while let Some(node) = ready.pop_first() {
+        order.push(node);
+        for &dependent in &dependents[node] {
+            remaining[dependent] -= 1;
+            if remaining[dependent] == 0 {
+                ready.insert(dependent);
+            }
+        }
+    }
+
+    if order.len() == nodes {
+        return Ok(order);
+    }
+    Err(find_cycle(&dependencies, &remaining))
+}
+
+/// Every unordered node has an unordered dependency, so following them must repeat
+fn find_cycle(dependencies: &[Vec<usize>], remaining: &[usize]) -> Vec<usize> {
+    let Some(start) = (0..remaining.len()).find(|&n| remaining[n] > 0) else {
+        return Vec::new();
+    };
+    let mut path = vec![start];
+    let mut node = start;
+    while let Some(&next) = dependencies[node].iter().find(|&&d| remaining[d] > 0) {
+        if let Some(position) = path.iter().position(|&n| n == next) {
+            let mut cycle = path.split_off(position);
+            cycle.push(next);
+            return cycle;
+        }
+        path.push(next);
+        node = next;
+    }
+    path
+}
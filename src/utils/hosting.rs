@@ -0,0 +1,222 @@
+use super::dry_run;
+use serde_json::{json, Value};
+use std::env;
+use std::fmt;
+use std::sync::RwLock;
+
+static GITLAB_HOSTS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Provider {
+    GitHub,
+    GitLab,
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Provider::GitHub => write!(f, "GitHub"),
+            Provider::GitLab => write!(f, "GitLab"),
+        }
+    }
+}
+
+/// A repository on a hosting provider, parsed from its clone url
+#[derive(Debug, PartialEq)]
+pub struct HostedRepo {
+    pub provider: Provider,
+    pub host: String,
+    /// The user, organization or group, which may be nested on GitLab
+    pub namespace: String,
+    pub name: String,
+}
+
+impl fmt::Display for HostedRepo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} on {}", self.namespace, self.name, self.provider)
+    }
+}
+
+/// Sets the self-hosted GitLab hosts, which are sent GITLAB_TOKEN like gitlab.com
+pub fn set_gitlab_hosts(hosts: Vec<String>) {
+    if let Ok(mut gitlab_hosts) = GITLAB_HOSTS.write() {
+        *gitlab_hosts = hosts;
+    }
+}
+
+/// Parses https, ssh and scp like urls of github.com, gitlab.com and the configured GitLab hosts
+pub fn parse(url: &str) -> Option<HostedRepo> {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("ssh://"))
+        .map(|rest| rest.split_once('/'))
+        .unwrap_or_else(|| url.split_once(':'))?;
+    let host = rest.0.rsplit('@').next()?.split(':').next()?.to_lowercase();
+    let path = rest.1.trim_end_matches('/').trim_end_matches(".git");
+    let (namespace, name) = path.rsplit_once('/')?;
+    if namespace.is_empty() || name.is_empty() {
+        return None;
+    }
+    let provider = match host.as_str() {
+        "github.com" => Provider::GitHub,
+        "gitlab.com" => Provider::GitLab,
+        host if is_gitlab_host(host) => Provider::GitLab,
+        _ => return None,
+    };
+    Some(HostedRepo {
+        provider,
+        host,
+        namespace: namespace.to_owned(),
+        name: name.to_owned(),
+    })
+}
+
+/// Whether the host is listed in gitlab_hosts, as tokens must not go to hosts that merely look like
+/// GitLab
+fn is_gitlab_host(host: &str) -> bool {
+    GITLAB_HOSTS
+        .read()
+        .is_ok_and(|hosts| hosts.iter().any(|listed| listed.eq_ignore_ascii_case(host)))
+}
+
+/// The API token from GITHUB_TOKEN or GH_TOKEN, or GITLAB_TOKEN
+pub fn token(provider: Provider) -> Option<String> {
+    let vars: &[&str] = match provider {
+        Provider::GitHub => &["GITHUB_TOKEN", "GH_TOKEN"],
+        Provider::GitLab => &["GITLAB_TOKEN"],
+    };
+    vars.iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|token| !token.is_empty())
+}
+
+pub fn exists(repo: &HostedRepo, token: &str) -> Result<bool, String> {
+    let url = match repo.provider {
+        Provider::GitHub => format!("{}/repos/{}/{}", api(repo), repo.namespace, repo.name),
+        Provider::GitLab => format!(
+            "{}/projects/{}",
+            api(repo),
+            encode(&format!("{}/{}", repo.namespace, repo.name))
+        ),
+    };
+    match request(repo, token, ureq::get(&url)).call() {
+        Ok(_) => Ok(true),
+        Err(ureq::Error::Status(404, _)) => Ok(false),
+        Err(err) => Err(format!("failed to look up repository {} - {}", repo, err)),
+    }
+}
+
+/// Creates a private repository, beneath the organization or group when it is not the user
+pub fn create(repo: &HostedRepo, token: &str) -> Result<(), String> {
+    if dry_run::skip(format_args!("create private repository {}", repo)) {
+        return Ok(());
+    }
+    log::debug!("creating private repository {}", repo);
+    let user = get(repo, token, &format!("{}/user", api(repo)))?;
+    let created = match repo.provider {
+        Provider::GitHub => {
+            let login = user["login"].as_str().unwrap_or_default();
+            let url = match login.eq_ignore_ascii_case(&repo.namespace) {
+                true => format!("{}/user/repos", api(repo)),
+                false => format!("{}/orgs/{}/repos", api(repo), repo.namespace),
+            };
+            request(repo, token, ureq::post(&url))
+                .send_json(json!({ "name": repo.name, "private": true }))
+        }
+        Provider::GitLab => {
+            let username = user["username"].as_str().unwrap_or_default();
+            let mut body = json!({
+                "name": repo.name,
+                "path": repo.name,
+                "visibility": "private",
+            });
+            if !username.eq_ignore_ascii_case(&repo.namespace) {
+                let url = format!("{}/namespaces/{}", api(repo), encode(&repo.namespace));
+                body["namespace_id"] = get(repo, token, &url)?["id"].clone();
+            }
+            request(repo, token, ureq::post(&format!("{}/projects", api(repo)))).send_json(body)
+        }
+    };
+    match created {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("failed to create repository {} - {}", repo, err)),
+    }
+}
+
+fn api(repo: &HostedRepo) -> String {
+    match repo.provider {
+        Provider::GitHub => "https://api.github.com".to_owned(),
+        Provider::GitLab => format!("https://{}/api/v4", repo.host),
+    }
+}
+
+fn request(repo: &HostedRepo, token: &str, request: ureq::Request) -> ureq::Request {
+    match repo.provider {
+        Provider::GitHub => request
+            .set("Accept", "application/vnd.github+json")
+            .set("Authorization", &format!("Bearer {}", token)),
+        Provider::GitLab => request.set("PRIVATE-TOKEN", token),
+    }
+    .set("User-Agent", "dotty")
+}
+
+fn get(repo: &HostedRepo, token: &str, url: &str) -> Result<Value, String> {
+    request(repo, token, ureq::get(url))
+        .call()
+        .map_err(|err| err.to_string())
+        .and_then(|response| response.into_json().map_err(|err| err.to_string()))
+        .map_err(|err| format!("failed to query {} - {}", url, err))
+}
+
+/// Paths are single segments in the GitLab API
+fn encode(path: &str) -> String {
+    path.replace('/', "%2F")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo(provider: Provider, host: &str, namespace: &str, name: &str) -> Option<HostedRepo> {
+        Some(HostedRepo {
+            provider,
+            host: host.to_owned(),
+            namespace: namespace.to_owned(),
+            name: name.to_owned(),
+        })
+    }
+
+    #[test]
+    fn parse_reads_https_ssh_and_scp_urls() {
+        let expected = repo(Provider::GitHub, "github.com", "user", "dotfiles");
+        assert_eq!(parse("https://github.com/user/dotfiles.git"), expected);
+        assert_eq!(parse("https://github.com/user/dotfiles/"), expected);
+        assert_eq!(parse("ssh://git@github.com:22/user/dotfiles"), expected);
+        assert_eq!(parse("git@GitHub.com:user/dotfiles.git"), expected);
+        assert_eq!(
+            parse("git@gitlab.com:group/sub/dotfiles.git"),
+            repo(Provider::GitLab, "gitlab.com", "group/sub", "dotfiles")
+        );
+    }
+
+    #[test]
+    fn parse_rejects_urls_without_namespace_and_name() {
+        assert_eq!(parse("https://github.com/dotfiles"), None);
+        assert_eq!(parse("git@github.com:/dotfiles"), None);
+        assert_eq!(parse("git@github.com:user/"), None);
+        assert_eq!(parse("/home/user/dotfiles"), None);
+    }
+
+    #[test]
+    fn parse_only_takes_listed_gitlab_hosts() {
+        assert_eq!(parse("https://gitlab.example.com/user/dotfiles"), None);
+        assert_eq!(parse("https://notgitlab.com/user/dotfiles"), None);
+        set_gitlab_hosts(vec!["GitLab.example.com".to_owned()]);
+        assert_eq!(
+            parse("https://gitlab.example.com/user/dotfiles"),
+            repo(Provider::GitLab, "gitlab.example.com", "user", "dotfiles")
+        );
+        assert_eq!(parse("https://notgitlab.com/user/dotfiles"), None);
+        set_gitlab_hosts(Vec::new());
+    }
+}
@@ -0,0 +1,227 @@
+use super::fs::PreserveTimes;
+use super::git::{self, MergeStrategy, SyncStages};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    Status {
+        root: PathBuf,
+        #[serde(default)]
+        profiles: Vec<String>,
+    },
+    Sync {
+        root: PathBuf,
+        url: Option<String>,
+        notify: bool,
+        #[serde(default)]
+        allow_secrets: bool,
+        #[serde(default)]
+        stages: SyncStages,
+        #[serde(default)]
+        rebase: bool,
+        #[serde(default)]
+        strategy: MergeStrategy,
+        #[serde(default)]
+        autocommit: bool,
+        #[serde(default)]
+        autostash: bool,
+        /// The --ssh-key of the client, used instead of the daemon's own
+        #[serde(default)]
+        ssh_key: Option<PathBuf>,
+        #[serde(default)]
+        accept_new: bool,
+        #[serde(default)]
+        profiles: Vec<String>,
+        /// Whether the client was run with --yes, as the daemon never assumes it on its own
+        #[serde(default)]
+        assume_yes: bool,
+    },
+    Restore {
+        root: PathBuf,
+        symlinks: bool,
+        overwrite: bool,
+        relabel: bool,
+        #[serde(default)]
+        profiles: Vec<String>,
+        #[serde(default)]
+        exclude: Vec<String>,
+        #[serde(default)]
+        preserve_times: Option<PreserveTimes>,
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default)]
+        locked: bool,
+        #[serde(default)]
+        prune: bool,
+        #[serde(default)]
+        ssh_key: Option<PathBuf>,
+        #[serde(default)]
+        accept_new: bool,
+        #[serde(default)]
+        assume_yes: bool,
+    },
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Response {
+    pub output: String,
+    pub error: Option<String>,
+}
+
+#[cfg(unix)]
+struct Socket {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub fn socket_path(repo: &Path) -> Result<PathBuf, String> {
+    Ok(git::git_dir(repo)?.join("dotty.sock"))
+}
+
+/// Sends a request to a running daemon, or returns none when no daemon is listening
+#[cfg(unix)]
+pub fn request(repo: &Path, request: &Request) -> Result<Option<Response>, String> {
+    // a daemon only listens in a repository that exists
+    let Ok(path) = socket_path(repo) else {
+        return Ok(None);
+    };
+    let mut stream = match UnixStream::connect(&path) {
+        Ok(stream) => stream,
+        Err(err)
+            if matches!(
+                err.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            log::trace!("no dotty daemon listening on {}", path.display());
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(format!(
+                "failed to connect to dotty daemon {} - {}",
+                path.display(),
+                err
+            ))
+        }
+    };
+    log::debug!("sending request to dotty daemon {}", path.display());
+
+    let response = write_message(&mut stream, request)
+        .and_then(|_| read_message::<Response>(&stream))
+        .map_err(|err| {
+            format!(
+                "failed to talk to dotty daemon {} - {}",
+                path.display(),
+                err
+            )
+        })?;
+    match response {
+        Some(response) => Ok(Some(response)),
+        None => Err(format!("dotty daemon {} closed connection", path.display())),
+    }
+}
+
+/// Sends a request to a running daemon, which needs unix sockets so is never listening on Windows
+#[cfg(windows)]
+pub fn request(_: &Path, _: &Request) -> Result<Option<Response>, String> {
+    Ok(None)
+}
+
+#[cfg(unix)]
+pub fn serve<F>(repo: &Path, mut handler: F) -> Result<(), String>
+where
+    F: FnMut(Request) -> Response,
+{
+    let path = socket_path(repo)?;
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            return Err(format!(
+                "dotty daemon is already listening on {}",
+                path.display()
+            ));
+        }
+        log::debug!("removing stale socket {}", path.display());
+        if let Err(err) = fs::remove_file(&path) {
+            return Err(format!("failed to remove {} - {}", path.display(), err));
+        }
+    }
+
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => return Err(format!("failed to listen on {} - {}", path.display(), err)),
+    };
+    let _socket = Socket { path: path.clone() };
+    log::info!("dotty daemon listening on {}", path.display());
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("failed to accept connection - {}", err);
+                continue;
+            }
+        };
+        let response = match read_message::<Request>(&stream) {
+            Ok(Some(request)) => {
+                log::debug!("handling {:?}", request);
+                handler(request)
+            }
+            Ok(None) => continue,
+            Err(err) => Response {
+                error: Some(format!("invalid request - {}", err)),
+                ..Default::default()
+            },
+        };
+        if let Err(err) = write_message(&mut stream, &response) {
+            log::warn!("failed to send response - {}", err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn serve<F>(repo: &Path, _: F) -> Result<(), String>
+where
+    F: FnMut(Request) -> Response,
+{
+    Err(format!(
+        "failed to listen on {} - the dotty daemon needs unix sockets",
+        socket_path(repo)?.display()
+    ))
+}
+
+#[cfg(unix)]
+fn write_message<T: Serialize>(stream: &mut UnixStream, message: &T) -> Result<(), String> {
+    let mut line = serde_json::to_string(message).map_err(|err| err.to_string())?;
+    line.push('\n');
+    stream
+        .write_all(line.as_bytes())
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(unix)]
+fn read_message<T: for<'de> Deserialize<'de>>(stream: &UnixStream) -> Result<Option<T>, String> {
+    let mut line = String::new();
+    match BufReader::new(stream).read_line(&mut line) {
+        Ok(0) => Ok(None),
+        Ok(_) => serde_json::from_str(&line)
+            .map(Some)
+            .map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
@@ -0,0 +1,268 @@
+use super::path;
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The known hosts of every user, which ssh also reads
+const SYSTEM_KNOWN_HOSTS: &str = "/etc/ssh/ssh_known_hosts";
+
+static ACCEPT_NEW: AtomicBool = AtomicBool::new(false);
+
+/// Trusts and records the keys of hosts that are not in known_hosts yet, like ssh's accept-new
+pub fn set_accept_new(accept: bool) {
+    ACCEPT_NEW.store(accept, Ordering::Relaxed);
+}
+
+pub fn accepts_new() -> bool {
+    ACCEPT_NEW.load(Ordering::Relaxed)
+}
+
+#[derive(Default)]
+struct Matches {
+    known: bool,
+    revoked: bool,
+    changed: bool,
+}
+
+/// Verifies the host key of an ssh remote against ~/.ssh/known_hosts and the system known hosts
+pub fn check(host: &str, key_type: &str, key: &[u8]) -> Result<(), String> {
+    let known_hosts = known_hosts_path()?;
+    let mut known = false;
+    let mut changed_in = None;
+    for file in [known_hosts.clone(), PathBuf::from(SYSTEM_KNOWN_HOSTS)] {
+        let matches = find(&file, host, key_type, key)?;
+        // like ssh, a revoked key is refused even when another file lists it
+        if matches.revoked {
+            return Err(format!(
+                "the host key of {} is marked as revoked in {}",
+                host,
+                file.display()
+            ));
+        }
+        if matches.known {
+            log::trace!("host key of {} found in {}", host, file.display());
+            known = true;
+        } else if matches.changed && changed_in.is_none() {
+            changed_in = Some(file);
+        }
+    }
+    if known {
+        return Ok(());
+    }
+    if let Some(changed_in) = changed_in {
+        return Err(format!(
+            "the {} host key of {} does not match the one in {}, which could mean someone is \
+             intercepting the connection. If the host key was changed on purpose, remove the old \
+             one with ssh-keygen -R {}",
+            key_type,
+            host,
+            changed_in.display(),
+            host
+        ));
+    }
+    if !ACCEPT_NEW.load(Ordering::Relaxed) {
+        return Err(format!(
+            "the host key of {} is not in {}, connect with ssh once to verify it or use --accept-new",
+            host,
+            known_hosts.display()
+        ));
+    }
+    add(&known_hosts, host, key_type, key)
+}
+
+/// How the lines of a known hosts file for the host compare to its key
+fn find(known_hosts: &Path, host: &str, key_type: &str, key: &[u8]) -> Result<Matches, String> {
+    let contents = match fs::read_to_string(known_hosts) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            return Err(format!(
+                "failed to read {} - {}",
+                known_hosts.display(),
+                err
+            ))
+        }
+    };
+    let mut matches = Matches::default();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace().peekable();
+        let marker = fields.next_if(|field| field.starts_with('@'));
+        let (Some(hosts), Some(line_type), Some(line_key)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        if marker == Some("@cert-authority") || !matches_host(hosts, host) {
+            continue;
+        }
+        let same_key = base64::decode_block(line_key).is_ok_and(|line_key| line_key == key);
+        match marker {
+            Some("@revoked") => matches.revoked |= same_key,
+            Some(_) => {}
+            None if same_key => matches.known = true,
+            None => matches.changed |= line_type == key_type,
+        }
+    }
+    Ok(matches)
+}
+
+fn add(known_hosts: &Path, host: &str, key_type: &str, key: &[u8]) -> Result<(), String> {
+    let line = format!("{} {} {}\n", host, key_type, base64::encode_block(key));
+    if let Some(parent) = known_hosts.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|err| format!("failed to create {} - {}", parent.display(), err))?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(known_hosts)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|err| {
+            format!(
+                "failed to add {} to {} - {}",
+                host,
+                known_hosts.display(),
+                err
+            )
+        })?;
+    log::warn!(
+        "permanently added the {} host key of {} to {}",
+        key_type,
+        host,
+        known_hosts.display()
+    );
+    Ok(())
+}
+
+fn known_hosts_path() -> Result<PathBuf, String> {
+    path::expand_home(Path::new("~/.ssh/known_hosts"))
+}
+
+/// Matches plain, [host]:port and hashed |1|salt|hash patterns, ignoring wildcards and negations
+///
+/// The port is unknown here, so hashed entries of hosts on other ports than 22 never match.
+fn matches_host(patterns: &str, host: &str) -> bool {
+    patterns.split(',').any(|pattern| {
+        if let Some(hashed) = pattern.strip_prefix("|1|") {
+            return matches_hashed(hashed, host);
+        }
+        let pattern = match pattern.strip_prefix('[') {
+            Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+            None => pattern,
+        };
+        pattern.eq_ignore_ascii_case(host)
+    })
+}
+
+fn matches_hashed(hashed: &str, host: &str) -> bool {
+    let Some((salt, hash)) = hashed.split_once('|') else {
+        return false;
+    };
+    let (Ok(salt), Ok(hash)) = (base64::decode_block(salt), base64::decode_block(hash)) else {
+        return false;
+    };
+    PKey::hmac(&salt)
+        .and_then(|pkey| {
+            let mut signer = Signer::new(MessageDigest::sha1(), &pkey)?;
+            signer.update(host.to_lowercase().as_bytes())?;
+            signer.sign_to_vec()
+        })
+        .is_ok_and(|signature| signature == hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"host key";
+    const OTHER_KEY: &[u8] = b"other host key";
+
+    /// A hashed pattern like the ones ssh-keygen -H writes
+    fn hashed(host: &str) -> String {
+        let salt = b"twenty bytes of salt";
+        let pkey = PKey::hmac(salt).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha1(), &pkey).unwrap();
+        signer.update(host.as_bytes()).unwrap();
+        format!(
+            "|1|{}|{}",
+            base64::encode_block(salt),
+            base64::encode_block(&signer.sign_to_vec().unwrap())
+        )
+    }
+
+    fn find_in(name: &str, contents: &str, key_type: &str, key: &[u8]) -> Matches {
+        let file =
+            std::env::temp_dir().join(format!("dotty-known-hosts-{}-{}", name, std::process::id()));
+        fs::write(&file, contents).unwrap();
+        let matches = find(&file, "github.com", key_type, key).unwrap();
+        fs::remove_file(&file).unwrap();
+        matches
+    }
+
+    #[test]
+    fn matches_host_reads_plain_and_bracketed_patterns() {
+        assert!(matches_host("github.com", "github.com"));
+        assert!(matches_host("140.82.121.4,GitHub.com", "github.com"));
+        assert!(matches_host("[git.example.com]:2222", "git.example.com"));
+        assert!(!matches_host("gitlab.com", "github.com"));
+        assert!(!matches_host("*.com,!github.com", "github.com"));
+    }
+
+    #[test]
+    fn matches_host_reads_hashed_patterns() {
+        assert!(matches_host(&hashed("github.com"), "github.com"));
+        assert!(matches_host(&hashed("github.com"), "GitHub.com"));
+        assert!(!matches_host(&hashed("github.com"), "gitlab.com"));
+        assert!(!matches_host("|1|not base64|", "github.com"));
+        assert!(!matches_host("|1|", "github.com"));
+    }
+
+    #[test]
+    fn find_knows_the_listed_key() {
+        let contents = format!(
+            "# comment\n\n{} ssh-ed25519 {}\n",
+            hashed("github.com"),
+            base64::encode_block(KEY)
+        );
+        let matches = find_in("known", &contents, "ssh-ed25519", KEY);
+        assert!(matches.known && !matches.changed && !matches.revoked);
+    }
+
+    #[test]
+    fn find_reports_changed_keys_of_the_same_type() {
+        let contents = format!(
+            "github.com ssh-ed25519 {}\n",
+            base64::encode_block(OTHER_KEY)
+        );
+        let matches = find_in("changed", &contents, "ssh-ed25519", KEY);
+        assert!(!matches.known && matches.changed);
+        let matches = find_in("other-type", &contents, "ssh-rsa", KEY);
+        assert!(!matches.known && !matches.changed);
+    }
+
+    #[test]
+    fn find_reports_revoked_keys_and_skips_cert_authorities() {
+        let key = base64::encode_block(KEY);
+        let contents = format!(
+            "@cert-authority github.com ssh-ed25519 {}\n@revoked github.com ssh-ed25519 {}\n",
+            key, key
+        );
+        let matches = find_in("revoked", &contents, "ssh-ed25519", KEY);
+        assert!(matches.revoked && !matches.known && !matches.changed);
+    }
+
+    #[test]
+    fn find_takes_a_missing_file_as_empty() {
+        let file = std::env::temp_dir().join("dotty-known-hosts-missing");
+        let matches = find(&file, "github.com", "ssh-ed25519", KEY).unwrap();
+        assert!(!matches.known && !matches.changed && !matches.revoked);
+    }
+}
@@ -0,0 +1,133 @@
+use super::dry_run;
+use super::path;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+pub fn is_supported() -> bool {
+    cfg!(target_os = "macos")
+}
+
+pub fn agent_path(label: &str) -> Result<PathBuf, String> {
+    path::canonicalize(&Path::new("~/Library/LaunchAgents").join(format!("{}.plist", label)))
+}
+
+pub fn install(
+    label: &str,
+    program_args: &[String],
+    interval: Option<Duration>,
+    at_login: bool,
+) -> Result<PathBuf, String> {
+    if dry_run::skip(format_args!("install launch agent {}", label)) {
+        return agent_path(label);
+    }
+    let agent = agent_path(label)?;
+    let log_file = path::canonicalize(&Path::new("~/Library/Logs").join(format!("{}.log", label)))?;
+
+    if agent.exists() {
+        log::debug!("replacing existing launch agent {}", agent.display());
+        unload(&agent)?;
+    }
+
+    let mut plist = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" ",
+        "\"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n",
+        "<plist version=\"1.0\">\n",
+        "<dict>\n",
+    ));
+    plist.push_str(&format!(
+        "  <key>Label</key>\n  <string>{}</string>\n",
+        escape(label)
+    ));
+    plist.push_str("  <key>ProgramArguments</key>\n  <array>\n");
+    for arg in program_args {
+        plist.push_str(&format!("    <string>{}</string>\n", escape(arg)));
+    }
+    plist.push_str("  </array>\n");
+    if let Some(interval) = interval {
+        plist.push_str(&format!(
+            "  <key>StartInterval</key>\n  <integer>{}</integer>\n",
+            interval.as_secs()
+        ));
+    }
+    plist.push_str(&format!(
+        "  <key>RunAtLoad</key>\n  <{}/>\n",
+        if at_login { "true" } else { "false" }
+    ));
+    for key in ["StandardOutPath", "StandardErrorPath"] {
+        plist.push_str(&format!(
+            "  <key>{}</key>\n  <string>{}</string>\n",
+            key,
+            escape(&log_file.display().to_string())
+        ));
+    }
+    plist.push_str("</dict>\n</plist>\n");
+
+    if let Some(parent) = agent.parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            return Err(format!(
+                "failed to create directory {} - {}",
+                parent.display(),
+                err
+            ));
+        }
+    }
+    if let Err(err) = fs::write(&agent, plist) {
+        return Err(format!("failed to write {} - {}", agent.display(), err));
+    }
+    launchctl(&["load", "-w"], &agent)?;
+    Ok(agent)
+}
+
+pub fn remove(label: &str) -> Result<bool, String> {
+    if dry_run::skip(format_args!("remove launch agent {}", label)) {
+        return Ok(agent_path(label)?.exists());
+    }
+    let agent = agent_path(label)?;
+    if !agent.exists() {
+        return Ok(false);
+    }
+    unload(&agent)?;
+    match fs::remove_file(&agent) {
+        Ok(_) => Ok(true),
+        Err(err) => Err(format!("unable to delete {} - {}", agent.display(), err)),
+    }
+}
+
+pub fn is_loaded(label: &str) -> bool {
+    Command::new("launchctl")
+        .args(["list", label])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+fn unload(agent: &Path) -> Result<(), String> {
+    launchctl(&["unload", "-w"], agent)
+}
+
+fn launchctl(args: &[&str], agent: &Path) -> Result<(), String> {
+    log::trace!("launchctl {} {}", args.join(" "), agent.display());
+    let output = match Command::new("launchctl").args(args).arg(agent).output() {
+        Ok(output) => output,
+        Err(err) => return Err(format!("failed to run launchctl - {}", err)),
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "launchctl {} {} failed - {}",
+            args.join(" "),
+            agent.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,82 @@
+use super::git;
+use super::string::random_string;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Lets child processes run by dotty reuse a lock held by their parent
+pub const TOKEN_ENV: &str = "DOTTY_LOCK_TOKEN";
+
+pub struct RepoLock {
+    path: PathBuf,
+    token: String,
+    owned: bool,
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        if self.owned {
+            log::trace!("releasing lock {}", self.path.display());
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl RepoLock {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+pub fn acquire(repo: &Path) -> Result<RepoLock, String> {
+    let path = git::git_dir(repo)?.join("dotty.lock");
+
+    if let Ok(token) = env::var(TOKEN_ENV) {
+        let held = fs::read_to_string(&path)
+            .map(|contents| contents.lines().next() == Some(token.as_str()))
+            .unwrap_or(false);
+        if held {
+            log::trace!("reusing lock {} held by parent process", path.display());
+            return Ok(RepoLock {
+                path,
+                token,
+                owned: false,
+            });
+        }
+    }
+
+    let token = random_string(16);
+    match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(mut file) => {
+            if let Err(err) = writeln!(file, "{}\n{}", token, process::id()) {
+                let _ = fs::remove_file(&path);
+                return Err(format!("failed to write lock {} - {}", path.display(), err));
+            }
+            log::trace!("acquired lock {}", path.display());
+            Ok(RepoLock {
+                path,
+                token,
+                owned: true,
+            })
+        }
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            let pid = fs::read_to_string(&path)
+                .ok()
+                .and_then(|contents| contents.lines().nth(1).map(|p| p.to_owned()))
+                .unwrap_or_else(|| "unknown".to_owned());
+            Err(format!(
+                "dotty repository {} is locked by process {}; remove {} if it is stale",
+                repo.display(),
+                pid,
+                path.display()
+            ))
+        }
+        Err(err) => Err(format!(
+            "failed to create lock {} - {}",
+            path.display(),
+            err
+        )),
+    }
+}
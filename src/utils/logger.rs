@@ -0,0 +1,108 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::str::FromStr;
+
+/// Directives in the env_logger style, e.g. `info,dotty::git=trace,dotty::fs=warn`
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+    default: Option<LevelFilter>,
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut filter = LogFilter {
+            default: None,
+            directives: Vec::new(),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => filter
+                    .directives
+                    .push((module.trim().to_owned(), parse_level(level)?)),
+                None => filter.default = Some(parse_level(directive)?),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl LogFilter {
+    /// The level of the most specific directive matching the target
+    pub fn level(&self, target: &str, default: LevelFilter) -> LevelFilter {
+        self.directives
+            .iter()
+            .filter(|(module, _)| matches(target, module))
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .or(self.default)
+            .unwrap_or(default)
+    }
+
+    pub fn max_level(&self, default: LevelFilter) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .chain([self.default.unwrap_or(default)])
+            .max()
+            .unwrap_or(default)
+    }
+}
+
+struct FilteredLogger {
+    filter: LogFilter,
+    default: LevelFilter,
+    inner: Box<dyn Log>,
+}
+
+impl Log for FilteredLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target(), self.default)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs the inner logger behind the filter, which must let every level through
+pub fn init(filter: LogFilter, default: LevelFilter, inner: Box<dyn Log>) -> Result<(), String> {
+    let max_level = filter.max_level(default);
+    match log::set_boxed_logger(Box::new(FilteredLogger {
+        filter,
+        default,
+        inner,
+    })) {
+        Ok(_) => {
+            log::set_max_level(max_level);
+            Ok(())
+        }
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Modules may be given without the utils segment, so `dotty::git` matches `dotty::utils::git`
+fn matches(target: &str, module: &str) -> bool {
+    let is_prefix = |target: &str| match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    };
+    is_prefix(target)
+        || target
+            .strip_prefix("dotty::utils::")
+            .is_some_and(|rest| is_prefix(&format!("dotty::{}", rest)))
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    match LevelFilter::from_str(level.trim()) {
+        Ok(level) => Ok(level),
+        Err(_) => Err(format!("invalid log level {}", level.trim())),
+    }
+}
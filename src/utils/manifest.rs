@@ -0,0 +1,145 @@
+use super::dry_run;
+use super::path;
+use super::state;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// The contents of a file when it was last verified
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Record {
+    pub checksum: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Check {
+    Unchanged,
+    /// Modified since it was recorded, as its modification time shows
+    Edited,
+    /// Changed contents with the same modification time, which editing never does
+    Corrupted,
+    /// Emptied or zeroed, as a crash in the middle of writing leaves files, whatever the modification time
+    Truncated,
+    Missing,
+    New,
+}
+
+/// Checksums of the managed files and deployed copies, kept in the state directory
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, Record>,
+}
+
+impl Manifest {
+    pub fn check(&self, path: &Path) -> Result<Check, String> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Check::Missing),
+            Err(err) => return Err(format!("failed to read {} - {}", path.display(), err)),
+        };
+        let Some(record) = self.files.get(path) else {
+            return Ok(Check::New);
+        };
+        if modified(path, &metadata)? != record.modified {
+            return match record.size > 0 && is_blank(path, &metadata)? {
+                true => Ok(Check::Truncated),
+                false => Ok(Check::Edited),
+            };
+        }
+        match metadata.len() == record.size
+            && state::checksum(path)?.as_ref() == Some(&record.checksum)
+        {
+            true => Ok(Check::Unchanged),
+            false => Ok(Check::Corrupted),
+        }
+    }
+
+    /// Records the file as it is now, unless its path is not UTF-8, which manifest.json cannot hold
+    pub fn record(&mut self, path: &Path) -> Result<(), String> {
+        if path.to_str().is_none() {
+            log::debug!(
+                "not recording {}, the path is not valid UTF-8",
+                path.display()
+            );
+            return Ok(());
+        }
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) => return Err(format!("failed to read {} - {}", path.display(), err)),
+        };
+        if let Some(checksum) = state::checksum(path)? {
+            let record = Record {
+                checksum,
+                size: metadata.len(),
+                modified: modified(path, &metadata)?,
+            };
+            self.files.insert(path.to_owned(), record);
+        }
+        Ok(())
+    }
+}
+
+/// Whether the file is empty or only has zero bytes
+fn is_blank(path: &Path, metadata: &fs::Metadata) -> Result<bool, String> {
+    if metadata.len() == 0 {
+        return Ok(true);
+    }
+    match fs::read(path) {
+        Ok(contents) => Ok(contents.iter().all(|byte| *byte == 0)),
+        Err(err) => Err(format!("failed to read {} - {}", path.display(), err)),
+    }
+}
+
+fn modified(path: &Path, metadata: &fs::Metadata) -> Result<DateTime<Utc>, String> {
+    match metadata.modified() {
+        Ok(modified) => Ok(modified.into()),
+        Err(err) => Err(format!(
+            "failed to get modification time of {} - {}",
+            path.display(),
+            err
+        )),
+    }
+}
+
+pub fn load() -> Result<Manifest, String> {
+    let manifest_path = path::state_dir()?.join(MANIFEST_FILE);
+    let contents = match fs::read_to_string(&manifest_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Manifest::default()),
+        Err(err) => {
+            return Err(format!(
+                "failed to read {} - {}",
+                manifest_path.display(),
+                err
+            ))
+        }
+    };
+    serde_json::from_str(&contents)
+        .map_err(|err| format!("failed to parse {} - {}", manifest_path.display(), err))
+}
+
+pub fn save(manifest: &Manifest) -> Result<(), String> {
+    let state_dir = path::state_dir()?;
+    let manifest_path = state_dir.join(MANIFEST_FILE);
+    if dry_run::skip(format_args!("write {}", manifest_path.display())) {
+        return Ok(());
+    }
+    let contents = serde_json::to_string_pretty(manifest)
+        .map_err(|err| format!("failed to serialize manifest - {}", err))?;
+    if let Err(err) = fs::create_dir_all(&state_dir) {
+        return Err(format!(
+            "failed to create directory {} - {}",
+            state_dir.display(),
+            err
+        ));
+    }
+    fs::write(&manifest_path, contents)
+        .map_err(|err| format!("failed to write {} - {}", manifest_path.display(), err))
+}
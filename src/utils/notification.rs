@@ -0,0 +1,64 @@
+use super::dry_run;
+use crate::config::Email;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use std::env;
+
+pub fn webhook(url: &str, payload: &serde_json::Value) -> Result<(), String> {
+    if dry_run::skip(format_args!("send notification to webhook {}", url)) {
+        return Ok(());
+    }
+    log::debug!("sending notification to webhook {}", url);
+    match ureq::post(url).send_json(payload) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!(
+            "failed to send webhook notification to {} - {}",
+            url, err
+        )),
+    }
+}
+
+pub fn email(settings: &Email, subject: &str, body: &str) -> Result<(), String> {
+    if dry_run::skip(format_args!("send notification email to {}", settings.to)) {
+        return Ok(());
+    }
+    log::debug!(
+        "sending notification email to {} via {}",
+        settings.to,
+        settings.smtp_host
+    );
+    let message = Message::builder()
+        .from(parse_mailbox(&settings.from)?)
+        .to(parse_mailbox(&settings.to)?)
+        .subject(subject)
+        .body(body.to_owned())
+        .map_err(|err| format!("failed to build notification email - {}", err))?;
+
+    let mut builder = SmtpTransport::starttls_relay(&settings.smtp_host).map_err(|err| {
+        format!(
+            "failed to connect to smtp host {} - {}",
+            settings.smtp_host, err
+        )
+    })?;
+    if let Some(port) = settings.smtp_port {
+        builder = builder.port(port);
+    }
+    if let Some(username) = &settings.username {
+        let password = env::var("DOTTY_SMTP_PASSWORD").unwrap_or_default();
+        builder = builder.credentials(Credentials::new(username.to_owned(), password));
+    }
+
+    match builder.build().send(&message) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!(
+            "failed to send notification email to {} - {}",
+            settings.to, err
+        )),
+    }
+}
+
+fn parse_mailbox(address: &str) -> Result<lettre::message::Mailbox, String> {
+    address
+        .parse()
+        .map_err(|err| format!("invalid email address {} - {}", address, err))
+}
@@ -0,0 +1,118 @@
+use super::dry_run;
+use super::users;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::process::{Command, Output};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Manager {
+    Brew,
+    Apt,
+    Pacman,
+    Cargo,
+}
+
+impl fmt::Display for Manager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Manager::Brew => "brew",
+            Manager::Apt => "apt",
+            Manager::Pacman => "pacman",
+            Manager::Cargo => "cargo",
+        })
+    }
+}
+
+impl Manager {
+    pub const ALL: [Manager; 4] = [Manager::Brew, Manager::Apt, Manager::Pacman, Manager::Cargo];
+
+    pub fn is_available(&self) -> bool {
+        let program = match self {
+            Manager::Brew => "brew",
+            Manager::Apt => "apt-get",
+            Manager::Pacman => "pacman",
+            Manager::Cargo => "cargo",
+        };
+        Command::new(program)
+            .arg("--version")
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    }
+
+    pub fn installed(&self) -> Result<BTreeSet<String>, String> {
+        let output = match self {
+            Manager::Brew => run("brew", &["list", "-1"], false)?,
+            Manager::Apt => run("dpkg-query", &["-W", "-f=${Package}\\n"], false)?,
+            Manager::Pacman => run("pacman", &["-Qq"], false)?,
+            Manager::Cargo => run("cargo", &["install", "--list"], false)?,
+        };
+        let stdout = check_success(&output, self, "list")?;
+        Ok(stdout
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with(char::is_whitespace))
+            .filter_map(|line| match self {
+                // cargo lists "name v1.0.0:" followed by the indented binaries
+                Manager::Cargo => line.split_whitespace().next(),
+                _ => Some(line.trim()),
+            })
+            .map(|name| name.to_owned())
+            .collect())
+    }
+
+    pub fn install(&self, packages: &[String]) -> Result<(), String> {
+        if dry_run::skip(format_args!(
+            "install {} with {}",
+            packages.join(", "),
+            self
+        )) {
+            return Ok(());
+        }
+        let mut args: Vec<&str> = match self {
+            Manager::Brew => vec!["install"],
+            Manager::Apt => vec!["install", "-y"],
+            Manager::Pacman => vec!["-S", "--needed", "--noconfirm"],
+            Manager::Cargo => vec!["install"],
+        };
+        args.extend(packages.iter().map(|p| p.as_str()));
+        let output = match self {
+            Manager::Brew => run("brew", &args, false)?,
+            Manager::Apt => run("apt-get", &args, true)?,
+            Manager::Pacman => run("pacman", &args, true)?,
+            Manager::Cargo => run("cargo", &args, false)?,
+        };
+        check_success(&output, self, "install").map(|_| ())
+    }
+}
+
+fn run(program: &str, args: &[&str], privileged: bool) -> Result<Output, String> {
+    let mut cmd = match privileged && !is_root() {
+        true => {
+            let mut cmd = Command::new("sudo");
+            cmd.arg(program);
+            cmd
+        }
+        false => Command::new(program),
+    };
+    log::trace!("{} {}", program, args.join(" "));
+    match cmd.args(args).output() {
+        Ok(output) => Ok(output),
+        Err(err) => Err(format!("failed to run {} - {}", program, err)),
+    }
+}
+
+fn is_root() -> bool {
+    users::current_uid() == 0
+}
+
+fn check_success(output: &Output, manager: &Manager, action: &str) -> Result<String, String> {
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed to {} packages - {}",
+            manager,
+            action,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
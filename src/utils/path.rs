@@ -1,17 +1,9 @@
 use home_dir::HomeDirExt;
-use std::path::{Path, PathBuf};
+use std::env;
+use std::path::{Component, Path, PathBuf};
 
 pub fn canonicalize(path: &Path) -> Result<PathBuf, String> {
-    let tilde_expanded = match path.expand_home() {
-        Ok(expanded) => expanded,
-        Err(err) => {
-            return Err(format!(
-                "failed to expand home dir {} - {}",
-                path.display(),
-                err
-            ))
-        }
-    };
+    let tilde_expanded = expand_home(path)?;
     let canonical = canonicalize_missing(&tilde_expanded)?;
     if !canonical.eq(path) {
         log::trace!(
@@ -23,6 +15,59 @@ pub fn canonicalize(path: &Path) -> Result<PathBuf, String> {
     Ok(canonical)
 }
 
+/// Canonicalizes the parent of `path`, leaving a final symlink unresolved
+pub fn absolute(path: &Path) -> Result<PathBuf, String> {
+    let tilde_expanded = expand_home(path)?;
+    match (tilde_expanded.parent(), tilde_expanded.file_name()) {
+        (Some(parent), Some(file_name)) if !parent.as_os_str().is_empty() => {
+            Ok(canonicalize(parent)?.join(file_name))
+        }
+        (_, Some(file_name)) => Ok(canonicalize(Path::new("."))?.join(file_name)),
+        _ => canonicalize(&tilde_expanded),
+    }
+}
+
+pub fn state_dir() -> Result<PathBuf, String> {
+    match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir).join("dotty")),
+        _ => canonicalize(Path::new("~/.local/state/dotty")),
+    }
+}
+
+pub fn data_dir() -> Result<PathBuf, String> {
+    match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir).join("dotty")),
+        _ => canonicalize(Path::new("~/.local/share/dotty")),
+    }
+}
+
+pub fn resolve_from(base: &Path, path: &Path) -> Result<PathBuf, String> {
+    canonicalize(&base.join(expand_home(path)?))
+}
+
+/// Replaces a leading ~, or %USERPROFILE% on Windows, with the home directory
+pub fn expand_home(path: &Path) -> Result<PathBuf, String> {
+    if cfg!(windows) {
+        if let Ok(rest) = path.strip_prefix("%USERPROFILE%") {
+            return match env::var_os("USERPROFILE") {
+                Some(profile) => Ok(PathBuf::from(profile).join(rest)),
+                None => Err(format!(
+                    "failed to expand home dir {} - USERPROFILE is not set",
+                    path.display()
+                )),
+            };
+        }
+    }
+    match path.expand_home() {
+        Ok(expanded) => Ok(expanded),
+        Err(err) => Err(format!(
+            "failed to expand home dir {} - {}",
+            path.display(),
+            err
+        )),
+    }
+}
+
 fn canonicalize_missing(path: &Path) -> Result<PathBuf, String> {
     if path.exists() {
         return match path.canonicalize() {
@@ -86,6 +131,22 @@ pub fn relative_from_root(root: &Path, path: &Path) -> Result<PathBuf, String> {
     Ok(relative)
 }
 
+/// Resolves . and .. in the path without touching the filesystem, as symlink targets may be missing
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
+            }
+            Component::ParentDir if normalized.has_root() => {}
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
 pub fn common_base_path(paths: &[PathBuf]) -> PathBuf {
     paths.iter().fold(PathBuf::new(), |accum, item| {
         if accum.as_os_str().is_empty() {
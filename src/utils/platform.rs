@@ -0,0 +1,160 @@
+use std::fs::{self, Metadata, Permissions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+
+/// Creates a symlink, which on Windows needs developer mode or administrator rights
+#[cfg(unix)]
+pub fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    unix_fs::symlink(original, link)
+}
+
+/// Creates a symlink, which on Windows needs developer mode or administrator rights
+///
+/// Without them directories are linked with a junction and files are copied instead.
+#[cfg(windows)]
+pub fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    use std::os::windows::fs as windows_fs;
+    use std::process::Command;
+
+    // ERROR_PRIVILEGE_NOT_HELD
+    const PRIVILEGE_NOT_HELD: i32 = 1314;
+
+    let result = match original.is_dir() {
+        true => windows_fs::symlink_dir(original, link),
+        false => windows_fs::symlink_file(original, link),
+    };
+    match result {
+        Err(err) if err.raw_os_error() == Some(PRIVILEGE_NOT_HELD) => {}
+        result => return result,
+    }
+    if original.is_dir() {
+        log::debug!(
+            "symlinks need developer mode, creating junction {} to {}",
+            link.display(),
+            original.display()
+        );
+        let output = Command::new("cmd")
+            .arg("/C")
+            .arg("mklink")
+            .arg("/J")
+            .arg(link)
+            .arg(original)
+            .output()?;
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(io::Error::other(
+                String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            )),
+        }
+    } else {
+        log::debug!(
+            "symlinks need developer mode, copying {} to {}",
+            original.display(),
+            link.display()
+        );
+        fs::copy(original, link).map(|_| ())
+    }
+}
+
+/// Removes a file or a link, including links to directories
+pub fn remove_link(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        // directory symlinks and junctions are removed as directories on Windows
+        Err(err) if cfg!(windows) && path.is_dir() => fs::remove_dir(path).map_err(|_| err),
+        result => result,
+    }
+}
+
+/// Whether both are the same file on the same device
+#[cfg(unix)]
+pub fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/// Whether both are the same file on the same device, which is never known on Windows
+#[cfg(windows)]
+pub fn same_file(_: &Metadata, _: &Metadata) -> bool {
+    false
+}
+
+/// The permission bits, where Windows only has read only files
+#[cfg(unix)]
+pub fn mode(metadata: &Metadata) -> u32 {
+    metadata.permissions().mode() & 0o7777
+}
+
+/// The permission bits, where Windows only has read only files
+#[cfg(windows)]
+pub fn mode(metadata: &Metadata) -> u32 {
+    match metadata.permissions().readonly() {
+        true => 0o444,
+        false => 0o644,
+    }
+}
+
+#[cfg(unix)]
+pub fn permissions(_: &Path, mode: u32) -> io::Result<Permissions> {
+    Ok(Permissions::from_mode(mode))
+}
+
+/// The current permissions of the path, read only unless the owner can write
+#[cfg(windows)]
+pub fn permissions(path: &Path, mode: u32) -> io::Result<Permissions> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    Ok(permissions)
+}
+
+/// The path of the raw bytes git stores, e.g. in index entries
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+/// The path of the raw bytes git stores, e.g. in index entries, which are UTF-8 on Windows
+#[cfg(windows)]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// The raw bytes git stores for the relative path
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> Vec<u8> {
+    path.as_os_str().as_bytes().to_vec()
+}
+
+/// The raw bytes git stores for the relative path, with forward slashes on Windows
+#[cfg(windows)]
+pub fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().replace('\\', "/").into_bytes()
+}
+
+/// The user and group ids owning the file
+#[cfg(unix)]
+pub fn owner(metadata: &Metadata) -> (u32, u32) {
+    (metadata.uid(), metadata.gid())
+}
+
+/// The user and group ids owning the file, which are always the current ones on Windows
+#[cfg(windows)]
+pub fn owner(_: &Metadata) -> (u32, u32) {
+    (super::users::current_uid(), super::users::current_gid())
+}
+
+#[cfg(unix)]
+pub fn chown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    unix_fs::chown(path, uid, gid)
+}
+
+#[cfg(windows)]
+pub fn chown(path: &Path, _: Option<u32>, _: Option<u32>) -> io::Result<()> {
+    log::debug!("not changing owner of {} on windows", path.display());
+    Ok(())
+}
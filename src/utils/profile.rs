@@ -0,0 +1,39 @@
+use std::env;
+use std::sync::RwLock;
+
+static ACTIVE: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Sets the active profiles, in increasing order of precedence
+pub fn activate(names: &[String]) -> Result<(), String> {
+    for name in names {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(format!("invalid profile name {}", name));
+        }
+    }
+    log::trace!("activating profiles {}", names.join(", "));
+    match ACTIVE.write() {
+        Ok(mut active) => {
+            *active = names.to_vec();
+            Ok(())
+        }
+        Err(err) => Err(format!("failed to activate profiles - {}", err)),
+    }
+}
+
+pub fn active() -> Vec<String> {
+    ACTIVE.read().map(|a| a.clone()).unwrap_or_default()
+}
+
+/// The short name of this machine, overridden by the DOTTY_HOST environment variable
+pub fn hostname() -> String {
+    match env::var("DOTTY_HOST") {
+        Ok(host) if !host.is_empty() => host,
+        _ => {
+            let host = gethostname::gethostname().to_string_lossy().into_owned();
+            match host.split_once('.') {
+                Some((short, _)) => short.to_owned(),
+                None => host,
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use super::dry_run;
+use inquire::{Confirm, MultiSelect, Select, Text};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// Makes every prompt fail instead of waiting for input
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+/// Answers yes to the confirmations before destructive operations
+pub fn assume_yes() {
+    set_assume_yes(true);
+}
+
+pub fn set_assume_yes(yes: bool) {
+    ASSUME_YES.store(yes, Ordering::Relaxed);
+}
+
+pub fn assumes_yes() -> bool {
+    ASSUME_YES.load(Ordering::Relaxed)
+}
+
+fn check_enabled(what: &str) -> Result<(), String> {
+    match DISABLED.load(Ordering::Relaxed) {
+        true => Err(format!("failed to prompt for {} - input is disabled", what)),
+        false => Ok(()),
+    }
+}
+
+pub fn multi_select(message: &str, options: Vec<String>) -> Result<Vec<usize>, String> {
+    if options.is_empty() {
+        return Ok(Vec::new());
+    }
+    check_enabled("selection")?;
+    match MultiSelect::new(message, options).raw_prompt() {
+        Ok(selected) => Ok(selected.into_iter().map(|o| o.index).collect()),
+        Err(err) => Err(format!("failed to prompt for selection - {}", err)),
+    }
+}
+
+pub fn select(message: &str, options: Vec<String>) -> Result<usize, String> {
+    check_enabled("selection")?;
+    match Select::new(message, options).raw_prompt() {
+        Ok(selected) => Ok(selected.index),
+        Err(err) => Err(format!("failed to prompt for selection - {}", err)),
+    }
+}
+
+pub fn confirm(message: &str) -> Result<bool, String> {
+    check_enabled("confirmation")?;
+    match Confirm::new(message).with_default(false).prompt() {
+        Ok(confirmed) => Ok(confirmed),
+        Err(err) => Err(format!("failed to prompt for confirmation - {}", err)),
+    }
+}
+
+/// Confirms a destructive operation, unless assuming yes or only logging what would be done
+pub fn proceed(message: &str) -> Result<bool, String> {
+    if ASSUME_YES.load(Ordering::Relaxed) || dry_run::is_enabled() {
+        return Ok(true);
+    }
+    confirm(message).map_err(|err| format!("{}, use --yes to proceed anyway", err))
+}
+
+pub fn text(message: &str) -> Result<String, String> {
+    check_enabled("input")?;
+    match Text::new(message).prompt() {
+        Ok(text) => Ok(text),
+        Err(err) => Err(format!("failed to prompt for input - {}", err)),
+    }
+}
@@ -0,0 +1,103 @@
+use regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+const PATTERNS: &[(&str, &str)] = &[
+    ("an AWS access key", r"\b(AKIA|ASIA)[0-9A-Z]{16}\b"),
+    (
+        "an AWS secret access key",
+        r#"(?i)aws_secret_access_key\s*[=:]\s*["']?[A-Za-z0-9/+=]{40}"#,
+    ),
+    (
+        "a private key",
+        r"-----BEGIN ([A-Z]+ )?PRIVATE KEY( BLOCK)?-----",
+    ),
+    (
+        "a GitHub token",
+        r"\b(gh[pousr]_[A-Za-z0-9]{36,}|github_pat_[A-Za-z0-9_]{22,})",
+    ),
+    ("a GitLab token", r"\bglpat-[A-Za-z0-9_-]{20,}"),
+    ("a Slack token", r"\bxox[abprs]-[A-Za-z0-9-]{10,}"),
+    ("a Google API key", r"\bAIza[0-9A-Za-z_-]{35}"),
+    ("a Stripe key", r"\b[rs]k_live_[0-9A-Za-z]{20,}"),
+    ("an npm token", r"\bnpm_[A-Za-z0-9]{36}\b"),
+];
+
+static REGEXES: LazyLock<Vec<(&str, Regex)>> = LazyLock::new(|| {
+    PATTERNS
+        .iter()
+        .map(|(kind, pattern)| (*kind, Regex::new(pattern).expect("invalid secret pattern")))
+        .collect()
+});
+
+/// A line that looks like it contains a credential
+pub struct Finding {
+    pub path: PathBuf,
+    pub line: usize,
+    pub kind: &'static str,
+}
+
+/// Scans a file, or every file beneath a directory, for common secret patterns
+pub fn scan(path: &Path) -> Result<Vec<Finding>, String> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            return Err(format!(
+                "failed to scan {} for secrets - {}",
+                path.display(),
+                err
+            ))
+        }
+    };
+    if metadata.is_dir() {
+        let mut findings = Vec::new();
+        for entry in super::fs::read_dir(path)? {
+            if entry.file_name().is_some_and(|name| name != ".git") {
+                findings.append(&mut scan(&entry)?);
+            }
+        }
+        return Ok(findings);
+    }
+    if !metadata.is_file() {
+        return Ok(Vec::new());
+    }
+
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            return Err(format!(
+                "failed to scan {} for secrets - {}",
+                path.display(),
+                err
+            ))
+        }
+    };
+    Ok(scan_contents(path, &contents))
+}
+
+/// Scans contents, such as staged ones, for common secret patterns, as those of the path
+pub fn scan_contents(path: &Path, contents: &[u8]) -> Vec<Finding> {
+    if contents.contains(&0) {
+        log::trace!("not scanning binary file {} for secrets", path.display());
+        return Vec::new();
+    }
+    let mut findings = Vec::new();
+    for (index, line) in String::from_utf8_lossy(contents).lines().enumerate() {
+        findings.append(&mut scan_line(path, index + 1, line));
+    }
+    findings
+}
+
+/// Scans one line, e.g. one a change adds, of the path
+pub fn scan_line(path: &Path, line_number: usize, line: &str) -> Vec<Finding> {
+    REGEXES
+        .iter()
+        .filter(|(_, regex)| regex.is_match(line))
+        .map(|(kind, _)| Finding {
+            path: path.to_owned(),
+            line: line_number,
+            kind,
+        })
+        .collect()
+}
@@ -0,0 +1,49 @@
+use super::dry_run;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const ENFORCE_FILE: &str = "/sys/fs/selinux/enforce";
+
+pub fn is_enforcing() -> bool {
+    fs::read_to_string(ENFORCE_FILE)
+        .map(|enforce| enforce.trim() == "1")
+        .unwrap_or(false)
+}
+
+/// Whether SELinux labels files, enforcing or permissive
+pub fn is_enabled() -> bool {
+    Path::new(ENFORCE_FILE).exists()
+}
+
+/// Labels a copy with the default context of where it is, rather than the one of its source
+pub fn apply_default_context(path: &Path) -> Result<(), String> {
+    match is_enabled() {
+        true => restorecon(&[path.to_owned()]),
+        false => Ok(()),
+    }
+}
+
+pub fn restorecon(paths: &[PathBuf]) -> Result<(), String> {
+    if dry_run::skip(format_args!(
+        "relabel {} paths with restorecon",
+        paths.len()
+    )) {
+        return Ok(());
+    }
+    if paths.is_empty() {
+        return Ok(());
+    }
+    log::debug!("relabeling {} paths with restorecon", paths.len());
+    let output = match Command::new("restorecon").arg("-R").args(paths).output() {
+        Ok(output) => output,
+        Err(err) => return Err(format!("failed to run restorecon - {}", err)),
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "restorecon failed - {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use super::path;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const RECORDS_FILE: &str = "snapshots.yaml";
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Btrfs,
+    Zfs,
+    Apfs,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub kind: Kind,
+    pub root: PathBuf,
+    pub created: DateTime<Utc>,
+}
+
+pub fn create(root: &Path) -> Result<Snapshot, String> {
+    let created = Utc::now();
+    let label = format!("dotty-{}", created.format("%Y%m%d%H%M%S"));
+
+    let (kind, name) = if cfg!(target_os = "macos") {
+        let output = run("tmutil", &["localsnapshot"])?;
+        let name = output
+            .lines()
+            .find_map(|line| line.split_whitespace().last())
+            .map(|name| name.trim_end_matches(['.', ':']).to_owned())
+            .unwrap_or(label);
+        (Kind::Apfs, name)
+    } else {
+        let (fs_type, source, mount_point) = find_mount(root)?;
+        match fs_type.as_str() {
+            "btrfs" => {
+                let dest = mount_point.join(".dotty-snapshots").join(&label);
+                if let Err(err) = fs::create_dir_all(mount_point.join(".dotty-snapshots")) {
+                    return Err(format!(
+                        "failed to create snapshot directory in {} - {}",
+                        mount_point.display(),
+                        err
+                    ));
+                }
+                run(
+                    "btrfs",
+                    &[
+                        "subvolume",
+                        "snapshot",
+                        "-r",
+                        &mount_point.display().to_string(),
+                        &dest.display().to_string(),
+                    ],
+                )?;
+                (Kind::Btrfs, dest.display().to_string())
+            }
+            "zfs" => {
+                let name = format!("{}@{}", source, label);
+                run("zfs", &["snapshot", &name])?;
+                (Kind::Zfs, name)
+            }
+            _ => {
+                return Err(format!(
+                    "cannot snapshot {} - {} filesystems are not supported",
+                    root.display(),
+                    fs_type
+                ))
+            }
+        }
+    };
+
+    let snapshot = Snapshot {
+        name,
+        kind,
+        root: root.to_owned(),
+        created,
+    };
+    record(&snapshot)?;
+    Ok(snapshot)
+}
+
+pub fn load_all() -> Result<Vec<Snapshot>, String> {
+    let records_path = path::state_dir()?.join(RECORDS_FILE);
+    if !records_path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = match fs::read_to_string(&records_path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            return Err(format!(
+                "failed to read {} - {}",
+                records_path.display(),
+                err
+            ))
+        }
+    };
+    serde_yaml::from_str(&contents)
+        .map_err(|err| format!("failed to parse {} - {}", records_path.display(), err))
+}
+
+fn record(snapshot: &Snapshot) -> Result<(), String> {
+    let state_dir = path::state_dir()?;
+    let records_path = state_dir.join(RECORDS_FILE);
+    let mut records = load_all()?;
+    records.push(Snapshot {
+        name: snapshot.name.clone(),
+        kind: snapshot.kind,
+        root: snapshot.root.clone(),
+        created: snapshot.created,
+    });
+
+    let contents = serde_yaml::to_string(&records)
+        .map_err(|err| format!("failed to serialize snapshot records - {}", err))?;
+    if let Err(err) = fs::create_dir_all(&state_dir) {
+        return Err(format!(
+            "failed to create directory {} - {}",
+            state_dir.display(),
+            err
+        ));
+    }
+    fs::write(&records_path, contents)
+        .map_err(|err| format!("failed to write {} - {}", records_path.display(), err))
+}
+
+fn find_mount(path: &Path) -> Result<(String, String, PathBuf), String> {
+    let mounts = match fs::read_to_string("/proc/self/mounts") {
+        Ok(mounts) => mounts,
+        Err(err) => return Err(format!("failed to read mounts - {}", err)),
+    };
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let source = fields.next()?;
+            let mount_point = PathBuf::from(fields.next()?.replace("\\040", " "));
+            let fs_type = fields.next()?;
+            Some((fs_type.to_owned(), source.to_owned(), mount_point))
+        })
+        .filter(|(_, _, mount_point)| path.starts_with(mount_point))
+        .max_by_key(|(_, _, mount_point)| mount_point.components().count())
+        .ok_or_else(|| format!("no mount point found for {}", path.display()))
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String, String> {
+    log::debug!("running {} {}", program, args.join(" "));
+    let output = match Command::new(program).args(args).output() {
+        Ok(output) => output,
+        Err(err) => return Err(format!("failed to run {} - {}", program, err)),
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "{} {} failed - {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
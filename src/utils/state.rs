@@ -0,0 +1,123 @@
+use super::dry_run;
+use super::path;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "state.json";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Method {
+    Symlink,
+    Hardlink,
+    Copy,
+    Render,
+}
+
+/// An entry restore deployed, as it was right after deploying it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Deployed {
+    pub repository: PathBuf,
+    /// The file in the repository
+    pub entry: PathBuf,
+    pub target: PathBuf,
+    pub method: Method,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    /// The sha256 of the deployed contents, for files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// The git blob of the deployed contents of copies, the base of three-way merges
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// What restore deployed from every repository, kept in the state directory
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    #[serde(default)]
+    pub deployed: Vec<Deployed>,
+}
+
+impl State {
+    /// Records the entry, replacing what was recorded for its target before
+    ///
+    /// Entries with paths that are not UTF-8 are forgotten instead, state.json cannot hold them.
+    pub fn record(&mut self, deployed: Deployed) {
+        self.forget(&deployed.target);
+        let paths = [&deployed.repository, &deployed.entry, &deployed.target];
+        if paths.iter().any(|path| path.to_str().is_none()) {
+            log::debug!(
+                "not recording {} in {}, the path is not valid UTF-8",
+                deployed.target.display(),
+                STATE_FILE
+            );
+            return;
+        }
+        self.deployed.push(deployed);
+    }
+
+    pub fn find(&self, target: &Path) -> Option<&Deployed> {
+        self.deployed
+            .iter()
+            .find(|deployed| deployed.target == target)
+    }
+
+    pub fn forget(&mut self, target: &Path) {
+        self.deployed.retain(|deployed| deployed.target != target);
+    }
+
+    /// The entries deployed from the repository
+    pub fn deployed_from<'a>(&'a self, repo: &'a Path) -> impl Iterator<Item = &'a Deployed> {
+        self.deployed.iter().filter(move |d| d.repository == repo)
+    }
+}
+
+pub fn load() -> Result<State, String> {
+    let state_path = path::state_dir()?.join(STATE_FILE);
+    let contents = match fs::read_to_string(&state_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(State::default()),
+        Err(err) => return Err(format!("failed to read {} - {}", state_path.display(), err)),
+    };
+    serde_json::from_str(&contents)
+        .map_err(|err| format!("failed to parse {} - {}", state_path.display(), err))
+}
+
+pub fn save(state: &State) -> Result<(), String> {
+    let state_dir = path::state_dir()?;
+    let state_path = state_dir.join(STATE_FILE);
+    if dry_run::skip(format_args!("write {}", state_path.display())) {
+        return Ok(());
+    }
+    let contents = serde_json::to_string_pretty(state)
+        .map_err(|err| format!("failed to serialize deployed state - {}", err))?;
+    if let Err(err) = fs::create_dir_all(&state_dir) {
+        return Err(format!(
+            "failed to create directory {} - {}",
+            state_dir.display(),
+            err
+        ));
+    }
+    fs::write(&state_path, contents)
+        .map_err(|err| format!("failed to write {} - {}", state_path.display(), err))
+}
+
+/// The sha256 of the contents of a file, following symlinks, or none for anything else
+pub fn checksum(path: &Path) -> Result<Option<String>, String> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let contents =
+        fs::read(path).map_err(|err| format!("failed to read {} - {}", path.display(), err))?;
+    let mut checksum = String::new();
+    for byte in Sha256::digest(contents) {
+        let _ = write!(checksum, "{:02x}", byte);
+    }
+    Ok(Some(checksum))
+}
@@ -1,4 +1,5 @@
 use rand::{distributions::Alphanumeric, Rng};
+use std::time::Duration;
 
 pub fn random_string(size: usize) -> String {
     rand::thread_rng()
@@ -7,3 +8,153 @@ pub fn random_string(size: usize) -> String {
         .map(char::from)
         .collect()
 }
+
+pub fn format_table(rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = Vec::new();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            let len = cell.chars().count();
+            match widths.get_mut(i) {
+                Some(width) => *width = (*width).max(len),
+                None => widths.push(len),
+            }
+        }
+    }
+
+    let mut table = String::new();
+    for row in rows {
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            if i + 1 == row.len() {
+                line.push_str(cell);
+            } else {
+                line.push_str(&format!("{:width$}  ", cell, width = widths[i]));
+            }
+        }
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = match amount.parse() {
+        Ok(amount) => amount,
+        Err(err) => return Err(format!("invalid duration {} - {}", value, err)),
+    };
+    let multiplier: u64 = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => {
+            return Err(format!(
+                "invalid duration {} - unknown unit {}",
+                value, unit
+            ))
+        }
+    };
+    match amount.checked_mul(multiplier) {
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+        None => Err(format!("invalid duration {} - it is too long", value)),
+    }
+}
+
+/// Parses a size such as 512K, 10MB or 1GiB into bytes, using powers of 1024
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = match amount.parse() {
+        Ok(amount) => amount,
+        Err(err) => return Err(format!("invalid size {} - {}", value, err)),
+    };
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(format!("invalid size {} - unknown unit {}", value, unit)),
+    };
+    match amount.checked_mul(multiplier) {
+        Some(size) => Ok(size),
+        None => Err(format!("invalid size {} - it is too large", value)),
+    }
+}
+
+/// How long ago in the largest whole unit, e.g. 3 days ago
+pub fn format_age(age: Duration) -> String {
+    let seconds = age.as_secs();
+    let (amount, unit) = match seconds {
+        s if s < 60 => return "just now".to_owned(),
+        s if s < 60 * 60 => (s / 60, "minute"),
+        s if s < 60 * 60 * 24 => (s / (60 * 60), "hour"),
+        s if s < 60 * 60 * 24 * 30 => (s / (60 * 60 * 24), "day"),
+        s if s < 60 * 60 * 24 * 365 => (s / (60 * 60 * 24 * 30), "month"),
+        s => (s / (60 * 60 * 24 * 365), "year"),
+    };
+    match amount {
+        1 => format!("1 {} ago", unit),
+        _ => format!("{} {}s ago", amount, unit),
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1}GB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.1}MB", b as f64 / (1u64 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1}KB", b as f64 / (1u64 << 10) as f64),
+        b => format!("{}B", b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_reads_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration(" 5m "), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflow() {
+        assert_eq!(
+            parse_duration(&format!("{}s", u64::MAX)),
+            Ok(Duration::from_secs(u64::MAX))
+        );
+        assert!(parse_duration(&format!("{}d", u64::MAX / 60)).is_err());
+        assert!(parse_duration("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn parse_size_reads_units() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("10 MB"), Ok(10 << 20));
+        assert_eq!(parse_size("1gib"), Ok(1 << 30));
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn parse_size_rejects_overflow() {
+        assert_eq!(parse_size(&u64::MAX.to_string()), Ok(u64::MAX));
+        assert!(parse_size(&format!("{}K", u64::MAX >> 9)).is_err());
+        assert!(parse_size(&format!("{}G", u64::MAX)).is_err());
+        assert!(parse_size("99999999999999999999").is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::ffi::OsStr;
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Retries filesystem operations that are denied permission with sudo, e.g. for entries in /etc
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether the operation that failed with the error should be retried with sudo
+pub fn applies(err: &io::Error) -> bool {
+    ENABLED.load(Ordering::Relaxed) && err.kind() == ErrorKind::PermissionDenied
+}
+
+/// Runs the program as root, with sudo prompting for a password on the terminal if it needs one
+pub fn run(program: &str, args: &[&OsStr]) -> Result<(), String> {
+    log::debug!("running {} with sudo", program);
+    match Command::new("sudo")
+        .arg("--")
+        .arg(program)
+        .args(args)
+        .status()
+    {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("sudo {} exited with {}", program, status)),
+        Err(err) => Err(format!("failed to run sudo {} - {}", program, err)),
+    }
+}
+
+/// Writes the contents to the path as root
+pub fn write(path: &Path, contents: &[u8]) -> Result<(), String> {
+    log::debug!("writing {} with sudo", path.display());
+    let mut child = match Command::new("sudo")
+        .args(["--", "tee"])
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => return Err(format!("failed to run sudo tee - {}", err)),
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(err) = stdin.write_all(contents) {
+            return Err(format!("failed to write {} - {}", path.display(), err));
+        }
+    }
+    match child.wait() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!(
+            "sudo tee {} exited with {}",
+            path.display(),
+            status
+        )),
+        Err(err) => Err(format!("failed to run sudo tee - {}", err)),
+    }
+}
@@ -0,0 +1,131 @@
+use super::dry_run;
+use super::path;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+pub fn is_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+pub fn timer_path(name: &str) -> Result<PathBuf, String> {
+    unit_path(&format!("{}.timer", name))
+}
+
+/// Writes a user service running the program and a timer starting it, then enables the timer
+///
+/// The user manager starts with the first login, so at login runs the service shortly after it.
+pub fn install(
+    name: &str,
+    description: &str,
+    program_args: &[String],
+    interval: Option<Duration>,
+    at_login: bool,
+) -> Result<PathBuf, String> {
+    if dry_run::skip(format_args!("install systemd user timer {}", name)) {
+        return timer_path(name);
+    }
+    let service = unit_path(&format!("{}.service", name))?;
+    let timer = timer_path(name)?;
+
+    let exec_start: Vec<String> = program_args.iter().map(|arg| quote(arg)).collect();
+    let service_unit = format!(
+        "[Unit]\nDescription={}\n\n[Service]\nType=oneshot\nExecStart={}\n",
+        description,
+        exec_start.join(" ")
+    );
+    let mut timer_unit = format!("[Unit]\nDescription={}\n\n[Timer]\n", description);
+    if at_login {
+        timer_unit.push_str("OnStartupSec=1min\n");
+    }
+    if let Some(interval) = interval {
+        timer_unit.push_str(&format!(
+            "OnActiveSec={}s\nOnUnitActiveSec={}s\n",
+            interval.as_secs(),
+            interval.as_secs()
+        ));
+    }
+    timer_unit.push_str("\n[Install]\nWantedBy=timers.target\n");
+
+    if let Some(parent) = timer.parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            return Err(format!(
+                "failed to create directory {} - {}",
+                parent.display(),
+                err
+            ));
+        }
+    }
+    for (path, contents) in [(&service, service_unit), (&timer, timer_unit)] {
+        if let Err(err) = fs::write(path, contents) {
+            return Err(format!("failed to write {} - {}", path.display(), err));
+        }
+    }
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", "--now", &format!("{}.timer", name)])?;
+    Ok(timer)
+}
+
+pub fn remove(name: &str) -> Result<bool, String> {
+    if dry_run::skip(format_args!("remove systemd user timer {}", name)) {
+        return Ok(timer_path(name)?.exists());
+    }
+    let timer = timer_path(name)?;
+    if !timer.exists() {
+        return Ok(false);
+    }
+    systemctl(&["disable", "--now", &format!("{}.timer", name)])?;
+    for path in [timer, unit_path(&format!("{}.service", name))?] {
+        match fs::remove_file(&path) {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(format!("unable to delete {} - {}", path.display(), err)),
+        }
+    }
+    systemctl(&["daemon-reload"])?;
+    Ok(true)
+}
+
+pub fn is_active(name: &str) -> bool {
+    Command::new("systemctl")
+        .args(["--user", "is-active", "--quiet", &format!("{}.timer", name)])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+fn unit_path(unit: &str) -> Result<PathBuf, String> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => path::canonicalize(Path::new("~/.config"))?,
+    };
+    Ok(config_dir.join("systemd").join("user").join(unit))
+}
+
+fn systemctl(args: &[&str]) -> Result<(), String> {
+    log::trace!("systemctl --user {}", args.join(" "));
+    let output = match Command::new("systemctl").arg("--user").args(args).output() {
+        Ok(output) => output,
+        Err(err) => return Err(format!("failed to run systemctl - {}", err)),
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "systemctl --user {} failed - {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Quotes an argument of ExecStart, where % starts a specifier
+fn quote(arg: &str) -> String {
+    format!(
+        "\"{}\"",
+        arg.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('%', "%%")
+    )
+}
@@ -0,0 +1,105 @@
+use super::git;
+use super::path;
+use super::profile;
+use super::prompt;
+use crate::config::{self, Config};
+use handlebars::{Handlebars, RenderErrorReason};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const EXTENSION: &str = "tmpl";
+
+pub fn is_template(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == EXTENSION) && path.is_file()
+}
+
+/// The path a template is rendered to, i.e. without the template extension
+pub fn rendered_path(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(extension) if extension == EXTENSION => path.with_extension(""),
+        _ => path.to_owned(),
+    }
+}
+
+/// Built in variables overridden by the variables in dotty.yaml and then by those of this machine
+pub fn variables(repo: &Path, config: &Config) -> Result<BTreeMap<String, String>, String> {
+    let mut variables = BTreeMap::from([
+        ("hostname".to_owned(), profile::hostname()),
+        ("os".to_owned(), env::consts::OS.to_owned()),
+        (
+            "username".to_owned(),
+            env::var("USER")
+                .or_else(|_| env::var("USERNAME"))
+                .unwrap_or_default(),
+        ),
+        (
+            "home".to_owned(),
+            path::canonicalize(Path::new("~"))?.display().to_string(),
+        ),
+    ]);
+    variables.extend(config.variables.clone());
+    variables.extend(config::load_local(repo)?.variables);
+    Ok(variables)
+}
+
+/// Renders the template, prompting for variables without a value and remembering the answers
+pub fn render(
+    repo: &Path,
+    template: &Path,
+    variables: &mut BTreeMap<String, String>,
+) -> Result<String, String> {
+    let source = match fs::read_to_string(template) {
+        Ok(source) => source,
+        Err(err) => {
+            return Err(format!(
+                "failed to read template {} - {}",
+                template.display(),
+                err
+            ))
+        }
+    };
+    let mut handlebars = Handlebars::new();
+    handlebars.set_strict_mode(true);
+    handlebars.register_escape_fn(handlebars::no_escape);
+    loop {
+        let err = match handlebars.render_template(&source, variables) {
+            Ok(rendered) => return Ok(rendered),
+            Err(err) => err,
+        };
+        match err.reason() {
+            RenderErrorReason::MissingVariable(Some(name))
+                if !name.contains(['.', '/', '[']) && !variables.contains_key(name) =>
+            {
+                let value = prompt::text(&format!("Value of {} for {}:", name, template.display()))
+                    .map_err(|prompt_err| {
+                        format!(
+                            "no value for {} in template {}, set it in {} - {}",
+                            name,
+                            template.display(),
+                            config::LOCAL_FILE_NAME,
+                            prompt_err
+                        )
+                    })?;
+                remember(repo, name, &value)?;
+                variables.insert(name.to_owned(), value);
+            }
+            _ => {
+                return Err(format!(
+                    "failed to render template {} - {}",
+                    template.display(),
+                    err
+                ))
+            }
+        }
+    }
+}
+
+fn remember(repo: &Path, name: &str, value: &str) -> Result<(), String> {
+    log::debug!("saving {} to {}", name, config::LOCAL_FILE_NAME);
+    let mut local = config::load_local(repo)?;
+    local.variables.insert(name.to_owned(), value.to_owned());
+    config::save_local(repo, &local)?;
+    git::exclude(&git::open(repo)?, &format!("/{}", config::LOCAL_FILE_NAME))
+}
@@ -0,0 +1,157 @@
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Scan,
+    Plan,
+    Git,
+    Filesystem,
+    Network,
+}
+
+#[derive(Default, Serialize)]
+struct PhaseTiming {
+    ms: f64,
+    calls: u32,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The totals of every thread, so phases timed in parallel workers are reported too
+static TOTALS: Mutex<BTreeMap<Phase, (Duration, u32)>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// The phases being timed on this thread, innermost last
+    static STACK: RefCell<Vec<(Phase, Instant)>> = const { RefCell::new(Vec::new()) };
+}
+
+fn add_total(phase: Phase, duration: Duration, calls: u32) {
+    if let Ok(mut totals) = TOTALS.lock() {
+        let total = totals.entry(phase).or_default();
+        total.0 += duration;
+        total.1 += calls;
+    }
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Times `func` against `phase`, excluding time spent in nested phases
+pub fn time<T, F: FnOnce() -> T>(phase: Phase, func: F) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return func();
+    }
+
+    STACK.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        let now = Instant::now();
+        if let Some((parent, start)) = stack.last().copied() {
+            add_total(parent, now - start, 0);
+        }
+        stack.push((phase, now));
+    });
+
+    let result = func();
+
+    STACK.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        let now = Instant::now();
+        if let Some((phase, start)) = stack.pop() {
+            add_total(phase, now - start, 1);
+        }
+        if let Some((_, start)) = stack.last_mut() {
+            *start = now;
+        }
+    });
+
+    result
+}
+
+/// Runs `func` without timing it against the current phase, for waiting on threads that time
+/// their own phases
+pub fn untimed<T, F: FnOnce() -> T>(func: F) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return func();
+    }
+
+    STACK.with(|stack| {
+        if let Some((phase, start)) = stack.borrow().last().copied() {
+            add_total(phase, start.elapsed(), 0);
+        }
+    });
+
+    let result = func();
+
+    STACK.with(|stack| {
+        if let Some((_, start)) = stack.borrow_mut().last_mut() {
+            *start = Instant::now();
+        }
+    });
+
+    result
+}
+
+pub fn report(total: Duration, json: bool) -> String {
+    let totals = TOTALS
+        .lock()
+        .map(|totals| totals.clone())
+        .unwrap_or_default();
+    let measured: Duration = totals.values().map(|t| t.0).sum();
+
+    if json {
+        let phases: BTreeMap<Phase, PhaseTiming> = totals
+            .into_iter()
+            .map(|(phase, (duration, calls))| {
+                (
+                    phase,
+                    PhaseTiming {
+                        ms: as_ms(duration),
+                        calls,
+                    },
+                )
+            })
+            .collect();
+        return serde_json::json!({
+            "total_ms": as_ms(total),
+            "other_ms": as_ms(total.saturating_sub(measured)),
+            "phases": phases,
+        })
+        .to_string()
+            + "\n";
+    }
+
+    let mut rows = vec![vec![
+        "PHASE".to_owned(),
+        "TIME".to_owned(),
+        "CALLS".to_owned(),
+    ]];
+    for (phase, (duration, calls)) in totals {
+        rows.push(vec![
+            format!("{:?}", phase).to_lowercase(),
+            format!("{:.1}ms", as_ms(duration)),
+            calls.to_string(),
+        ]);
+    }
+    rows.push(vec![
+        "other".to_owned(),
+        format!("{:.1}ms", as_ms(total.saturating_sub(measured))),
+        String::new(),
+    ]);
+    rows.push(vec![
+        "total".to_owned(),
+        format!("{:.1}ms", as_ms(total)),
+        String::new(),
+    ]);
+    super::string::format_table(&rows)
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
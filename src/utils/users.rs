@@ -0,0 +1,155 @@
+#[cfg(unix)]
+use std::ffi::{CStr, CString};
+#[cfg(unix)]
+use std::mem::MaybeUninit;
+#[cfg(unix)]
+use std::os::raw::c_char;
+#[cfg(unix)]
+use std::ptr;
+
+#[cfg(unix)]
+const BUFFER_SIZE: usize = 16384;
+
+#[cfg(unix)]
+pub fn current_uid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+#[cfg(unix)]
+pub fn current_gid() -> u32 {
+    unsafe { libc::getegid() }
+}
+
+/// The name of the user, or its id when it has no name
+#[cfg(unix)]
+pub fn user_name(uid: u32) -> String {
+    let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+    let mut buffer = vec![0 as c_char; BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let found = unsafe {
+        libc::getpwuid_r(
+            uid,
+            passwd.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    } == 0
+        && !result.is_null();
+    match found {
+        true => unsafe { CStr::from_ptr(passwd.assume_init().pw_name) }
+            .to_string_lossy()
+            .into_owned(),
+        false => uid.to_string(),
+    }
+}
+
+/// The name of the group, or its id when it has no name
+#[cfg(unix)]
+pub fn group_name(gid: u32) -> String {
+    let mut group = MaybeUninit::<libc::group>::uninit();
+    let mut buffer = vec![0 as c_char; BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let found = unsafe {
+        libc::getgrgid_r(
+            gid,
+            group.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    } == 0
+        && !result.is_null();
+    match found {
+        true => unsafe { CStr::from_ptr(group.assume_init().gr_name) }
+            .to_string_lossy()
+            .into_owned(),
+        false => gid.to_string(),
+    }
+}
+
+/// The id of the user with the name, which may also be a numeric id
+#[cfg(unix)]
+pub fn uid(name: &str) -> Result<u32, String> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid);
+    }
+    let c_name = CString::new(name).map_err(|_| format!("invalid user name {}", name))?;
+    let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+    let mut buffer = vec![0 as c_char; BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let found = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            passwd.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    } == 0
+        && !result.is_null();
+    match found {
+        true => Ok(unsafe { passwd.assume_init() }.pw_uid),
+        false => Err(format!("no user named {}", name)),
+    }
+}
+
+/// The id of the group with the name, which may also be a numeric id
+#[cfg(unix)]
+pub fn gid(name: &str) -> Result<u32, String> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let c_name = CString::new(name).map_err(|_| format!("invalid group name {}", name))?;
+    let mut group = MaybeUninit::<libc::group>::uninit();
+    let mut buffer = vec![0 as c_char; BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let found = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            group.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    } == 0
+        && !result.is_null();
+    match found {
+        true => Ok(unsafe { group.assume_init() }.gr_gid),
+        false => Err(format!("no group named {}", name)),
+    }
+}
+
+#[cfg(windows)]
+pub fn current_uid() -> u32 {
+    0
+}
+
+#[cfg(windows)]
+pub fn current_gid() -> u32 {
+    0
+}
+
+#[cfg(windows)]
+pub fn user_name(uid: u32) -> String {
+    uid.to_string()
+}
+
+#[cfg(windows)]
+pub fn group_name(gid: u32) -> String {
+    gid.to_string()
+}
+
+/// The id of the user, which must be numeric on Windows
+#[cfg(windows)]
+pub fn uid(name: &str) -> Result<u32, String> {
+    name.parse()
+        .map_err(|_| format!("no user named {} on windows", name))
+}
+
+/// The id of the group, which must be numeric on Windows
+#[cfg(windows)]
+pub fn gid(name: &str) -> Result<u32, String> {
+    name.parse()
+        .map_err(|_| format!("no group named {} on windows", name))
+}
@@ -0,0 +1,55 @@
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+/// Watches the files in the directory, outside of .git, calling `on_change` with the changed
+/// paths once nothing has changed for the debounce duration. Runs until the watcher fails
+pub fn watch(
+    dir: &Path,
+    debounce: Duration,
+    mut on_change: impl FnMut(&[PathBuf]),
+) -> Result<(), String> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)
+        .map_err(|err| format!("failed to create file watcher - {}", err))?;
+    watcher
+        .watch(dir, RecursiveMode::Recursive)
+        .map_err(|err| format!("failed to watch {} - {}", dir.display(), err))?;
+    log::info!("watching {} for changes", dir.display());
+
+    let git_dir = dir.join(".git");
+    let mut changed: Vec<PathBuf> = Vec::new();
+    loop {
+        let received = match changed.is_empty() {
+            true => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            false => receiver.recv_timeout(debounce),
+        };
+        match received {
+            Ok(Ok(event)) if is_change(&event) => {
+                for path in event.paths {
+                    if !path.starts_with(&git_dir) && !changed.contains(&path) {
+                        log::trace!("{} changed", path.display());
+                        changed.push(path);
+                    }
+                }
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => log::warn!("failed to watch {} - {}", dir.display(), err),
+            Err(RecvTimeoutError::Timeout) => {
+                on_change(&changed);
+                changed.clear();
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(format!("stopped watching {}", dir.display()))
+            }
+        }
+    }
+}
+
+fn is_change(event: &Event) -> bool {
+    matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    )
+}